@@ -0,0 +1,6 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use squashfs::fuzzing::MalformedImage;
+
+fuzz_target!(|input: MalformedImage| squashfs::fuzzing::inode_table(&input.image));
@@ -0,0 +1,16 @@
+use std::env;
+use std::process::exit;
+
+fn main() {
+    let Some(dir) = env::args_os().nth(1) else {
+        eprintln!("usage: seed-corpus <dir>");
+        exit(2);
+    };
+    match squashfs::fuzzing::write_corpus(&dir) {
+        Ok(count) => println!("wrote {} seeds", count),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Result;
+
+use crate::image::Image;
+use crate::inode::{FileType, InodeHeader};
+use crate::path::SqshPath;
+use crate::ReadSeek;
+
+/// Number and size of the entries of one type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TypeStats {
+    pub count: u64,
+    /// Contents of regular files, listings of directories and targets of
+    /// symlinks, in bytes. Hard linked files are counted once.
+    pub size: u64,
+}
+
+/// Composition of a directory, as returned by `Image::dir_stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DirStats {
+    pub path: SqshPath,
+    /// Entries directly in the directory, by type.
+    pub entries: BTreeMap<FileType, TypeStats>,
+    /// All the entries below the directory, by type.
+    pub total: BTreeMap<FileType, TypeStats>,
+    /// Largest regular files below the directory, largest first.
+    pub largest: Vec<(SqshPath, u64)>,
+}
+
+impl DirStats {
+    /// Size of all the entries below the directory.
+    pub fn total_size(&self) -> u64 {
+        self.total.values().map(|t| t.size).sum()
+    }
+}
+
+fn size(inode: &InodeHeader) -> u64 {
+    match inode {
+        InodeHeader::Regular(r) => r.file_size() as u64,
+        InodeHeader::LRegular(r) => r.file_size(),
+        // without the implicit `.` and `..`
+        InodeHeader::Directory(d) => (d.file_size() as u64).saturating_sub(3),
+        InodeHeader::LDirectory(d) => (d.file_size() as u64).saturating_sub(3),
+        InodeHeader::Symlink(s) | InodeHeader::LSymlink(s) => s.target().len() as u64,
+        _ => 0,
+    }
+}
+
+fn add(stats: &mut BTreeMap<FileType, TypeStats>, file_type: FileType, count: u64, size: u64) {
+    let stats = stats.entry(file_type).or_default();
+    stats.count += count;
+    stats.size += size;
+}
+
+// Keeps the `n` largest files, largest first and then by path.
+fn keep_largest(files: &mut Vec<(SqshPath, u64)>, n: usize) {
+    files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    files.truncate(n);
+}
+
+pub(crate) fn dir_stats<R: ReadSeek>(
+    image: &Image<R>,
+    path: &[u8],
+    largest: usize,
+) -> Result<Vec<DirStats>> {
+    let mut dirs: Vec<DirStats> = vec![];
+    // index in `dirs` of each directory path, and of its parent
+    let mut index: HashMap<SqshPath, usize> = HashMap::new();
+    let mut parents: Vec<Option<usize>> = vec![];
+    let mut seen = HashSet::new();
+    for entry in image.walk(path)? {
+        let entry = entry?;
+        let parent = entry.path.parent().and_then(|p| index.get(&p).copied());
+        if let Some(parent) = parent {
+            let size = match seen.insert(entry.inode.inode_number()) {
+                true => size(&entry.inode),
+                false => 0,
+            };
+            add(&mut dirs[parent].entries, entry.inode.file_type(), 1, size);
+            if entry.inode.is_file() && size > 0 {
+                dirs[parent].largest.push((entry.path.clone(), size));
+            }
+        }
+        if entry.inode.is_dir() {
+            index.insert(entry.path.clone(), dirs.len());
+            parents.push(parent);
+            dirs.push(DirStats {
+                path: entry.path,
+                ..Default::default()
+            });
+        }
+    }
+
+    // directories come after their parent, so folding them in reverse adds
+    // each to its parent once complete
+    for i in (0..dirs.len()).rev() {
+        let entries = dirs[i].entries.clone();
+        for (file_type, stats) in entries {
+            add(&mut dirs[i].total, file_type, stats.count, stats.size);
+        }
+        keep_largest(&mut dirs[i].largest, largest);
+        if let Some(parent) = parents[i] {
+            let (total, files) = (dirs[i].total.clone(), dirs[i].largest.clone());
+            for (file_type, stats) in total {
+                add(&mut dirs[parent].total, file_type, stats.count, stats.size);
+            }
+            dirs[parent].largest.extend(files);
+        }
+    }
+    Ok(dirs)
+}
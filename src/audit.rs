@@ -0,0 +1,99 @@
+use std::fmt::Display;
+use std::io::Result;
+
+use crate::image::Image;
+use crate::inode::FileType;
+use crate::path::SqshPath;
+use crate::ReadSeek;
+
+/// Kind of entry reported by `Image::audit`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Issue {
+    /// File other than a directory with the setuid bit.
+    Setuid,
+    /// File other than a directory with the setgid bit. Directories use it
+    /// to pass their group on and aren't reported.
+    Setgid,
+    /// Regular file or directory anyone can write to. Directories with the
+    /// sticky bit, like `/tmp`, are not reported.
+    WorldWritable,
+    /// Block or character device outside of `/dev`.
+    DeviceOutsideDev,
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Issue::Setuid => "setuid",
+            Issue::Setgid => "setgid",
+            Issue::WorldWritable => "world-writable",
+            Issue::DeviceOutsideDev => "device outside /dev",
+        })
+    }
+}
+
+/// Entry reported by `Image::audit`, with the metadata that got it
+/// reported. Owners are given as ids, not names.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub path: SqshPath,
+    pub issue: Issue,
+    pub file_type: FileType,
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} ({:?} {:04o} {}:{})",
+            self.path, self.issue, self.file_type, self.mode, self.uid, self.gid
+        )
+    }
+}
+
+pub(crate) fn audit<R: ReadSeek>(image: &Image<R>, path: &[u8]) -> Result<Vec<Finding>> {
+    let ids = image.id_table()?;
+    let dev = SqshPath::new("/dev");
+    let mut findings = vec![];
+    for entry in image.walk(path)? {
+        let entry = entry?;
+        let inode = &entry.inode;
+        let mode = inode.mode();
+        let file_type = inode.file_type();
+        let is_dir = file_type == FileType::Directory;
+        let mut issues = vec![];
+        if !is_dir && mode & 0o4000 != 0 {
+            issues.push(Issue::Setuid);
+        }
+        if !is_dir && mode & 0o2000 != 0 {
+            issues.push(Issue::Setgid);
+        }
+        let sticky_dir = is_dir && mode & 0o1000 != 0;
+        if mode & 0o002 != 0 && (file_type == FileType::Regular || is_dir && !sticky_dir) {
+            issues.push(Issue::WorldWritable);
+        }
+        if matches!(file_type, FileType::BlockDevice | FileType::CharDevice)
+            && entry.path.strip_prefix(&dev).is_none()
+        {
+            issues.push(Issue::DeviceOutsideDev);
+        }
+        if issues.is_empty() {
+            continue;
+        }
+        let (uid, gid) = ids.owner(inode)?;
+        for issue in issues {
+            findings.push(Finding {
+                path: entry.path.clone(),
+                issue,
+                file_type,
+                mode,
+                uid,
+                gid,
+            });
+        }
+    }
+    Ok(findings)
+}
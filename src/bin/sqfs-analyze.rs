@@ -0,0 +1,56 @@
+use std::{
+    env, fs,
+    io::{BufReader, Error, ErrorKind, Result},
+};
+
+use squashfs::image::Image;
+
+// usage: sqfs-analyze [--top N] IMAGE [PATH]
+// Lists the N directories with the largest trees (10 by default), with the
+// composition of each by file type and its largest files.
+fn main() -> Result<()> {
+    let mut top = 10;
+    let mut positional = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--top" => {
+                let value = args.next().ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, format!("{} needs a value", arg))
+                })?;
+                top = value.parse().map_err(|e| {
+                    Error::new(ErrorKind::InvalidInput, format!("{}: {}", value, e))
+                })?;
+            }
+            _ if arg.starts_with("--") => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown option {}", arg),
+                ))
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let image = positional
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no image given"))?;
+    let path = positional.next().unwrap_or_else(|| "/".into());
+
+    let image = Image::new(BufReader::new(fs::File::open(image)?))?;
+    let mut dirs = image.dir_stats(path, 3)?;
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.total_size()));
+    for dir in dirs.iter().take(top) {
+        println!("{} {}", dir.total_size(), dir.path);
+        for (file_type, stats) in &dir.total {
+            println!(
+                "  {:?}: {} entries, {} bytes",
+                file_type, stats.count, stats.size
+            );
+        }
+        for (path, size) in &dir.largest {
+            println!("  {} {}", size, path);
+        }
+    }
+    Ok(())
+}
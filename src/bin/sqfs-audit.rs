@@ -0,0 +1,82 @@
+use std::{
+    env, fs,
+    io::{self, BufReader, Error, ErrorKind, Result},
+    process,
+};
+
+use squashfs::image::Image;
+use squashfs::report::{Report, Severity};
+
+// usage: sqfs-audit [--format text|json|sarif] [--check] [--blocks] IMAGE [PATH]
+// Lists setuid and setgid files, world-writable files and devices outside
+// /dev. With --check, the link counts are checked too and anything odd
+// found while reading the image is reported. With --blocks, every data
+// block and fragment is decompressed and those that fail are reported, which
+// is quicker than extracting to validate flashed media. Exits with status 1
+// if an error level item is reported.
+fn main() -> Result<()> {
+    let mut format = "text".to_string();
+    let mut check = false;
+    let mut blocks = false;
+    let mut positional = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = args.next().ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, format!("{} needs a value", arg))
+                })?;
+            }
+            "--check" => check = true,
+            "--blocks" => blocks = true,
+            _ if arg.starts_with("--") => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown option {}", arg),
+                ))
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let name = positional
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no image given"))?;
+    let path = positional.next().unwrap_or_else(|| "/".into());
+
+    let image = Image::new(BufReader::new(fs::File::open(&name)?))?;
+    let mut report = Report::new();
+    for finding in &image.audit(&path)? {
+        report.push(finding);
+    }
+    if blocks {
+        for block in &image.verify_blocks(&path)? {
+            report.push(block);
+        }
+    }
+    if check {
+        image.check_links()?;
+        for warning in &image.warnings() {
+            report.push(warning);
+        }
+    }
+    match format.as_str() {
+        "text" => {
+            for item in &report.items {
+                println!("{:?}: {}", item.severity, item.message);
+            }
+        }
+        "json" => report.write_json(io::stdout().lock())?,
+        "sarif" => report.write_sarif(&name, io::stdout().lock())?,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown format {}", format),
+            ))
+        }
+    }
+    if report.max_severity() == Some(Severity::Error) {
+        process::exit(1);
+    }
+    Ok(())
+}
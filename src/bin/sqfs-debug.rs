@@ -0,0 +1,143 @@
+use std::{
+    env, fs,
+    io::{BufReader, Error, ErrorKind, Result},
+};
+
+use squashfs::{
+    image::{Image, MetadataBlock, TableKind},
+    inode::read_inode_header,
+};
+
+const TABLES: [TableKind; 7] = [
+    TableKind::Inode,
+    TableKind::Directory,
+    TableKind::Fragment,
+    TableKind::Export,
+    TableKind::Id,
+    TableKind::Xattr,
+    TableKind::XattrId,
+];
+
+// usage: sqfs-debug IMAGE [INODE...]
+// INODE is either an offset into the decompressed inode table (decimal or
+// 0x prefixed) or a block:offset pair as stored in inode references.
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let fname = args.next().unwrap_or_else(|| "./test.sqfs".into());
+    let f = BufReader::new(fs::File::open(fname)?);
+    let image = Image::new(f)?;
+
+    println!("superblock:{}", image.superblock());
+    for description in image.superblock().flags().describe() {
+        println!("  {}", description);
+    }
+
+    for kind in TABLES {
+        let blocks = image.metadata_blocks(kind)?;
+        if blocks.is_empty() {
+            continue;
+        }
+        println!("{:?} table: {} blocks", kind, blocks.len());
+        let mut table_offset = 0;
+        for block in &blocks {
+            println!(
+                "  @{:#010x} {:>5} -> {:>5} bytes {} (table offset {:#x})",
+                block.start,
+                block.disk_size,
+                block.size,
+                if block.compressed {
+                    "compressed"
+                } else {
+                    "uncompressed"
+                },
+                table_offset
+            );
+            table_offset += block.size;
+        }
+    }
+
+    let inode_blocks = image.metadata_blocks(TableKind::Inode)?;
+    let inode_table = image.raw_table(TableKind::Inode)?;
+    let inode_table_start = image.superblock().inode_table_start() as u64;
+    for arg in args {
+        let offset = parse_inode_offset(&arg, &inode_blocks, inode_table_start)?;
+        if offset >= inode_table.len() {
+            println!("inode {}: offset {:#x} past end of table", arg, offset);
+            continue;
+        }
+        let mut record = &inode_table[offset..];
+        let inode = read_inode_header(&mut record, image.superblock());
+        let size = inode_table.len() - offset - record.len();
+        let bytes = &inode_table[offset..offset + size];
+
+        println!("inode {} (table offset {:#x}, {} bytes)", arg, offset, size);
+        annotate_base_header(bytes);
+        match inode {
+            Ok(inode) => println!("  {}", inode),
+            Err(e) => println!("  parse error: {}", e),
+        }
+        hexdump(bytes, offset);
+    }
+
+    Ok(())
+}
+
+fn parse_number(s: &str) -> Result<u64> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}: {}", s, e)))
+}
+
+fn parse_inode_offset(arg: &str, blocks: &[MetadataBlock], table_start: u64) -> Result<usize> {
+    let (block, offset) = match arg.split_once(':') {
+        Some((block, offset)) => (parse_number(block)?, parse_number(offset)?),
+        None => return Ok(parse_number(arg)? as usize),
+    };
+    let mut table_offset = 0;
+    for b in blocks {
+        if b.start - table_start == block {
+            return Ok(table_offset + offset as usize);
+        }
+        table_offset += b.size;
+    }
+    Err(Error::new(
+        ErrorKind::InvalidInput,
+        format!("no inode metadata block starts at {:#x}", block),
+    ))
+}
+
+fn annotate_base_header(bytes: &[u8]) {
+    if bytes.len() < 16 {
+        return;
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+    let u32_at =
+        |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+    println!("  +0  inode_type   {}", u16_at(0));
+    println!("  +2  mode         {:o}", u16_at(2));
+    println!("  +4  uid index    {}", u16_at(4));
+    println!("  +6  gid index    {}", u16_at(6));
+    println!("  +8  mtime        {}", u32_at(8));
+    println!("  +12 inode_number {}", u32_at(12));
+}
+
+fn hexdump(bytes: &[u8], base: usize) {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|b| match b {
+                0x20..=0x7e => *b as char,
+                _ => '.',
+            })
+            .collect();
+        println!(
+            "  {:08x}  {:<47}  |{}|",
+            base + i * 16,
+            hex.join(" "),
+            ascii
+        );
+    }
+}
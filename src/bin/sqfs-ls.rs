@@ -0,0 +1,74 @@
+use std::{
+    env, fs,
+    io::{self, BufReader, Error, ErrorKind, Result, Write},
+};
+
+use squashfs::image::Image;
+
+// usage: sqfs-ls [--format text|json|csv|tree] IMAGE [PATH]
+// Lists the tree at PATH, the root by default, sorted by name. The text
+// format is meant for people, like ls -l; json and csv are for scripts and
+// tree draws the hierarchy.
+fn main() -> Result<()> {
+    let mut format = "text".to_string();
+    let mut positional = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = args.next().ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, format!("{} needs a value", arg))
+                })?;
+            }
+            _ if arg.starts_with("--") => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown option {}", arg),
+                ))
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let name = positional
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no image given"))?;
+    let path = positional.next().unwrap_or_else(|| "/".into());
+
+    let image = Image::new(BufReader::new(fs::File::open(&name)?))?;
+    let manifest = image.manifest(&path)?;
+    let out = io::stdout().lock();
+    match format.as_str() {
+        "text" => {
+            let mut out = io::BufWriter::new(out);
+            for entry in &manifest.entries {
+                let size = match (entry.size, entry.rdev) {
+                    (Some(size), _) => size.to_string(),
+                    (_, Some(rdev)) => format!(
+                        "{},{}",
+                        (rdev & 0xfff00) >> 8,
+                        (rdev & 0xff) | ((rdev >> 12) & 0xfff00)
+                    ),
+                    _ => String::new(),
+                };
+                write!(
+                    out,
+                    "{:?} {:04o} {}/{} {:>10} {}",
+                    entry.file_type, entry.mode, entry.uid, entry.gid, size, entry.path
+                )?;
+                if let Some(target) = &entry.link_target {
+                    write!(out, " -> {}", String::from_utf8_lossy(target))?;
+                }
+                writeln!(out)?;
+            }
+            out.flush()
+        }
+        "json" => manifest.write_json(out),
+        "csv" => manifest.write_csv(out),
+        "tree" => manifest.write_tree(out),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unknown format {}", format),
+        )),
+    }
+}
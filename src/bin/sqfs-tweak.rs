@@ -0,0 +1,76 @@
+use std::{
+    env,
+    fs::OpenOptions,
+    io::{Error, ErrorKind, Result},
+};
+
+use squashfs::superblock::{patch_superblock, Flags};
+use squashfs::INVALID_BLK;
+
+// Flags that can be changed without rewriting any table.
+const FLAGS: [(&str, Flags); 3] = [
+    ("exportable", Flags::NFSEXPORT_TABLE_EXISTS),
+    ("duplicates", Flags::DATA_DEDUPLICATED),
+    ("always-fragments", Flags::FRAGMENTS_ALWAYS_GENERATED),
+];
+
+// usage: sqfs-tweak [--mkfs-time SECONDS] [--set-flag FLAG]... [--clear-flag FLAG]... IMAGE
+// FLAG is one of exportable, duplicates or always-fragments. Clearing
+// exportable also drops the export table pointer; setting it requires the
+// image to still have one. The image is left untouched if the result
+// doesn't validate.
+fn main() -> Result<()> {
+    let mut mkfs_time = None;
+    let mut set = Flags::empty();
+    let mut clear = Flags::empty();
+    let mut image = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, format!("{} needs a value", arg))
+            })
+        };
+        match arg.as_str() {
+            "--mkfs-time" => {
+                let value = value()?;
+                let time = value.parse().map_err(|e| {
+                    Error::new(ErrorKind::InvalidInput, format!("{}: {}", value, e))
+                })?;
+                mkfs_time = Some(time);
+            }
+            "--set-flag" => set |= parse_flag(&value()?)?,
+            "--clear-flag" => clear |= parse_flag(&value()?)?,
+            _ if arg.starts_with("--") => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unknown option {}", arg),
+                ))
+            }
+            _ => image = Some(arg),
+        }
+    }
+    let image = image.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no image given"))?;
+
+    let mut file = OpenOptions::new().read(true).write(true).open(image)?;
+    let sb = patch_superblock(&mut file, |sb| {
+        if let Some(time) = mkfs_time {
+            sb.set_mkfs_time(time);
+        }
+        sb.set_flags((sb.flags() | set) - clear);
+        if clear.contains(Flags::NFSEXPORT_TABLE_EXISTS) {
+            sb.set_export_table_start(INVALID_BLK);
+        }
+    })?;
+    println!("mkfs_time {}", sb.mkfs_time());
+    println!("flags {:?}", sb.flags());
+    Ok(())
+}
+
+fn parse_flag(name: &str) -> Result<Flags> {
+    FLAGS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, flag)| *flag)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("unknown flag {}", name)))
+}
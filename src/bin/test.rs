@@ -11,16 +11,16 @@ fn main() -> Result<()> {
     let fname = fname.get_or_insert("./test.sqfs".into());
     let f = BufReader::new(fs::File::open(fname)?);
 
-    let mut image = Image::new(f)?;
+    let image = Image::new(f)?;
 
-    let (fragments, id_table, lookup_table, _root_inode, inode_headers) = image.read_fs()?;
+    let fs = image.read_fs()?;
 
     let mut directories = 0;
     let mut regulars = 0;
     let mut symlinks = 0;
     let mut devs = 0;
     let mut ipcs = 0;
-    for inode_header in &inode_headers {
+    for inode_header in fs.inodes() {
         match inode_header {
             InodeHeader::Directory(_) | InodeHeader::LDirectory(_) => directories += 1,
             InodeHeader::Regular(_) | InodeHeader::LRegular(_) => regulars += 1,
@@ -32,7 +32,7 @@ fn main() -> Result<()> {
 
     eprintln!(
         "inodes {}, directories {}, regulars {}, symlinks {}, devs {}, ipcs {}",
-        inode_headers.len(),
+        fs.inodes().len(),
         directories,
         regulars,
         symlinks,
@@ -40,11 +40,11 @@ fn main() -> Result<()> {
         ipcs
     );
 
-    eprintln!("fragments.len {}", fragments.len());
+    eprintln!("fragments.len {}", fs.fragments().len());
 
-    eprintln!("lookup_table.len {}", lookup_table.len());
+    eprintln!("lookup_table.len {}", fs.export_table().len());
 
-    eprintln!("id_table {:?}", id_table.ids());
+    eprintln!("id_table {:?}", fs.id_table().ids());
 
     // if let Some(_filename) = args.next() && let InodeHeader::Directory(_dir) = root_inode {
     //     let root = image.opendir(&dir)?;
@@ -0,0 +1,169 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Bounds of one cache. An entry is evicted when either limit is exceeded;
+/// a limit of 0 disables the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheLimits {
+    pub max_entries: usize,
+    /// Approximate, counting the decoded size of entries.
+    pub max_bytes: usize,
+}
+
+impl CacheLimits {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        Self {
+            max_entries,
+            max_bytes,
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(0, 0)
+    }
+}
+
+/// Limits of the caches of an `Image`, set with `Image::set_cache_config`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Decoded inodes, by inode number.
+    pub inodes: CacheLimits,
+    /// Decompressed inode and directory table blocks, by position in the
+    /// image.
+    pub metadata_blocks: CacheLimits,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            inodes: CacheLimits::new(4096, 1 << 20),
+            metadata_blocks: CacheLimits::new(64, 64 * 8192),
+        }
+    }
+}
+
+impl CacheConfig {
+    /// The default limits, shrunk to take at most about `bytes` in all.
+    pub fn within(bytes: usize) -> Self {
+        let default = Self::default();
+        let total = default.inodes.max_bytes + default.metadata_blocks.max_bytes;
+        if bytes >= total {
+            return default;
+        }
+        let scale = |limits: CacheLimits| {
+            let max_bytes = limits.max_bytes * bytes / total;
+            CacheLimits::new(limits.max_entries, max_bytes)
+        };
+        Self {
+            inodes: scale(default.inodes),
+            metadata_blocks: scale(default.metadata_blocks),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Current number of entries.
+    pub entries: usize,
+    /// Current approximate size of the entries.
+    pub bytes: usize,
+}
+
+/// Statistics of the caches of an `Image`, as returned by
+/// `Image::cache_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImageCacheStats {
+    pub inodes: CacheStats,
+    pub metadata_blocks: CacheStats,
+}
+
+/// Least recently used cache bounded by `CacheLimits`.
+#[derive(Clone, Debug)]
+pub(crate) struct LruCache<K, V> {
+    limits: CacheLimits,
+    entries: HashMap<K, (V, usize, u64)>,
+    // last use of each key, oldest first
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> LruCache<K, V> {
+    pub(crate) fn new(limits: CacheLimits) -> Self {
+        Self {
+            limits,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &K) -> Option<V> {
+        let Some((value, _, used)) = self.entries.get_mut(key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        self.order.remove(used);
+        self.tick += 1;
+        *used = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(value.clone())
+    }
+
+    /// Adds an entry taking about `size` bytes, evicting the least recently
+    /// used ones to stay within the limits. An entry too large to keep
+    /// still replaces the one stored under `key`.
+    pub(crate) fn insert(&mut self, key: K, value: V, size: usize) {
+        if size > self.limits.max_bytes || self.limits.max_entries == 0 {
+            self.remove(&key);
+            return;
+        }
+        self.tick += 1;
+        let entry = (value, size, self.tick);
+        if let Some((_, old_size, used)) = self.entries.insert(key.clone(), entry) {
+            self.order.remove(&used);
+            self.stats.bytes -= old_size;
+        }
+        self.order.insert(self.tick, key);
+        self.stats.bytes += size;
+        self.shrink();
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) {
+        if let Some((_, size, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+            self.stats.bytes -= size;
+        }
+    }
+
+    pub(crate) fn set_limits(&mut self, limits: CacheLimits) {
+        self.limits = limits;
+        self.shrink();
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            ..self.stats
+        }
+    }
+
+    fn shrink(&mut self) {
+        while self.entries.len() > self.limits.max_entries
+            || self.stats.bytes > self.limits.max_bytes
+        {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, size, _)) = self.entries.remove(&oldest) {
+                self.stats.bytes -= size;
+                self.stats.evictions += 1;
+            }
+        }
+    }
+}
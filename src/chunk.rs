@@ -0,0 +1,182 @@
+use std::io::{BufRead, Error, ErrorKind, Read, Result, Write};
+
+use sha2::{Digest as _, Sha256};
+
+use crate::image::Image;
+use crate::index::Digest;
+use crate::ReadSeek;
+
+const CHUNK_INDEX_MAGIC: &[u8; 8] = b"SQFSCIDX";
+
+/// A content defined chunk of a byte stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub offset: u64,
+    pub size: u32,
+    pub digest: Digest,
+}
+
+/// Splits streams into chunks whose boundaries depend only on the nearby
+/// content, like casync does, so an insertion or removal only changes the
+/// chunks around it and the rest can be fetched from a chunk store.
+#[derive(Clone, Copy, Debug)]
+pub struct Chunker {
+    min_size: usize,
+    max_size: usize,
+    mask: u64,
+}
+
+impl Default for Chunker {
+    /// casync's defaults: 16 KiB minimum, 64 KiB average, 256 KiB maximum.
+    fn default() -> Self {
+        Self::new(16 * 1024, 64 * 1024, 256 * 1024)
+    }
+}
+
+// Gear hash table; any fixed set of random values works, so it is
+// generated with splitmix64 instead of being spelled out.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state = 0x5173_6873_6673_2121u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+const GEAR: [u64; 256] = gear_table();
+
+impl Chunker {
+    /// Chunk sizes are kept within `min_size..=max_size`. `avg_size` is
+    /// rounded up to a power of two.
+    pub fn new(min_size: usize, avg_size: usize, max_size: usize) -> Self {
+        assert!(0 < min_size && min_size <= avg_size && avg_size <= max_size);
+        Self {
+            min_size,
+            max_size,
+            // the top bits of the gear hash depend on the last 64 bytes
+            mask: !(u64::MAX >> avg_size.next_power_of_two().trailing_zeros()),
+        }
+    }
+
+    /// Returns the length of the first chunk of `data`, or None if more
+    /// data is needed to find the boundary.
+    fn boundary(&self, data: &[u8], eof: bool) -> Option<usize> {
+        if data.len() <= self.min_size {
+            return eof.then_some(data.len());
+        }
+        let mut hash = 0u64;
+        let end = data.len().min(self.max_size);
+        for (i, c) in data[..end].iter().enumerate() {
+            hash = (hash << 1).wrapping_add(GEAR[*c as usize]);
+            if i >= self.min_size && hash & self.mask == 0 {
+                return Some(i + 1);
+            }
+        }
+        (end == self.max_size || eof).then_some(end)
+    }
+
+    pub fn chunks<R: Read>(&self, mut reader: R) -> Result<ChunkIndex> {
+        let mut chunks = vec![];
+        let mut buf = Vec::with_capacity(2 * self.max_size);
+        let mut offset = 0u64;
+        let mut eof = false;
+        loop {
+            while !eof && buf.len() < self.max_size {
+                let filled = buf.len();
+                buf.resize(2 * self.max_size, 0);
+                let read = reader.read(&mut buf[filled..])?;
+                buf.truncate(filled + read);
+                eof = read == 0;
+            }
+            let len = match self.boundary(&buf, eof) {
+                Some(0) | None => return Ok(ChunkIndex(chunks)),
+                Some(len) => len,
+            };
+            chunks.push(Chunk {
+                offset,
+                size: len as u32,
+                digest: Sha256::digest(&buf[..len]).into(),
+            });
+            offset += len as u64;
+            buf.drain(..len);
+        }
+    }
+
+    /// Chunks the raw bytes of a whole image.
+    pub fn chunk_image<R: Read>(&self, image: R) -> Result<ChunkIndex> {
+        self.chunks(image)
+    }
+
+    /// Chunks each regular file of an image on its own. Hard linked files
+    /// are listed under every path.
+    pub fn chunk_files<R: ReadSeek>(&self, image: &Image<R>) -> Result<Vec<(Vec<u8>, ChunkIndex)>> {
+        let mut files = vec![];
+        for entry in image.walk("/")? {
+            let entry = entry?;
+            if entry.inode.is_file() {
+                let index = self.chunks(image.open_file(&entry.inode)?)?;
+                files.push((entry.path.into(), index));
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Chunks of a stream, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChunkIndex(Vec<Chunk>);
+
+impl ChunkIndex {
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.0
+    }
+
+    pub fn size(&self) -> u64 {
+        self.0.last().map(|c| c.offset + c.size as u64).unwrap_or(0)
+    }
+
+    // struct chunk_index {
+    // 	0 8 char			magic[8];
+    // 	8 8 unsigned long long		chunks;
+    // 	struct { unsigned int size; char digest[32]; } chunk[0];
+    // };
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        writer.write_all(CHUNK_INDEX_MAGIC)?;
+        writer.write_all(&(self.0.len() as u64).to_le_bytes())?;
+        for chunk in &self.0 {
+            writer.write_all(&chunk.size.to_le_bytes())?;
+            writer.write_all(&chunk.digest)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: BufRead>(mut reader: R) -> Result<Self> {
+        let mut header = [0; 16];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != CHUNK_INDEX_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "bad chunk index magic"));
+        }
+        let count = u64::from_le_bytes(header[8..].try_into().unwrap());
+        let mut chunks = vec![];
+        let mut offset = 0;
+        for _ in 0..count {
+            let mut entry = [0; 36];
+            reader.read_exact(&mut entry)?;
+            let size = u32::from_le_bytes(entry[..4].try_into().unwrap());
+            chunks.push(Chunk {
+                offset,
+                size,
+                digest: entry[4..].try_into().unwrap(),
+            });
+            offset += size as u64;
+        }
+        Ok(Self(chunks))
+    }
+}
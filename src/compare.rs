@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::io::Result;
+
+use crate::image::Image;
+use crate::inode::{FileType, InodeHeader};
+use crate::path::SqshPath;
+use crate::utils::same_content;
+use crate::walk::{Order, WalkOptions};
+use crate::xattr::Xattr;
+use crate::ReadSeek;
+
+/// Difference between two images, as returned by `Image::compare`. Values
+/// are given as in the first image, then as in the second. Owners are
+/// `(uid, gid)` pairs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    /// Only in the second image.
+    Added,
+    /// Only in the first image.
+    Removed,
+    FileType(FileType, FileType),
+    Mode(u16, u16),
+    Owner((u32, u32), (u32, u32)),
+    Mtime(u32, u32),
+    Size(u64, u64),
+    Content,
+    LinkTarget(Vec<u8>, Vec<u8>),
+    /// Device number of a block or character device.
+    Device(u32, u32),
+    /// Extended attributes, sorted by name.
+    Xattrs(Vec<Xattr>, Vec<Xattr>),
+}
+
+/// Controls what `Image::compare` counts as a difference. Everything is
+/// compared by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompareOptions {
+    ignore_mtime: bool,
+    ignore_owner: bool,
+    ignore_mode: bool,
+    ignore_xattrs: bool,
+}
+
+impl CompareOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ignores modification times, which differ between builds of the same
+    /// tree unless `SOURCE_DATE_EPOCH` or the like is set.
+    pub fn ignore_mtime(&mut self, ignore: bool) -> &mut Self {
+        self.ignore_mtime = ignore;
+        self
+    }
+
+    pub fn ignore_owner(&mut self, ignore: bool) -> &mut Self {
+        self.ignore_owner = ignore;
+        self
+    }
+
+    pub fn ignore_mode(&mut self, ignore: bool) -> &mut Self {
+        self.ignore_mode = ignore;
+        self
+    }
+
+    pub fn ignore_xattrs(&mut self, ignore: bool) -> &mut Self {
+        self.ignore_xattrs = ignore;
+        self
+    }
+
+    /// Only compares the tree itself: paths, file types, file contents,
+    /// symlink targets and device numbers.
+    pub fn content_only(&mut self) -> &mut Self {
+        self.ignore_mtime(true)
+            .ignore_owner(true)
+            .ignore_mode(true)
+            .ignore_xattrs(true)
+    }
+}
+
+fn tree<R: ReadSeek>(image: &Image<R>, path: &[u8]) -> Result<BTreeMap<SqshPath, InodeHeader>> {
+    let mut options = WalkOptions::new();
+    options.order(Order::ByName);
+    image
+        .walk_with(path, &options)?
+        .map(|entry| entry.map(|e| (e.path, e.inode)))
+        .collect()
+}
+
+fn file_size(inode: &InodeHeader) -> Option<u64> {
+    match inode {
+        InodeHeader::Regular(r) => Some(r.file_size() as u64),
+        InodeHeader::LRegular(r) => Some(r.file_size()),
+        _ => None,
+    }
+}
+
+fn rdev(inode: &InodeHeader) -> Option<u32> {
+    match inode {
+        InodeHeader::Dev(d) => Some(d.rdev()),
+        InodeHeader::LDev(d) => Some(d.rdev()),
+        _ => None,
+    }
+}
+
+// Xattrs of `inode`, in an order that doesn't depend on how they are stored.
+fn sorted_xattrs<R: ReadSeek>(image: &Image<R>, inode: &InodeHeader) -> Result<Vec<Xattr>> {
+    let mut xattrs = image.xattrs(inode)?;
+    xattrs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(xattrs)
+}
+
+fn below_gone(path: &SqshPath, gone: &[SqshPath]) -> bool {
+    gone.iter().any(|dir| path.strip_prefix(dir).is_some())
+}
+
+pub(crate) fn compare<R: ReadSeek, S: ReadSeek>(
+    image: &Image<R>,
+    other: &Image<S>,
+    path: &[u8],
+    options: &CompareOptions,
+) -> Result<Vec<(SqshPath, Difference)>> {
+    let old = tree(image, path)?;
+    let mut new = tree(other, path)?;
+    let (old_ids, new_ids) = (image.id_table()?, other.id_table()?);
+    let mut differences = vec![];
+    // directories in only one image, or replaced, whose contents are not
+    // reported
+    let mut gone: Vec<SqshPath> = vec![];
+    for (path, a) in old {
+        let b = new.remove(&path);
+        if below_gone(&path, &gone) {
+            continue;
+        }
+        let Some(b) = b else {
+            if a.is_dir() {
+                gone.push(path.clone());
+            }
+            differences.push((path, Difference::Removed));
+            continue;
+        };
+        if a.file_type() != b.file_type() {
+            if a.is_dir() || b.is_dir() {
+                gone.push(path.clone());
+            }
+            differences.push((path, Difference::FileType(a.file_type(), b.file_type())));
+            continue;
+        }
+
+        let mut push = |difference| differences.push((path.clone(), difference));
+        let symlink = a.file_type() == FileType::Symlink;
+        if !options.ignore_mode && !symlink && a.mode() & 0o7777 != b.mode() & 0o7777 {
+            push(Difference::Mode(a.mode() & 0o7777, b.mode() & 0o7777));
+        }
+        if !options.ignore_owner {
+            let (owner_a, owner_b) = (old_ids.owner(&a)?, new_ids.owner(&b)?);
+            if owner_a != owner_b {
+                push(Difference::Owner(owner_a, owner_b));
+            }
+        }
+        if !options.ignore_mtime && a.mtime() != b.mtime() {
+            push(Difference::Mtime(a.mtime(), b.mtime()));
+        }
+        if !options.ignore_xattrs {
+            let (xattrs_a, xattrs_b) = (sorted_xattrs(image, &a)?, sorted_xattrs(other, &b)?);
+            if xattrs_a != xattrs_b {
+                push(Difference::Xattrs(xattrs_a, xattrs_b));
+            }
+        }
+        if let (Some(size_a), Some(size_b)) = (file_size(&a), file_size(&b)) {
+            if size_a != size_b {
+                push(Difference::Size(size_a, size_b));
+            } else if !same_content(image.open_file(&a)?, other.open_file(&b)?)? {
+                push(Difference::Content);
+            }
+        }
+        if let (
+            InodeHeader::Symlink(s) | InodeHeader::LSymlink(s),
+            InodeHeader::Symlink(t) | InodeHeader::LSymlink(t),
+        ) = (&a, &b)
+        {
+            if s.target() != t.target() {
+                push(Difference::LinkTarget(
+                    s.target().to_vec(),
+                    t.target().to_vec(),
+                ));
+            }
+        }
+        if let (Some(rdev_a), Some(rdev_b)) = (rdev(&a), rdev(&b)) {
+            if rdev_a != rdev_b {
+                push(Difference::Device(rdev_a, rdev_b));
+            }
+        }
+    }
+    for (path, b) in new {
+        if below_gone(&path, &gone) {
+            continue;
+        }
+        if b.is_dir() {
+            gone.push(path.clone());
+        }
+        differences.push((path, Difference::Added));
+    }
+    differences.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(differences)
+}
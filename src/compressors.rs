@@ -1,14 +1,25 @@
 use bitflags::bitflags;
 use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
 
 use std::fmt::{self, Debug, Display};
-use std::io::{copy, Read, Result, Write};
-use std::{mem, slice};
-use xz2::read::XzDecoder;
-use xz2::stream::Stream;
+#[cfg(not(feature = "native-codecs"))]
+use std::io::BufReader;
+use std::io::{copy, Error, ErrorKind, Read, Result, Write};
+use std::sync::Arc;
+#[cfg(feature = "native-codecs")]
+use xz2::{
+    read::XzDecoder,
+    stream::{Check, Filters, LzmaOptions, Stream},
+    write::XzEncoder,
+};
 
-use crate::utils::{get_set_field, get_set_field_tuple};
-use crate::ReadSeek;
+#[cfg(not(any(feature = "native-codecs", feature = "pure-rust-codecs")))]
+compile_error!("either the native-codecs or the pure-rust-codecs feature is required");
+
+use crate::utils::{get_set_field, get_set_field_tuple, split_array};
+use crate::{ReadSeek, METADATA_SIZE};
 
 pub trait Decompress {
     fn decompress<R: Read + ?Sized, W: Write + ?Sized>(
@@ -18,11 +29,72 @@ pub trait Decompress {
     ) -> Result<u64>;
 }
 
+pub trait Compress {
+    fn compress<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> Result<u64>;
+}
+
+/// Names of the compressors squashfs defines, by superblock id from 1.
+const NAMES: [&str; 6] = ["gzip", "lzo", "lzma", "xz", "lz4", "zstd"];
+/// Ids of the compressors this crate reads and writes.
+pub const SUPPORTED_IDS: [u16; 3] = [1, 4, 6];
+
+/// Name of the compressor with superblock id `id`, if squashfs defines it.
+pub fn compressor_name(id: u16) -> Option<&'static str> {
+    NAMES.get((id as usize).checked_sub(1)?).copied()
+}
+
+/// Compressor of an image that this crate can't decompress, carried by the
+/// `Unsupported` error of opening it. Reach it with
+/// `error.get_ref().and_then(|e| e.downcast_ref::<UnsupportedCompressor>())`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnsupportedCompressor {
+    pub id: u16,
+    /// Name squashfs gives the id, or `unknown`.
+    pub name: &'static str,
+}
+
+impl UnsupportedCompressor {
+    pub(crate) fn error(id: u16) -> Error {
+        let name = compressor_name(id).unwrap_or("unknown");
+        Error::new(ErrorKind::Unsupported, Self { id, name })
+    }
+}
+
+impl Display for UnsupportedCompressor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} compression (id {}) is not supported, only",
+            self.name, self.id
+        )?;
+        for (i, id) in SUPPORTED_IDS.iter().enumerate() {
+            let separator = match i {
+                0 => " ",
+                _ if i == SUPPORTED_IDS.len() - 1 => " and ",
+                _ => ", ",
+            };
+            write!(
+                f,
+                "{}{}",
+                separator,
+                compressor_name(*id).unwrap_or_default()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UnsupportedCompressor {}
+
 #[derive(Clone, Debug)]
 pub enum Compressor {
     GZIP(GzipCompressor),
     XZ(XZCompressor),
-    // ZSTD(ZSTDCompressor),
+    ZSTD(ZSTDCompressor),
     // LZO,
     // LZMA,
     // LZ4,
@@ -56,13 +128,55 @@ impl Compressor {
                 };
                 Ok(Compressor::XZ(XZCompressor::new(opts)))
             }
+            6 => {
+                let mut zstd = ZSTDCompressor::new(None);
+                if compressor_options_present {
+                    reader.read_exact(&mut zstd.0)?;
+                    // anything after the level is a dictionary
+                    let mut dictionary = vec![];
+                    reader
+                        .take(MAX_ZSTD_DICTIONARY_SIZE as u64 + 1)
+                        .read_to_end(&mut dictionary)?;
+                    if !dictionary.is_empty() {
+                        zstd.set_dictionary(Some(dictionary.into()))?;
+                    }
+                }
+                Ok(Compressor::ZSTD(zstd))
+            }
             // 2 => Ok(Self::LZO),
             // 3 => Ok(Self::LZMA),
             // 5 => Ok(Self::LZ4),
-            // 6 => Ok(Self::ZSTD),
-            _ => todo!(),
+            id => Err(UnsupportedCompressor::error(id)),
+        }
+    }
+
+    /// Compressor id as stored in the superblock, 0 for `Undefined`.
+    pub fn id(&self) -> u16 {
+        match self {
+            Self::GZIP(_) => 1,
+            Self::XZ(_) => 4,
+            Self::ZSTD(_) => 6,
+            Self::Undefined => 0,
         }
     }
+
+    /// Writes the compressor options block, which follows the superblock
+    /// when `Flags::COMPRESSOR_OPTIONS_PRESENT` is set.
+    pub fn write_options<W: Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        let options: &[u8] = match self {
+            Self::GZIP(c) => &c.0,
+            Self::XZ(c) => &c.to_bytes(),
+            Self::ZSTD(c) => &c.0,
+            Self::Undefined => return Err(UnsupportedCompressor::error(0)),
+        };
+        writer.write_all(options)?;
+        let dictionary = match self {
+            Self::ZSTD(c) => c.dictionary().unwrap_or_default(),
+            _ => &[],
+        };
+        writer.write_all(dictionary)?;
+        Ok((options.len() + dictionary.len()) as u64)
+    }
 }
 
 impl Display for Compressor {
@@ -70,29 +184,45 @@ impl Display for Compressor {
         match self {
             Self::GZIP(c) => Display::fmt(c, f),
             Self::XZ(c) => Display::fmt(c, f),
-            _ => unimplemented!(),
+            Self::ZSTD(c) => Display::fmt(c, f),
+            Self::Undefined => f.write_str("undefined"),
         }
     }
 }
 
 impl Decompress for Compressor {
-    fn decompress<R: ?Sized, W: ?Sized>(&self, reader: &mut R, writer: &mut W) -> Result<u64>
-    where
-        R: Read,
-        W: Write,
-    {
+    fn decompress<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> Result<u64> {
         match self {
             Compressor::GZIP(c) => Decompress::decompress(c, reader, writer),
             Compressor::XZ(c) => Decompress::decompress(c, reader, writer),
-            Compressor::Undefined => unimplemented!(),
+            Compressor::ZSTD(c) => Decompress::decompress(c, reader, writer),
+            Compressor::Undefined => Err(UnsupportedCompressor::error(0)),
+        }
+    }
+}
+
+impl Compress for Compressor {
+    fn compress<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> Result<u64> {
+        match self {
+            Compressor::GZIP(c) => Compress::compress(c, reader, writer),
+            Compressor::XZ(c) => Compress::compress(c, reader, writer),
+            Compressor::ZSTD(c) => Compress::compress(c, reader, writer),
+            Compressor::Undefined => Err(UnsupportedCompressor::error(0)),
         }
     }
 }
 
 impl Default for Compressor {
     fn default() -> Self {
-        // Self::ZSTD(Default::default())
-        todo!()
+        Self::ZSTD(Default::default())
     }
 }
 
@@ -113,26 +243,35 @@ impl Default for Compressor {
 // }
 
 bitflags! {
+    /// Branch filters mksquashfs may try before LZMA2, keeping whichever
+    /// gives the smallest block.
     pub struct XZFilters: u32 {
-        // const X86 = 0x0001;
-        // const POWER_PC = 0x0002;
-        // const IA54 = 0x0004;
-        // const ARM = 0x0008;
-        // const ARM_THUMB = 0x0010;
-        // const SPARC = 0x0020;
-        const X86 = 0x0004;
-        const POWER_PC = 0x0005;
-        const IA64 = 0x0006;
-        const ARM = 0x0007;
-        const ARM_THUMB = 0x008;
-        const SPARC = 0x009;
-        const UNKNOWN = 0xffff;
+        const X86 = 0x0001;
+        const POWER_PC = 0x0002;
+        const IA64 = 0x0004;
+        const ARM = 0x0008;
+        const ARM_THUMB = 0x0010;
+        const SPARC = 0x0020;
     }
 }
 
+// Adds a branch filter to an xz filter chain.
+#[cfg(feature = "native-codecs")]
+type AddFilter = fn(&mut Filters) -> &mut Filters;
+
+#[cfg(feature = "native-codecs")]
+const XZ_BRANCH_FILTERS: [(XZFilters, AddFilter); 6] = [
+    (XZFilters::X86, Filters::x86),
+    (XZFilters::POWER_PC, Filters::powerpc),
+    (XZFilters::IA64, Filters::ia64),
+    (XZFilters::ARM, Filters::arm),
+    (XZFilters::ARM_THUMB, Filters::arm_thumb),
+    (XZFilters::SPARC, Filters::sparc),
+];
+
 impl XZFilters {
     pub fn from_le_bytes(bytes: [u8; 4]) -> Self {
-        unsafe { Self::from_bits_unchecked(u32::from_le_bytes(bytes)) }
+        Self::from_bits_truncate(u32::from_le_bytes(bytes))
     }
 
     pub fn to_le_bytes(&self) -> [u8; 4] {
@@ -146,8 +285,9 @@ impl Display for XZFilters {
     }
 }
 
+const XZ_DEFAULT_DICTIONARY_SIZE: u32 = 128 * 1024;
+
 #[derive(Clone, Debug)]
-#[repr(C)]
 pub struct XZCompressor {
     dictionary_size: [u8; 4],
     // dictionary_size: u32,
@@ -165,14 +305,28 @@ impl XZCompressor {
     const SIZE: usize = 8;
 
     fn new(bytes: Option<[u8; Self::SIZE]>) -> Self {
-        // assert_eq!(Self::SIZE, mem::size_of::<Self>());
-        let mut xzc = unsafe { mem::zeroed() };
-        unsafe {
-            let config_slice = slice::from_raw_parts_mut(&mut xzc as *mut _ as *mut u8, Self::SIZE);
-            // reader.read_exact(config_slice)?;
-            let bytes = bytes.unwrap_or_default();
-            config_slice.copy_from_slice(&bytes);
+        let bytes = bytes.unwrap_or_default();
+        let mut rest = &bytes[..];
+        Self {
+            dictionary_size: split_array(&mut rest),
+            filters: split_array(&mut rest),
         }
+    }
+
+    fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..4].copy_from_slice(&self.dictionary_size);
+        bytes[4..].copy_from_slice(&self.filters);
+        bytes
+    }
+}
+
+const _: () = assert!(std::mem::size_of::<XZCompressor>() == XZCompressor::SIZE);
+
+impl Default for XZCompressor {
+    fn default() -> Self {
+        let mut xzc = Self::new(None);
+        xzc.set_dictionary_size(XZ_DEFAULT_DICTIONARY_SIZE);
         xzc
     }
 }
@@ -184,9 +338,81 @@ impl Decompress for XZCompressor {
         decompressed: &mut W,
     ) -> Result<u64> {
         // TODO: check flags argument is filter
-        let s = Stream::new_stream_decoder(1000000, 0)?;
-        let mut decoder = XzDecoder::new_stream(compressed, s);
-        copy(&mut decoder, decompressed)
+        #[cfg(feature = "native-codecs")]
+        {
+            let s = Stream::new_stream_decoder(1000000, 0)?;
+            let mut decoder = XzDecoder::new_stream(compressed, s);
+            copy(&mut decoder, decompressed)
+        }
+        #[cfg(not(feature = "native-codecs"))]
+        {
+            let mut output = CountingWriter(decompressed, 0);
+            lzma_rs::xz_decompress(&mut BufReader::new(compressed), &mut output)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("xz: {:?}", e)))?;
+            Ok(output.1)
+        }
+    }
+}
+
+impl Compress for XZCompressor {
+    fn compress<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        uncompressed: &mut R,
+        compressed: &mut W,
+    ) -> Result<u64> {
+        let filters = u32::from_le_bytes(self.filters);
+        if filters & !XZFilters::all().bits() != 0 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unknown xz filters {:#x}", filters),
+            ));
+        }
+        // like mksquashfs, try no branch filter and each one set, and keep
+        // the smallest result
+        #[cfg(feature = "native-codecs")]
+        let buf = {
+            let mut opts = LzmaOptions::new_preset(6)?;
+            if self.dictionary_size() != 0 {
+                opts.dict_size(self.dictionary_size());
+            }
+            let mut data = vec![];
+            uncompressed.read_to_end(&mut data)?;
+            let encode = |branch: Option<AddFilter>| -> Result<Vec<u8>> {
+                let mut filters = Filters::new();
+                if let Some(branch) = branch {
+                    branch(&mut filters);
+                }
+                filters.lzma2(&opts);
+                let s = Stream::new_stream_encoder(&filters, Check::Crc32)?;
+                let mut encoder = XzEncoder::new_stream(Vec::new(), s);
+                encoder.write_all(&data)?;
+                encoder.finish()
+            };
+            let mut best = encode(None)?;
+            for (flag, branch) in XZ_BRANCH_FILTERS {
+                if self.filters().contains(flag) {
+                    let buf = encode(Some(branch))?;
+                    if buf.len() < best.len() {
+                        best = buf;
+                    }
+                }
+            }
+            best
+        };
+        #[cfg(not(feature = "native-codecs"))]
+        let buf = {
+            if !self.filters().is_empty() {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "xz branch filters need the native-codecs feature",
+                ));
+            }
+            let mut buf = Vec::new();
+            lzma_rs::xz_compress(&mut BufReader::new(uncompressed), &mut buf)?;
+            buf
+        };
+        compressed.write_all(&buf)?;
+        Ok(buf.len() as u64)
     }
 }
 
@@ -218,8 +444,20 @@ impl GzipCompressor {
     }
 
     get_set_field_tuple!(compression_level, set_compression_level, u32, 0, 4);
-    get_set_field_tuple!(window_size, set_window_size, u32, 4, 4);
-    get_set_field_tuple!(strategies, set_strategies, u16, 8, 2);
+    get_set_field_tuple!(window_size, set_window_size, u16, 4, 2);
+    get_set_field_tuple!(strategies, set_strategies, u16, 6, 2);
+}
+
+const _: () = assert!(std::mem::size_of::<GzipCompressor>() == GzipCompressor::SIZE);
+
+impl Default for GzipCompressor {
+    fn default() -> Self {
+        let mut gzc = Self::new(None);
+        gzc.set_compression_level(9);
+        gzc.set_window_size(15);
+        gzc.set_strategies(GzipStrategies::DEFAULT.bits);
+        gzc
+    }
 }
 
 impl Decompress for GzipCompressor {
@@ -235,6 +473,33 @@ impl Decompress for GzipCompressor {
     }
 }
 
+impl Compress for GzipCompressor {
+    fn compress<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        uncompressed: &mut R,
+        compressed: &mut W,
+    ) -> Result<u64> {
+        // flate2 always uses a 15 bit window and the default strategy
+        let strategies = self.strategies() & !GzipStrategies::DEFAULT.bits;
+        if !matches!(self.window_size(), 0 | 15) || strategies != 0 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "gzip window size {} and strategies {:#x} are not supported, \
+                     only a window size of 15 and the default strategy",
+                    self.window_size(),
+                    self.strategies()
+                ),
+            ));
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(self.compression_level()));
+        copy(uncompressed, &mut encoder)?;
+        let buf = encoder.finish()?;
+        compressed.write_all(&buf)?;
+        Ok(buf.len() as u64)
+    }
+}
+
 impl Display for GzipCompressor {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -246,3 +511,184 @@ impl Display for GzipCompressor {
         )
     }
 }
+
+/// Largest zstd dictionary an image can hold: it follows the level in the
+/// compressor options, which are a single metadata block.
+pub const MAX_ZSTD_DICTIONARY_SIZE: usize = METADATA_SIZE - ZSTDCompressor::SIZE;
+
+/// Options of the zstd compressor. Besides the level squashfs defines, a
+/// dictionary can follow it in the options block, which every block of the
+/// image may then be compressed with. This is a convention of this crate:
+/// the kernel and squashfs-tools can't read images using a dictionary.
+#[derive(Clone, Debug)]
+pub struct ZSTDCompressor([u8; 4], Option<Arc<[u8]>>);
+
+impl ZSTDCompressor {
+    const SIZE: usize = 4;
+
+    fn new(bytes: Option<[u8; Self::SIZE]>) -> Self {
+        let bytes = bytes.unwrap_or([0; Self::SIZE]);
+        Self(bytes, None)
+    }
+
+    get_set_field_tuple!(compression_level, set_compression_level, u32, 0, 4, last);
+
+    pub fn dictionary(&self) -> Option<&[u8]> {
+        self.1.as_deref()
+    }
+
+    /// Sets the dictionary blocks are compressed with, as trained by
+    /// `train_dictionary` or the zstd tool. Blocks compressed without it
+    /// still decompress with it.
+    pub fn set_dictionary(&mut self, dictionary: Option<Arc<[u8]>>) -> Result<()> {
+        if let Some(dictionary) = &dictionary {
+            if dictionary.len() > MAX_ZSTD_DICTIONARY_SIZE {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "zstd dictionary of {} bytes, at most {} fit",
+                        dictionary.len(),
+                        MAX_ZSTD_DICTIONARY_SIZE
+                    ),
+                ));
+            }
+            // at least the magic and the dictionary id
+            if dictionary.len() < 8 || !dictionary.starts_with(&ZSTD_DICTIONARY_MAGIC) {
+                return Err(Error::new(ErrorKind::InvalidData, "not a zstd dictionary"));
+            }
+        }
+        self.1 = dictionary;
+        Ok(())
+    }
+
+    /// Trains a dictionary of at most `max_size` bytes over `samples`,
+    /// such as the small files packed into fragment blocks. zstd needs a
+    /// fair number of samples, and fails with too few.
+    pub fn train_dictionary<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> Result<Vec<u8>> {
+        let max_size = max_size.min(MAX_ZSTD_DICTIONARY_SIZE);
+        #[cfg(feature = "native-codecs")]
+        return zstd::dict::from_samples(samples, max_size);
+        #[cfg(not(feature = "native-codecs"))]
+        {
+            let _ = (samples, max_size);
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "zstd dictionaries can't be trained without the native codecs",
+            ))
+        }
+    }
+}
+
+// starts zstd dictionaries, as opposed to raw content ones
+const ZSTD_DICTIONARY_MAGIC: [u8; 4] = [0x37, 0xa4, 0x30, 0xec];
+
+const _: () = assert!(ZSTDCompressor::FIELDS_END == ZSTDCompressor::SIZE);
+
+impl Default for ZSTDCompressor {
+    fn default() -> Self {
+        let mut zc = Self::new(None);
+        zc.set_compression_level(15);
+        zc
+    }
+}
+
+impl Decompress for ZSTDCompressor {
+    fn decompress<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        compressed: &mut R,
+        decompressed: &mut W,
+    ) -> Result<u64> {
+        #[cfg(feature = "native-codecs")]
+        let mut decoder = match self.dictionary() {
+            Some(dictionary) => zstd::stream::read::Decoder::with_dictionary(
+                std::io::BufReader::new(compressed),
+                dictionary,
+            )?,
+            None => zstd::stream::read::Decoder::new(compressed)?,
+        };
+        #[cfg(not(feature = "native-codecs"))]
+        let mut decoder = {
+            let mut frames = ruzstd::decoding::FrameDecoder::new();
+            if let Some(dictionary) = self.dictionary() {
+                let dictionary = ruzstd::decoding::Dictionary::decode_dict(dictionary)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, format!("zstd: {}", e)))?;
+                frames
+                    .add_dict(dictionary)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, format!("zstd: {}", e)))?;
+            }
+            ruzstd::decoding::StreamingDecoder::new_with_decoder(compressed, frames)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("zstd: {}", e)))?
+        };
+        copy(&mut decoder, decompressed)
+    }
+}
+
+impl Compress for ZSTDCompressor {
+    fn compress<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        uncompressed: &mut R,
+        compressed: &mut W,
+    ) -> Result<u64> {
+        #[cfg(feature = "native-codecs")]
+        // in one go, as mksquashfs does, so the frame records its size and
+        // needs no larger window than the block: the kernel has no more
+        let buf = {
+            let mut data = vec![];
+            uncompressed.read_to_end(&mut data)?;
+            let level = self.compression_level() as i32;
+            match self.dictionary() {
+                Some(dictionary) => {
+                    zstd::bulk::Compressor::with_dictionary(level, dictionary)?.compress(&data)?
+                }
+                None => zstd::bulk::compress(&data, level)?,
+            }
+        };
+        // only the fastest level is implemented, without dictionaries
+        #[cfg(not(feature = "native-codecs"))]
+        let buf = {
+            if self.dictionary().is_some() {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "zstd dictionaries need the native codecs to compress",
+                ));
+            }
+            ruzstd::encoding::compress_to_vec(
+                uncompressed,
+                ruzstd::encoding::CompressionLevel::Fastest,
+            )
+        };
+        compressed.write_all(&buf)?;
+        Ok(buf.len() as u64)
+    }
+}
+
+impl Display for ZSTDCompressor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.dictionary() {
+            Some(dictionary) => write!(
+                f,
+                "[{} dictionary {}]",
+                self.compression_level(),
+                dictionary.len()
+            ),
+            None => write!(f, "[{}]", self.compression_level()),
+        }
+    }
+}
+
+// Counts the bytes written through it, for decoders that don't report it.
+#[cfg(not(feature = "native-codecs"))]
+struct CountingWriter<'a, W: Write + ?Sized>(&'a mut W, u64);
+
+#[cfg(not(feature = "native-codecs"))]
+impl<'a, W: Write + ?Sized> Write for CountingWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = self.0.write(buf)?;
+        self.1 += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.0.flush()
+    }
+}
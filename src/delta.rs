@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use flate2::Crc;
+
+use crate::superblock::Superblock;
+
+const DELTA_MAGIC: &[u8; 8] = b"SQFSDLT1";
+// size of the chunks of the old image that are looked up in the new one
+const WINDOW: usize = 64;
+// candidates kept per hash, so repetitive images stay linear
+const MAX_CANDIDATES: usize = 8;
+
+const OP_END: u8 = 0;
+const OP_COPY: u8 = 1;
+const OP_INSERT: u8 = 2;
+
+// struct delta_header {
+// 	0 8 char			magic[8];
+// 	8 8 unsigned long long		old_size;
+// 	16 4 unsigned int		old_crc;
+// 	20 8 unsigned long long		new_size;
+// 	28 4 unsigned int		new_crc;
+// };
+// followed by ops: OP_COPY offset u64 len u64, OP_INSERT len u64 bytes,
+// OP_END
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+// Weak rolling checksum over a WINDOW sized window, as used by rsync.
+#[derive(Default)]
+struct RollingHash {
+    a: u32,
+    b: u32,
+}
+
+impl RollingHash {
+    fn new(window: &[u8]) -> Self {
+        let mut hash = Self::default();
+        for (i, c) in window.iter().enumerate() {
+            hash.a = hash.a.wrapping_add(*c as u32);
+            hash.b = hash.b.wrapping_add((window.len() - i) as u32 * *c as u32);
+        }
+        hash
+    }
+
+    fn roll(&mut self, out: u8, a_in: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(a_in as u32);
+        self.b = self
+            .b
+            .wrapping_sub(WINDOW as u32 * out as u32)
+            .wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn write_copy<W: Write>(writer: &mut W, offset: usize, len: usize) -> Result<()> {
+    writer.write_all(&[OP_COPY])?;
+    writer.write_all(&(offset as u64).to_le_bytes())?;
+    writer.write_all(&(len as u64).to_le_bytes())
+}
+
+fn write_insert<W: Write>(writer: &mut W, data: &[u8]) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    writer.write_all(&[OP_INSERT])?;
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    writer.write_all(data)
+}
+
+/// Writes a patch turning the `old` image into the `new` one. Both must be
+/// squashfs images.
+///
+/// Unchanged data and metadata blocks are found wherever they moved to in
+/// the new image and are sent as references into the old image, so the
+/// patch is mostly made of the blocks that actually changed. Patches are
+/// smallest when both images use the same compressor and options.
+pub fn diff<W: Write>(old: &[u8], new: &[u8], mut patch: W) -> Result<()> {
+    Superblock::new(&mut &old[..])?;
+    Superblock::new(&mut &new[..])?;
+
+    patch.write_all(DELTA_MAGIC)?;
+    patch.write_all(&(old.len() as u64).to_le_bytes())?;
+    patch.write_all(&crc32(old).to_le_bytes())?;
+    patch.write_all(&(new.len() as u64).to_le_bytes())?;
+    patch.write_all(&crc32(new).to_le_bytes())?;
+
+    let mut chunks: HashMap<u32, Vec<usize>> = HashMap::new();
+    for start in (0..old.len().saturating_sub(WINDOW - 1)).step_by(WINDOW) {
+        let candidates = chunks
+            .entry(RollingHash::new(&old[start..start + WINDOW]).value())
+            .or_default();
+        if candidates.len() < MAX_CANDIDATES {
+            candidates.push(start);
+        }
+    }
+
+    // start of the new image bytes not yet covered by an op
+    let mut pending = 0;
+    let mut pos = 0;
+    let mut hash = None;
+    while pos + WINDOW <= new.len() {
+        let window = &new[pos..pos + WINDOW];
+        let h = hash.get_or_insert_with(|| RollingHash::new(window));
+        let found = chunks.get(&h.value()).and_then(|candidates| {
+            candidates
+                .iter()
+                .filter(|c| &old[**c..**c + WINDOW] == window)
+                .map(|c| {
+                    let len = old[*c..]
+                        .iter()
+                        .zip(&new[pos..])
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    (*c, len)
+                })
+                .max_by_key(|(_, len)| *len)
+        });
+
+        match found {
+            Some((start, len)) => {
+                let back = old[..start]
+                    .iter()
+                    .rev()
+                    .zip(new[pending..pos].iter().rev())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                write_insert(&mut patch, &new[pending..pos - back])?;
+                write_copy(&mut patch, start - back, len + back)?;
+                pos += len;
+                pending = pos;
+                hash = None;
+            }
+            None => {
+                if pos + WINDOW < new.len() {
+                    h.roll(new[pos], new[pos + WINDOW]);
+                }
+                pos += 1;
+            }
+        }
+    }
+    write_insert(&mut patch, &new[pending..])?;
+    patch.write_all(&[OP_END])
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn invalid_patch(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid patch: {}", msg))
+}
+
+/// Rebuilds the new image from the `old` one and a patch made by `diff`.
+/// Fails if `old` is not the image the patch was made from, or if the
+/// result doesn't match the checksum recorded in the patch.
+pub fn apply<R: Read, W: Write>(old: &[u8], mut patch: R, mut new: W) -> Result<()> {
+    let mut magic = [0; 8];
+    patch.read_exact(&mut magic)?;
+    if &magic != DELTA_MAGIC {
+        return Err(invalid_patch("bad magic"));
+    }
+    let old_size = read_u64(&mut patch)?;
+    let old_crc = read_u32(&mut patch)?;
+    let new_size = read_u64(&mut patch)?;
+    let new_crc = read_u32(&mut patch)?;
+    if old_size != old.len() as u64 || old_crc != crc32(old) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "patch was made from a different image",
+        ));
+    }
+
+    let mut crc = Crc::new();
+    let mut written = 0u64;
+    let mut buf = vec![];
+    loop {
+        let mut op = [0];
+        patch.read_exact(&mut op)?;
+        let data = match op[0] {
+            OP_END => break,
+            OP_COPY => {
+                let offset = read_u64(&mut patch)?;
+                let len = read_u64(&mut patch)?;
+                offset
+                    .checked_add(len)
+                    .filter(|end| *end <= old.len() as u64)
+                    .ok_or_else(|| invalid_patch("copy out of range"))?;
+                if written.saturating_add(len) > new_size {
+                    return Err(invalid_patch("copy past the end of the image"));
+                }
+                &old[offset as usize..(offset + len) as usize]
+            }
+            OP_INSERT => {
+                let len = read_u64(&mut patch)?;
+                if written.saturating_add(len) > new_size {
+                    return Err(invalid_patch("insert past the end of the image"));
+                }
+                buf.clear();
+                (&mut patch).take(len).read_to_end(&mut buf)?;
+                if buf.len() as u64 != len {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "truncated patch"));
+                }
+                &buf[..]
+            }
+            _ => return Err(invalid_patch("unknown op")),
+        };
+        crc.update(data);
+        written += data.len() as u64;
+        new.write_all(data)?;
+    }
+
+    if written != new_size || crc.sum() != new_crc {
+        return Err(invalid_patch("checksum mismatch"));
+    }
+    Ok(())
+}
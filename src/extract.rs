@@ -0,0 +1,762 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File, FileTimes, OpenOptions, Permissions};
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::ops::RangeInclusive;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{lchown, symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::file::RawBlock;
+use crate::fragments::FragmentEntry;
+use crate::idmap::IdMap;
+use crate::image::{Image, TableKind};
+use crate::inode::{FileType, InodeHeader};
+use crate::metrics::{BlockKind, MeteredDecompressor};
+use crate::mtree;
+use crate::path::SqshPath;
+use crate::transform::{TransformWriter, Transforms};
+use crate::utils::trace_span;
+use crate::walk::WalkOptions;
+use crate::warning::Warning;
+use crate::xattr::{Xattr, XattrNamespaces};
+use crate::{ReadSeek, COMPRESSED_BIT_BLOCK, INVALID_FRAG};
+
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    /// Maps the uids of the image to the ones set on disk. Ids are kept as
+    /// is when unset.
+    pub uid_map: Option<IdMap>,
+    pub gid_map: Option<IdMap>,
+    /// When set, owners and device nodes that can't be applied for lack of
+    /// privileges are written to this file as an mtree spec instead of
+    /// failing the extraction, so a privileged step can apply them later.
+    /// Devices are extracted as empty regular files.
+    pub sidecar: Option<PathBuf>,
+    /// What to do with entries already on disk.
+    pub on_conflict: Conflict,
+    /// Extracts into directories already on disk where the image has a
+    /// directory, instead of handling them as conflicts. Their mode and
+    /// mtime are still set.
+    pub merge: bool,
+    /// Extracts only the entries matching the filter, with the directories
+    /// leading to them.
+    pub filter: Option<ExtractFilter>,
+    pub symlinks: SymlinkRewrite,
+    /// Order the tree is extracted in, and limits on its size that fail
+    /// the extraction when exceeded, for untrusted images.
+    pub walk: WalkOptions,
+    /// Extracts what an image cut off at its end still holds instead of
+    /// failing: owners are left as is if the id table is missing, and
+    /// regular files whose contents are cut off are left out. Each is
+    /// reported in `Image::warnings`.
+    pub partial: bool,
+    /// Transforms applied to the contents of regular files as they are
+    /// written, such as decompressing them.
+    pub transforms: Transforms,
+    /// Namespaces of the xattrs restored, none by default. Restoring
+    /// `trusted.` xattrs takes CAP_SYS_ADMIN, and `security.` ones such as
+    /// SELinux labels and file capabilities may not mean the same outside
+    /// the system the image is for. Xattrs that can't be set, such as for
+    /// lack of privileges or support from the filesystem, are reported in
+    /// `Image::warnings`, as are all of them on systems other than Linux.
+    /// Linux doesn't allow user xattrs on entries other than regular files
+    /// and directories, so those are left out.
+    pub xattrs: XattrNamespaces,
+}
+
+/// How `Image::extract` writes absolute symlink targets. Relative targets
+/// are always kept.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkRewrite {
+    /// Keeps targets as they are, for use as a chroot.
+    #[default]
+    Keep,
+    /// Makes targets inside the extracted tree relative to the link, so they
+    /// resolve inside the destination. Targets outside it are kept.
+    Relative,
+    /// Makes targets inside the extracted tree start with this path instead
+    /// of the root of the tree, such as the destination itself. Targets
+    /// outside it are kept.
+    Prefix(PathBuf),
+}
+
+impl SymlinkRewrite {
+    // Rewrites the `target` of the link at `link`, in a tree extracted from
+    // `root`.
+    fn rewrite<'a>(&self, target: &'a [u8], link: &SqshPath, root: &SqshPath) -> Cow<'a, [u8]> {
+        if *self == SymlinkRewrite::Keep || !target.starts_with(b"/") {
+            return Cow::Borrowed(target);
+        }
+        let absolute = SqshPath::new(target);
+        let below: Vec<&[u8]> = match absolute.strip_prefix(root) {
+            Some(below) => below.collect(),
+            None => return Cow::Borrowed(target),
+        };
+        match self {
+            SymlinkRewrite::Keep => Cow::Borrowed(target),
+            SymlinkRewrite::Relative => {
+                let parent = link.parent().unwrap_or_else(SqshPath::root);
+                let dir: Vec<&[u8]> = parent.strip_prefix(root).into_iter().flatten().collect();
+                let common = dir.iter().zip(&below).take_while(|(a, b)| a == b).count();
+                let mut components = vec![&b".."[..]; dir.len() - common];
+                components.extend_from_slice(&below[common..]);
+                match components.is_empty() {
+                    true => Cow::Borrowed(b"."),
+                    false => Cow::Owned(components.join(&b'/')),
+                }
+            }
+            SymlinkRewrite::Prefix(prefix) => {
+                let mut rewritten = prefix.as_os_str().as_bytes().to_vec();
+                for name in below {
+                    if !rewritten.ends_with(b"/") {
+                        rewritten.push(b'/');
+                    }
+                    rewritten.extend_from_slice(name);
+                }
+                Cow::Owned(rewritten)
+            }
+        }
+    }
+}
+
+/// Selects entries by path and metadata. An entry matches if it matches
+/// every criterion set.
+#[derive(Clone, Debug, Default)]
+pub struct ExtractFilter {
+    /// Glob patterns as for `SqshPath::matches`, matched against paths from
+    /// the root of the image. The entries under a matching directory match
+    /// too.
+    pub paths: Vec<Vec<u8>>,
+    /// Owner, as stored in the image rather than mapped.
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Mode bits that must all be set, such as 0o4000 for setuid files.
+    pub mode_bits: u16,
+    /// Range of file sizes, which only regular files match.
+    pub size: Option<RangeInclusive<u64>>,
+    pub mtime: Option<RangeInclusive<u32>>,
+}
+
+impl ExtractFilter {
+    // Whether an entry matches every criterion other than its path.
+    // Owners are unknown in partial extractions without an id table, and
+    // then don't match.
+    fn matches_metadata(&self, inode: &InodeHeader, owner: Option<(u32, u32)>) -> bool {
+        let size = match inode {
+            InodeHeader::Regular(r) => Some(r.file_size() as u64),
+            InodeHeader::LRegular(r) => Some(r.file_size()),
+            _ => None,
+        };
+        self.uid.is_none_or(|uid| owner.is_some_and(|o| o.0 == uid))
+            && self.gid.is_none_or(|gid| owner.is_some_and(|o| o.1 == gid))
+            && inode.mode() & self.mode_bits == self.mode_bits
+            && self
+                .size
+                .as_ref()
+                .is_none_or(|range| size.is_some_and(|size| range.contains(&size)))
+            && self
+                .mtime
+                .as_ref()
+                .is_none_or(|range| range.contains(&inode.mtime()))
+    }
+}
+
+/// What `Image::extract` does when an entry already exists on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Conflict {
+    /// Fails the extraction.
+    #[default]
+    Error,
+    /// Leaves what is on disk and skips the entry, and all under it.
+    Skip,
+    /// Removes what is on disk, with all under it, first.
+    Overwrite,
+    /// Extracts the entry next to it, as `name.1`, `name.2` or the first
+    /// such name that is free.
+    Rename,
+}
+
+// Decodes a device number as the kernel stores it in squashfs inodes into
+// its major and minor numbers.
+fn device(rdev: u32) -> (u32, u32) {
+    (
+        (rdev & 0xfff00) >> 8,
+        (rdev & 0xff) | ((rdev >> 12) & 0xfff00),
+    )
+}
+
+fn mknod(path: &Path, mode: libc::mode_t, dev: libc::dev_t) -> Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    match unsafe { libc::mknod(path.as_ptr(), mode, dev) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+fn set_mtime(file: &File, mtime: u32) -> Result<()> {
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(mtime as u64);
+    file.set_times(FileTimes::new().set_accessed(mtime).set_modified(mtime))
+}
+
+// Sets xattrs on the entry at `path`, without following symlinks. Those
+// that can't be set are returned as warnings for `entry`.
+#[cfg(target_os = "linux")]
+fn set_xattrs(path: &Path, xattrs: &[Xattr], entry: &SqshPath) -> Result<Vec<Warning>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    Ok(apply_xattrs(xattrs, entry, |name, value| unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    }))
+}
+
+// Sets xattrs on an open file, which writing to would clear capabilities.
+#[cfg(target_os = "linux")]
+fn set_file_xattrs(file: &File, xattrs: &[Xattr], entry: &SqshPath) -> Vec<Warning> {
+    use std::os::fd::AsRawFd;
+
+    apply_xattrs(xattrs, entry, |name, value| unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    })
+}
+
+// Sets each xattr with `set`, like restoring other metadata on a best-effort
+// basis: the target filesystem may not support xattrs, or some namespaces
+// may take privileges the extraction doesn't have.
+#[cfg(target_os = "linux")]
+fn apply_xattrs(
+    xattrs: &[Xattr],
+    entry: &SqshPath,
+    set: impl Fn(&CString, &[u8]) -> libc::c_int,
+) -> Vec<Warning> {
+    let mut warnings = vec![];
+    for xattr in xattrs {
+        let result = match CString::new(&xattr.name[..]) {
+            Ok(name) if set(&name, &xattr.value) == 0 => continue,
+            Ok(_) => Error::last_os_error(),
+            Err(e) => e.into(),
+        };
+        warnings.push(Warning::XattrNotSet {
+            path: entry.clone(),
+            name: xattr.name.clone(),
+            kind: result.kind(),
+        });
+    }
+    warnings
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_xattrs(_path: &Path, xattrs: &[Xattr], entry: &SqshPath) -> Result<Vec<Warning>> {
+    Ok(xattrs
+        .iter()
+        .map(|xattr| Warning::XattrNotSet {
+            path: entry.clone(),
+            name: xattr.name.clone(),
+            kind: ErrorKind::Unsupported,
+        })
+        .collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_file_xattrs(_file: &File, xattrs: &[Xattr], entry: &SqshPath) -> Vec<Warning> {
+    set_xattrs(Path::new(""), xattrs, entry).unwrap_or_default()
+}
+
+/// Most file blocks read ahead of the writer thread, fewer if the image
+/// has a memory budget.
+const PREFETCH_BLOCKS: usize = 16;
+
+enum Job {
+    /// The file to set the mode and mtime of, and where its contents go.
+    Open(File, Box<dyn TransformWriter>),
+    Block(RawBlock),
+    /// Sets the mode and xattrs once the contents are written, which would
+    /// otherwise clear the setuid and setgid bits and file capabilities.
+    Close {
+        mode: u32,
+        mtime: u32,
+        xattrs: Vec<Xattr>,
+        path: SqshPath,
+    },
+}
+
+// Decompresses and writes file contents on a separate thread, so the
+// extraction loop reads the blocks of the next files while earlier ones are
+// decompressed.
+struct Writer {
+    jobs: Option<SyncSender<Job>>,
+    // returns the xattrs that couldn't be set
+    thread: Option<JoinHandle<Result<Vec<Warning>>>>,
+}
+
+impl Writer {
+    fn new(compressor: MeteredDecompressor, prefetch: usize) -> Self {
+        let (jobs, queue) = mpsc::sync_channel(prefetch);
+        Self {
+            jobs: Some(jobs),
+            thread: Some(thread::spawn(move || write_files(queue, compressor))),
+        }
+    }
+
+    fn send(&mut self, job: Job) -> Result<()> {
+        let sent = match &self.jobs {
+            Some(jobs) => jobs.send(job).is_ok(),
+            None => false,
+        };
+        if !sent {
+            // the writer stopped on an error, which join returns
+            self.finish()?;
+            return Err(Error::other("file writer stopped"));
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<Vec<Warning>> {
+        self.jobs = None;
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| Error::other("file writer panicked"))?,
+            None => Ok(vec![]),
+        }
+    }
+}
+
+fn write_files(queue: Receiver<Job>, compressor: MeteredDecompressor) -> Result<Vec<Warning>> {
+    let mut warnings = vec![];
+    let mut file = None;
+    let mut block = vec![];
+    for job in queue {
+        match job {
+            Job::Open(opened, out) => file = Some((opened, out)),
+            Job::Block(raw) => {
+                raw.decode(&compressor, &mut block)?;
+                if let Some((_, out)) = &mut file {
+                    out.write_all(&block)?;
+                }
+            }
+            Job::Close {
+                mode,
+                mtime,
+                xattrs,
+                path,
+            } => {
+                if let Some((file, out)) = file.take() {
+                    out.finish()?;
+                    file.set_permissions(Permissions::from_mode(mode))?;
+                    warnings.extend(set_file_xattrs(&file, &xattrs, &path));
+                    set_mtime(&file, mtime)?;
+                }
+            }
+        }
+    }
+    Ok(warnings)
+}
+
+fn rdev(inode: &InodeHeader) -> u32 {
+    match inode {
+        InodeHeader::Dev(d) => d.rdev(),
+        InodeHeader::LDev(d) => d.rdev(),
+        _ => 0,
+    }
+}
+
+fn permission_denied<T>(result: &Result<T>) -> bool {
+    matches!(result, Err(e) if e.kind() == ErrorKind::PermissionDenied)
+}
+
+// Creates the entry at `target`, pointing symlinks to `link`. Regular files
+// are created empty and returned, to be written by the `Writer`.
+fn create(inode: &InodeHeader, target: &Path, link: &[u8]) -> Result<Option<File>> {
+    let mode = (inode.mode() & 0o7777) as libc::mode_t;
+    let kind = match inode.file_type() {
+        FileType::BlockDevice => libc::S_IFBLK,
+        FileType::CharDevice => libc::S_IFCHR,
+        FileType::Fifo => libc::S_IFIFO,
+        _ => libc::S_IFSOCK,
+    };
+    match inode {
+        InodeHeader::Directory(_) | InodeHeader::LDirectory(_) => fs::create_dir_all(target)?,
+        InodeHeader::Regular(_) | InodeHeader::LRegular(_) => {
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(target)?;
+            return Ok(Some(file));
+        }
+        InodeHeader::Symlink(_) | InodeHeader::LSymlink(_) => {
+            symlink(OsStr::from_bytes(link), target)?
+        }
+        InodeHeader::Dev(_) | InodeHeader::LDev(_) => {
+            let (major, minor) = device(rdev(inode));
+            mknod(target, kind | mode, libc::makedev(major, minor))?
+        }
+        InodeHeader::IPC(_) | InodeHeader::LIPC(_) => mknod(target, kind | mode, 0)?,
+    }
+    Ok(None)
+}
+
+// Applies the conflict policy if `target` exists. Returns where to extract
+// the entry, or None to skip it. Existing directories are reused for
+// directories at the root of the extraction, or everywhere when merging.
+fn resolve(
+    target: PathBuf,
+    is_dir: bool,
+    is_root: bool,
+    options: &ExtractOptions,
+) -> Result<Option<PathBuf>> {
+    let existing = match fs::symlink_metadata(&target) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Some(target)),
+        Err(e) => return Err(e),
+    };
+    if is_dir && existing.is_dir() && (is_root || options.merge) {
+        return Ok(Some(target));
+    }
+    match options.on_conflict {
+        Conflict::Error => Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists", target.display()),
+        )),
+        Conflict::Skip => Ok(None),
+        Conflict::Overwrite => {
+            match existing.is_dir() {
+                true => fs::remove_dir_all(&target)?,
+                false => fs::remove_file(&target)?,
+            }
+            Ok(Some(target))
+        }
+        Conflict::Rename => {
+            let name = target.file_name().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} already exists", target.display()),
+                )
+            })?;
+            for i in 1.. {
+                let mut renamed = name.to_os_string();
+                renamed.push(format!(".{}", i));
+                let renamed = target.with_file_name(renamed);
+                match fs::symlink_metadata(&renamed) {
+                    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Some(renamed)),
+                    Err(e) => return Err(e),
+                    Ok(_) => {}
+                }
+            }
+            unreachable!()
+        }
+    }
+}
+
+// Reads a table with `read`, unless it is cut off and the extraction is
+// partial, in which case it is reported missing.
+fn partial_table<R: ReadSeek, T>(
+    image: &Image<R>,
+    options: &ExtractOptions,
+    kind: TableKind,
+    read: impl FnOnce() -> Result<T>,
+) -> Result<Option<T>> {
+    match read() {
+        Err(e)
+            if options.partial
+                && (e.kind() == ErrorKind::UnexpectedEof
+                    || image.missing_tables()?.contains(&kind)) =>
+        {
+            image.warn(Warning::MissingTable(kind));
+            Ok(None)
+        }
+        result => result.map(Some),
+    }
+}
+
+// Whether all of a regular file's contents are within the image, given
+// its fragment table if it could be read.
+fn is_readable<R: ReadSeek>(
+    image: &Image<R>,
+    inode: &InodeHeader,
+    fragments: Option<&[FragmentEntry]>,
+) -> bool {
+    let (start, file_size, fragment, sizes) = match inode {
+        InodeHeader::Regular(r) => (
+            r.start_block() as u64,
+            r.file_size() as u64,
+            r.fragment(),
+            r.blocks(),
+        ),
+        InodeHeader::LRegular(r) => (r.start_block(), r.file_size(), r.fragment(), r.blocks()),
+        _ => return true,
+    };
+    let disk_size = sizes
+        .iter()
+        .map(|b| (b & !COMPRESSED_BIT_BLOCK) as u64)
+        .sum();
+    let block_size = image.superblock().block_size() as u64;
+    let has_tail = fragment != INVALID_FRAG && file_size > sizes.len() as u64 * block_size;
+    image.is_available(start, disk_size)
+        && (!has_tail
+            || fragments
+                .and_then(|f| f.get(fragment as usize))
+                .is_some_and(|f| {
+                    image.is_available(f.start_block(), (f.size() & !COMPRESSED_BIT_BLOCK) as u64)
+                }))
+}
+
+// Walks the tree at `path` for the entries matching `filter`, returning
+// their paths along with the directories between them and `path`.
+fn select<R: ReadSeek>(
+    image: &Image<R>,
+    path: &[u8],
+    filter: &ExtractFilter,
+    options: &ExtractOptions,
+) -> Result<HashSet<SqshPath>> {
+    let ids = partial_table(image, options, TableKind::Id, || image.id_table())?;
+    let mut selected = HashSet::new();
+    // directories whose path matches, so everything under them does
+    let mut matched = HashSet::new();
+    let mut root = None;
+    for entry in image.walk_with(path, &options.walk)? {
+        let entry = entry?;
+        let root = root.get_or_insert_with(|| entry.path.clone());
+        let path_matches = filter.paths.is_empty()
+            || entry.path.parent().is_some_and(|p| matched.contains(&p))
+            || filter.paths.iter().any(|p| entry.path.matches(p));
+        if path_matches && entry.inode.is_dir() {
+            matched.insert(entry.path.clone());
+        }
+        let owner = match &ids {
+            Some(ids) => Some(ids.owner(&entry.inode)?),
+            None => None,
+        };
+        if !path_matches || !filter.matches_metadata(&entry.inode, owner) {
+            continue;
+        }
+        let mut path = Some(entry.path);
+        while let Some(p) = path {
+            if &p == root || !selected.insert(p.clone()) {
+                break;
+            }
+            path = p.parent();
+        }
+        selected.insert(root.clone());
+    }
+    Ok(selected)
+}
+
+pub(crate) fn extract<R: ReadSeek>(
+    image: &Image<R>,
+    path: &[u8],
+    dest: &Path,
+    options: &ExtractOptions,
+) -> Result<()> {
+    let ids = partial_table(image, options, TableKind::Id, || image.id_table())?;
+    let fragments = match options.partial {
+        true => partial_table(image, options, TableKind::Fragment, || image.fragments())?,
+        false => None,
+    };
+    // first path extracted for each inode, to recreate hard links
+    let mut extracted: HashMap<u32, PathBuf> = HashMap::new();
+    // modes and times of directories are set once their contents are written
+    let mut dirs = vec![];
+    let mut sidecar = match &options.sidecar {
+        Some(path) => {
+            let mut sidecar = BufWriter::new(File::create(path)?);
+            sidecar.write_all(b"#mtree\n")?;
+            Some(sidecar)
+        }
+        None => None,
+    };
+    // a quarter of the budget for queued blocks, as for the read buffers
+    let block_size = image.superblock().block_size() as usize;
+    let prefetch = image.memory_budget().map_or(PREFETCH_BLOCKS, |budget| {
+        (budget / 4 / block_size).clamp(1, PREFETCH_BLOCKS)
+    });
+    let mut writer = Writer::new(image.decompressor(BlockKind::Data)?, prefetch);
+    // where the directories of the image were extracted, to extract their
+    // entries into; directories skipped are missing
+    let mut targets: HashMap<SqshPath, PathBuf> = HashMap::new();
+    let selected = match &options.filter {
+        Some(filter) => Some(select(image, path, filter, options)?),
+        None => None,
+    };
+    let mut root = None;
+    for entry in image.walk_with(path, &options.walk)? {
+        let entry = entry?;
+        if selected.as_ref().is_some_and(|s| !s.contains(&entry.path)) {
+            continue;
+        }
+        trace_span!(
+            DEBUG,
+            "extract_entry",
+            path = %entry.path,
+            inode = entry.inode.inode_number()
+        );
+        let inode = entry.inode;
+        let is_root = root.is_none();
+        let root = root.get_or_insert_with(|| entry.path.clone());
+        let target = match (is_root, entry.path.parent(), entry.path.file_name()) {
+            (true, _, _) => dest.to_path_buf(),
+            (false, Some(parent), Some(name)) => match targets.get(&parent) {
+                Some(dir) => dir.join(OsStr::from_bytes(name)),
+                None => continue,
+            },
+            _ => continue,
+        };
+        let target = match resolve(target, inode.is_dir(), is_root, options)? {
+            Some(target) => target,
+            None => continue,
+        };
+        if inode.is_dir() {
+            targets.insert(entry.path.clone(), target.clone());
+        }
+
+        if !inode.is_dir() {
+            if let Some(first) = extracted.get(&inode.inode_number()) {
+                fs::hard_link(first, &target)?;
+                continue;
+            }
+        }
+        if options.partial && !is_readable(image, &inode, fragments.as_deref()) {
+            image.warn(Warning::Unreadable(entry.path));
+            continue;
+        }
+        let mut dropped = false;
+        let link = match &inode {
+            InodeHeader::Symlink(s) | InodeHeader::LSymlink(s) => {
+                options.symlinks.rewrite(s.target(), &entry.path, root)
+            }
+            _ => Cow::Borrowed(&b""[..]),
+        };
+        let created = create(&inode, &target, &link);
+        let file = if sidecar.is_some() && permission_denied(&created) {
+            File::create(&target)?;
+            dropped = true;
+            None
+        } else {
+            created?
+        };
+
+        let owner = match &ids {
+            Some(ids) => {
+                let (mut uid, mut gid) = ids.owner(&inode)?;
+                if let Some(map) = &options.uid_map {
+                    uid = map.map(uid);
+                }
+                if let Some(map) = &options.gid_map {
+                    gid = map.map(gid);
+                }
+                let chowned = lchown(&target, Some(uid), Some(gid));
+                if sidecar.is_some() && permission_denied(&chowned) {
+                    dropped = true;
+                } else {
+                    chowned?;
+                }
+                Some((uid, gid))
+            }
+            None => None,
+        };
+        if let (Some(sidecar), true) = (&mut sidecar, dropped) {
+            let mut line = b".".to_vec();
+            for name in target.strip_prefix(dest).into_iter().flat_map(Path::iter) {
+                line.push(b'/');
+                mtree::escape(name.as_bytes(), &mut line);
+            }
+            write!(
+                line,
+                " type={} mode={:04o}",
+                mtree::type_keyword(inode.file_type()),
+                inode.mode() & 0o7777,
+            )?;
+            if let Some((uid, gid)) = owner {
+                write!(line, " uid={} gid={}", uid, gid)?;
+            }
+            if matches!(inode, InodeHeader::Dev(_) | InodeHeader::LDev(_)) {
+                let (major, minor) = device(rdev(&inode));
+                write!(line, " device=native,{},{}", major, minor)?;
+            }
+            if inode.file_type() == FileType::Symlink {
+                line.extend_from_slice(b" link=");
+                mtree::escape(&link, &mut line);
+            }
+            line.push(b'\n');
+            sidecar.write_all(&line)?;
+        }
+
+        // xattrs of the namespaces selected, set after chown, which clears
+        // file capabilities. Linux only allows user xattrs on regular files
+        // and directories.
+        let user_allowed = matches!(inode.file_type(), FileType::Regular | FileType::Directory);
+        let xattrs: Vec<Xattr> = match options.xattrs.is_empty() {
+            true => vec![],
+            false => image
+                .xattrs(&inode)?
+                .into_iter()
+                .filter(|x| options.xattrs.intersects(x.namespace()))
+                .filter(|x| user_allowed || x.namespace() != XattrNamespaces::USER)
+                .collect(),
+        };
+        if inode.file_type() != FileType::Regular {
+            for warning in set_xattrs(&target, &xattrs, &entry.path)? {
+                image.warn(warning);
+            }
+        }
+
+        // after chown, which clears the setuid and setgid bits
+        let mode = (inode.mode() & 0o7777) as u32;
+        match inode.file_type() {
+            FileType::Directory => dirs.push((target, inode)),
+            FileType::Symlink => {}
+            FileType::Regular => {
+                if let Some(file) = file {
+                    let out = Box::new(BufWriter::new(file.try_clone()?));
+                    let out = options.transforms.wrap(&entry.path, out)?;
+                    writer.send(Job::Open(file, out))?;
+                    let mut contents = image.open_file(&inode)?;
+                    while let Some(raw) = contents.next_raw_block()? {
+                        writer.send(Job::Block(raw))?;
+                    }
+                    writer.send(Job::Close {
+                        mode,
+                        mtime: inode.mtime(),
+                        xattrs,
+                        path: entry.path.clone(),
+                    })?;
+                }
+                extracted.insert(inode.inode_number(), target);
+            }
+            _ => {
+                fs::set_permissions(&target, Permissions::from_mode(mode))?;
+                extracted.insert(inode.inode_number(), target);
+            }
+        }
+    }
+    for warning in writer.finish()? {
+        image.warn(warning);
+    }
+
+    for (dir, inode) in dirs.iter().rev() {
+        // opened first, as the mode may not let the owner read it
+        let file = File::open(dir)?;
+        let mode = (inode.mode() & 0o7777) as u32;
+        file.set_permissions(Permissions::from_mode(mode))?;
+        set_mtime(&file, inode.mtime())?;
+    }
+    if let Some(mut sidecar) = sidecar {
+        sidecar.flush()?;
+    }
+    Ok(())
+}
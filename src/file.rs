@@ -0,0 +1,195 @@
+use std::io::{Error, ErrorKind, Read, Result};
+use std::vec;
+
+use crate::compressors::Decompress;
+use crate::fragments::FragmentEntry;
+use crate::image::Image;
+use crate::metrics::{BlockKind, MeteredDecompressor};
+use crate::pool::PooledBuffer;
+use crate::read::decompress_data_block;
+use crate::utils::trace_span;
+use crate::{ReadSeek, COMPRESSED_BIT_BLOCK};
+
+/// Reads the contents of a regular file, created by `Image::open_file`.
+/// Blocks are decompressed one at a time as the file is read.
+pub struct FileReader<'a, R: ReadSeek> {
+    image: &'a Image<R>,
+    compressor: MeteredDecompressor,
+    blocks: vec::IntoIter<u32>,
+    // disk offset of the next non-sparse block
+    next: u64,
+    // fragment index and offset of the file tail, if any
+    fragment: Option<(u32, u32)>,
+    // entry of that fragment, when already known
+    fragment_entry: Option<FragmentEntry>,
+    // file bytes not read from the image yet
+    remaining: u64,
+    block: PooledBuffer,
+    position: usize,
+}
+
+/// Block of a file as read from the image, before decompression. Reading
+/// and decoding are split so they can happen on different threads.
+#[derive(Debug)]
+pub(crate) enum RawBlock {
+    Sparse(usize),
+    Data {
+        data: PooledBuffer,
+        size: u32,
+        expected: usize,
+    },
+    /// Fragment block holding the tail of the file at `offset`.
+    Tail {
+        data: PooledBuffer,
+        size: u32,
+        offset: usize,
+        expected: usize,
+    },
+}
+
+impl RawBlock {
+    /// Decompresses the block into `out`, replacing its contents.
+    pub(crate) fn decode<C: Decompress + ?Sized>(
+        &self,
+        compressor: &C,
+        out: &mut Vec<u8>,
+    ) -> Result<()> {
+        out.clear();
+        let expected = match self {
+            RawBlock::Sparse(expected) => {
+                out.resize(*expected, 0);
+                return Ok(());
+            }
+            RawBlock::Data {
+                data,
+                size,
+                expected,
+            } => {
+                decompress_data_block(data, out, compressor, *size)?;
+                *expected
+            }
+            RawBlock::Tail {
+                data,
+                size,
+                offset,
+                expected,
+            } => {
+                decompress_data_block(data, out, compressor, *size)?;
+                if offset + expected > out.len() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "file tail past the end of its fragment",
+                    ));
+                }
+                out.truncate(offset + expected);
+                out.drain(..offset);
+                *expected
+            }
+        };
+        if out.len() != expected {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("data block is {} bytes, expected {}", out.len(), expected),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<'a, R: ReadSeek> FileReader<'a, R> {
+    pub(crate) fn new(
+        image: &'a Image<R>,
+        start: u64,
+        blocks: Vec<u32>,
+        fragment: Option<(u32, u32)>,
+        file_size: u64,
+    ) -> Result<Self> {
+        Ok(Self {
+            image,
+            compressor: image.decompressor(BlockKind::Data)?,
+            blocks: blocks.into_iter(),
+            next: start,
+            fragment,
+            fragment_entry: None,
+            remaining: file_size,
+            block: PooledBuffer::take(),
+            position: 0,
+        })
+    }
+
+    /// Takes the entry of the fragment holding the file tail from `table`,
+    /// the whole fragment table, instead of reading it from the image.
+    pub(crate) fn with_fragment_table(mut self, table: &[FragmentEntry]) -> Self {
+        if let Some((index, _)) = self.fragment {
+            self.fragment_entry = table.get(index as usize).copied();
+        }
+        self
+    }
+
+    /// Reads the next block of the file without decompressing it, or
+    /// returns `None` at the end of the file.
+    pub(crate) fn next_raw_block(&mut self) -> Result<Option<RawBlock>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let expected = self
+            .remaining
+            .min(self.image.superblock().block_size() as u64) as usize;
+        let raw = match self.blocks.next() {
+            Some(0) => RawBlock::Sparse(expected),
+            Some(size) => {
+                let mut data = PooledBuffer::take();
+                self.image.read_raw_data_block(self.next, size, &mut data)?;
+                self.next += (size & !COMPRESSED_BIT_BLOCK) as u64;
+                RawBlock::Data {
+                    data,
+                    size,
+                    expected,
+                }
+            }
+            None => {
+                let (index, offset) = self
+                    .fragment
+                    .take()
+                    .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "file data ends early"))?;
+                let entry = match self.fragment_entry.take() {
+                    Some(entry) => entry,
+                    None => self.image.fragment(index)?,
+                };
+                let mut data = PooledBuffer::take();
+                self.image
+                    .read_raw_data_block(entry.start_block(), entry.size(), &mut data)?;
+                RawBlock::Tail {
+                    data,
+                    size: entry.size(),
+                    offset: offset as usize,
+                    expected,
+                }
+            }
+        };
+        self.remaining -= expected as u64;
+        Ok(Some(raw))
+    }
+}
+
+impl<'a, R: ReadSeek> Read for FileReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.position == self.block.len() {
+            trace_span!(
+                TRACE,
+                "file_block",
+                offset = self.next,
+                remaining = self.remaining
+            );
+            let Some(raw) = self.next_raw_block()? else {
+                return Ok(0);
+            };
+            raw.decode(&self.compressor, &mut self.block)?;
+            self.position = 0;
+        }
+        let len = buf.len().min(self.block.len() - self.position);
+        buf[..len].copy_from_slice(&self.block[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
@@ -2,9 +2,11 @@ use crate::utils::get_set_field_tuple;
 
 pub const FRAGMENT_ENTRY_SIZE: usize = 16;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct FragmentEntry([u8; FRAGMENT_ENTRY_SIZE]);
 
+const _: () = assert!(std::mem::size_of::<FragmentEntry>() == FRAGMENT_ENTRY_SIZE);
+
 impl FragmentEntry {
     pub fn new(entry: [u8; FRAGMENT_ENTRY_SIZE]) -> Self {
         Self(entry)
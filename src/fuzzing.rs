@@ -0,0 +1,201 @@
+use std::fs;
+use std::io::{Cursor, Result};
+use std::path::Path;
+
+use arbitrary::{Arbitrary, Unstructured};
+
+use crate::compressors::{Compressor, GzipCompressor, XZCompressor, ZSTDCompressor};
+use crate::image::Image;
+use crate::superblock::Superblock;
+use crate::testing::{generate, GenerateOptions, Spec};
+use crate::SUPERBLOCK_SIZE;
+
+// Bounds on generated trees, so images stay small enough to fuzz quickly.
+const MAX_DEPTH: u32 = 3;
+const MAX_ENTRIES: usize = 8;
+const MAX_FILE_SIZE: usize = 64 * 1024;
+
+/// Change made to a well-formed image to get a malformed one. Offsets and
+/// lengths are taken modulo the image length.
+#[derive(Clone, Debug, Arbitrary)]
+pub enum Mutation {
+    /// Xors the byte at `offset` with `mask`.
+    Flip { offset: u32, mask: u8 },
+    /// Overwrites 8 bytes at `offset`, as a table start or size would be.
+    Overwrite { offset: u32, value: u64 },
+    /// Overwrites 8 bytes of the superblock, which random offsets in the
+    /// rest of the image rarely hit.
+    Superblock { offset: u8, value: u64 },
+    /// Cuts the image to `len` bytes.
+    Truncate { len: u32 },
+}
+
+impl Mutation {
+    pub fn apply(&self, image: &mut Vec<u8>) {
+        if image.is_empty() {
+            return;
+        }
+        match *self {
+            Mutation::Flip { offset, mask } => {
+                let len = image.len();
+                image[offset as usize % len] ^= mask;
+            }
+            Mutation::Overwrite { offset, value } => {
+                let start = offset as usize % image.len();
+                let end = image.len().min(start + 8);
+                image[start..end].copy_from_slice(&value.to_le_bytes()[..end - start]);
+            }
+            Mutation::Superblock { offset, value } => {
+                let start = offset as usize % (SUPERBLOCK_SIZE - 8);
+                if image.len() >= start + 8 {
+                    image[start..start + 8].copy_from_slice(&value.to_le_bytes());
+                }
+            }
+            Mutation::Truncate { len } => {
+                let len = len as usize % image.len();
+                image.truncate(len);
+            }
+        }
+    }
+}
+
+/// Image built by `testing::generate` from an arbitrary tree, then broken
+/// by arbitrary mutations, for the fuzz targets that need input deep
+/// enough to get past the superblock.
+#[derive(Clone, Debug)]
+pub struct MalformedImage {
+    pub image: Vec<u8>,
+    pub mutations: Vec<Mutation>,
+}
+
+impl<'a> Arbitrary<'a> for MalformedImage {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let root = arbitrary_dir(u, 0)?;
+        let options = GenerateOptions {
+            compressor: match u.int_in_range(0..=2)? {
+                0 => Compressor::GZIP(GzipCompressor::default()),
+                1 => Compressor::XZ(XZCompressor::default()),
+                _ => Compressor::ZSTD(ZSTDCompressor::default()),
+            },
+            block_size: 4096 << u.int_in_range(0..=8)?,
+            fragments: u.arbitrary()?,
+            mkfs_time: u.arbitrary()?,
+            ..GenerateOptions::default()
+        };
+        let mut image = generate(&root, &options).map_err(|_| arbitrary::Error::IncorrectFormat)?;
+        let mutations: Vec<Mutation> = u.arbitrary()?;
+        for mutation in &mutations {
+            mutation.apply(&mut image);
+        }
+        Ok(Self { image, mutations })
+    }
+}
+
+fn arbitrary_dir(u: &mut Unstructured<'_>, depth: u32) -> arbitrary::Result<Spec> {
+    let count = u.int_in_range(0..=MAX_ENTRIES)?;
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let spec = match u.int_in_range(0..=6)? {
+            0 if depth < MAX_DEPTH => arbitrary_dir(u, depth + 1)?,
+            0 | 1 => {
+                let len = u.int_in_range(0..=MAX_FILE_SIZE)?;
+                let byte: u8 = u.arbitrary()?;
+                Spec::file(vec![byte; len])
+            }
+            2 => Spec::file(u.arbitrary::<Vec<u8>>()?),
+            3 => Spec::symlink(u.arbitrary::<Vec<u8>>()?),
+            4 => Spec::block_device(u.arbitrary()?),
+            5 => Spec::fifo(),
+            _ => Spec::socket(),
+        };
+        let spec = spec
+            .with_mode(u.arbitrary::<u16>()? & 0o7777)
+            .with_owner(u.arbitrary()?, u.arbitrary()?)
+            .with_mtime(u.arbitrary()?);
+        entries.push((format!("{}", i), spec));
+    }
+    Ok(Spec::dir(entries))
+}
+
+/// Parses a superblock from `data` and checks it as opening an image of
+/// `data.len()` bytes would.
+pub fn superblock(data: &[u8]) {
+    if let Ok(superblock) = Superblock::new(&mut Cursor::new(data)) {
+        let _ = superblock.validate(data.len() as u64);
+        let _ = superblock.flags().describe();
+    }
+}
+
+/// Parses compressor options: the compressor id is in the first 2 bytes
+/// and the options follow.
+pub fn compressor_options(data: &[u8]) {
+    let [low, high, options @ ..] = data else {
+        return;
+    };
+    let id = u16::from_le_bytes([*low, *high]);
+    if let Ok(compressor) = Compressor::new(id, true, &mut Cursor::new(options)) {
+        let _ = compressor.write_options(&mut vec![]);
+    }
+}
+
+/// Opens `data` as an image and reads its whole inode table.
+pub fn inode_table(data: &[u8]) {
+    if let Ok(image) = Image::new(Cursor::new(data)) {
+        let _ = image.inodes();
+    }
+}
+
+/// Opens `data` as an image and walks its tree, reading every directory
+/// listing and the inode of each entry.
+pub fn directory_table(data: &[u8]) {
+    let Ok(image) = Image::new(Cursor::new(data)) else {
+        return;
+    };
+    let Ok(walk) = image.walk("/") else {
+        return;
+    };
+    // a corrupted listing can loop back to a parent
+    for entry in walk.take(1 << 16) {
+        if entry.is_err() {
+            break;
+        }
+    }
+}
+
+/// Well-formed images to seed the corpora of the fuzz targets with, by
+/// name.
+pub fn seeds() -> Result<Vec<(&'static str, Vec<u8>)>> {
+    let empty = Spec::dir::<&str>([]);
+    let tree = Spec::dir([
+        ("file", Spec::file(vec![7; 200_000])),
+        ("tail", Spec::file("tail")),
+        ("link", Spec::symlink("file")),
+        ("dev", Spec::block_device(0x0801)),
+        ("fifo", Spec::fifo()),
+        ("sub", Spec::dir([("sock", Spec::socket())])),
+    ]);
+    let wide = Spec::dir((0..300).map(|i| (format!("entry-{:03}", i), Spec::fifo())));
+    let no_fragments = GenerateOptions {
+        fragments: false,
+        ..GenerateOptions::default()
+    };
+    Ok(vec![
+        ("empty", generate(&empty, &GenerateOptions::default())?),
+        ("tree", generate(&tree, &GenerateOptions::default())?),
+        ("tree-no-fragments", generate(&tree, &no_fragments)?),
+        ("wide", generate(&wide, &GenerateOptions::default())?),
+    ])
+}
+
+/// Writes the seeds into `dir`, one file each, returning how many were
+/// written. Structure-aware targets read their input as arbitrary bytes
+/// rather than images, and aren't seeded.
+pub fn write_corpus<P: AsRef<Path>>(dir: P) -> Result<usize> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let seeds = seeds()?;
+    for (name, image) in &seeds {
+        fs::write(dir.join(name), image)?;
+    }
+    Ok(seeds.len())
+}
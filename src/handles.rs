@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+
+use crate::cache::{CacheLimits, LruCache};
+use crate::file::RawBlock;
+use crate::image::Image;
+use crate::inode::InodeHeader;
+use crate::metrics::{BlockKind, MeteredDecompressor};
+use crate::pool::PooledBuffer;
+use crate::{ReadSeek, COMPRESSED_BIT_BLOCK, INVALID_FRAG};
+
+/// Identifies a file opened in a `HandlePool`.
+pub type Handle = u64;
+
+// What an open file needs to find any of its blocks.
+struct BlockMap {
+    // disk offset of each block, sparse ones included
+    starts: Vec<u64>,
+    sizes: Vec<u32>,
+    // fragment index and offset of the file tail, if any
+    fragment: Option<(u32, u32)>,
+    file_size: u64,
+}
+
+/// Regular files opened for reading at any offset, as FUSE-style consumers
+/// do, with many files open at once. Opening a file only keeps its block
+/// map: the decompressed block last read is kept for at most `max_live`
+/// handles, the least recently read dropping theirs first, and is decoded
+/// again from the block map when needed.
+pub struct HandlePool<'a, R: ReadSeek> {
+    image: &'a Image<R>,
+    compressor: MeteredDecompressor,
+    files: HashMap<Handle, BlockMap>,
+    next: Handle,
+    // index and contents of the block last read by each live handle
+    live: LruCache<Handle, (usize, Arc<Vec<u8>>)>,
+}
+
+impl<'a, R: ReadSeek> HandlePool<'a, R> {
+    pub(crate) fn new(image: &'a Image<R>, max_live: usize) -> Result<Self> {
+        Ok(Self {
+            image,
+            compressor: image.decompressor(BlockKind::Data)?,
+            files: HashMap::new(),
+            next: 1,
+            live: LruCache::new(CacheLimits::new(max_live, usize::MAX)),
+        })
+    }
+
+    /// Opens a regular file, returning its handle. Nothing is read.
+    pub fn open(&mut self, inode: &InodeHeader) -> Result<Handle> {
+        let (start, fragment, offset, file_size, sizes) = match inode {
+            InodeHeader::Regular(r) => (
+                r.start_block() as u64,
+                r.fragment(),
+                r.offset(),
+                r.file_size() as u64,
+                r.blocks(),
+            ),
+            InodeHeader::LRegular(r) => (
+                r.start_block(),
+                r.fragment(),
+                r.offset(),
+                r.file_size(),
+                r.blocks(),
+            ),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "not a regular file")),
+        };
+        let mut starts = Vec::with_capacity(sizes.len());
+        let mut next = start;
+        for size in sizes {
+            starts.push(next);
+            next += (size & !COMPRESSED_BIT_BLOCK) as u64;
+        }
+        let handle = self.next;
+        self.next += 1;
+        self.files.insert(
+            handle,
+            BlockMap {
+                starts,
+                sizes: sizes.to_vec(),
+                fragment: (fragment != INVALID_FRAG).then_some((fragment, offset)),
+                file_size,
+            },
+        );
+        Ok(handle)
+    }
+
+    /// Reads from `offset` in the file, returning 0 at or past its end.
+    pub fn read_at(&mut self, handle: Handle, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let file = self.files.get(&handle).ok_or_else(|| bad_handle(handle))?;
+        if offset >= file.file_size || buf.is_empty() {
+            return Ok(0);
+        }
+        let block_size = self.image.superblock().block_size() as u64;
+        let index = (offset / block_size) as usize;
+        let block = match self.live.get(&handle) {
+            Some((live, block)) if live == index => block,
+            _ => {
+                let block = Arc::new(self.decode(file, index)?);
+                let size = block.capacity();
+                self.live.insert(handle, (index, block.clone()), size);
+                block
+            }
+        };
+        let start = (offset % block_size) as usize;
+        let len = buf.len().min(block.len().saturating_sub(start));
+        buf[..len].copy_from_slice(&block[start..start + len]);
+        Ok(len)
+    }
+
+    /// Closes a file, dropping its block map and decoded block.
+    pub fn close(&mut self, handle: Handle) -> Result<()> {
+        self.files
+            .remove(&handle)
+            .ok_or_else(|| bad_handle(handle))?;
+        self.live.remove(&handle);
+        Ok(())
+    }
+
+    /// Number of open files.
+    pub fn open_files(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Number of open files holding a decoded block.
+    pub fn live(&self) -> usize {
+        self.live.stats().entries
+    }
+
+    // Reads and decompresses the `index`th block of a file.
+    fn decode(&self, file: &BlockMap, index: usize) -> Result<Vec<u8>> {
+        let block_size = self.image.superblock().block_size() as u64;
+        let expected = (file.file_size - index as u64 * block_size).min(block_size) as usize;
+        let raw = match file.sizes.get(index) {
+            Some(0) => RawBlock::Sparse(expected),
+            Some(&size) => {
+                let mut data = PooledBuffer::take();
+                self.image
+                    .read_raw_data_block(file.starts[index], size, &mut data)?;
+                RawBlock::Data {
+                    data,
+                    size,
+                    expected,
+                }
+            }
+            None => {
+                let (fragment, offset) = file
+                    .fragment
+                    .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "file data ends early"))?;
+                let entry = self.image.fragment(fragment)?;
+                let mut data = PooledBuffer::take();
+                self.image
+                    .read_raw_data_block(entry.start_block(), entry.size(), &mut data)?;
+                RawBlock::Tail {
+                    data,
+                    size: entry.size(),
+                    offset: offset as usize,
+                    expected,
+                }
+            }
+        };
+        let mut block = vec![];
+        raw.decode(&self.compressor, &mut block)?;
+        Ok(block)
+    }
+}
+
+fn bad_handle(handle: Handle) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("no open file with handle {}", handle),
+    )
+}
@@ -0,0 +1,75 @@
+use std::io::{BufRead, Error, ErrorKind, Result};
+
+/// A range of `count` ids starting at `inside` in the image, mapped to the
+/// ids starting at `outside` on the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdRange {
+    pub inside: u32,
+    pub outside: u32,
+    pub count: u32,
+}
+
+/// Id mapping applied to owners when extracting or building an image, in
+/// the format of /proc/<pid>/uid_map: one `inside outside count` range per
+/// line. Like the kernel does, ids no range covers map to the overflow id.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IdMap(Vec<IdRange>);
+
+pub const OVERFLOW_ID: u32 = 65534;
+
+impl IdMap {
+    pub fn new(ranges: Vec<IdRange>) -> Self {
+        Self(ranges)
+    }
+
+    pub fn parse<R: BufRead>(reader: R) -> Result<Self> {
+        let mut ranges = vec![];
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<u32> = line
+                .split_whitespace()
+                .map(|f| f.parse())
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|_| bad_map(line))?;
+            match fields[..] {
+                [inside, outside, count] => ranges.push(IdRange {
+                    inside,
+                    outside,
+                    count,
+                }),
+                _ => return Err(bad_map(line)),
+            }
+        }
+        Ok(Self(ranges))
+    }
+
+    pub fn ranges(&self) -> &[IdRange] {
+        &self.0
+    }
+
+    /// Host id of an image id.
+    pub fn map(&self, id: u32) -> u32 {
+        self.0
+            .iter()
+            .find(|r| id >= r.inside && id - r.inside < r.count)
+            .map(|r| r.outside + (id - r.inside))
+            .unwrap_or(OVERFLOW_ID)
+    }
+
+    /// Image id of a host id, the mapping used when building an image.
+    pub fn unmap(&self, id: u32) -> u32 {
+        self.0
+            .iter()
+            .find(|r| id >= r.outside && id - r.outside < r.count)
+            .map(|r| r.inside + (id - r.outside))
+            .unwrap_or(OVERFLOW_ID)
+    }
+}
+
+fn bad_map(line: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("bad id map line: {}", line))
+}
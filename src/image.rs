@@ -1,265 +1,1717 @@
-use core::panic;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::fmt::Debug;
-use std::io::{copy, Error, ErrorKind, Read, Result, SeekFrom};
-use std::ops::DerefMut;
+use std::io::{copy, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::ops::{self, DerefMut, Range};
+use std::path::Path;
+use std::sync::Arc;
 use std::{mem, vec};
 
-use crate::compressors::Compressor;
+use crate::analyze::{self, DirStats};
+use crate::audit::{self, Finding};
+use crate::cache::{CacheConfig, ImageCacheStats, LruCache};
+use crate::compare::{self, CompareOptions, Difference};
+use crate::compressors::{Compressor, UnsupportedCompressor, SUPPORTED_IDS};
+#[cfg(unix)]
+use crate::extract::{self, ExtractOptions};
+use crate::file::FileReader;
 use crate::fragments::{FragmentEntry, FRAGMENT_ENTRY_SIZE};
-use crate::inode::{scan_inode_table, DirectoryEntry, InodeEntry, InodeHeader};
-use crate::read::{self, read_block, FragmentTableReader};
+use crate::handles::HandlePool;
+use crate::inode::{
+    check_root_inode, read_directory_listing, read_inode_header, scan_inode_table, DirectoryEntry,
+    DirectoryHeader, FileType, InodeHeader, InodeRef, DIRECTORY_HEADER_MAX_COUNT,
+};
+use crate::integrity::{self, BadBlock};
+use crate::manifest::Manifest;
+use crate::metrics::{
+    BlockKind, CacheKind, MeteredDecompressor, Metrics, NoMetrics, Phase, PhaseTimer,
+};
+use crate::overlay::CowOverlay;
+use crate::path::SqshPath;
+use crate::pool::PoolLimit;
+#[cfg(feature = "positioned-io")]
+use crate::positioned::ReadAtReader;
+use crate::read::{self, read_block, IndexedTableReader, TrackedReader};
+use crate::readdir::Readdir;
+use crate::salvage::{self, Salvage};
+use crate::spool::Spooled;
 use crate::superblock::{Flags, Superblock};
-use crate::{ReadSeek, INVALID_BLK, METADATA_SIZE, SUPERBLOCK_SIZE};
+use crate::trim::{self, Trimmed};
+use crate::utils::{decode_le_slice, trace_span};
+#[cfg(unix)]
+use crate::verify::{self, Mismatch};
+use crate::walk::{Entries, ListOptions, Order, Walk, WalkOptions};
+use crate::warning::Warning;
+use crate::xattr::{Xattr, XattrId, XATTR_ID_ENTRY_SIZE};
+use crate::{
+    ReadSeek, COMPRESSED_BIT_BLOCK, INVALID_BLK, INVALID_FRAG, INVALID_XATTR, METADATA_SIZE,
+    PADDING_SIZE, SUPERBLOCK_SIZE,
+};
 
 const INODE_ENTRY_SIZE: usize = 8;
 
+// A decompressed metadata block and its size on disk, header included.
+type CachedBlock = (Arc<Vec<u8>>, u64);
+const XATTR_TABLE_HEADER_SIZE: usize = 16;
+
+/// On-disk metadata tables that can be fetched with `Image::raw_table`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableKind {
+    Inode,
+    Directory,
+    Fragment,
+    Id,
+    /// Xattr key/value store.
+    Xattr,
+    /// Xattr id entries pointing into the key/value store.
+    XattrId,
+    Export,
+}
+
+/// Controls how `Image::lookup_with` resolves symlinks, like
+/// `std::fs::OpenOptions` does for opening files. Nothing is followed by
+/// default, which is how `Image::lookup` resolves paths.
+#[derive(Clone, Copy, Debug)]
+pub struct LookupOptions {
+    follow_intermediate: bool,
+    follow_final: bool,
+    max_symlinks: u32,
+}
+
+impl Default for LookupOptions {
+    fn default() -> Self {
+        Self {
+            follow_intermediate: false,
+            follow_final: false,
+            // as in Linux
+            max_symlinks: 40,
+        }
+    }
+}
+
+impl LookupOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Follows symlinks to directories in the middle of the path.
+    pub fn follow_intermediate(&mut self, follow: bool) -> &mut Self {
+        self.follow_intermediate = follow;
+        self
+    }
+
+    /// Follows the symlink the path ends with, if it does.
+    pub fn follow_final(&mut self, follow: bool) -> &mut Self {
+        self.follow_final = follow;
+        self
+    }
+
+    /// Number of symlinks followed before the lookup fails as a loop.
+    pub fn max_symlinks(&mut self, max: u32) -> &mut Self {
+        self.max_symlinks = max;
+        self
+    }
+}
+
+/// Location and sizes of a single metadata block.
+#[derive(Clone, Copy, Debug)]
+pub struct MetadataBlock {
+    /// Offset of the block header in the image.
+    pub start: u64,
+    pub compressed: bool,
+    /// Size on disk, excluding the 2 byte header.
+    pub disk_size: u16,
+    /// Decompressed size.
+    pub size: usize,
+}
+
+/// Space used by a file or directory tree, as returned by `Image::disk_usage`.
+/// Hard links are counted once.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Sum of regular file sizes.
+    pub apparent_size: u64,
+    /// Bytes taken by the files' own data blocks, as stored. Sparse blocks
+    /// take none.
+    pub disk_size: u64,
+    /// Bytes of file tails packed into fragment blocks shared with other
+    /// files. These are part of `apparent_size` but not of `disk_size`.
+    pub fragment_bytes: u64,
+}
+
+/// Entry counts and total file size under a directory, as returned by
+/// `Image::dir_size`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DirSize {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// Device, fifo and socket nodes.
+    pub others: u64,
+    /// Sum of regular file sizes, counting hard linked files once.
+    pub size: u64,
+}
+
+/// Decoded tables of an image, as returned by `Image::read_fs`.
+#[derive(Debug)]
+pub struct Filesystem {
+    superblock: Superblock,
+    root: InodeHeader,
+    inodes: Vec<InodeHeader>,
+    fragments: Vec<FragmentEntry>,
+    id_table: IDTable,
+    export_table: Vec<u64>,
+    xattr_ids: Vec<XattrId>,
+}
+
+impl Filesystem {
+    pub fn superblock(&self) -> &Superblock {
+        &self.superblock
+    }
+
+    pub fn root(&self) -> &InodeHeader {
+        &self.root
+    }
+
+    /// All inodes, in inode table order.
+    pub fn inodes(&self) -> &[InodeHeader] {
+        &self.inodes
+    }
+
+    pub fn fragments(&self) -> &[FragmentEntry] {
+        &self.fragments
+    }
+
+    pub fn id_table(&self) -> &IDTable {
+        &self.id_table
+    }
+
+    /// Inode references by inode number minus 1, empty without an export
+    /// table.
+    pub fn export_table(&self) -> &[u64] {
+        &self.export_table
+    }
+
+    /// Empty without xattrs.
+    pub fn xattr_ids(&self) -> &[XattrId] {
+        &self.xattr_ids
+    }
+}
+
+enum TableLocation {
+    Absent,
+    Run { start: u64, end: u64 },
+    Indexed { index_start: u64, bytes: usize },
+}
+
 #[derive(Clone, Debug)]
 pub struct Image<R: ReadSeek> {
-    reader: RefCell<R>,
+    reader: RefCell<TrackedReader<R>>,
     superblock: Superblock,
-    inode_hash_table: HashMap<i64, RefCell<InodeEntry>>,
-    directory_hash_table: HashMap<i64, RefCell<DirectoryEntry>>,
+    // inode and directory table blocks by position in the image
+    metadata_cache: RefCell<LruCache<u64, CachedBlock>>,
+    warnings: RefCell<Vec<Warning>>,
+    inode_cache: RefCell<LruCache<u32, InodeHeader>>,
+    metrics: Arc<dyn Metrics>,
+    lenient_root: bool,
+    memory_budget: Option<usize>,
+    // share of the budget for the buffer pool, released with the image
+    pool_limit: Option<PoolLimit>,
+    // length of the reader when opened, short of `bytes_used` if truncated
+    len: u64,
+}
+
+#[cfg(feature = "positioned-io")]
+impl<I: positioned_io::ReadAt + positioned_io::Size> Image<ReadAtReader<I>> {
+    /// Opens an image read through a positioned reader.
+    pub fn from_read_at(inner: I) -> Result<Self> {
+        Image::new(ReadAtReader::new(Arc::new(inner)))
+    }
+}
+
+impl Image<Spooled> {
+    /// Opens an image from a stream that can't seek, like a pipe. The
+    /// stream is read to its end first, into memory if it is at most
+    /// `memory_limit` bytes long and into a temporary file otherwise.
+    pub fn from_stream<S: Read>(stream: S, memory_limit: u64) -> Result<Self> {
+        Image::new(Spooled::new(stream, memory_limit)?)
+    }
 }
 
 impl<'a, R: ReadSeek> Image<R> {
-    pub fn new(mut reader: R) -> Result<Self> {
+    pub fn new(reader: R) -> Result<Self> {
+        Self::with_metrics(reader, Arc::new(NoMetrics))
+    }
+
+    /// Opens an image reporting to `metrics` from the start, so opening it
+    /// is measured too.
+    pub fn with_metrics(mut reader: R, metrics: Arc<dyn Metrics>) -> Result<Self> {
+        let timer = PhaseTimer::new(&*metrics, Phase::Open);
         let sb = Superblock::new(&mut reader)?;
-        Ok(Self {
-            reader: reader.into(),
+        // fail now rather than on the first read
+        if !SUPPORTED_IDS.contains(&sb.compressor()) {
+            return Err(UnsupportedCompressor::error(sb.compressor()));
+        }
+        let len = reader.seek(SeekFrom::End(0))?;
+        trace_span!(
+            INFO,
+            "open",
+            bytes_used = sb.bytes_used(),
+            block_size = sb.block_size(),
+            inodes = sb.inodes()
+        );
+        let image = Self {
+            reader: RefCell::new(TrackedReader::new(reader, metrics.clone())),
             superblock: sb,
-            inode_hash_table: HashMap::new(),
-            directory_hash_table: HashMap::new(),
-        })
+            metadata_cache: RefCell::new(LruCache::new(CacheConfig::default().metadata_blocks)),
+            warnings: RefCell::new(vec![]),
+            inode_cache: RefCell::new(LruCache::new(CacheConfig::default().inodes)),
+            metrics: metrics.clone(),
+            lenient_root: false,
+            memory_budget: None,
+            pool_limit: None,
+            len,
+        };
+        image.check_superblock()?;
+        drop(timer);
+        Ok(image)
+    }
+
+    /// Anomalies found so far. Tables are checked as they are read, so
+    /// this grows as more of the image is accessed.
+    pub fn warnings(&self) -> Vec<Warning> {
+        self.warnings.borrow().clone()
+    }
+
+    pub(crate) fn warn(&self, warning: Warning) {
+        let mut warnings = self.warnings.borrow_mut();
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+
+    fn check_superblock(&self) -> Result<()> {
+        let sb = &self.superblock;
+        let flags = sb.raw_flags();
+        if flags & !Flags::all().bits() != 0 {
+            self.warn(Warning::UnknownFlags(flags & !Flags::all().bits()));
+        }
+        if (sb.version_major(), sb.version_minor()) != (4, 0) {
+            self.warn(Warning::Version(sb.version_major(), sb.version_minor()));
+        }
+
+        if self.len < sb.bytes_used() {
+            self.warn(Warning::Truncated {
+                len: self.len,
+                bytes_used: sb.bytes_used(),
+            });
+        }
+
+        let mut reader = self.reader.borrow_mut();
+        let end = self.len.min(sb.bytes_used().next_multiple_of(PADDING_SIZE));
+        if end > sb.bytes_used() {
+            let mut padding = vec![];
+            reader.seek(SeekFrom::Start(sb.bytes_used()))?;
+            reader
+                .deref_mut()
+                .take(end - sb.bytes_used())
+                .read_to_end(&mut padding)?;
+            if let Some(i) = padding.iter().position(|b| *b != 0) {
+                drop(reader);
+                self.warn(Warning::Padding {
+                    offset: sb.bytes_used() + i as u64,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // The decompressed metadata block at `start`, and its size on disk.
+    fn cached_metadata_block(&self, start: u64) -> Result<CachedBlock> {
+        if let Some(block) = self.metadata_cache.borrow_mut().get(&start) {
+            self.metrics.cache_hit(CacheKind::MetadataBlocks);
+            return Ok(block);
+        }
+        self.metrics.cache_miss(CacheKind::MetadataBlocks);
+        if start >= self.superblock.bytes_used() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "metadata runs past the end of the image",
+            ));
+        }
+        let compressor = self.decompressor(BlockKind::Metadata)?;
+        let mut buf = Vec::with_capacity(METADATA_SIZE);
+        let disk_size = read_block(
+            self.reader.borrow_mut().deref_mut(),
+            &mut buf,
+            &compressor,
+            start,
+            None,
+        )? as u64;
+        let size = buf.len();
+        let block = (Arc::new(buf), disk_size);
+        self.metadata_cache
+            .borrow_mut()
+            .insert(start, block.clone(), size);
+        Ok(block)
+    }
+
+    // Parses a record starting `offset` bytes into the metadata block at
+    // `table_start + block`, adding the following blocks of the table
+    // while the record runs past them.
+    fn parse_metadata<T>(
+        &self,
+        table: Range<u64>,
+        block: u32,
+        offset: u16,
+        parse: impl Fn(&mut &[u8]) -> Result<T>,
+    ) -> Result<T> {
+        let mut next = table.start + block as u64;
+        let (first, disk_size) = self.cached_metadata_block(next)?;
+        next += disk_size;
+        let mut buf = first
+            .get(offset as usize..)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("metadata offset out of range: {}:{}", block, offset),
+                )
+            })?;
+        loop {
+            match parse(&mut &buf[..]) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof && next < table.end => {
+                    let (block, disk_size) = self.cached_metadata_block(next)?;
+                    buf.extend_from_slice(&block);
+                    next += disk_size;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Changes the limits of the caches, evicting entries as needed.
+    pub fn set_cache_config(&mut self, config: CacheConfig) {
+        self.inode_cache.get_mut().set_limits(config.inodes);
+        self.metadata_cache
+            .get_mut()
+            .set_limits(config.metadata_blocks);
+    }
+
+    /// Keeps the memory used by the caches and read buffers of the image,
+    /// and by blocks queued during extraction, to about `bytes`: half goes
+    /// to the caches, as `CacheConfig::within`, a quarter to reading
+    /// ahead in table scans and extraction, and a quarter to the block
+    /// buffers kept for reuse. That pool is shared by all images, so it
+    /// keeps to the smallest budget of the images alive; a budget stops
+    /// applying to it when replaced or when the image is dropped.
+    /// Operations still hold the few blocks they are working on past it,
+    /// and whole tables when they return them.
+    pub fn set_memory_budget(&mut self, bytes: usize) {
+        self.set_cache_config(CacheConfig::within(bytes / 2));
+        self.reader.get_mut().set_bulk_read(bytes / 4);
+        self.pool_limit = Some(PoolLimit::new(bytes / 4));
+        self.memory_budget = Some(bytes);
+    }
+
+    pub fn memory_budget(&self) -> Option<usize> {
+        self.memory_budget
+    }
+
+    /// Sends the events of further accesses to `metrics`.
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.reader.get_mut().set_metrics(metrics.clone());
+        self.metrics = metrics;
     }
 
-    pub fn get_inode_metadata(&mut self, start: i64) -> Result<RefCell<InodeEntry>> {
-        if let Some(entry) = self.inode_hash_table.get(&start) {
-            Ok(entry.clone())
-        } else {
-            let compressor = self.compressor()?;
-            let inode_start = self.superblock.inode_table_start();
-            let reader = self.reader.get_mut();
-            let mut buf = Vec::with_capacity(METADATA_SIZE);
+    // The image compressor, reporting the blocks of `kind` it decompresses.
+    pub(crate) fn decompressor(&self, kind: BlockKind) -> Result<MeteredDecompressor> {
+        Ok(MeteredDecompressor::new(
+            self.compressor()?,
+            kind,
+            self.metrics.clone(),
+        ))
+    }
 
-            read_block(
-                reader,
-                &mut buf,
-                &compressor,
-                (inode_start + start) as u64,
-                Some(METADATA_SIZE as u32),
-            )?;
-            let entry = InodeEntry::new(buf)?;
-            self.inode_hash_table.insert(start, RefCell::new(entry));
-            self.inode_hash_table
-                .get(&start)
-                .map(Clone::clone)
-                .ok_or(Error::new(ErrorKind::Other, "no entry found"))
+    pub fn cache_stats(&self) -> ImageCacheStats {
+        ImageCacheStats {
+            inodes: self.inode_cache.borrow().stats(),
+            metadata_blocks: self.metadata_cache.borrow().stats(),
         }
     }
 
     pub fn export_table(&self) -> Result<Vec<u64>> {
-        let lookup_table_start = self.superblock.export_table_start();
-        if lookup_table_start == INVALID_BLK {
-            return Ok(vec![]);
+        decode_le_slice(&self.raw_table(TableKind::Export)?)
+    }
+
+    pub fn id_table(&self) -> Result<IDTable> {
+        let bytes = self.superblock.no_ids() as usize * mem::size_of::<u32>();
+        let table = self.read_indexed_table(self.superblock.id_table_start(), bytes)?;
+        Ok(IDTable(decode_le_slice(&table)?))
+    }
+
+    /// Reads the single id at `index`, like `id_table()?.get(index)` but
+    /// without reading the rest of the table, for images with many ids.
+    pub fn id(&self, index: u16) -> Result<u32> {
+        if index >= self.superblock.no_ids() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("id index {} out of range", index),
+            ));
         }
-        let inodes = self.superblock.inodes() as usize;
-        let lookup_bytes = inodes * INODE_ENTRY_SIZE as usize;
-        // indexes
-        let lookup_blocks = (lookup_bytes as usize + METADATA_SIZE - 1) / METADATA_SIZE;
-        let lookup_block_bytes = lookup_blocks * mem::size_of::<u64>();
-        let compressor = self.compressor()?;
+        let mut bytes = [0; mem::size_of::<u32>()];
+        self.read_table_entry(self.superblock.id_table_start(), index as usize, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn compressor(&self) -> Result<Compressor> {
+        let id = self.superblock.compressor();
+        if !self
+            .superblock
+            .flags()
+            .contains(Flags::COMPRESSOR_OPTIONS_PRESENT)
+        {
+            return Compressor::new(id, false, &mut std::io::empty());
+        }
+        // the options are a metadata block, which mksquashfs stores
+        // uncompressed; the options don't change how it decompresses
+        let mut options = vec![];
+        read_block(
+            self.reader.borrow_mut().deref_mut(),
+            &mut options,
+            &Compressor::new(id, false, &mut std::io::empty())?,
+            SUPERBLOCK_SIZE as u64,
+            None,
+        )?;
+        Compressor::new(id, true, &mut std::io::Cursor::new(options))
+    }
+
+    /// Reads all the tables of the image at once.
+    pub fn read_fs(&self) -> Result<Filesystem> {
+        let (root, inodes) = self.inodes()?;
+        Ok(Filesystem {
+            superblock: self.superblock,
+            root,
+            inodes,
+            fragments: self.fragments()?,
+            id_table: self.id_table()?,
+            export_table: self.export_table()?,
+            xattr_ids: self.xattr_ids()?,
+        })
+    }
+
+    pub fn inodes(&self) -> Result<(InodeHeader, Vec<InodeHeader>)> {
+        let compressor = self.decompressor(BlockKind::Metadata)?;
+        let extent = self.superblock.inode_table_start() as u64
+            ..self.superblock.directory_table_start() as u64;
+        self.scan_extent(extent, |reader| {
+            scan_inode_table(reader, &self.superblock, &compressor, self.lenient_root)
+        })
+        .and_then(|(root, inodes)| {
+            self.check_root(&root)?;
+            Ok((root, inodes))
+        })
+    }
 
-        dbg!(inodes, lookup_bytes, lookup_blocks, lookup_block_bytes);
+    /// Returns the whole inode table, decompressed, for scanning with
+    /// `InodeViews` without parsing every inode into an `InodeHeader`.
+    pub fn inode_table(&self) -> Result<Vec<u8>> {
+        self.read_metadata_run(
+            self.superblock.inode_table_start() as u64,
+            self.superblock.directory_table_start() as u64,
+        )
+    }
 
+    pub fn fragments(&self) -> Result<Vec<FragmentEntry>> {
+        let table = self.raw_table(TableKind::Fragment)?;
+        Ok(table
+            .chunks_exact(FRAGMENT_ENTRY_SIZE)
+            .map(|entry| FragmentEntry::new(entry.try_into().unwrap()))
+            .collect())
+    }
+
+    // Runs `scan` with the reads it makes in `extent` issued in large
+    // chunks, for functions reading a whole table.
+    fn scan_extent<T>(
+        &self,
+        extent: Range<u64>,
+        scan: impl FnOnce(&mut TrackedReader<R>) -> Result<T>,
+    ) -> Result<T> {
+        let _timer = PhaseTimer::new(&*self.metrics, Phase::TableScan);
+        trace_span!(
+            DEBUG,
+            "table_scan",
+            start = extent.start,
+            size = extent.end.saturating_sub(extent.start)
+        );
         let mut reader = self.reader.borrow_mut();
-        let reader = reader.deref_mut();
+        reader.set_extent(extent);
+        let result = scan(&mut reader);
+        reader.set_extent(0..0);
+        result
+    }
 
-        // let mut index = vec![0u8; lookup_block_bytes];
-        let mut index = Vec::with_capacity(lookup_block_bytes);
-        reader.seek(SeekFrom::Start(lookup_table_start as u64))?;
-        copy(&mut reader.take(lookup_block_bytes as u64), &mut index)?;
-
-        let index: Vec<i64> = index
-            .chunks(mem::size_of::<i64>())
-            .map(|x| {
-                let v = match x.try_into() {
-                    Ok(v) => v,
-                    Err(e) => {
-                        panic!("{}", e);
-                    }
-                };
-                i64::from_le_bytes(v)
-            })
-            .collect();
-
-        if index.len() != lookup_blocks {
-            panic!(
-                "index.len {}, inodes {}, lookup_blocks {}",
-                index.len(),
-                inodes,
-                lookup_blocks
-            );
+    /// Reads the image as stored, up to `bytes_used` and without the padding
+    /// after it, for hashing or copying the image itself. Reading fails if
+    /// the image is shorter than its superblock says.
+    pub fn raw_bytes(&self) -> RawBytes<'_, R> {
+        RawBytes {
+            image: self,
+            position: 0,
+            len: self.superblock.bytes_used(),
+        }
+    }
+
+    pub fn superblock(&'a self) -> &'a Superblock {
+        &self.superblock
+    }
+
+    /// Returns the decompressed but otherwise unparsed bytes of a metadata
+    /// table. Tables absent from the image are returned empty.
+    pub fn raw_table(&self, kind: TableKind) -> Result<Vec<u8>> {
+        match self.table_location(kind)? {
+            TableLocation::Run { start, end } => self.read_metadata_run(start, end),
+            TableLocation::Indexed { index_start, bytes } => {
+                self.read_indexed_table(index_start, bytes)
+            }
+            TableLocation::Absent => Ok(vec![]),
         }
+    }
 
-        let mut all_inodes = Vec::with_capacity(inodes);
-        for (i, ind) in index.iter().enumerate().take(lookup_blocks) {
-            let expected = match (i + 1) != lookup_blocks {
-                true => METADATA_SIZE,
-                false => (lookup_bytes as usize) & (METADATA_SIZE - 1),
+    /// Tables cut off by the end of the image, as in an interrupted
+    /// download: they, or the locations needed to find them, run past the
+    /// end of the reader. Tables are stored after the file data and the
+    /// inode and directory tables, so files can usually still be listed and
+    /// most read. Each table missing is added to `warnings`.
+    pub fn missing_tables(&self) -> Result<Vec<TableKind>> {
+        let mut missing = vec![];
+        for kind in [
+            TableKind::Inode,
+            TableKind::Directory,
+            TableKind::Fragment,
+            TableKind::Export,
+            TableKind::Id,
+            TableKind::Xattr,
+            TableKind::XattrId,
+        ] {
+            let end = match self.table_location(kind) {
+                Ok(TableLocation::Absent) => continue,
+                Ok(TableLocation::Run { end, .. }) => end,
+                Ok(TableLocation::Indexed { index_start, bytes }) => {
+                    index_start + (bytes.div_ceil(METADATA_SIZE) * mem::size_of::<u64>()) as u64
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => u64::MAX,
+                Err(e) => return Err(e),
             };
+            if end > self.len {
+                self.warn(Warning::MissingTable(kind));
+                missing.push(kind);
+            }
+        }
+        Ok(missing)
+    }
 
-            dbg!(i, inodes, ind, expected);
-
-            let mut block = vec![0u8; expected];
-            read::read_block(
-                reader,
-                &mut (&mut block[..]),
-                &compressor,
-                *ind as u64,
-                Some(expected as u32),
-            )?;
-            all_inodes.append(&mut block);
-        }
-
-        all_inodes
-            .chunks(mem::size_of::<u64>())
-            .map(|x| match x.try_into() {
-                Ok(buf) => Ok(u64::from_le_bytes(buf)),
-                Err(e) => Err(Error::new(
-                    ErrorKind::Other,
-                    format!("bad lookup id {:?}: {}", x, e),
-                )),
-            })
-            .collect()
+    // Whether the `disk_size` bytes at `start` are within the reader.
+    pub(crate) fn is_available(&self, start: u64, disk_size: u64) -> bool {
+        start.saturating_add(disk_size) <= self.len
     }
 
-    pub fn id_table(&self) -> Result<IDTable> {
-        let no_ids = self.superblock.no_ids();
+    /// Reads the inode header an inode reference points to, following it
+    /// into the next metadata blocks if it straddles a block boundary.
+    pub fn open_by_ref(&self, inode_ref: InodeRef) -> Result<InodeHeader> {
+        let table = self.superblock.inode_table_start() as u64
+            ..self.superblock.directory_table_start() as u64;
+        self.parse_metadata(table, inode_ref.block(), inode_ref.offset(), |record| {
+            read_inode_header(record, &self.superblock)
+        })
+    }
 
-        let no_ids_bytes = no_ids as usize * mem::size_of::<u32>();
-        let no_ids_blocks = (no_ids_bytes + METADATA_SIZE - 1) / METADATA_SIZE;
-        let no_ids_block_bytes = no_ids_blocks * mem::size_of::<i64>();
+    /// Reads the directory header `offset` bytes into the block at `block`
+    /// in the directory table, with the entries it covers, as found from a
+    /// directory inode or a `DirectoryIndex`.
+    pub fn read_dir_header(
+        &self,
+        block: u32,
+        offset: u16,
+    ) -> Result<(DirectoryHeader, Vec<DirectoryEntry>)> {
+        let table = self.superblock.directory_table_start() as u64..self.directory_table_end()?;
+        self.parse_metadata(table, block, offset, |record| {
+            let header = DirectoryHeader::from_reader(record)?;
+            if header.count() > DIRECTORY_HEADER_MAX_COUNT {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("directory header count too large: {}", header.count()),
+                ));
+            }
+            let entries = (0..=header.count())
+                .map(|_| DirectoryEntry::from_reader(&header, record))
+                .collect::<Result<_>>()?;
+            Ok((header, entries))
+        })
+    }
 
-        let compressor = self.compressor()?;
+    /// Inode of a directory entry. Recently used inodes are cached by inode
+    /// number, so lookups and walks over the same paths don't decode them
+    /// again.
+    pub fn open_entry(&self, dirent: &DirectoryEntry) -> Result<InodeHeader> {
+        if let Some(inode) = self.inode_cache.borrow_mut().get(&dirent.inode_number()) {
+            self.metrics.cache_hit(CacheKind::Inodes);
+            return Ok(inode);
+        }
+        self.metrics.cache_miss(CacheKind::Inodes);
+        let inode = self.open_by_ref(dirent.inode_ref())?;
+        let size = inode.memory_size();
+        self.inode_cache
+            .borrow_mut()
+            .insert(dirent.inode_number(), inode.clone(), size);
+        Ok(inode)
+    }
+
+    /// Reads the inodes of directory entries, in the same order. Entries
+    /// are sorted by inode location first so each metadata block is read
+    /// and decompressed once, instead of once per entry with `open_by_ref`.
+    pub fn stat_all(&self, entries: &[DirectoryEntry]) -> Result<Vec<InodeHeader>> {
+        let compressor = self.decompressor(BlockKind::Metadata)?;
         let mut reader = self.reader.borrow_mut();
         let reader = reader.deref_mut();
 
-        dbg!("no_ids_block_bytes {}", no_ids_block_bytes);
-        // let mut index = vec![0u8; no_ids_block_bytes];
-        let mut index = Vec::with_capacity(no_ids_block_bytes);
-        reader.seek(SeekFrom::Start(self.superblock.id_table_start() as u64))?;
-        copy(&mut reader.take(no_ids_block_bytes as u64), &mut index)?;
-        let index: Vec<i64> = index
-            .chunks(mem::size_of::<i64>())
-            .map(|x| {
-                let v = match x.try_into() {
-                    Ok(v) => v,
-                    Err(e) => {
-                        panic!("{}", e);
+        let table_start = self.superblock.inode_table_start() as u64;
+        let table_end = self.superblock.directory_table_start() as u64;
+        let mut order: Vec<usize> = (0..entries.len()).collect();
+        order.sort_by_key(|i| entries[*i].inode_ref());
+
+        let mut inodes: Vec<Option<InodeHeader>> = entries.iter().map(|_| None).collect();
+        // run of consecutive blocks held in buf, as (block, start in buf)
+        let mut blocks: Vec<(u32, usize)> = vec![];
+        let mut buf = Vec::with_capacity(METADATA_SIZE);
+        let mut next = 0;
+        for i in order {
+            let inode_ref = entries[i].inode_ref();
+            let block_start = match blocks.iter().find(|(b, _)| *b == inode_ref.block()) {
+                Some((_, start)) => *start,
+                None => {
+                    blocks.clear();
+                    buf.clear();
+                    next = table_start + inode_ref.block() as u64;
+                    blocks.push((inode_ref.block(), 0));
+                    next += read_block(reader, &mut buf, &compressor, next, None)? as u64;
+                    0
+                }
+            };
+            let start = block_start + inode_ref.offset() as usize;
+            if start >= buf.len() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("inode offset out of range: {}", inode_ref),
+                ));
+            }
+            loop {
+                match read_inode_header(&mut &buf[start..], &self.superblock) {
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof && next < table_end => {
+                        blocks.push(((next - table_start) as u32, buf.len()));
+                        next += read_block(reader, &mut buf, &compressor, next, None)? as u64;
+                    }
+                    result => {
+                        inodes[i] = Some(result?);
+                        break;
                     }
+                }
+            }
+        }
+        Ok(inodes.into_iter().flatten().collect())
+    }
+
+    pub fn root(&self) -> Result<InodeHeader> {
+        let root = self.open_by_ref(self.superblock.root_inode_ref())?;
+        self.check_root(&root)?;
+        Ok(root)
+    }
+
+    /// Lets a root inode that isn't a directory through, recording
+    /// `Warning::RootNotDirectory` instead of failing. Only inode level
+    /// access is meaningful on such images; path based APIs still fail.
+    pub fn set_lenient_root(&mut self, lenient: bool) {
+        self.lenient_root = lenient;
+    }
+
+    fn check_root(&self, root: &InodeHeader) -> Result<()> {
+        match check_root_inode(root) {
+            Err(_) if self.lenient_root => {
+                self.warn(Warning::RootNotDirectory(root.file_type()));
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Lists a directory in on-disk order, see `Order::OnDisk`. `.` and
+    /// `..` are not stored; see `list_dir_with` to have them listed.
+    pub fn read_dir(&self, dir: &InodeHeader) -> Result<Vec<DirectoryEntry>> {
+        let (block, offset, file_size) = match dir {
+            InodeHeader::Directory(d) => (d.start_block(), d.offset(), d.file_size() as u32),
+            InodeHeader::LDirectory(d) => (d.start_block(), d.offset(), d.file_size()),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "not a directory")),
+        };
+        // the size accounts for the implicit `.` and `..` entries
+        let size = file_size.saturating_sub(3) as usize;
+        if size == 0 {
+            return Ok(vec![]);
+        }
+        let table_start = self.superblock.directory_table_start() as u64;
+        let listing = self.read_metadata(table_start, block, offset, size)?;
+        read_directory_listing(&mut &listing[..], size as u64)
+    }
+
+    /// Lists a directory from `offset` on, with `.` and `..` first, as FUSE
+    /// and NFS frontends do. Offset 0 starts from the beginning and each
+    /// entry carries the offset to resume after it, which stays valid for
+    /// as long as the image is open and across handles.
+    pub fn readdir(&self, dir: &InodeHeader, offset: i64) -> Result<Readdir> {
+        Readdir::new(self, dir, offset)
+    }
+
+    /// Lists a directory in the given order.
+    pub fn read_dir_with(&self, dir: &InodeHeader, order: Order) -> Result<Vec<DirectoryEntry>> {
+        let mut entries = self.read_dir(dir)?;
+        order.sort(&mut entries);
+        Ok(entries)
+    }
+
+    /// Lists the directory at `path`. Entries carry their name and type, so
+    /// unlike walking, this reads no inode but the directory's own.
+    pub fn list_dir<P: AsRef<[u8]>>(&self, path: P) -> Result<Vec<DirectoryEntry>> {
+        self.read_dir(&self.lookup(path)?)
+    }
+
+    /// Lists the directory at `path` as set in `options`. The `.` and `..`
+    /// entries, when asked for, carry the inode number and reference of the
+    /// directory and of its parent; the root is its own parent.
+    pub fn list_dir_with<P: AsRef<[u8]>>(
+        &self,
+        path: P,
+        options: &ListOptions,
+    ) -> Result<Vec<DirectoryEntry>> {
+        let (path, dir) = self.resolve(path.as_ref(), &LookupOptions::default())?;
+        let mut entries = self.read_dir_with(&dir, options.order)?;
+        if options.dots {
+            let parent = path.parent().unwrap_or_else(SqshPath::root);
+            let parent_number = self.parent_number(&dir)?;
+            let dot = |name: &[u8], inode_ref: InodeRef, number: u32| {
+                let header = DirectoryHeader::new(1, inode_ref.block(), number);
+                DirectoryEntry::new(&header, inode_ref.offset(), 0, FileType::Directory, name)
+            };
+            let dots = [
+                dot(b".", self.dir_ref(&path)?, dir.inode_number()),
+                dot(b"..", self.dir_ref(&parent)?, parent_number),
+            ];
+            entries.splice(0..0, dots);
+        }
+        Ok(entries)
+    }
+
+    // Inode number of the parent of a directory, as stored in its inode.
+    // The root is its own parent: mksquashfs stores one past the last
+    // inode for it.
+    pub(crate) fn parent_number(&self, dir: &InodeHeader) -> Result<u32> {
+        let parent = match dir {
+            InodeHeader::Directory(d) => d.parent_inode(),
+            InodeHeader::LDirectory(d) => d.parent_inode(),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "not a directory")),
+        };
+        match dir.inode_number() == self.root()?.inode_number() {
+            true => Ok(dir.inode_number()),
+            false => Ok(parent),
+        }
+    }
+
+    // Reference to the inode of a resolved directory path, which is only
+    // stored in the listing of its parent.
+    fn dir_ref(&self, path: &SqshPath) -> Result<InodeRef> {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Ok(self.superblock.root_inode_ref());
+        };
+        self.list_dir(&parent)?
+            .into_iter()
+            .find(|e| e.name() == name)
+            .map(|e| e.inode_ref())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{}: not found", path)))
+    }
+
+    /// Resolves a path from the image root, without following symlinks.
+    pub fn lookup<P: AsRef<[u8]>>(&self, path: P) -> Result<InodeHeader> {
+        self.lookup_with(path, &LookupOptions::default())
+    }
+
+    /// Resolves a path from the image root, following symlinks as set in
+    /// `options`. Links are resolved within the image: absolute targets
+    /// start from the image root and `..` never leaves it.
+    pub fn lookup_with<P: AsRef<[u8]>>(
+        &self,
+        path: P,
+        options: &LookupOptions,
+    ) -> Result<InodeHeader> {
+        self.resolve(path.as_ref(), options).map(|(_, inode)| inode)
+    }
+
+    /// Whether `path` exists, failing only if the image can't be read.
+    pub fn try_exists<P: AsRef<[u8]>>(&self, path: P) -> Result<bool> {
+        match self.lookup(path) {
+            Ok(_) => Ok(true),
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Like `try_exists`, but read errors count as the path not existing.
+    pub fn exists<P: AsRef<[u8]>>(&self, path: P) -> bool {
+        self.try_exists(path).unwrap_or(false)
+    }
+
+    /// Symlinks aren't followed, so this is false for a link to a directory.
+    pub fn is_dir<P: AsRef<[u8]>>(&self, path: P) -> bool {
+        self.lookup(path).map(|i| i.is_dir()).unwrap_or(false)
+    }
+
+    pub fn is_file<P: AsRef<[u8]>>(&self, path: P) -> bool {
+        self.lookup(path).map(|i| i.is_file()).unwrap_or(false)
+    }
+
+    /// Walks the tree rooted at `path` depth-first, listing directories in
+    /// on-disk order.
+    pub fn walk<P: AsRef<[u8]>>(&self, path: P) -> Result<Walk<'_, R>> {
+        self.walk_with(path, &WalkOptions::default())
+    }
+
+    pub fn walk_with<P: AsRef<[u8]>>(&self, path: P, options: &WalkOptions) -> Result<Walk<'_, R>> {
+        let (path, inode) = self.resolve(path.as_ref(), &LookupOptions::default())?;
+        Ok(Walk::new(self, path, inode, *options))
+    }
+
+    /// Every entry of the image with the contents of its regular files,
+    /// for converting to a format like tar in one pass. Entries without
+    /// data come first, in `walk` order, followed by files in the order
+    /// their data is stored, so reading each file in turn moves forward
+    /// through the image except to reach fragment blocks.
+    ///
+    /// The fragment table is read upfront too, so file tails don't need a
+    /// trip to the table each.
+    pub fn entries(&self) -> Result<Entries<'_, R>> {
+        let fragments = self.fragments()?;
+        let mut entries: Vec<_> = self
+            .walk("/")?
+            .map(|entry| entry.map(|entry| (data_start(&entry.inode, &fragments), entry)))
+            .collect::<Result<_>>()?;
+        entries.sort_by_key(|(start, _)| *start);
+        Ok(Entries::new(
+            self,
+            entries.into_iter().map(|(_, entry)| entry).collect(),
+            fragments,
+        ))
+    }
+
+    /// Opens a regular file for reading.
+    pub fn open_file(&self, inode: &InodeHeader) -> Result<FileReader<'_, R>> {
+        let (start, fragment, offset, file_size, blocks) = match inode {
+            InodeHeader::Regular(r) => (
+                r.start_block() as u64,
+                r.fragment(),
+                r.offset(),
+                r.file_size() as u64,
+                r.blocks(),
+            ),
+            InodeHeader::LRegular(r) => (
+                r.start_block(),
+                r.fragment(),
+                r.offset(),
+                r.file_size(),
+                r.blocks(),
+            ),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "not a regular file")),
+        };
+        let fragment = match fragment {
+            INVALID_FRAG => None,
+            fragment => Some((fragment, offset)),
+        };
+        FileReader::new(self, start, blocks.to_vec(), fragment, file_size)
+    }
+
+    /// A pool to open many regular files at once and read them at any
+    /// offset, keeping a decompressed block for at most `max_live` of them.
+    pub fn handles(&self, max_live: usize) -> Result<HandlePool<'_, R>> {
+        HandlePool::new(self, max_live)
+    }
+
+    pub fn disk_usage<P: AsRef<[u8]>>(&self, path: P) -> Result<DiskUsage> {
+        let block_size = self.superblock.block_size() as u64;
+        let mut usage = DiskUsage::default();
+        let mut seen = HashSet::new();
+        for entry in self.walk(path)? {
+            let inode = entry?.inode;
+            let (file_size, fragment, blocks) = match &inode {
+                InodeHeader::Regular(r) => (r.file_size() as u64, r.fragment(), r.blocks()),
+                InodeHeader::LRegular(r) => (r.file_size(), r.fragment(), r.blocks()),
+                _ => continue,
+            };
+            if !seen.insert(inode.inode_number()) {
+                continue;
+            }
+            usage.apparent_size += file_size;
+            usage.disk_size += blocks
+                .iter()
+                .map(|b| (b & !COMPRESSED_BIT_BLOCK) as u64)
+                .sum::<u64>();
+            if fragment != INVALID_FRAG {
+                usage.fragment_bytes += file_size.saturating_sub(blocks.len() as u64 * block_size);
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Counts the entries below a directory, not including the directory
+    /// itself. Symlinks are counted but never followed, so link loops can't
+    /// make the count recurse.
+    pub fn dir_size<P: AsRef<[u8]>>(&self, path: P) -> Result<DirSize> {
+        let mut walk = self.walk(path)?;
+        match walk.next() {
+            Some(Ok(entry)) if !entry.inode.is_dir() => {
+                return Err(Error::new(ErrorKind::InvalidInput, "not a directory"))
+            }
+            Some(Err(e)) => return Err(e),
+            _ => {}
+        }
+
+        let mut dir_size = DirSize::default();
+        let mut seen = HashSet::new();
+        for entry in walk {
+            let inode = entry?.inode;
+            let file_size = match &inode {
+                InodeHeader::Regular(r) => r.file_size() as u64,
+                InodeHeader::LRegular(r) => r.file_size(),
+                InodeHeader::Directory(_) | InodeHeader::LDirectory(_) => {
+                    dir_size.directories += 1;
+                    continue;
+                }
+                InodeHeader::Symlink(_) | InodeHeader::LSymlink(_) => {
+                    dir_size.symlinks += 1;
+                    continue;
+                }
+                _ => {
+                    dir_size.others += 1;
+                    continue;
+                }
+            };
+            dir_size.files += 1;
+            if seen.insert(inode.inode_number()) {
+                dir_size.size += file_size;
+            }
+        }
+        Ok(dir_size)
+    }
+
+    /// Summarizes each directory of the tree at `path`, in walk order: the
+    /// number and size of its entries by type, directly in it and in all
+    /// of its subtree, and the `largest` biggest files of its subtree.
+    pub fn dir_stats<P: AsRef<[u8]>>(&self, path: P, largest: usize) -> Result<Vec<DirStats>> {
+        analyze::dir_stats(self, path.as_ref(), largest)
+    }
+
+    /// Reads and decompresses every data block and fragment holding the
+    /// contents of the files below `path`, without assembling the files,
+    /// and returns the blocks that fail, by offset. Blocks shared by several
+    /// files are read once. Metadata that can't be read fails the call.
+    pub fn verify_blocks<P: AsRef<[u8]>>(&self, path: P) -> Result<Vec<BadBlock>> {
+        integrity::verify_blocks(self, path.as_ref())
+    }
+
+    /// Lists the tree at `path` with the metadata of each entry, sorted by
+    /// name, to be written out as JSON, CSV or a drawn tree.
+    pub fn manifest<P: AsRef<[u8]>>(&self, path: P) -> Result<Manifest> {
+        Manifest::new(self, path.as_ref())
+    }
+
+    /// Lists the entries of the tree at `path` that compliance checks
+    /// usually flag: setuid and setgid files, world-writable files and
+    /// devices outside `/dev`. An entry is listed once per issue, in walk
+    /// order.
+    pub fn audit<P: AsRef<[u8]>>(&self, path: P) -> Result<Vec<Finding>> {
+        audit::audit(self, path.as_ref())
+    }
+
+    /// Recomputes link counts from the directory entries of the whole tree
+    /// and compares them with the `nlink` of each inode: a directory has 2
+    /// plus one link per subdirectory, and other inodes one link per entry
+    /// pointing to them. Mismatches are returned sorted by inode number,
+    /// and also added to `warnings`.
+    pub fn check_links(&self) -> Result<Vec<Warning>> {
+        // nlink and entries counted for each inode
+        let mut links: BTreeMap<u32, (u32, u32)> = BTreeMap::new();
+        let mut visited = HashSet::new();
+        let mut dirs = vec![self.root()?];
+        while let Some(dir) = dirs.pop() {
+            if !visited.insert(dir.inode_number()) {
+                continue;
+            }
+            let mut subdirs = 0;
+            for inode in self.stat_all(&self.read_dir(&dir)?)? {
+                if inode.is_dir() {
+                    subdirs += 1;
+                    dirs.push(inode);
+                } else {
+                    links
+                        .entry(inode.inode_number())
+                        .or_insert((inode.nlink(), 0))
+                        .1 += 1;
+                }
+            }
+            links.insert(dir.inode_number(), (dir.nlink(), 2 + subdirs));
+        }
+
+        let mut warnings = vec![];
+        for (inode_number, (nlink, expected)) in links {
+            if nlink != expected {
+                let warning = Warning::LinkCount {
+                    inode_number,
+                    nlink,
+                    expected,
                 };
-                i64::from_le_bytes(v)
-            })
-            .collect();
+                self.warn(warning.clone());
+                warnings.push(warning);
+            }
+        }
+        Ok(warnings)
+    }
+
+    /// Compares the tree at `path` with the same tree in `other`, such as
+    /// a rebuild of the image, as set in `options`. Returns the paths that
+    /// differ, sorted; the contents of a directory in only one of the
+    /// images are not listed.
+    pub fn compare<P: AsRef<[u8]>, S: ReadSeek>(
+        &self,
+        other: &Image<S>,
+        path: P,
+        options: &CompareOptions,
+    ) -> Result<Vec<(SqshPath, Difference)>> {
+        compare::compare(self, other, path.as_ref(), options)
+    }
+
+    /// Compares the image tree with a directory on disk, such as a flashed
+    /// root filesystem: file types, modes, owners, symlink targets and file
+    /// contents. Timestamps are not compared. Returns the paths that differ,
+    /// in walk order; the contents of a directory missing on disk are not
+    /// listed.
+    #[cfg(unix)]
+    pub fn verify_against<P: AsRef<Path>>(&self, path: P) -> Result<Vec<(SqshPath, Mismatch)>> {
+        verify::verify(self, path.as_ref())
+    }
 
-        let mut id_table = Vec::with_capacity(no_ids as usize);
-        for (i, index) in index.iter().enumerate().take(no_ids_blocks) {
-            let expected = match (i + 1) != no_ids_blocks as usize {
-                true => METADATA_SIZE,
-                false => no_ids_bytes & (METADATA_SIZE - 1),
+    /// Extracts the tree at `path` into the `dest` directory, which is
+    /// created if needed. Owners, modes, hard links, devices and the mtime of
+    /// files and directories are restored, and xattrs of the namespaces in
+    /// `options.xattrs`. Entries already on disk are handled as
+    /// `options.on_conflict` says.
+    #[cfg(unix)]
+    pub fn extract<P: AsRef<[u8]>, D: AsRef<Path>>(
+        &self,
+        path: P,
+        dest: D,
+        options: &ExtractOptions,
+    ) -> Result<()> {
+        let _timer = PhaseTimer::new(&*self.metrics, Phase::Extract);
+        trace_span!(
+            INFO,
+            "extract",
+            path = %String::from_utf8_lossy(path.as_ref()),
+            dest = %dest.as_ref().display()
+        );
+        extract::extract(self, path.as_ref(), dest.as_ref(), options)
+    }
+
+    // Resolves a path to its inode and the path of that inode, with the
+    // followed symlinks replaced by their targets.
+    fn resolve(&self, path: &[u8], options: &LookupOptions) -> Result<(SqshPath, InodeHeader)> {
+        let _timer = PhaseTimer::new(&*self.metrics, Phase::Lookup);
+        trace_span!(DEBUG, "lookup", path = %String::from_utf8_lossy(path));
+        let not_found = || {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("{}: not found", String::from_utf8_lossy(path)),
+            )
+        };
+        let mut pending: VecDeque<Vec<u8>> =
+            path.split(|c| *c == b'/').map(<[u8]>::to_vec).collect();
+        let mut resolved = SqshPath::root();
+        let mut parents = vec![];
+        let mut inode = self.root()?;
+        let mut links = 0;
+        while let Some(name) = pending.pop_front() {
+            if !inode.is_dir() {
+                return Err(Error::new(
+                    ErrorKind::NotADirectory,
+                    format!("{}: not a directory", String::from_utf8_lossy(path)),
+                ));
+            }
+            match &name[..] {
+                b"" | b"." => continue,
+                b".." => {
+                    if let Some(parent) = parents.pop() {
+                        inode = parent;
+                        resolved = resolved.parent().unwrap_or_default();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let dirent = self
+                .read_dir(&inode)?
+                .into_iter()
+                .find(|e| e.name() == name)
+                .ok_or_else(not_found)?;
+            let child = self.open_entry(&dirent)?;
+
+            // a trailing slash makes the last component an intermediate one
+            let follow = match pending.is_empty() {
+                true => options.follow_final,
+                false => options.follow_intermediate,
             };
+            if let (true, InodeHeader::Symlink(s) | InodeHeader::LSymlink(s)) = (follow, &child) {
+                links += 1;
+                if links > options.max_symlinks {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "{}: too many levels of symbolic links",
+                            String::from_utf8_lossy(path)
+                        ),
+                    ));
+                }
+                if s.target().starts_with(b"/") {
+                    inode = self.root()?;
+                    resolved = SqshPath::root();
+                    parents.clear();
+                }
+                for name in s.target().split(|c| *c == b'/').rev() {
+                    pending.push_front(name.to_vec());
+                }
+                continue;
+            }
+            resolved.push(&name)?;
+            parents.push(mem::replace(&mut inode, child));
+        }
+        Ok((resolved, inode))
+    }
+
+    /// Best-effort recovery for images with damaged tables: scans the whole
+    /// image for metadata blocks instead of following table pointers,
+    /// parses whatever inode records can be found in them, and rebuilds
+    /// their paths from the directory listings found, as
+    /// `salvage::rebuild_tree` does. Only the metadata blocks found are kept
+    /// in memory.
+    pub fn salvage(&self) -> Result<Salvage> {
+        let compressor = self.compressor()?;
+        let mut reader = self.reader.borrow_mut();
+        let reader = reader.deref_mut();
 
-            dbg!(index, no_ids, i, no_ids_blocks, expected);
-
-            let mut block = vec![0u8; expected];
-            read::read_block(
-                reader,
-                &mut block,
-                &compressor,
-                *index as u64,
-                Some(expected as u32),
-            )?;
-            id_table.append(&mut block);
-        }
-
-        let id_table = id_table
-            .chunks(mem::size_of::<i32>())
-            .map(|x| {
-                let buf: [u8; 4] = match x.try_into() {
-                    Ok(v) => v,
-                    Err(e) => {
-                        panic!("{}", e);
+        let end = reader.seek(SeekFrom::End(0))?;
+        let blocks =
+            salvage::scan_metadata_blocks(reader, &compressor, SUPERBLOCK_SIZE as u64, end)?;
+        let inodes = salvage::recover_inodes(&blocks, &self.superblock);
+        let entries = salvage::rebuild_tree(&blocks, &inodes, &self.superblock);
+        Ok(Salvage {
+            blocks,
+            inodes,
+            entries,
+        })
+    }
+
+    /// Writes what `salvage` recovered under `dest`: directories, symlinks
+    /// and the contents of regular files, read through their inodes as far
+    /// as the data and fragment blocks allow. Returns the files that
+    /// couldn't be read in full, with the error.
+    #[cfg(unix)]
+    pub fn extract_salvaged<D: AsRef<Path>>(
+        &self,
+        salvage: &Salvage,
+        dest: D,
+    ) -> Result<Vec<(SqshPath, Error)>> {
+        salvage::extract(self, salvage, dest.as_ref())
+    }
+
+    /// A writable view of the image, whose changes are kept in memory.
+    pub fn overlay(&self) -> Result<CowOverlay<'_, R>> {
+        CowOverlay::new(self)
+    }
+
+    /// Writes a copy of the image without the paths matching `exclude`, and
+    /// everything under them, to `out`. Patterns match whole paths from the
+    /// root, where `*` matches within a name, `?` a single byte and `**`
+    /// any number of directories, like `usr/share/locale/**/*.mo`.
+    ///
+    /// Data and fragment blocks are copied without recompressing them;
+    /// fragment blocks are kept whole even if only some of the files packed
+    /// in them remain. Inodes are renumbered and the metadata tables
+    /// rewritten with the image's compressor.
+    pub fn trim<P: AsRef<[u8]>, W: Write + Seek>(&self, exclude: &[P], out: W) -> Result<Trimmed> {
+        trim::trim(self, exclude, out)
+    }
+
+    // Copies `len` bytes of the image at `start` as they are stored.
+    pub(crate) fn copy_raw<W: Write + ?Sized>(
+        &self,
+        start: u64,
+        len: u64,
+        out: &mut W,
+    ) -> Result<()> {
+        let mut reader = self.reader.borrow_mut();
+        reader.seek(SeekFrom::Start(start))?;
+        let copied = copy(&mut reader.deref_mut().take(len), out)?;
+        if copied < len {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("image ends before {}", start + len),
+            ));
+        }
+        Ok(())
+    }
+
+    // Where the xattr key/value store is, as stored.
+    pub(crate) fn xattr_store(&self) -> Result<Option<Range<u64>>> {
+        match self.table_location(TableKind::Xattr)? {
+            TableLocation::Run { start, end } => Ok(Some(start..end)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Lists the metadata blocks making up a table, in table order.
+    pub fn metadata_blocks(&self, kind: TableKind) -> Result<Vec<MetadataBlock>> {
+        let mut blocks = vec![];
+        match self.table_location(kind)? {
+            TableLocation::Run { mut start, end } => {
+                while start < end {
+                    let block = self.metadata_block(start)?;
+                    start += block.disk_size as u64 + 2;
+                    blocks.push(block);
+                }
+            }
+            TableLocation::Indexed { index_start, bytes } => {
+                for start in self.table_index(index_start, bytes)? {
+                    blocks.push(self.metadata_block(start)?);
+                }
+            }
+            TableLocation::Absent => {}
+        }
+        Ok(blocks)
+    }
+
+    fn metadata_block(&self, start: u64) -> Result<MetadataBlock> {
+        let compressor = self.decompressor(BlockKind::Metadata)?;
+        let mut reader = self.reader.borrow_mut();
+        let reader = reader.deref_mut();
+
+        reader.seek(SeekFrom::Start(start))?;
+        let (compressed, disk_size) = read::read_block_header(reader)?;
+        let mut buf = Vec::with_capacity(METADATA_SIZE);
+        read_block(reader, &mut buf, &compressor, start, None)?;
+        Ok(MetadataBlock {
+            start,
+            compressed,
+            disk_size,
+            size: buf.len(),
+        })
+    }
+
+    pub(crate) fn fragment(&self, index: u32) -> Result<FragmentEntry> {
+        if index >= self.superblock.fragments() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("fragment index out of range: {}", index),
+            ));
+        }
+        let mut bytes = [0; FRAGMENT_ENTRY_SIZE];
+        self.read_table_entry(
+            self.superblock.fragment_table_start(),
+            index as usize,
+            &mut bytes,
+        )?;
+        let entry = FragmentEntry::new(bytes);
+        if entry.unused() != 0 {
+            self.warn(Warning::UnusedField {
+                field: "fragment unused",
+                index: Some(index),
+                value: entry.unused() as u64,
+            });
+        }
+        Ok(entry)
+    }
+
+    /// The xattr id table, empty when the image has no xattrs.
+    pub fn xattr_ids(&self) -> Result<Vec<XattrId>> {
+        let table = self.raw_table(TableKind::XattrId)?;
+        Ok(table
+            .chunks_exact(XATTR_ID_ENTRY_SIZE)
+            .map(|entry| XattrId::new(entry.try_into().unwrap()))
+            .collect())
+    }
+
+    fn xattr_id(&self, index: u32) -> Result<XattrId> {
+        let (_, ids) = self
+            .xattr_table_header()?
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "image has no xattr table"))?;
+        if index >= ids {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("xattr index out of range: {}", index),
+            ));
+        }
+        let mut bytes = [0; XATTR_ID_ENTRY_SIZE];
+        self.read_table_entry(
+            self.superblock.xattr_id_table_start() as u64 + XATTR_TABLE_HEADER_SIZE as u64,
+            index as usize,
+            &mut bytes,
+        )?;
+        Ok(XattrId::new(bytes))
+    }
+
+    /// Extended attributes of an inode, with their full names. Only
+    /// extended inode types can carry xattrs.
+    pub fn xattrs(&self, inode: &InodeHeader) -> Result<Vec<Xattr>> {
+        let index = match inode.xattr() {
+            Some(index) if index != INVALID_XATTR => index,
+            _ => return Ok(vec![]),
+        };
+        let id = self.xattr_id(index)?;
+        let store = self
+            .xattr_store()?
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "image has no xattr table"))?;
+        // the id's size is that of the names and values as listed, not as
+        // stored, so the entries are parsed until `count` of them are read
+        let entries = self.parse_metadata(
+            store.clone(),
+            id.inode_ref().block(),
+            id.inode_ref().offset(),
+            |record| {
+                (0..id.count())
+                    .map(|_| Xattr::from_reader(record))
+                    .collect::<Result<Vec<_>>>()
+            },
+        )?;
+
+        let mut xattrs = Vec::with_capacity(entries.len());
+        for (mut xattr, out_of_line) in entries {
+            if out_of_line {
+                let value_ref = match <[u8; 8]>::try_from(&xattr.value[..]) {
+                    Ok(value_ref) => InodeRef::from(u64::from_le_bytes(value_ref)),
+                    Err(_) => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "bad out of line xattr value reference",
+                        ))
                     }
                 };
-                u32::from_le_bytes(buf)
-            })
-            .collect();
+                xattr.value = self.parse_metadata(
+                    store.clone(),
+                    value_ref.block(),
+                    value_ref.offset(),
+                    |record| {
+                        let mut size = [0; 4];
+                        record.read_exact(&mut size)?;
+                        let size = u32::from_le_bytes(size) as usize;
+                        record.get(..size).map(<[u8]>::to_vec).ok_or_else(|| {
+                            Error::new(ErrorKind::UnexpectedEof, "truncated xattr value")
+                        })
+                    },
+                )?;
+            }
+            xattrs.push(xattr);
+        }
+        Ok(xattrs)
+    }
+
+    // Reads the `index`th entry of an indexed table, sized like `entry`.
+    // Entries never straddle metadata blocks.
+    fn read_table_entry(&self, index_start: u64, index: usize, entry: &mut [u8]) -> Result<()> {
+        let table_offset = index * entry.len();
+        let block = table_offset / METADATA_SIZE;
+        let offset = table_offset % METADATA_SIZE;
+        let block_start = self.table_index(index_start, (block + 1) * METADATA_SIZE)?[block];
 
-        Ok(IDTable(id_table))
+        let compressor = self.decompressor(BlockKind::Metadata)?;
+        let mut reader = self.reader.borrow_mut();
+        let mut buf = Vec::with_capacity(METADATA_SIZE);
+        read_block(reader.deref_mut(), &mut buf, &compressor, block_start, None)?;
+        let bytes = buf.get(offset..offset + entry.len()).ok_or_else(|| {
+            Error::new(ErrorKind::UnexpectedEof, "truncated metadata table block")
+        })?;
+        entry.copy_from_slice(bytes);
+        Ok(())
     }
 
-    pub fn compressor(&self) -> Result<Compressor> {
+    pub(crate) fn read_raw_data_block(
+        &self,
+        start: u64,
+        size: u32,
+        buf: &mut Vec<u8>,
+    ) -> Result<()> {
         let mut reader = self.reader.borrow_mut();
-        let reader = reader.deref_mut();
+        read::read_raw_data_block(reader.deref_mut(), buf, start, size)
+    }
 
-        let compressor_options_present = self
-            .superblock
-            .flags()
-            .contains(Flags::COMPRESSOR_OPTIONS_PRESENT);
-        reader.seek(SeekFrom::Start(SUPERBLOCK_SIZE as u64))?;
-        Compressor::new(
-            self.superblock.compressor(),
-            compressor_options_present,
-            reader,
-        )
+    // Reads `len` bytes of a metadata table, starting `offset` bytes into
+    // the block at `table_start + block`.
+    fn read_metadata(
+        &self,
+        table_start: u64,
+        block: u32,
+        offset: u16,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let end = offset as usize + len;
+        let mut next = table_start + block as u64;
+        let mut buf = Vec::with_capacity(end.min(METADATA_SIZE));
+        while buf.len() < end {
+            let (block, disk_size) = self.cached_metadata_block(next)?;
+            buf.extend_from_slice(&block);
+            next += disk_size;
+        }
+        buf.truncate(end);
+        buf.drain(..offset as usize);
+        Ok(buf)
     }
 
-    pub fn read_fs(
-        &mut self,
-    ) -> Result<(
-        Vec<FragmentEntry>,
-        IDTable,
-        Vec<u64>,
-        InodeHeader,
-        Vec<InodeHeader>,
-    )> {
-        let fragment_table = self.fragments()?;
-        let id_table = self.id_table()?;
-        let (root, inode_table) = self.inodes()?;
-        let export_table = self.export_table()?;
+    fn table_location(&self, kind: TableKind) -> Result<TableLocation> {
+        let sb = &self.superblock;
+        let location = match kind {
+            TableKind::Inode => TableLocation::Run {
+                start: sb.inode_table_start() as u64,
+                end: sb.directory_table_start() as u64,
+            },
+            TableKind::Directory => TableLocation::Run {
+                start: sb.directory_table_start() as u64,
+                end: self.directory_table_end()?,
+            },
+            TableKind::Fragment => TableLocation::Indexed {
+                index_start: sb.fragment_table_start(),
+                bytes: sb.fragments() as usize * FRAGMENT_ENTRY_SIZE,
+            },
+            TableKind::Id => TableLocation::Indexed {
+                index_start: sb.id_table_start(),
+                bytes: sb.no_ids() as usize * mem::size_of::<u32>(),
+            },
+            TableKind::Export if sb.export_table_start() == INVALID_BLK => TableLocation::Absent,
+            TableKind::Export => TableLocation::Indexed {
+                index_start: sb.export_table_start() as u64,
+                bytes: sb.inodes() as usize * INODE_ENTRY_SIZE,
+            },
+            TableKind::Xattr => match self.xattr_table_header()? {
+                Some((kv_start, ids)) => {
+                    let index_start =
+                        sb.xattr_id_table_start() as u64 + XATTR_TABLE_HEADER_SIZE as u64;
+                    let end = self
+                        .first_table_block(index_start, ids as usize * XATTR_ID_ENTRY_SIZE)?
+                        .unwrap_or(sb.xattr_id_table_start() as u64);
+                    TableLocation::Run {
+                        start: kv_start,
+                        end,
+                    }
+                }
+                None => TableLocation::Absent,
+            },
+            TableKind::XattrId => match self.xattr_table_header()? {
+                Some((_, ids)) => TableLocation::Indexed {
+                    index_start: sb.xattr_id_table_start() as u64 + XATTR_TABLE_HEADER_SIZE as u64,
+                    bytes: ids as usize * XATTR_ID_ENTRY_SIZE,
+                },
+                None => TableLocation::Absent,
+            },
+        };
+        Ok(location)
+    }
 
-        Ok((fragment_table, id_table, export_table, root, inode_table))
+    // Reads consecutive metadata blocks in [start, end).
+    fn read_metadata_run(&self, mut start: u64, end: u64) -> Result<Vec<u8>> {
+        let compressor = self.decompressor(BlockKind::Metadata)?;
+        self.scan_extent(start..end, |reader| {
+            let mut table = Vec::new();
+            while start < end {
+                start += read_block(reader, &mut table, &compressor, start, None)? as u64;
+            }
+            Ok(table)
+        })
     }
 
-    pub fn inodes(&self) -> Result<(InodeHeader, Vec<InodeHeader>)> {
-        let compressor = self.compressor()?;
+    // Reads the u64 block pointers of a table of `bytes` bytes whose index
+    // starts at `index_start`.
+    fn table_index(&self, index_start: u64, bytes: usize) -> Result<Vec<u64>> {
         let mut reader = self.reader.borrow_mut();
-        let mut reader = reader.by_ref();
+        read::read_table_index(reader.deref_mut(), index_start, bytes)
+    }
 
-        scan_inode_table(&mut reader, &self.superblock, &compressor)
+    // Reads a table of `bytes` bytes stored as metadata blocks referenced by
+    // an index of u64 pointers at `index_start`.
+    fn read_indexed_table(&self, index_start: u64, bytes: usize) -> Result<Vec<u8>> {
+        let index = self.table_index(index_start, bytes)?;
+        let compressor = self.decompressor(BlockKind::Metadata)?;
+        let extent = index.first().copied().unwrap_or(index_start)..index_start;
+
+        self.scan_extent(extent, |reader| {
+            IndexedTableReader::new(reader, &compressor, index, bytes).read_table()
+        })
     }
 
-    pub fn fragments(&self) -> Result<Vec<FragmentEntry>> {
-        let compressor = self.compressor()?;
+    // Returns the location of the first metadata block of an indexed table,
+    // or None if it is empty or its index is cut off by the end of the
+    // image.
+    fn first_table_block(&self, index_start: u64, bytes: usize) -> Result<Option<u64>> {
+        if bytes == 0 {
+            return Ok(None);
+        }
         let mut reader = self.reader.borrow_mut();
-        let mut reader = reader.by_ref();
+        let mut buf = [0; 8];
+        reader.seek(SeekFrom::Start(index_start))?;
+        match reader.read_exact(&mut buf) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            result => result.map(|()| Some(u64::from_le_bytes(buf))),
+        }
+    }
 
-        let mut ftr = FragmentTableReader::new(&mut reader, &compressor, self.superblock())?;
+    // Returns the xattr key/value store start and the number of xattr ids.
+    fn xattr_table_header(&self) -> Result<Option<(u64, u32)>> {
+        if self.superblock.xattr_id_table_start() == INVALID_BLK {
+            return Ok(None);
+        }
+        let mut reader = self.reader.borrow_mut();
+        let mut buf = [0; XATTR_TABLE_HEADER_SIZE];
+        reader.seek(SeekFrom::Start(
+            self.superblock.xattr_id_table_start() as u64
+        ))?;
+        reader.read_exact(&mut buf)?;
+        drop(reader);
+        let table: Vec<u64> = decode_le_slice(&buf)?;
+        if table[1] >> 32 != 0 {
+            self.warn(Warning::UnusedField {
+                field: "xattr table unused",
+                index: None,
+                value: table[1] >> 32,
+            });
+        }
+        Ok(Some((table[0], table[1] as u32)))
+    }
 
-        let fragments = ftr.fragments();
-        let mut list = Vec::with_capacity(fragments);
-        for _ in 0..fragments {
-            let mut buf = [0; FRAGMENT_ENTRY_SIZE];
-            ftr.read_exact(&mut buf[..])?;
-            list.push(FragmentEntry::new(buf));
+    // The directory table runs until the first block of whichever table
+    // the writer placed after it.
+    fn directory_table_end(&self) -> Result<u64> {
+        let sb = &self.superblock;
+        let start = sb.directory_table_start() as u64;
+        let mut candidates = vec![
+            sb.bytes_used(),
+            sb.fragment_table_start(),
+            sb.id_table_start(),
+        ];
+        candidates.push(
+            self.first_table_block(
+                sb.fragment_table_start(),
+                sb.fragments() as usize * FRAGMENT_ENTRY_SIZE,
+            )?
+            .unwrap_or(u64::MAX),
+        );
+        candidates.push(
+            self.first_table_block(
+                sb.id_table_start(),
+                sb.no_ids() as usize * mem::size_of::<u32>(),
+            )?
+            .unwrap_or(u64::MAX),
+        );
+        if sb.export_table_start() != INVALID_BLK {
+            candidates.push(sb.export_table_start() as u64);
+            candidates.push(
+                self.first_table_block(
+                    sb.export_table_start() as u64,
+                    sb.inodes() as usize * INODE_ENTRY_SIZE,
+                )?
+                .unwrap_or(u64::MAX),
+            );
+        }
+        match self.xattr_table_header() {
+            Ok(Some((kv_start, _))) => candidates.push(kv_start),
+            Err(e) if e.kind() != ErrorKind::UnexpectedEof => return Err(e),
+            _ => {}
         }
-        Ok(list)
+        Ok(candidates
+            .into_iter()
+            .filter(|c| *c > start)
+            .min()
+            .unwrap_or(start))
     }
+}
 
-    pub fn superblock(&'a self) -> &'a Superblock {
-        &self.superblock
+// Offset of the first block holding data of a regular file, or 0 for other
+// inodes and empty files.
+fn data_start(inode: &InodeHeader, fragments: &[FragmentEntry]) -> u64 {
+    let (start, blocks, fragment) = match inode {
+        InodeHeader::Regular(r) => (r.start_block() as u64, r.blocks(), r.fragment()),
+        InodeHeader::LRegular(r) => (r.start_block(), r.blocks(), r.fragment()),
+        _ => return 0,
+    };
+    match fragments.get(fragment as usize) {
+        _ if !blocks.is_empty() => start,
+        Some(fragment) => fragment.start_block(),
+        None => 0,
+    }
+}
+
+/// Reader returned by `Image::raw_bytes`.
+#[derive(Debug)]
+pub struct RawBytes<'a, R: ReadSeek> {
+    image: &'a Image<R>,
+    position: u64,
+    len: u64,
+}
+
+impl<R: ReadSeek> RawBytes<'_, R> {
+    /// Number of bytes read to the end, `bytes_used` of the superblock.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<R: ReadSeek> Read for RawBytes<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = (buf.len() as u64).min(self.len.saturating_sub(self.position)) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut reader = self.image.reader.borrow_mut();
+        reader.seek(SeekFrom::Start(self.position))?;
+        let read = reader.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "image ends at {}, before bytes_used {}",
+                    self.position, self.len
+                ),
+            ));
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: ReadSeek> Seek for RawBytes<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position =
+            position.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid seek"))?;
+        Ok(self.position)
     }
 }
 
@@ -276,14 +1728,58 @@ impl IntoIterator for IDTable {
 }
 
 impl IDTable {
-    fn get_id(&self, uid_gid: u16) -> u32 {
-        match self.0.get(uid_gid as usize) {
-            Some(v) => *v,
-            None => 0,
-        }
+    /// Looks up the id an inode's `uid` or `guid` index refers to, None
+    /// if the index is out of range.
+    pub fn get(&self, index: u16) -> Option<u32> {
+        self.0.get(index as usize).copied()
+    }
+
+    /// Resolves the `(uid, gid)` an inode's id indices refer to, failing
+    /// with InvalidData if either is out of range.
+    pub fn owner(&self, inode: &InodeHeader) -> Result<(u32, u32)> {
+        let id = |index: u16| {
+            self.get(index).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("id index {} out of range", index),
+                )
+            })
+        };
+        Ok((id(inode.uid())?, id(inode.guid())?))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The ids in index order.
+    pub fn iter(&self) -> std::slice::Iter<'_, u32> {
+        self.0.iter()
     }
 
     pub fn ids(&self) -> &[u32] {
         &self.0
     }
 }
+
+/// Panics when the index is out of range; `get` returns None instead.
+impl ops::Index<u16> for IDTable {
+    type Output = u32;
+
+    fn index(&self, index: u16) -> &u32 {
+        &self.0[index as usize]
+    }
+}
+
+impl<'a> IntoIterator for &'a IDTable {
+    type Item = &'a u32;
+    type IntoIter = std::slice::Iter<'a, u32>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
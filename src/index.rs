@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Error, ErrorKind, Result, Write};
+
+use sha2::{Digest as _, Sha256};
+
+use crate::image::Image;
+use crate::ReadSeek;
+
+/// SHA-256 of a file's contents.
+pub type Digest = [u8; 32];
+
+/// Paths of the regular files in an image, grouped by content digest.
+///
+/// The index is saved in `sha256sum` format, one line per path, so it can
+/// be kept next to an image and compared with the index of a later image
+/// without reading either image again.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContentIndex {
+    files: BTreeMap<Digest, Vec<Vec<u8>>>,
+}
+
+/// Paths that differ between two indexes, as returned by
+/// `ContentIndex::changes`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub added: Vec<Vec<u8>>,
+    pub removed: Vec<Vec<u8>>,
+    pub modified: Vec<Vec<u8>>,
+}
+
+impl ContentIndex {
+    /// Hashes every regular file in the image. Hard linked files are read
+    /// once.
+    pub fn build<R: ReadSeek>(image: &Image<R>) -> Result<Self> {
+        let mut index = Self::default();
+        let mut hashed: HashMap<u32, Digest> = HashMap::new();
+        for entry in image.walk("/")? {
+            let entry = entry?;
+            if !entry.inode.is_file() {
+                continue;
+            }
+            let digest = match hashed.get(&entry.inode.inode_number()) {
+                Some(digest) => *digest,
+                None => {
+                    let mut hasher = Sha256::new();
+                    io::copy(&mut image.open_file(&entry.inode)?, &mut hasher)?;
+                    let digest = hasher.finalize().into();
+                    hashed.insert(entry.inode.inode_number(), digest);
+                    digest
+                }
+            };
+            index.insert(digest, entry.path.into());
+        }
+        Ok(index)
+    }
+
+    pub fn insert(&mut self, digest: Digest, path: Vec<u8>) {
+        self.files.entry(digest).or_default().push(path);
+    }
+
+    /// Paths whose contents hash to `digest`.
+    pub fn paths(&self, digest: &Digest) -> &[Vec<u8>] {
+        self.files
+            .get(digest)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Digest, &[Vec<u8>])> {
+        self.files.iter().map(|(d, p)| (d, p.as_slice()))
+    }
+
+    fn by_path(&self) -> HashMap<&[u8], &Digest> {
+        self.iter()
+            .flat_map(|(digest, paths)| paths.iter().map(move |p| (p.as_slice(), digest)))
+            .collect()
+    }
+
+    /// Compares this index with the index of a newer image.
+    pub fn changes(&self, newer: &ContentIndex) -> Changes {
+        let old = self.by_path();
+        let new = newer.by_path();
+        let mut changes = Changes::default();
+        for (path, digest) in &new {
+            match old.get(path) {
+                None => changes.added.push(path.to_vec()),
+                Some(old_digest) if old_digest != digest => changes.modified.push(path.to_vec()),
+                Some(_) => {}
+            }
+        }
+        for path in old.keys() {
+            if !new.contains_key(path) {
+                changes.removed.push(path.to_vec());
+            }
+        }
+        changes.added.sort();
+        changes.removed.sort();
+        changes.modified.sort();
+        changes
+    }
+
+    /// Writes the index in `sha256sum` format. Paths containing a newline
+    /// or a backslash are escaped the way GNU coreutils does it.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        for (digest, paths) in self.iter() {
+            for path in paths {
+                let escape = path.iter().any(|c| matches!(c, b'\\' | b'\n'));
+                let mut line = Vec::with_capacity(path.len() + 67);
+                if escape {
+                    line.push(b'\\');
+                }
+                for b in digest {
+                    write!(line, "{:02x}", b)?;
+                }
+                line.extend_from_slice(b"  ");
+                for c in path {
+                    match c {
+                        b'\\' if escape => line.extend_from_slice(b"\\\\"),
+                        b'\n' => line.extend_from_slice(b"\\n"),
+                        c => line.push(*c),
+                    }
+                }
+                line.push(b'\n');
+                writer.write_all(&line)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: BufRead>(mut reader: R) -> Result<Self> {
+        let mut index = Self::default();
+        let mut line = vec![];
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                return Ok(index);
+            }
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            let (escaped, line) = match line.strip_prefix(b"\\") {
+                Some(rest) => (true, rest),
+                None => (false, &line[..]),
+            };
+            if line.len() < 66 || &line[64..66] != b"  " {
+                return Err(invalid_line(line));
+            }
+            let mut digest = [0; 32];
+            for (i, b) in digest.iter_mut().enumerate() {
+                let hex =
+                    std::str::from_utf8(&line[i * 2..i * 2 + 2]).map_err(|_| invalid_line(line))?;
+                *b = u8::from_str_radix(hex, 16).map_err(|_| invalid_line(line))?;
+            }
+
+            let mut path = Vec::with_capacity(line.len() - 66);
+            let mut chars = line[66..].iter();
+            while let Some(c) = chars.next() {
+                match (c, escaped) {
+                    (b'\\', true) => match chars.next() {
+                        Some(b'\\') => path.push(b'\\'),
+                        Some(b'n') => path.push(b'\n'),
+                        _ => return Err(invalid_line(line)),
+                    },
+                    (c, _) => path.push(*c),
+                }
+            }
+            index.insert(digest, path);
+        }
+    }
+}
+
+fn invalid_line(line: &[u8]) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("bad index line: {}", String::from_utf8_lossy(line)),
+    )
+}
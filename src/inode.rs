@@ -1,15 +1,20 @@
 use crate::{
-    compressors::Compressor, read::read_block, superblock::Superblock, utils::get_set_field_tuple,
+    compressors::Decompress,
+    inode_ref,
+    read::read_block,
+    superblock::{Superblock, SuperblockError},
+    utils::{decode_le_slice, get_set_field_tuple, trace_span},
     ReadSeek, INVALID_FRAG, METADATA_SIZE,
 };
-use core::slice;
 use std::{
+    borrow::Cow,
     fmt::{Debug, Display, Write},
     io::Error,
-    io::{self, ErrorKind, Read, Result},
-    mem::{self, size_of},
-    str,
+    io::{self, ErrorKind, Read, Result, SeekFrom},
+    mem, str,
 };
+#[cfg(unix)]
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
 
 #[derive(Debug)]
 pub enum InodeType {
@@ -34,8 +39,14 @@ impl InodeType {
     pub fn from_reader<R: Read + ?Sized>(reader: &mut R) -> Result<Self> {
         let mut bytes: [u8; 2] = [0; 2];
         reader.read_exact(&mut bytes)?;
-        let inode_type = u16::from_le_bytes(bytes);
-        Ok(inode_type.into())
+        let value = u16::from_le_bytes(bytes);
+        match value.into() {
+            Self::Unknown => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("bad inode type {}", value),
+            )),
+            inode_type => Ok(inode_type),
+        }
     }
 }
 
@@ -56,10 +67,7 @@ impl From<u16> for InodeType {
             12 => Self::LCharacterDevice,
             13 => Self::LNamedPipe,
             14 => Self::LSocket,
-            _ => {
-                dbg!("bad inode_type: {}", value);
-                Self::Unknown
-            }
+            _ => Self::Unknown,
         }
     }
 }
@@ -86,6 +94,44 @@ impl From<InodeType> for u16 {
     }
 }
 
+/// Reference to an inode: the position of the metadata block holding it,
+/// relative to the inode table start, and its offset in the decompressed
+/// block, packed as stored in the superblock and directory entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InodeRef(u64);
+
+impl InodeRef {
+    pub fn new(block: u32, offset: u16) -> Self {
+        Self(inode_ref::pack(block, offset))
+    }
+
+    pub fn block(&self) -> u32 {
+        inode_ref::unpack(self.0).0
+    }
+
+    pub fn offset(&self) -> u16 {
+        inode_ref::unpack(self.0).1
+    }
+}
+
+impl From<u64> for InodeRef {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<InodeRef> for u64 {
+    fn from(inode_ref: InodeRef) -> Self {
+        inode_ref.0
+    }
+}
+
+impl Display for InodeRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.block(), self.offset())
+    }
+}
+
 pub fn read_inode_header<R: Read + ?Sized>(
     reader: &mut R,
     superblock: &Superblock,
@@ -136,16 +182,15 @@ pub fn read_inode_header<R: Read + ?Sized>(
             let lipc = LIPCInodeHeader::from_parsed_inode_type(inode_type, reader)?;
             InodeHeader::LIPC(lipc)
         }
-        _ => {
-            dbg!(format!("bad inode_type: {:?}", inode_type));
-            unimplemented!()
+        InodeType::Unknown => {
+            return Err(Error::new(ErrorKind::InvalidData, "bad inode type"));
         }
     };
 
     Ok(inode_header)
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum InodeHeader {
     Directory(DirectoryInodeHeader),
     LDirectory(LDirectoryInodeHeader),
@@ -159,6 +204,198 @@ pub enum InodeHeader {
     LIPC(LIPCInodeHeader),
 }
 
+/// Kind of file an inode describes, whether basic or extended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FileType {
+    Directory,
+    Regular,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+    Fifo,
+    Socket,
+}
+
+impl FileType {
+    /// File type of a basic or extended inode type number.
+    pub fn from_inode_type(inode_type: u16) -> Option<Self> {
+        match inode_type {
+            1 | 8 => Some(Self::Directory),
+            2 | 9 => Some(Self::Regular),
+            3 | 10 => Some(Self::Symlink),
+            4 | 11 => Some(Self::BlockDevice),
+            5 | 12 => Some(Self::CharDevice),
+            6 | 13 => Some(Self::Fifo),
+            7 | 14 => Some(Self::Socket),
+            _ => None,
+        }
+    }
+
+    /// Basic inode type number, as stored in directory entries.
+    pub fn inode_type(self) -> u16 {
+        match self {
+            Self::Directory => 1,
+            Self::Regular => 2,
+            Self::Symlink => 3,
+            Self::BlockDevice => 4,
+            Self::CharDevice => 5,
+            Self::Fifo => 6,
+            Self::Socket => 7,
+        }
+    }
+}
+
+macro_rules! each_inode {
+    ($inode:expr, $i:ident => $e:expr) => {
+        match $inode {
+            InodeHeader::Directory($i) => $e,
+            InodeHeader::LDirectory($i) => $e,
+            InodeHeader::Regular($i) => $e,
+            InodeHeader::LRegular($i) => $e,
+            InodeHeader::Symlink($i) => $e,
+            InodeHeader::LSymlink($i) => $e,
+            InodeHeader::Dev($i) => $e,
+            InodeHeader::LDev($i) => $e,
+            InodeHeader::IPC($i) => $e,
+            InodeHeader::LIPC($i) => $e,
+        }
+    };
+}
+
+impl InodeHeader {
+    /// Writes the inode in its on-disk format, as read by
+    /// `read_inode_header`. Returns the number of bytes written.
+    pub fn write_to<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        each_inode!(self, i => i.write_to(writer))
+    }
+
+    /// Approximate memory used by the inode, for cache accounting.
+    pub(crate) fn memory_size(&self) -> usize {
+        let heap = match self {
+            InodeHeader::LDirectory(d) => d.1.as_ref().map_or(0, |i| {
+                i.iter().map(|i| mem::size_of_val(i) + i.1.len()).sum()
+            }),
+            InodeHeader::Regular(r) => {
+                r.1.as_ref().map_or(0, String::len) + r.2.as_ref().map_or(0, |b| b.len() * 4)
+            }
+            InodeHeader::LRegular(r) => r.1.as_ref().map_or(0, |b| b.len() * 4),
+            InodeHeader::Symlink(s) | InodeHeader::LSymlink(s) => s.1.len(),
+            InodeHeader::Dev(d) => d.1.as_ref().map_or(0, String::len),
+            _ => 0,
+        };
+        mem::size_of::<Self>() + heap
+    }
+
+    pub fn inode_number(&self) -> u32 {
+        each_inode!(self, i => i.inode_number())
+    }
+
+    /// Permission bits; the file type is given by the variant.
+    pub fn mode(&self) -> u16 {
+        each_inode!(self, i => i.mode())
+    }
+
+    /// Index into the id table.
+    pub fn uid(&self) -> u16 {
+        each_inode!(self, i => i.uid())
+    }
+
+    /// Index into the id table.
+    pub fn guid(&self) -> u16 {
+        each_inode!(self, i => i.guid())
+    }
+
+    pub fn mtime(&self) -> u32 {
+        each_inode!(self, i => i.mtime())
+    }
+
+    /// Number of directory entries pointing to the inode, plus the `.` and
+    /// `..` of subdirectories for directories. Basic regular files don't
+    /// store one and always have a single link.
+    pub fn nlink(&self) -> u32 {
+        match self {
+            InodeHeader::Regular(_) => 1,
+            InodeHeader::Directory(i) => i.nlink(),
+            InodeHeader::LDirectory(i) => i.nlink(),
+            InodeHeader::LRegular(i) => i.nlink(),
+            InodeHeader::Symlink(i) | InodeHeader::LSymlink(i) => i.nlink(),
+            InodeHeader::Dev(i) => i.nlink(),
+            InodeHeader::LDev(i) => i.nlink(),
+            InodeHeader::IPC(i) => i.nlink(),
+            InodeHeader::LIPC(i) => i.nlink(),
+        }
+    }
+
+    pub(crate) fn set_inode_number(&mut self, inode_number: u32) {
+        each_inode!(self, i => i.set_inode_number(inode_number))
+    }
+
+    pub(crate) fn set_mode(&mut self, mode: u16) {
+        each_inode!(self, i => i.set_mode(mode))
+    }
+
+    pub(crate) fn set_uid(&mut self, uid: u16) {
+        each_inode!(self, i => i.set_uid(uid))
+    }
+
+    pub(crate) fn set_guid(&mut self, guid: u16) {
+        each_inode!(self, i => i.set_guid(guid))
+    }
+
+    pub(crate) fn set_mtime(&mut self, mtime: u32) {
+        each_inode!(self, i => i.set_mtime(mtime))
+    }
+
+    /// Does nothing for basic regular files, which have no link count.
+    pub(crate) fn set_nlink(&mut self, nlink: u32) {
+        match self {
+            InodeHeader::Regular(_) => {}
+            InodeHeader::Directory(i) => i.set_nlink(nlink),
+            InodeHeader::LDirectory(i) => i.set_nlink(nlink),
+            InodeHeader::LRegular(i) => i.set_nlink(nlink),
+            InodeHeader::Symlink(i) | InodeHeader::LSymlink(i) => i.set_nlink(nlink),
+            InodeHeader::Dev(i) => i.set_nlink(nlink),
+            InodeHeader::LDev(i) => i.set_nlink(nlink),
+            InodeHeader::IPC(i) => i.set_nlink(nlink),
+            InodeHeader::LIPC(i) => i.set_nlink(nlink),
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self, InodeHeader::Directory(_) | InodeHeader::LDirectory(_))
+    }
+
+    /// Index into the xattr id table, for the inode types that have one.
+    pub fn xattr(&self) -> Option<u32> {
+        match self {
+            InodeHeader::LDirectory(i) => Some(i.xattr()),
+            InodeHeader::LRegular(i) => Some(i.xattr()),
+            InodeHeader::LSymlink(i) => i.2,
+            InodeHeader::LDev(i) => Some(i.xattr()),
+            InodeHeader::LIPC(i) => Some(i.xattr()),
+            _ => None,
+        }
+    }
+
+    pub fn is_file(&self) -> bool {
+        matches!(self, InodeHeader::Regular(_) | InodeHeader::LRegular(_))
+    }
+
+    pub fn file_type(&self) -> FileType {
+        match self {
+            InodeHeader::Directory(_) | InodeHeader::LDirectory(_) => FileType::Directory,
+            InodeHeader::Regular(_) | InodeHeader::LRegular(_) => FileType::Regular,
+            InodeHeader::Symlink(_) | InodeHeader::LSymlink(_) => FileType::Symlink,
+            InodeHeader::Dev(i) if matches!(i.inode_type(), 4 | 11) => FileType::BlockDevice,
+            InodeHeader::LDev(i) if matches!(i.inode_type(), 4 | 11) => FileType::BlockDevice,
+            InodeHeader::Dev(_) | InodeHeader::LDev(_) => FileType::CharDevice,
+            InodeHeader::IPC(i) if matches!(i.inode_type(), 6 | 13) => FileType::Fifo,
+            InodeHeader::LIPC(i) if matches!(i.inode_type(), 6 | 13) => FileType::Fifo,
+            InodeHeader::IPC(_) | InodeHeader::LIPC(_) => FileType::Socket,
+        }
+    }
+}
+
 impl Display for InodeHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -213,7 +450,7 @@ impl Display for InodeHeader {
 
 pub const DIRECTORY_INODE_HEADER_SIZE: usize = 32;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DirectoryInodeHeader([u8; DIRECTORY_INODE_HEADER_SIZE]);
 
 impl DirectoryInodeHeader {
@@ -241,7 +478,12 @@ impl DirectoryInodeHeader {
     get_set_field_tuple!(nlink, set_nlink, u32, 20, 4);
     get_set_field_tuple!(file_size, set_file_size, u16, 24, 2);
     get_set_field_tuple!(offset, set_offset, u16, 26, 2);
-    get_set_field_tuple!(parent_inode, set_parent_inode, u32, 28, 4);
+    get_set_field_tuple!(parent_inode, set_parent_inode, u32, 28, 4, last);
+
+    pub fn write_to<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.0)?;
+        Ok(self.0.len() as u64)
+    }
 }
 
 impl Display for DirectoryInodeHeader {
@@ -277,20 +519,20 @@ impl Display for DirectoryInodeHeader {
 
 pub const LDIRECTORY_INODE_HEADER_SIZE: usize = 40;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct LDirectoryInodeHeader(
     [u8; LDIRECTORY_INODE_HEADER_SIZE],
     Option<Vec<DirectoryIndex>>,
 );
 
 fn readdir<R: ReadSeek>(
-    reader: R,
-    root_name: bool,
-    start_block: u32,
-    offset: u16,
-    file_size: u32,
-    last_directory_block: u64,
-    superblock: &Superblock,
+    _reader: R,
+    _root_name: bool,
+    _start_block: u32,
+    _offset: u16,
+    _file_size: u32,
+    _last_directory_block: u64,
+    _superblock: &Superblock,
 ) {
     todo!()
 }
@@ -310,13 +552,7 @@ impl LDirectoryInodeHeader {
         let mut inode = Self(buf, None);
         let mut index = Vec::with_capacity(inode.i_count() as usize);
         for _i in 0..inode.i_count() {
-            let dir_ind = DirectoryIndex::from_reader(reader)?;
-            // TODO: check what the rest of the buffer contains
-            io::copy(
-                &mut reader.take((dir_ind.size() + 1) as u64),
-                &mut io::sink(),
-            )?;
-            index.push(dir_ind);
+            index.push(DirectoryIndex::from_reader(reader)?);
         }
         inode.1 = Some(index);
         Ok(inode)
@@ -336,7 +572,7 @@ impl LDirectoryInodeHeader {
     }
 
     pub fn inodes(&self) -> &[DirectoryIndex] {
-        todo!()
+        self.1.as_deref().unwrap_or_default()
     }
 
     get_set_field_tuple!(inode_type, set_inode_type, u16, 0, 2);
@@ -352,7 +588,17 @@ impl LDirectoryInodeHeader {
     get_set_field_tuple!(parent_inode, set_parent_inode, u32, 28, 4);
     get_set_field_tuple!(i_count, set_i_count, u16, 32, 2);
     get_set_field_tuple!(offset, set_offset, u16, 34, 2);
-    get_set_field_tuple!(xattr, set_xattr, u32, 36, 4);
+    get_set_field_tuple!(xattr, set_xattr, u32, 36, 4, last);
+
+    /// Writes the header followed by the directory index.
+    pub fn write_to<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.0)?;
+        let mut written = self.0.len() as u64;
+        for index in self.inodes() {
+            written += index.write_to(writer)?;
+        }
+        Ok(written)
+    }
 }
 
 impl Display for LDirectoryInodeHeader {
@@ -379,19 +625,50 @@ impl Display for LDirectoryInodeHeader {
 
 pub const DIRECTORY_INDEX_SIZE: usize = 12;
 
-#[derive(Debug)]
-pub struct DirectoryIndex([u8; DIRECTORY_INDEX_SIZE]);
+/// Index entry of an extended directory, pointing to the listing header
+/// whose first name is `name`.
+#[derive(Clone, Debug)]
+pub struct DirectoryIndex([u8; DIRECTORY_INDEX_SIZE], Vec<u8>);
 
 impl DirectoryIndex {
+    /// Index entry for the header at byte `index` of the listing, in the
+    /// metadata block at `start_block`, whose first entry is `name`.
+    pub fn new(index: u32, start_block: u32, name: &[u8]) -> Self {
+        let mut entry = Self([0; DIRECTORY_INDEX_SIZE], name.to_vec());
+        entry.set_index(index);
+        entry.set_start_block(start_block);
+        entry.set_size(name.len().saturating_sub(1) as u32);
+        entry
+    }
+
     fn from_reader<R: Read + ?Sized>(reader: &mut R) -> Result<Self> {
         let mut buf = [0; DIRECTORY_INDEX_SIZE];
         reader.read_exact(&mut buf)?;
-        Ok(Self(buf))
+        let mut index = Self(buf, vec![]);
+        let name_size = index.size() as u64 + 1;
+        reader.take(name_size).read_to_end(&mut index.1)?;
+        if index.1.len() as u64 != name_size {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "truncated directory index",
+            ));
+        }
+        Ok(index)
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.1
+    }
+
+    pub fn write_to<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.0)?;
+        writer.write_all(&self.1)?;
+        Ok((self.0.len() + self.1.len()) as u64)
     }
 
     get_set_field_tuple!(index, set_index, u32, 0, 4);
     get_set_field_tuple!(start_block, set_start_block, u32, 4, 4);
-    get_set_field_tuple!(size, set_size, u32, 8, 4);
+    get_set_field_tuple!(size, set_size, u32, 8, 4, last);
 }
 
 impl Display for DirectoryIndex {
@@ -423,7 +700,7 @@ impl Display for DirectoryIndex {
 
 pub const REGULAR_INODE_HEADER_SIZE: usize = 32;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct RegularInodeHeader(
     [u8; REGULAR_INODE_HEADER_SIZE],
     Option<String>,
@@ -445,7 +722,7 @@ impl RegularInodeHeader {
         let mut inode = Self(buf, None, None);
         let fragments = inode.fragment();
         if fragments != INVALID_FRAG && fragments > superblock.fragments() {
-            return Err(Error::new(ErrorKind::Other, "corrupted filesystem"));
+            return Err(Error::other("corrupted filesystem"));
         }
         let fragment_blocks = fragment_blocks(fragments, inode.file_size() as u64, superblock);
         if fragment_blocks > 0 {
@@ -464,7 +741,21 @@ impl RegularInodeHeader {
     get_set_field_tuple!(start_block, set_start_block, u32, 16, 4);
     get_set_field_tuple!(fragment, set_fragment, u32, 20, 4);
     get_set_field_tuple!(offset, set_offset, u32, 24, 4);
-    get_set_field_tuple!(file_size, set_file_size, u32, 28, 4);
+    get_set_field_tuple!(file_size, set_file_size, u32, 28, 4, last);
+
+    /// Sizes of the full data blocks; a zero size marks a sparse block.
+    pub fn blocks(&self) -> &[u32] {
+        self.2.as_deref().unwrap_or_default()
+    }
+
+    /// Writes the header followed by the block list.
+    pub fn write_to<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.0)?;
+        for block in self.blocks() {
+            writer.write_all(&block.to_le_bytes())?;
+        }
+        Ok((self.0.len() + self.blocks().len() * 4) as u64)
+    }
 }
 
 impl Display for RegularInodeHeader {
@@ -501,7 +792,7 @@ impl Display for RegularInodeHeader {
 
 pub const LREGULAR_INODE_HEADER_SIZE: usize = 56;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct LRegularInodeHeader([u8; LREGULAR_INODE_HEADER_SIZE], Option<Vec<u32>>);
 
 impl LRegularInodeHeader {
@@ -536,7 +827,21 @@ impl LRegularInodeHeader {
     get_set_field_tuple!(nlink, set_nlink, u32, 40, 4);
     get_set_field_tuple!(fragment, set_fragment, u32, 44, 4);
     get_set_field_tuple!(offset, set_offset, u32, 48, 4);
-    get_set_field_tuple!(xattr, set_xattr, u32, 52, 4);
+    get_set_field_tuple!(xattr, set_xattr, u32, 52, 4, last);
+
+    /// Sizes of the full data blocks; a zero size marks a sparse block.
+    pub fn blocks(&self) -> &[u32] {
+        self.1.as_deref().unwrap_or_default()
+    }
+
+    /// Writes the header followed by the block list.
+    pub fn write_to<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.0)?;
+        for block in self.blocks() {
+            writer.write_all(&block.to_le_bytes())?;
+        }
+        Ok((self.0.len() + self.blocks().len() * 4) as u64)
+    }
 }
 
 impl Display for LRegularInodeHeader {
@@ -569,7 +874,7 @@ impl Display for LRegularInodeHeader {
 
 pub const SYMLINK_INODE_HEADER_SIZE: usize = 24;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SymlinkInodeHeader([u8; SYMLINK_INODE_HEADER_SIZE], Vec<u8>, Option<u32>);
 
 impl SymlinkInodeHeader {
@@ -588,6 +893,9 @@ impl SymlinkInodeHeader {
         let mut inode = Self(buf, vec![], None);
         let mut r = reader.take(inode.symlink_size() as u64);
         r.read_to_end(&mut inode.1)?;
+        if inode.1.len() != inode.symlink_size() as usize {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "truncated symlink"));
+        }
         if is_extended {
             let mut r = reader.take(mem::size_of::<u32>() as u64);
             let mut buf = [0; 4];
@@ -598,11 +906,19 @@ impl SymlinkInodeHeader {
         Ok(inode)
     }
 
-    fn symlink(&self) -> &str {
-        match str::from_utf8(&self.1) {
-            Ok(v) => v,
-            Err(e) => panic!("symlink not utf8 readable: {}", e),
-        }
+    /// Raw target of the link.
+    pub fn target(&self) -> &[u8] {
+        &self.1
+    }
+
+    /// Target for display, with invalid UTF-8 replaced.
+    pub fn target_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.1)
+    }
+
+    #[cfg(unix)]
+    pub fn target_os(&self) -> &OsStr {
+        OsStr::from_bytes(&self.1)
     }
 
     get_set_field_tuple!(inode_type, set_inode_type, u16, 0, 2);
@@ -613,7 +929,20 @@ impl SymlinkInodeHeader {
 
     get_set_field_tuple!(inode_number, set_inode_number, u32, 12, 4);
     get_set_field_tuple!(nlink, set_nlink, u32, 16, 4);
-    get_set_field_tuple!(symlink_size, set_symlink_size, u32, 20, 4);
+    get_set_field_tuple!(symlink_size, set_symlink_size, u32, 20, 4, last);
+
+    /// Writes the header, the target and, for extended symlinks, the xattr
+    /// index.
+    pub fn write_to<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.0)?;
+        writer.write_all(&self.1)?;
+        let mut written = (self.0.len() + self.1.len()) as u64;
+        if let Some(xattr) = self.2 {
+            writer.write_all(&xattr.to_le_bytes())?;
+            written += 4;
+        }
+        Ok(written)
+    }
 }
 
 impl Display for SymlinkInodeHeader {
@@ -626,7 +955,7 @@ impl Display for SymlinkInodeHeader {
             self.nlink(),
             self.symlink_size(),
             self.mtime(),
-            self.symlink()
+            self.target_lossy()
         )
     }
 }
@@ -645,7 +974,7 @@ impl Display for SymlinkInodeHeader {
 
 pub const DEV_INODE_HEADER_SIZE: usize = 24;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DevInodeHeader([u8; DEV_INODE_HEADER_SIZE], Option<String>);
 
 impl DevInodeHeader {
@@ -676,7 +1005,12 @@ impl DevInodeHeader {
 
     get_set_field_tuple!(inode_number, set_inode_number, u32, 12, 4);
     get_set_field_tuple!(nlink, set_nlink, u32, 16, 4);
-    get_set_field_tuple!(rdev, set_rdev, u32, 20, 4);
+    get_set_field_tuple!(rdev, set_rdev, u32, 20, 4, last);
+
+    pub fn write_to<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.0)?;
+        Ok(self.0.len() as u64)
+    }
 }
 
 impl Display for DevInodeHeader {
@@ -707,7 +1041,7 @@ impl Display for DevInodeHeader {
 
 pub const LDEV_INODE_HEADER_SIZE: usize = 28;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct LDevInodeHeader([u8; LDEV_INODE_HEADER_SIZE]);
 
 impl LDevInodeHeader {
@@ -745,7 +1079,12 @@ impl LDevInodeHeader {
     get_set_field_tuple!(inode_number, set_inode_number, u32, 12, 4);
     get_set_field_tuple!(nlink, set_nlink, u32, 16, 4);
     get_set_field_tuple!(rdev, set_rdev, u32, 20, 4);
-    get_set_field_tuple!(xattr, set_xattr, u32, 24, 4);
+    get_set_field_tuple!(xattr, set_xattr, u32, 24, 4, last);
+
+    pub fn write_to<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.0)?;
+        Ok(self.0.len() as u64)
+    }
 }
 
 impl Display for LDevInodeHeader {
@@ -775,7 +1114,7 @@ impl Display for LDevInodeHeader {
 
 pub const IPC_INODE_HEADER_SIZE: usize = 20;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct IPCInodeHeader([u8; IPC_INODE_HEADER_SIZE]);
 
 impl IPCInodeHeader {
@@ -805,7 +1144,12 @@ impl IPCInodeHeader {
     get_set_field_tuple!(mtime, set_mtime, u32, 8, 4);
 
     get_set_field_tuple!(inode_number, set_inode_number, u32, 12, 4);
-    get_set_field_tuple!(nlink, set_nlink, u32, 16, 4);
+    get_set_field_tuple!(nlink, set_nlink, u32, 16, 4, last);
+
+    pub fn write_to<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.0)?;
+        Ok(self.0.len() as u64)
+    }
 }
 
 impl Display for IPCInodeHeader {
@@ -835,7 +1179,7 @@ impl Display for IPCInodeHeader {
 
 pub const LIPC_INODE_HEADER_SIZE: usize = 24;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct LIPCInodeHeader([u8; LIPC_INODE_HEADER_SIZE]);
 
 impl LIPCInodeHeader {
@@ -872,7 +1216,12 @@ impl LIPCInodeHeader {
 
     get_set_field_tuple!(inode_number, set_inode_number, u32, 12, 4);
     get_set_field_tuple!(nlink, set_nlink, u32, 16, 4);
-    get_set_field_tuple!(xattr, set_xattr, u32, 20, 4);
+    get_set_field_tuple!(xattr, set_xattr, u32, 20, 4, last);
+
+    pub fn write_to<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.0)?;
+        Ok(self.0.len() as u64)
+    }
 }
 
 impl Display for LIPCInodeHeader {
@@ -888,7 +1237,7 @@ impl Display for LIPCInodeHeader {
     }
 }
 
-fn fragment_blocks(fragment: u32, file_size: u64, superblock: &Superblock) -> u64 {
+pub(crate) fn fragment_blocks(fragment: u32, file_size: u64, superblock: &Superblock) -> u64 {
     if fragment == INVALID_FRAG {
         (file_size + superblock.block_size() as u64 - 1) >> superblock.block_log()
     } else {
@@ -897,158 +1246,276 @@ fn fragment_blocks(fragment: u32, file_size: u64, superblock: &Superblock) -> u6
 }
 
 fn block_list<R: Read + ?Sized>(blocks: u64, reader: &mut R) -> Result<Vec<u32>> {
-    const U32_SIZE: usize = mem::size_of::<u32>();
-    let blocks_list_size = blocks as usize * U32_SIZE;
-    let mut reader = reader.take(blocks_list_size as u64);
-    let mut blocks_list = Vec::with_capacity(blocks_list_size);
+    const U32_SIZE: u64 = mem::size_of::<u32>() as u64;
+    let blocks_list_size = blocks.saturating_mul(U32_SIZE);
+    let mut reader = reader.take(blocks_list_size);
+    let mut blocks_list = Vec::new();
     reader.read_to_end(&mut blocks_list)?;
-    let blocks_list = blocks_list
-        .chunks(U32_SIZE)
-        .map(|x| {
-            let mut buf: [u8; U32_SIZE] = [0; U32_SIZE];
-            buf.clone_from_slice(x);
-            u32::from_le_bytes(buf)
-        })
-        .collect();
-    Ok(blocks_list)
+    if blocks_list.len() as u64 != blocks_list_size {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated block list"));
+    }
+    decode_le_slice(&blocks_list)
 }
 
+// struct squashfs_dir_header {
+// 	0 4 unsigned int		count;
+// 	4 4 unsigned int		start_block;
+// 	8 4 unsigned int		inode_number;
+// };
+
+pub const DIRECTORY_HEADER_SIZE: usize = 12;
+// a header covers at most 256 entries
+pub(crate) const DIRECTORY_HEADER_MAX_COUNT: u32 = 255;
+
 #[derive(Clone, Debug)]
-pub struct DirectoryEntry {
-    offset: [u8; 2],
-    inode_number: [u8; 2],
-    type_: [u8; 2],
-    size: [u8; 2],
-    // name
+pub struct DirectoryHeader([u8; DIRECTORY_HEADER_SIZE]);
+
+impl DirectoryHeader {
+    /// Header for `entries` entries, between 1 and 256, whose inodes are in
+    /// the metadata block at `start_block`.
+    pub fn new(entries: u32, start_block: u32, inode_number: u32) -> Self {
+        let mut header = Self([0; DIRECTORY_HEADER_SIZE]);
+        header.set_count(entries.saturating_sub(1));
+        header.set_start_block(start_block);
+        header.set_inode_number(inode_number);
+        header
+    }
+
+    pub fn from_reader<R: Read + ?Sized>(reader: &mut R) -> Result<Self> {
+        let mut buf = [0; DIRECTORY_HEADER_SIZE];
+        reader.read_exact(&mut buf)?;
+        Ok(Self(buf))
+    }
+
+    pub fn write_to<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.0)?;
+        Ok(self.0.len() as u64)
+    }
+
+    get_set_field_tuple!(count, set_count, u32, 0, 4);
+    get_set_field_tuple!(start_block, set_start_block, u32, 4, 4);
+    get_set_field_tuple!(inode_number, set_inode_number, u32, 8, 4, last);
 }
 
+// struct squashfs_dir_entry {
+// 	0 2 unsigned short		offset;
+// 	2 2 short			inode_number;
+// 	4 2 unsigned short		type;
+// 	6 2 unsigned short		size;
+// 	char			name[0];
+// };
+
+pub const DIRECTORY_ENTRY_SIZE: usize = 8;
+
+/// A directory entry along with the fields it inherits from its header.
+#[derive(Clone, Debug)]
+pub struct DirectoryEntry([u8; DIRECTORY_ENTRY_SIZE], Vec<u8>, DirectoryHeader);
+
+// The fields of each on-disk struct cover it up to its end, so a field
+// left out or an offset off by a few bytes fails the build.
+const _: () = {
+    assert!(DirectoryInodeHeader::FIELDS_END == DIRECTORY_INODE_HEADER_SIZE);
+    assert!(LDirectoryInodeHeader::FIELDS_END == LDIRECTORY_INODE_HEADER_SIZE);
+    assert!(DirectoryIndex::FIELDS_END == DIRECTORY_INDEX_SIZE);
+    assert!(RegularInodeHeader::FIELDS_END == REGULAR_INODE_HEADER_SIZE);
+    assert!(LRegularInodeHeader::FIELDS_END == LREGULAR_INODE_HEADER_SIZE);
+    assert!(SymlinkInodeHeader::FIELDS_END == SYMLINK_INODE_HEADER_SIZE);
+    assert!(DevInodeHeader::FIELDS_END == DEV_INODE_HEADER_SIZE);
+    assert!(LDevInodeHeader::FIELDS_END == LDEV_INODE_HEADER_SIZE);
+    assert!(IPCInodeHeader::FIELDS_END == IPC_INODE_HEADER_SIZE);
+    assert!(LIPCInodeHeader::FIELDS_END == LIPC_INODE_HEADER_SIZE);
+    assert!(DirectoryHeader::FIELDS_END == DIRECTORY_HEADER_SIZE);
+    assert!(DirectoryEntry::FIELDS_END == DIRECTORY_ENTRY_SIZE);
+};
+
 impl DirectoryEntry {
-    pub fn new<R: Read>(mut reader: R) -> io::Result<Self> {
-        let mut de: Self = unsafe { mem::zeroed() };
-        unsafe {
-            let de_slice =
-                slice::from_raw_parts_mut(&mut de as *mut _ as *mut u8, size_of::<Self>());
-            reader.read_exact(de_slice)?;
+    /// Entry for the inode at `offset` in the header's inode block.
+    pub fn new(
+        header: &DirectoryHeader,
+        offset: u16,
+        inode_offset: i16,
+        file_type: FileType,
+        name: &[u8],
+    ) -> Self {
+        let mut entry = Self([0; DIRECTORY_ENTRY_SIZE], name.to_vec(), header.clone());
+        entry.set_offset(offset);
+        entry.set_inode_offset(inode_offset);
+        entry.set_inode_type(file_type.inode_type());
+        entry.set_size(name.len().saturating_sub(1) as u16);
+        entry
+    }
+
+    pub fn from_reader<R: Read + ?Sized>(header: &DirectoryHeader, reader: &mut R) -> Result<Self> {
+        let mut buf = [0; DIRECTORY_ENTRY_SIZE];
+        reader.read_exact(&mut buf)?;
+        let mut entry = Self(buf, vec![], header.clone());
+        let name_size = entry.size() as u64 + 1;
+        reader.take(name_size).read_to_end(&mut entry.1)?;
+        if entry.1.len() as u64 != name_size {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "truncated directory entry name",
+            ));
         }
+        Ok(entry)
+    }
 
-        Ok(de)
+    /// Entry name, as stored. Names aren't required to be valid UTF-8.
+    pub fn name(&self) -> &[u8] {
+        &self.1
     }
 
-    // get_set_field_tuple!(offset, set_offset, u16, 0, 2);
-    // get_set_field_tuple!(inode_offset, set_inode_offset, u16, 2, 2);
-    // get_set_field_tuple!(_type, set_type, u16, 4, 2);
-    // get_set_field_tuple!(name_size, set_name_size, u16, 6, 2);
+    /// Name for display, with invalid UTF-8 replaced.
+    pub fn name_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.1)
+    }
 
-    // get_set_field_tuple!(count, set_count, u32, 0, 4);
-    // get_set_field_tuple!(start_block, set_start_block, u32, 4, 4);
-    // get_set_field_tuple!(inode_number, set_inode_number, u32, 8, 4);
-}
+    #[cfg(unix)]
+    pub fn name_os(&self) -> &OsStr {
+        OsStr::from_bytes(&self.1)
+    }
 
-#[derive(Clone, Debug)]
-pub struct InodeEntry(Vec<u8>);
+    pub fn inode_ref(&self) -> InodeRef {
+        InodeRef::new(self.2.start_block(), self.offset())
+    }
 
-impl InodeEntry {
-    pub fn new(buf: Vec<u8>) -> io::Result<Self> {
-        Ok(Self(buf))
+    /// Type of the inode, as recorded in the entry itself, so listings can
+    /// be typed without reading each inode. None if the type is invalid.
+    pub fn file_type(&self) -> Option<FileType> {
+        FileType::from_inode_type(self.inode_type())
+    }
+
+    pub fn inode_number(&self) -> u32 {
+        self.2
+            .inode_number()
+            .wrapping_add(self.inode_offset() as u32)
+    }
+
+    get_set_field_tuple!(offset, set_offset, u16, 0, 2);
+    get_set_field_tuple!(inode_offset, set_inode_offset, i16, 2, 2);
+    get_set_field_tuple!(inode_type, set_inode_type, u16, 4, 2);
+    get_set_field_tuple!(size, set_size, u16, 6, 2, last);
+
+    /// Writes the entry, but not its header.
+    pub fn write_to<W: io::Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.0)?;
+        writer.write_all(&self.1)?;
+        Ok((self.0.len() + self.1.len()) as u64)
     }
 }
 
-pub fn get_directory_metadata<R: ReadSeek>(
+/// Parses a directory listing of `size` bytes, which is the directory
+/// inode file size minus 3.
+pub fn read_directory_listing<R: Read + ?Sized>(
     reader: &mut R,
-    compressor: &Compressor,
-    directory_start: i64,
-    start: i64,
-    offset: i64,
-) -> Result<DirectoryEntry> {
-    let mut buf = vec![];
-    read_block(
-        reader,
-        &mut buf,
-        compressor,
-        (directory_start + start) as u64,
-        None,
-    )?;
-    if offset >= buf.len() as i64 {
-        return Err(Error::new(ErrorKind::Other, "offset out of range"));
+    size: u64,
+) -> Result<Vec<DirectoryEntry>> {
+    let mut reader = reader.take(size);
+    let mut entries = vec![];
+    while reader.limit() > 0 {
+        let header = DirectoryHeader::from_reader(&mut reader)?;
+        if header.count() > DIRECTORY_HEADER_MAX_COUNT {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("directory header count too large: {}", header.count()),
+            ));
+        }
+        for _ in 0..=header.count() {
+            entries.push(DirectoryEntry::from_reader(&header, &mut reader)?);
+        }
     }
-    let entry = DirectoryEntry::new(&buf[offset as usize..])?;
-
-    // for _i in 0..entry.count() {}
-    Ok(entry)
+    Ok(entries)
 }
 
-// pub fn opendir(&mut self, directory: &DirectoryInodeHeader) -> Result<DirectoryEntry> {
-//     let offset = directory.offset();
-//     let start_block = directory.start_block();
-//
-//     let dir_inode = self.get_directory_metadata(start_block as i64, offset as i64)?;
-//     Ok(dir_inode.clone())
-// }
+/// Fails with `SuperblockError::RootNotDirectory` unless `root` is a
+/// directory, which the rest of the crate assumes the root to be.
+pub fn check_root_inode(root: &InodeHeader) -> Result<()> {
+    if root.is_dir() {
+        return Ok(());
+    }
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        SuperblockError::RootNotDirectory(root.file_type()),
+    ))
+}
 
-pub fn scan_inode_table<R: ReadSeek>(
+/// Reads the whole inode table, returning the root inode and all the
+/// inodes in table order. Fails if the root inode isn't a directory, unless
+/// `lenient_root` is set.
+pub fn scan_inode_table<R: ReadSeek, C: Decompress + ?Sized>(
     reader: &mut R,
     superblock: &Superblock,
-    compressor: &Compressor,
+    compressor: &C,
+    lenient_root: bool,
 ) -> Result<(InodeHeader, Vec<InodeHeader>)> {
-    let root_inode = superblock.root_inode();
+    let root_inode = superblock.root_inode_ref();
     let mut start = superblock.inode_table_start();
     let end = superblock.directory_table_start();
 
-    dbg!(
-        "scan_inode_table: root_inode {}, inode_table_start {}, directory_table_start {}",
-        root_inode,
-        start,
-        end
+    trace_span!(
+        DEBUG,
+        "scan_inode_table",
+        root_inode = %root_inode,
+        inode_table_start = start,
+        directory_table_start = end
     );
 
-    // let root_inode_start = start + squashfs_inode_blk(superblock.root_inode());
-    let root_inode_start = start + (((root_inode >> 16) as u32) as i64);
-    let root_inode_offset = (root_inode as u32 & 0xffff) as u32;
+    // the bounds come from the file, and are checked before anything is
+    // read or allocated from them
+    let len = reader.seek(SeekFrom::End(0))?;
+    if start < 0 || end < start || end as u64 > len {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "inode table at {}..{} is not within the image of {} bytes",
+                start, end, len
+            ),
+        ));
+    }
+
+    let root_inode_start = start + root_inode.block() as i64;
+    let root_inode_offset = root_inode.offset() as u32;
 
     // let inode = inodeHeader; // may be result
     let mut root_inode_block: Option<usize> = None; // may be result
 
-    let mut inode_table =
-        Vec::with_capacity(((end - start) as usize + METADATA_SIZE) & !(METADATA_SIZE - 1_usize));
+    let mut inode_table = vec![];
     while start < end {
         if start == root_inode_start {
-            root_inode_block = Some(inode_table.len() as usize);
-            dbg!("found root_inode_block: {}", inode_table.len());
-        } else {
-            dbg!(
-                "CHECK start = {}, end = {}, root_inode = {}, diff = {}",
-                start,
-                end,
-                root_inode_start,
-                start - root_inode_start
-            );
+            root_inode_block = Some(inode_table.len());
         }
         let mut buf = Vec::with_capacity(METADATA_SIZE);
         let compressed_size = read_block(reader, &mut buf, compressor, start as u64, None)?;
         start += compressed_size as i64;
 
         if start != end && buf.len() != METADATA_SIZE {
-            panic!(
-                "corrupted: bad metadata size; start = {}, end = {}, buf.len = {}",
-                start,
-                end,
-                buf.len()
-            );
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "bad inode table block size {} before {}, table ends at {}",
+                    buf.len(),
+                    start,
+                    end
+                ),
+            ));
         }
         inode_table.append(&mut buf);
     }
 
-    let root_inode_block = match root_inode_block {
-        Some(r) => r,
-        None => {
-            panic!("corrupted: no root inode block found");
-        }
-    };
+    let root_inode_block = root_inode_block.ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("root inode {} is not at a block start", root_inode),
+        )
+    })?;
 
-    if (inode_table.len() - root_inode_block as usize)
+    if (inode_table.len() - root_inode_block)
         < (root_inode_offset + DIRECTORY_INODE_HEADER_SIZE as u32) as usize
     {
-        panic!("corrupted: root inode metadata size incorrect");
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "root inode past the end of the inode table",
+        ));
     }
 
     let _root_inode_size: usize =
@@ -1058,28 +1525,12 @@ pub fn scan_inode_table<R: ReadSeek>(
         &mut inode_table[(root_inode_block + root_inode_offset as usize)..].as_ref(),
         superblock,
     )?;
-    match dir_inode {
-        InodeHeader::Directory(ref d) => {
-            dbg!(
-                "ROOT INODE: dir mode {:o} parent {}",
-                d.mode(),
-                d.parent_inode()
-            );
-        }
-        InodeHeader::LDirectory(ref d) => {
-            dbg!(
-                "ROOT INODE: ldir mode {:o} parent {}",
-                d.mode(),
-                d.parent_inode(),
-            );
-        }
-        _ => {
-            dbg!("ROOT INODE not directory {:?}", &dir_inode);
-        }
+    if !lenient_root {
+        check_root_inode(&dir_inode)?;
     }
 
     let mut inode_table = &inode_table[..];
-    let mut inode_headers = Vec::with_capacity(superblock.inodes() as usize);
+    let mut inode_headers = vec![];
     while !inode_table.is_empty() {
         let i = read_inode_header(&mut inode_table, superblock)?;
         inode_headers.push(i);
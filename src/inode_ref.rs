@@ -0,0 +1,24 @@
+use crate::METADATA_SIZE;
+
+/// Bits holding the offset in the decompressed block.
+pub const OFFSET_BITS: u32 = 16;
+pub const OFFSET_MASK: u64 = (1 << OFFSET_BITS) - 1;
+/// Offsets at or past this don't point into a metadata block.
+pub const MAX_OFFSET: u16 = METADATA_SIZE as u16;
+
+/// Packs an inode reference as stored in the superblock, directory entries
+/// and the export table: `block` is the position of the metadata block
+/// holding the inode, relative to the inode table start, and `offset` the
+/// inode's offset in the decompressed block.
+pub const fn pack(block: u32, offset: u16) -> u64 {
+    ((block as u64) << OFFSET_BITS) | offset as u64
+}
+
+/// Splits a reference into its block position and offset. Bits above the
+/// 48 used are ignored, as the kernel does.
+pub const fn unpack(inode_ref: u64) -> (u32, u16) {
+    (
+        (inode_ref >> OFFSET_BITS) as u32,
+        (inode_ref & OFFSET_MASK) as u16,
+    )
+}
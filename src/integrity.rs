@@ -0,0 +1,159 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Range;
+
+use crate::image::Image;
+use crate::inode::InodeHeader;
+use crate::metrics::BlockKind;
+use crate::path::SqshPath;
+use crate::read::decompress_data_block;
+use crate::{ReadSeek, COMPRESSED_BIT_BLOCK, INVALID_FRAG};
+
+/// Data block that can't be read or decompressed, as reported by
+/// `Image::verify_blocks`.
+#[derive(Debug)]
+pub struct BadBlock {
+    /// Bytes of the block in the image. Empty if the block can't even be
+    /// located, as for a fragment missing from the fragment table.
+    pub range: Range<u64>,
+    /// Files with contents in the block: several for fragments and for
+    /// blocks shared by identical files.
+    pub paths: Vec<SqshPath>,
+    pub error: Error,
+}
+
+impl Display for BadBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "bytes {:#x}..{:#x}: {}",
+            self.range.start, self.range.end, self.error
+        )?;
+        for path in &self.paths {
+            write!(f, "\n  {}", path)?;
+        }
+        Ok(())
+    }
+}
+
+// Block to check, with what it must decompress to.
+struct Block {
+    size: u32,
+    // exact size for file blocks, or the least size for fragments
+    expected: usize,
+    fragment: bool,
+    paths: Vec<SqshPath>,
+}
+
+pub(crate) fn verify_blocks<R: ReadSeek>(image: &Image<R>, path: &[u8]) -> Result<Vec<BadBlock>> {
+    let block_size = image.superblock().block_size() as u64;
+    let fragments = image.fragments()?;
+    let mut bad = vec![];
+    // blocks by offset, so each is read once and the image front to back
+    let mut blocks: BTreeMap<u64, Block> = BTreeMap::new();
+    let mut seen = HashSet::new();
+    for entry in image.walk(path)? {
+        let entry = entry?;
+        let (mut start, file_size, fragment, offset, sizes) = match &entry.inode {
+            InodeHeader::Regular(r) => (
+                r.start_block() as u64,
+                r.file_size() as u64,
+                r.fragment(),
+                r.offset(),
+                r.blocks(),
+            ),
+            InodeHeader::LRegular(r) => (
+                r.start_block(),
+                r.file_size(),
+                r.fragment(),
+                r.offset(),
+                r.blocks(),
+            ),
+            _ => continue,
+        };
+        // hard links share every block
+        if !seen.insert(entry.inode.inode_number()) {
+            continue;
+        }
+        let mut remaining = file_size;
+        for &size in sizes {
+            let expected = remaining.min(block_size);
+            remaining -= expected;
+            // sparse
+            if size == 0 {
+                continue;
+            }
+            let block = blocks.entry(start).or_insert(Block {
+                size,
+                expected: expected as usize,
+                fragment: false,
+                paths: vec![],
+            });
+            block.paths.push(entry.path.clone());
+            start += (size & !COMPRESSED_BIT_BLOCK) as u64;
+        }
+        if fragment == INVALID_FRAG || remaining == 0 {
+            continue;
+        }
+        let Some(fragment_entry) = fragments.get(fragment as usize) else {
+            bad.push(BadBlock {
+                range: 0..0,
+                paths: vec![entry.path],
+                error: Error::new(
+                    ErrorKind::InvalidData,
+                    format!("fragment {} not in the fragment table", fragment),
+                ),
+            });
+            continue;
+        };
+        let block = blocks.entry(fragment_entry.start_block()).or_insert(Block {
+            size: fragment_entry.size(),
+            expected: 0,
+            fragment: true,
+            paths: vec![],
+        });
+        block.expected = block.expected.max(offset as usize + remaining as usize);
+        block.paths.push(entry.path);
+    }
+
+    let compressor = image.decompressor(BlockKind::Data)?;
+    let mut raw = vec![];
+    let mut data = vec![];
+    for (start, block) in blocks {
+        let disk_size = (block.size & !COMPRESSED_BIT_BLOCK) as u64;
+        let result = image
+            .read_raw_data_block(start, block.size, &mut raw)
+            .and_then(|()| {
+                data.clear();
+                decompress_data_block(&raw, &mut data, &compressor, block.size)
+            })
+            .and_then(|_| {
+                let fits = match block.fragment {
+                    true => data.len() >= block.expected && data.len() as u64 <= block_size,
+                    false => data.len() == block.expected,
+                };
+                match fits {
+                    true => Ok(()),
+                    false => Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "block decompressed to {} bytes, expected {}{}",
+                            data.len(),
+                            if block.fragment { "at least " } else { "" },
+                            block.expected
+                        ),
+                    )),
+                }
+            });
+        if let Err(error) = result {
+            bad.push(BadBlock {
+                range: start..start + disk_size,
+                paths: block.paths,
+                error,
+            });
+        }
+    }
+    bad.sort_by_key(|b| (b.range.start, b.range.end));
+    Ok(bad)
+}
@@ -1,5 +1,3 @@
-#![feature(int_log)]
-
 // sqsh in binary
 pub const MAGIC: u32 = 0x7371_7368;
 pub const SUPERBLOCK_SIZE: usize = 96;
@@ -9,18 +7,68 @@ pub const INVALID_FRAG: u32 = 0xffffffff;
 pub const INVALID_XATTR: u32 = 0xffffffff;
 pub const INVALID_BLK: i64 = -1;
 pub const USED_BLK: i64 = -2;
+// set in data block sizes stored uncompressed
+pub const COMPRESSED_BIT_BLOCK: u32 = 1 << 24;
+/// Images are padded to a multiple of this size.
+pub const PADDING_SIZE: u64 = 4096;
 
 use std::io::{Read, Seek};
 pub trait ReadSeek: Read + Seek {}
 impl<RS: Read + Seek> ReadSeek for RS {}
 
+pub mod analyze;
+pub mod audit;
+pub mod cache;
+#[cfg(feature = "index")]
+pub mod chunk;
+pub mod compare;
 pub mod compressors;
+pub mod delta;
+#[cfg(unix)]
+pub mod extract;
+pub mod file;
 mod fragments;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+pub mod handles;
+pub mod idmap;
 pub mod image;
+#[cfg(feature = "index")]
+pub mod index;
 pub mod inode;
+pub mod inode_ref;
+pub mod integrity;
+pub mod manifest;
+pub mod metrics;
+pub mod mtree;
+pub mod overlay;
+pub mod path;
+mod pool;
+#[cfg(feature = "positioned-io")]
+pub mod positioned;
 pub(crate) mod read;
-pub(crate) mod superblock;
+pub mod readdir;
+pub mod report;
+mod rewrite;
+pub mod salvage;
+pub mod selection;
+#[cfg(feature = "selinux")]
+pub mod selinux;
+pub mod source;
+pub mod spool;
+pub mod superblock;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod transform;
+pub mod trim;
 pub(crate) mod utils;
+#[cfg(unix)]
+pub mod verify;
+pub mod view;
+pub mod walk;
+pub mod warning;
+pub mod write;
+pub mod xattr;
 
 #[cfg(test)]
 mod tests;
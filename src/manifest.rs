@@ -0,0 +1,196 @@
+use std::io::{Result, Write};
+
+use crate::image::Image;
+use crate::inode::{FileType, InodeHeader};
+use crate::mtree::type_keyword;
+use crate::path::SqshPath;
+use crate::report::push_string;
+use crate::walk::{Order, WalkOptions};
+use crate::ReadSeek;
+
+/// Entry of a listing of an image, as returned by `Image::manifest`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: SqshPath,
+    pub file_type: FileType,
+    /// Permission bits, including setuid, setgid and sticky.
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u32,
+    /// Entries with the same inode number are hard links to each other.
+    pub inode_number: u32,
+    /// Size of regular files.
+    pub size: Option<u64>,
+    pub link_target: Option<Vec<u8>>,
+    /// Device number of block and character devices.
+    pub rdev: Option<u32>,
+}
+
+/// Listing of an image in `walk` order by name, which can be written out
+/// in forms meant for scripts rather than people. Paths and link targets
+/// that aren't valid UTF-8 are written with the invalid bytes replaced.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub(crate) fn new<R: ReadSeek>(image: &Image<R>, path: &[u8]) -> Result<Self> {
+        let ids = image.id_table()?;
+        let mut options = WalkOptions::new();
+        options.order(Order::ByName);
+        let mut entries = vec![];
+        for entry in image.walk_with(path, &options)? {
+            let entry = entry?;
+            let inode = &entry.inode;
+            let (uid, gid) = ids.owner(inode)?;
+            entries.push(ManifestEntry {
+                file_type: inode.file_type(),
+                mode: inode.mode() & 0o7777,
+                uid,
+                gid,
+                mtime: inode.mtime(),
+                inode_number: inode.inode_number(),
+                size: match inode {
+                    InodeHeader::Regular(r) => Some(r.file_size() as u64),
+                    InodeHeader::LRegular(r) => Some(r.file_size()),
+                    _ => None,
+                },
+                link_target: match inode {
+                    InodeHeader::Symlink(s) | InodeHeader::LSymlink(s) => Some(s.target().to_vec()),
+                    _ => None,
+                },
+                rdev: match inode {
+                    InodeHeader::Dev(d) => Some(d.rdev()),
+                    InodeHeader::LDev(d) => Some(d.rdev()),
+                    _ => None,
+                },
+                path: entry.path,
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    /// Writes the entries as a JSON array of objects with `path`, `type`,
+    /// `mode`, `uid`, `gid`, `mtime` and `inode`, and `size`, `target` and
+    /// `rdev` when they apply. Types are named as in mtree specifications.
+    pub fn write_json<W: Write>(&self, mut out: W) -> Result<()> {
+        let mut json = String::from("[");
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("\n  {\"path\": ");
+            push_string(&mut json, &entry.path.to_string_lossy());
+            json.push_str(", \"type\": ");
+            push_string(&mut json, type_keyword(entry.file_type));
+            json.push_str(&format!(
+                ", \"mode\": {}, \"uid\": {}, \"gid\": {}, \"mtime\": {}, \"inode\": {}",
+                entry.mode, entry.uid, entry.gid, entry.mtime, entry.inode_number
+            ));
+            if let Some(size) = entry.size {
+                json.push_str(&format!(", \"size\": {}", size));
+            }
+            if let Some(target) = &entry.link_target {
+                json.push_str(", \"target\": ");
+                push_string(&mut json, &String::from_utf8_lossy(target));
+            }
+            if let Some(rdev) = entry.rdev {
+                json.push_str(&format!(", \"rdev\": {}", rdev));
+            }
+            json.push('}');
+        }
+        json.push_str("\n]\n");
+        out.write_all(json.as_bytes())
+    }
+
+    /// Writes the entries as CSV with a header line. Modes are in octal and
+    /// fields that don't apply are empty.
+    pub fn write_csv<W: Write>(&self, mut out: W) -> Result<()> {
+        let mut csv = String::from("path,type,mode,uid,gid,mtime,inode,size,target,rdev\n");
+        for entry in &self.entries {
+            push_csv_field(&mut csv, &entry.path.to_string_lossy());
+            csv.push_str(&format!(
+                ",{},{:04o},{},{},{},{},",
+                type_keyword(entry.file_type),
+                entry.mode,
+                entry.uid,
+                entry.gid,
+                entry.mtime,
+                entry.inode_number
+            ));
+            if let Some(size) = entry.size {
+                csv.push_str(&size.to_string());
+            }
+            csv.push(',');
+            if let Some(target) = &entry.link_target {
+                push_csv_field(&mut csv, &String::from_utf8_lossy(target));
+            }
+            csv.push(',');
+            if let Some(rdev) = entry.rdev {
+                csv.push_str(&rdev.to_string());
+            }
+            csv.push('\n');
+        }
+        out.write_all(csv.as_bytes())
+    }
+
+    /// Writes the entries as an indented tree, as tree(1) draws it: the
+    /// first entry by its path and the others by name, symlinks followed by
+    /// their target.
+    pub fn write_tree<W: Write>(&self, mut out: W) -> Result<()> {
+        let Some(first) = self.entries.first() else {
+            return Ok(());
+        };
+        let base = first.path.components().count();
+        let depth = |entry: &ManifestEntry| entry.path.components().count() - base;
+        // whether each entry is the last of its directory, found from the
+        // end: walk order puts an entry's contents right after it
+        let mut last = vec![false; self.entries.len()];
+        let mut seen = vec![];
+        for (i, entry) in self.entries.iter().enumerate().rev() {
+            let depth = depth(entry);
+            seen.resize(depth + 1, false);
+            last[i] = !seen[depth];
+            seen[depth] = true;
+        }
+
+        let mut tree = String::new();
+        // whether each ancestor of the entry drawn is the last of its own
+        // directory, so no more lines go down from it
+        let mut ancestors: Vec<bool> = vec![];
+        for (i, entry) in self.entries.iter().enumerate() {
+            let depth = depth(entry);
+            if depth == 0 {
+                tree.push_str(&entry.path.to_string_lossy());
+            } else {
+                ancestors.truncate(depth - 1);
+                for &done in &ancestors {
+                    tree.push_str(if done { "    " } else { "│   " });
+                }
+                tree.push_str(if last[i] { "└── " } else { "├── " });
+                let name = entry.path.file_name().unwrap_or_default();
+                tree.push_str(&String::from_utf8_lossy(name));
+                ancestors.push(last[i]);
+            }
+            if let Some(target) = &entry.link_target {
+                tree.push_str(" -> ");
+                tree.push_str(&String::from_utf8_lossy(target));
+            }
+            tree.push('\n');
+        }
+        out.write_all(tree.as_bytes())
+    }
+}
+
+// Appends `s` as a CSV field, quoted when it has to be.
+fn push_csv_field(csv: &mut String, s: &str) {
+    if !s.contains([',', '"', '\n', '\r']) {
+        csv.push_str(s);
+        return;
+    }
+    csv.push('"');
+    csv.push_str(&s.replace('"', "\"\""));
+    csv.push('"');
+}
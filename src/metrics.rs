@@ -0,0 +1,132 @@
+use std::fmt::Debug;
+use std::io::{Read, Result, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::compressors::{Compressor, Decompress};
+
+/// Receives events about the work an `Image` does, set with
+/// `Image::set_metrics`, to feed counters and histograms of a metrics
+/// library. Every method does nothing by default. Methods can be called
+/// from the threads extraction starts, and should be cheap.
+pub trait Metrics: Debug + Send + Sync {
+    /// `bytes` were read from the underlying reader.
+    fn bytes_read(&self, _bytes: u64) {}
+
+    /// A block of `compressed` bytes was decompressed into `uncompressed`
+    /// bytes, taking `elapsed`. Blocks stored uncompressed aren't reported.
+    fn block_decompressed(
+        &self,
+        _kind: BlockKind,
+        _compressed: u64,
+        _uncompressed: u64,
+        _elapsed: Duration,
+    ) {
+    }
+
+    fn cache_hit(&self, _cache: CacheKind) {}
+
+    fn cache_miss(&self, _cache: CacheKind) {}
+
+    /// An operation of `phase` finished, successfully or not, after
+    /// `elapsed`.
+    fn phase(&self, _phase: Phase, _elapsed: Duration) {}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlockKind {
+    Metadata,
+    /// File data, including fragment blocks.
+    Data,
+}
+
+/// Caches configured with `CacheConfig`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CacheKind {
+    Inodes,
+    MetadataBlocks,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Reading and checking the superblock in `Image::new`.
+    Open,
+    /// Reading a whole metadata table, like `Image::inodes` does.
+    TableScan,
+    /// Resolving a path.
+    Lookup,
+    Extract,
+}
+
+/// Ignores all events. This is what images use until `Image::set_metrics`
+/// is called.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// Reports the time until it is dropped as `phase`.
+pub(crate) struct PhaseTimer<'a> {
+    metrics: &'a dyn Metrics,
+    phase: Phase,
+    start: Instant,
+}
+
+impl<'a> PhaseTimer<'a> {
+    pub(crate) fn new(metrics: &'a dyn Metrics, phase: Phase) -> Self {
+        Self {
+            metrics,
+            phase,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for PhaseTimer<'_> {
+    fn drop(&mut self) {
+        self.metrics.phase(self.phase, self.start.elapsed());
+    }
+}
+
+/// Compressor reporting the blocks it decompresses.
+#[derive(Clone, Debug)]
+pub(crate) struct MeteredDecompressor {
+    compressor: Compressor,
+    kind: BlockKind,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl MeteredDecompressor {
+    pub(crate) fn new(compressor: Compressor, kind: BlockKind, metrics: Arc<dyn Metrics>) -> Self {
+        Self {
+            compressor,
+            kind,
+            metrics,
+        }
+    }
+}
+
+impl Decompress for MeteredDecompressor {
+    fn decompress<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> Result<u64> {
+        let start = Instant::now();
+        let mut reader = CountingReader(reader, 0);
+        let written = self.compressor.decompress(&mut reader, writer)?;
+        self.metrics
+            .block_decompressed(self.kind, reader.1, written, start.elapsed());
+        Ok(written)
+    }
+}
+
+struct CountingReader<'a, R: Read + ?Sized>(&'a mut R, u64);
+
+impl<R: Read + ?Sized> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.0.read(buf)?;
+        self.1 += read as u64;
+        Ok(read)
+    }
+}
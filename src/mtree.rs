@@ -0,0 +1,92 @@
+use crate::inode::FileType;
+#[cfg(feature = "index")]
+use {
+    crate::image::Image,
+    crate::index::Digest,
+    crate::inode::InodeHeader,
+    crate::ReadSeek,
+    sha2::{Digest as _, Sha256},
+    std::collections::HashMap,
+    std::io::{self, Result, Write},
+};
+
+// Escapes a path or link target the way mtree(5) expects: whitespace,
+// non-printable bytes and the characters it gives a meaning to are written
+// as a backslash and three octal digits.
+pub(crate) fn escape(name: &[u8], out: &mut Vec<u8>) {
+    for c in name {
+        match c {
+            b'!'..=b'~' if !matches!(c, b'#' | b'=' | b'\\') => out.push(*c),
+            c => out.extend_from_slice(format!("\\{:03o}", c).as_bytes()),
+        }
+    }
+}
+
+pub(crate) fn type_keyword(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::Directory => "dir",
+        FileType::Regular => "file",
+        FileType::Symlink => "link",
+        FileType::BlockDevice => "block",
+        FileType::CharDevice => "char",
+        FileType::Fifo => "fifo",
+        FileType::Socket => "socket",
+    }
+}
+
+/// Writes a BSD mtree specification of the image, one line per path with
+/// its type, mode, owner and, for regular files, size and SHA-256 digest or,
+/// for symlinks, target. Paths are relative to the image root, which is `.`.
+#[cfg(feature = "index")]
+pub fn write_spec<R: ReadSeek, W: Write>(image: &Image<R>, mut writer: W) -> Result<()> {
+    let ids = image.id_table()?;
+    let mut hashed: HashMap<u32, Digest> = HashMap::new();
+    writer.write_all(b"#mtree\n")?;
+    for entry in image.walk("/")? {
+        let entry = entry?;
+        let inode = &entry.inode;
+        let mut line = b".".to_vec();
+        if !entry.path.is_root() {
+            escape(&entry.path, &mut line);
+        }
+
+        let (uid, gid) = ids.owner(inode)?;
+        write!(
+            line,
+            " type={} mode={:04o} uid={} gid={}",
+            type_keyword(inode.file_type()),
+            inode.mode() & 0o7777,
+            uid,
+            gid,
+        )?;
+
+        let file_size = match inode {
+            InodeHeader::Regular(r) => Some(r.file_size() as u64),
+            InodeHeader::LRegular(r) => Some(r.file_size()),
+            _ => None,
+        };
+        if let Some(size) = file_size {
+            let digest = match hashed.get(&inode.inode_number()) {
+                Some(digest) => *digest,
+                None => {
+                    let mut hasher = Sha256::new();
+                    io::copy(&mut image.open_file(inode)?, &mut hasher)?;
+                    let digest = hasher.finalize().into();
+                    hashed.insert(inode.inode_number(), digest);
+                    digest
+                }
+            };
+            write!(line, " size={} sha256digest=", size)?;
+            for b in digest {
+                write!(line, "{:02x}", b)?;
+            }
+        }
+        if let InodeHeader::Symlink(s) | InodeHeader::LSymlink(s) = inode {
+            line.extend_from_slice(b" link=");
+            escape(s.target(), &mut line);
+        }
+        line.push(b'\n');
+        writer.write_all(&line)?;
+    }
+    Ok(())
+}
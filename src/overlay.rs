@@ -0,0 +1,1057 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+#[cfg(feature = "async")]
+use std::future::poll_fn;
+use std::io::{self, Cursor, Error, ErrorKind, Read, Result, Seek, Write};
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use std::sync::Arc;
+#[cfg(feature = "async")]
+use std::task::Poll;
+
+#[cfg(feature = "async")]
+use futures_io::{AsyncRead, AsyncSeek, AsyncWrite};
+#[cfg(feature = "index")]
+use sha2::{Digest as _, Sha256};
+
+use crate::image::{IDTable, Image};
+#[cfg(feature = "index")]
+use crate::index::ContentIndex;
+use crate::inode::{FileType, InodeHeader};
+use crate::path::SqshPath;
+use crate::rewrite::{self, block_on, new_inode, rewrite, RewriteOptions, SeekSink, Sink};
+use crate::superblock::Flags;
+use crate::{ReadSeek, INVALID_FRAG, PADDING_SIZE};
+
+/// Entry as `CowOverlay` shows it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OverlayEntry {
+    pub file_type: FileType,
+    /// Permission bits, including setuid, setgid and sticky.
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u32,
+    /// Size of regular files.
+    pub size: Option<u64>,
+    pub link_target: Option<Vec<u8>>,
+    /// Device number of block and character devices.
+    pub rdev: Option<u32>,
+    /// Whether the entry was created or changed in the overlay, rather than
+    /// read as is from the image.
+    pub changed: bool,
+}
+
+/// Order the data of new and replaced files is written in by
+/// `CowOverlay::commit`. Files written next to each other share fragment
+/// blocks and compress better when they are alike.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Grouping {
+    /// As the tree is walked, directory by directory.
+    #[default]
+    Tree,
+    /// By the extension of the file name, ignoring case, then as the tree
+    /// is walked. Files without one come first.
+    Extension,
+}
+
+/// How `CowOverlay::commit` compresses the data of a new or replaced file,
+/// as chosen by the callback of `CommitOptions::compression`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionChoice {
+    /// With the compressor of the image, or uncompressed for the files with
+    /// one of the `uncompressed_extensions`.
+    #[default]
+    Default,
+    /// Stored uncompressed, tail included.
+    Uncompressed,
+    /// With the compressor of the image at another level, from 1 to 9 for
+    /// gzip and 1 to 22 for zstd. xz has none. The tail is still packed
+    /// into a fragment block, compressed at the level of the image.
+    Level(u32),
+}
+
+/// Change to the permission bits of the entries of the new image, as a
+/// chmod run over the tree, see `CommitOptions::mode_rule`. Symlinks are
+/// left alone unless the rule is for them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModeRule {
+    pattern: Option<Vec<u8>>,
+    file_type: Option<FileType>,
+    clear: u16,
+    set: u16,
+}
+
+impl ModeRule {
+    /// Sets the permission bits to `mode`, as `chmod 0755`.
+    pub fn set(mode: u16) -> Self {
+        Self::new(0o7777, mode & 0o7777)
+    }
+
+    /// Adds `bits`, as `chmod +x`.
+    pub fn add(bits: u16) -> Self {
+        Self::new(0, bits & 0o7777)
+    }
+
+    /// Clears `bits`, as `chmod -s`, such as 0o6000 to strip setuid and
+    /// setgid.
+    pub fn remove(bits: u16) -> Self {
+        Self::new(bits & 0o7777, 0)
+    }
+
+    fn new(clear: u16, set: u16) -> Self {
+        Self {
+            pattern: None,
+            file_type: None,
+            clear,
+            set,
+        }
+    }
+
+    /// Only applies the rule to entries of `file_type`.
+    pub fn file_type(mut self, file_type: FileType) -> Self {
+        self.file_type = Some(file_type);
+        self
+    }
+
+    /// Only applies the rule to the entries whose path matches a glob
+    /// `pattern`, as for `SqshPath::matches`.
+    pub fn matching<P: Into<Vec<u8>>>(mut self, pattern: P) -> Self {
+        self.pattern = Some(pattern.into());
+        self
+    }
+
+    fn apply(&self, path: &SqshPath, file_type: FileType, mode: u16) -> u16 {
+        let applies = match self.file_type {
+            Some(only) => only == file_type,
+            None => file_type != FileType::Symlink,
+        };
+        match applies && self.pattern.as_ref().is_none_or(|p| path.matches(p)) {
+            true => mode & !self.clear | self.set,
+            false => mode,
+        }
+    }
+}
+
+type ChooseFn = dyn Fn(&SqshPath, &OverlayEntry) -> CompressionChoice + Send + Sync;
+
+// callback of `CommitOptions::compression`
+#[derive(Clone)]
+struct ChooseCompression(Arc<ChooseFn>);
+
+impl Debug for ChooseCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ChooseCompression")
+    }
+}
+
+/// Controls the image `CowOverlay::commit` writes.
+#[derive(Clone, Debug, Default)]
+pub struct CommitOptions {
+    mkfs_time: Option<u32>,
+    clamp_mtime: Option<u32>,
+    fragments: Option<bool>,
+    zstd_dictionary: Option<usize>,
+    grouping: Grouping,
+    padding: Option<u64>,
+    alignment: u64,
+    uncompressed_extensions: Vec<Vec<u8>>,
+    compression: Option<ChooseCompression>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mode_rules: Vec<ModeRule>,
+    #[cfg(feature = "index")]
+    digest_manifest: Option<SqshPath>,
+}
+
+impl CommitOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the creation time of the new image, which is that of the image
+    /// by default.
+    pub fn mkfs_time(&mut self, mkfs_time: u32) -> &mut Self {
+        self.mkfs_time = Some(mkfs_time);
+        self
+    }
+
+    /// Sets the mtime of the entries of the new image that are later than
+    /// `epoch` to it, those of the image included, as `SOURCE_DATE_EPOCH`
+    /// is used for reproducible builds. Set `mkfs_time` to it as well for
+    /// the superblock to match.
+    pub fn clamp_mtime(&mut self, epoch: u32) -> &mut Self {
+        self.clamp_mtime = Some(epoch);
+        self
+    }
+
+    /// Whether the tails of new files are packed into fragment blocks. They
+    /// are by default, unless the image doesn't use fragments.
+    pub fn fragments(&mut self, fragments: bool) -> &mut Self {
+        self.fragments = Some(fragments);
+        self
+    }
+
+    /// Sets the order new and replaced files are written in, as they are
+    /// found in the tree by default.
+    pub fn grouping(&mut self, grouping: Grouping) -> &mut Self {
+        self.grouping = grouping;
+        self
+    }
+
+    /// Pads the new image with zeros to a multiple of `size` bytes, as block
+    /// devices need, 4096 by default. 0 leaves it unpadded, as
+    /// `mksquashfs -nopad`.
+    pub fn padding(&mut self, size: u64) -> &mut Self {
+        self.padding = Some(size);
+        self
+    }
+
+    /// Starts the data of each file and each fragment block at a multiple
+    /// of `alignment` bytes of the image, padding with zeros before them,
+    /// as for images executed in place from flash. 0, the default, packs
+    /// them one after the other.
+    pub fn align_data(&mut self, alignment: u64) -> &mut Self {
+        self.alignment = alignment;
+        self
+    }
+
+    /// Stores new and replaced files whose name ends with one of
+    /// `extensions`, ignoring case, uncompressed, as for data that is
+    /// already compressed. Each of their blocks has the uncompressed bit
+    /// set and their tail is their last block, not in a fragment block.
+    /// Blocks of other files that compression doesn't shrink are stored
+    /// uncompressed as well.
+    pub fn uncompressed_extensions<I, E>(&mut self, extensions: I) -> &mut Self
+    where
+        I: IntoIterator<Item = E>,
+        E: AsRef<[u8]>,
+    {
+        self.uncompressed_extensions = extensions
+            .into_iter()
+            .map(|e| e.as_ref().to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Owns every entry of the new image by root, as `force_uid(0)` and
+    /// `force_gid(0)`.
+    pub fn all_root(&mut self) -> &mut Self {
+        self.force_uid(0).force_gid(0)
+    }
+
+    /// Owns every entry of the new image by `uid`, those of the image
+    /// included.
+    pub fn force_uid(&mut self, uid: u32) -> &mut Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Sets the group of every entry of the new image to `gid`, those of
+    /// the image included.
+    pub fn force_gid(&mut self, gid: u32) -> &mut Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Adds a rule changing the permission bits of the entries of the new
+    /// image, those of the image included. Rules apply in the order they
+    /// are added, each to the mode the last left. Hard links of the image
+    /// get the mode of the first of their paths found.
+    pub fn mode_rule(&mut self, rule: ModeRule) -> &mut Self {
+        self.mode_rules.push(rule);
+        self
+    }
+
+    /// Chooses how each new and replaced file is compressed from its path
+    /// and entry, such as not compressing media or compressing rarely read
+    /// files harder. Files left to `CompressionChoice::Default` still
+    /// follow `uncompressed_extensions`.
+    pub fn compression<F>(&mut self, choose: F) -> &mut Self
+    where
+        F: Fn(&SqshPath, &OverlayEntry) -> CompressionChoice + Send + Sync + 'static,
+    {
+        self.compression = Some(ChooseCompression(Arc::new(choose)));
+        self
+    }
+
+    /// Adds a manifest of the SHA-256 digests of the regular files at
+    /// `path`, in the `sha256sum` format `ContentIndex::write_to` writes,
+    /// so the image can check itself once mounted. The digests are those
+    /// of the tree committed; a regular file already at `path` is replaced
+    /// and left out.
+    #[cfg(feature = "index")]
+    pub fn digest_manifest<P: AsRef<[u8]>>(&mut self, path: P) -> &mut Self {
+        self.digest_manifest = Some(SqshPath::new(path));
+        self
+    }
+
+    /// Trains a zstd dictionary of at most `max_size` bytes over the tails
+    /// of the new and replaced files, which are packed into fragment
+    /// blocks, and compresses the image with it. The dictionary is stored
+    /// after the level in the compressor options, see `ZSTDCompressor`:
+    /// only this crate reads the new image, not the kernel.
+    ///
+    /// Needs a zstd image without a dictionary, and enough new files for
+    /// zstd to train on.
+    pub fn zstd_dictionary(&mut self, max_size: usize) -> &mut Self {
+        self.zstd_dictionary = Some(max_size);
+        self
+    }
+}
+
+/// Outcome of `CowOverlay::commit`.
+#[derive(Clone, Debug, Default)]
+pub struct Committed {
+    /// Size of the new image, before padding.
+    pub bytes_used: u64,
+    /// Bytes of data and fragment blocks copied from the image as stored.
+    pub copied: u64,
+    /// Bytes of data and fragment blocks written for new and replaced
+    /// files.
+    pub written: u64,
+    /// Bytes of data and fragment blocks of the image that several files
+    /// share, which were copied once instead of once for each.
+    pub deduplicated: u64,
+    /// New and replaced files written, and the bytes of their contents
+    /// compressed into `written`.
+    pub files: u64,
+    pub bytes_in: u64,
+    /// Fragment blocks written for the tails of new and replaced files,
+    /// and how full they are on average, from 0 to 1.
+    pub fragment_blocks: u64,
+    pub fragment_fill: f64,
+}
+
+/// Writable view of an image, see `Image::overlay`. Entries can be created,
+/// replaced, changed and removed; changes are kept in memory, and lookups
+/// and listings show the image with them applied. Paths are resolved
+/// without following symlinks.
+///
+/// New entries are owned by 0:0 with an mtime of 0, and have a mode of 0755
+/// for directories, 0777 for symlinks and 0644 otherwise.
+pub struct CowOverlay<'a, R: ReadSeek> {
+    image: &'a Image<R>,
+    ids: IDTable,
+    // entries created, changed or removed by path, None for a removal;
+    // nothing under a removed path is kept
+    changes: BTreeMap<SqshPath, Option<Node>>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Node {
+    pub(crate) mode: u16,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) mtime: u32,
+    pub(crate) kind: Kind,
+    pub(crate) changed: bool,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum Kind {
+    // inode of the image, whose contents and, for a directory, entries are
+    // kept
+    Base(InodeHeader),
+    Dir,
+    File(Arc<[u8]>),
+    Symlink(Vec<u8>),
+    // device number, 0 for fifos and sockets
+    Special(FileType, u32),
+}
+
+impl Node {
+    fn new(kind: Kind) -> Self {
+        let mode = match kind {
+            Kind::Dir => 0o755,
+            Kind::Symlink(_) => 0o777,
+            _ => 0o644,
+        };
+        Self {
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            kind,
+            changed: true,
+        }
+    }
+
+    pub(crate) fn file_type(&self) -> FileType {
+        match &self.kind {
+            Kind::Base(inode) => inode.file_type(),
+            Kind::Dir => FileType::Directory,
+            Kind::File(_) => FileType::Regular,
+            Kind::Symlink(_) => FileType::Symlink,
+            Kind::Special(file_type, _) => *file_type,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.file_type() == FileType::Directory
+    }
+
+    fn entry(&self) -> OverlayEntry {
+        let (size, link_target, rdev) = match &self.kind {
+            Kind::Base(InodeHeader::Regular(r)) => (Some(r.file_size() as u64), None, None),
+            Kind::Base(InodeHeader::LRegular(r)) => (Some(r.file_size()), None, None),
+            Kind::Base(InodeHeader::Symlink(s) | InodeHeader::LSymlink(s)) => {
+                (None, Some(s.target().to_vec()), None)
+            }
+            Kind::Base(InodeHeader::Dev(d)) => (None, None, Some(d.rdev())),
+            Kind::Base(InodeHeader::LDev(d)) => (None, None, Some(d.rdev())),
+            Kind::File(data) => (Some(data.len() as u64), None, None),
+            Kind::Symlink(target) => (None, Some(target.clone()), None),
+            Kind::Special(FileType::BlockDevice | FileType::CharDevice, rdev) => {
+                (None, None, Some(*rdev))
+            }
+            _ => (None, None, None),
+        };
+        OverlayEntry {
+            file_type: self.file_type(),
+            mode: self.mode,
+            uid: self.uid,
+            gid: self.gid,
+            mtime: self.mtime,
+            size,
+            link_target,
+            rdev,
+            changed: self.changed,
+        }
+    }
+}
+
+fn not_found(path: &SqshPath) -> Error {
+    Error::new(ErrorKind::NotFound, format!("{}: not found", path))
+}
+
+impl<'a, R: ReadSeek> CowOverlay<'a, R> {
+    pub(crate) fn new(image: &'a Image<R>) -> Result<Self> {
+        Ok(Self {
+            image,
+            ids: image.id_table()?,
+            changes: BTreeMap::new(),
+        })
+    }
+
+    pub fn image(&self) -> &'a Image<R> {
+        self.image
+    }
+
+    /// Whether anything was changed since the overlay was created.
+    pub fn is_changed(&self) -> bool {
+        !self.changes.is_empty()
+    }
+
+    pub fn lookup<P: AsRef<[u8]>>(&self, path: P) -> Result<OverlayEntry> {
+        Ok(self.node(&SqshPath::new(path))?.entry())
+    }
+
+    /// Whether `path` exists, failing only if the image can't be read.
+    pub fn try_exists<P: AsRef<[u8]>>(&self, path: P) -> Result<bool> {
+        match self.lookup(path) {
+            Ok(_) => Ok(true),
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn exists<P: AsRef<[u8]>>(&self, path: P) -> bool {
+        self.try_exists(path).unwrap_or(false)
+    }
+
+    pub fn is_dir<P: AsRef<[u8]>>(&self, path: P) -> bool {
+        self.lookup(path)
+            .map(|e| e.file_type == FileType::Directory)
+            .unwrap_or(false)
+    }
+
+    pub fn is_file<P: AsRef<[u8]>>(&self, path: P) -> bool {
+        self.lookup(path)
+            .map(|e| e.file_type == FileType::Regular)
+            .unwrap_or(false)
+    }
+
+    /// Lists a directory by name, with the entries of the image it still
+    /// has and those created in it.
+    pub fn list_dir<P: AsRef<[u8]>>(&self, path: P) -> Result<Vec<(Vec<u8>, OverlayEntry)>> {
+        let path = SqshPath::new(path);
+        let dir = self.node(&path)?;
+        Ok(self
+            .children(&path, &dir)?
+            .into_iter()
+            .map(|(name, node)| (name, node.entry()))
+            .collect())
+    }
+
+    /// Opens a regular file, reading the image for files that weren't
+    /// replaced.
+    pub fn open<P: AsRef<[u8]>>(&self, path: P) -> Result<Box<dyn Read + 'a>> {
+        match self.node(&SqshPath::new(path))?.kind {
+            Kind::Base(inode) => Ok(Box::new(self.image.open_file(&inode)?)),
+            Kind::File(data) => Ok(Box::new(Cursor::new(data))),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "not a regular file")),
+        }
+    }
+
+    pub fn read<P: AsRef<[u8]>>(&self, path: P) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        self.open(path)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Creates a regular file, or replaces the contents of one, which keeps
+    /// its attributes.
+    pub fn write_file<P: AsRef<[u8]>, D: Into<Vec<u8>>>(&mut self, path: P, data: D) -> Result<()> {
+        let path = SqshPath::new(path);
+        let data: Arc<[u8]> = data.into().into();
+        match self.node(&path) {
+            Ok(mut node) if node.file_type() == FileType::Regular => {
+                node.kind = Kind::File(data);
+                node.changed = true;
+                self.changes.insert(path, Some(node));
+                Ok(())
+            }
+            Ok(_) => Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{}: not a regular file", path),
+            )),
+            Err(e) if e.kind() == ErrorKind::NotFound => self.create(path, Kind::File(data)),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn create_dir<P: AsRef<[u8]>>(&mut self, path: P) -> Result<()> {
+        self.create(SqshPath::new(path), Kind::Dir)
+    }
+
+    pub fn symlink<P: AsRef<[u8]>, T: Into<Vec<u8>>>(&mut self, path: P, target: T) -> Result<()> {
+        let target = target.into();
+        if target.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "empty symlink target"));
+        }
+        self.create(SqshPath::new(path), Kind::Symlink(target))
+    }
+
+    /// Creates a block or character device, a fifo or a socket. `rdev` is
+    /// the device number encoded as stored in inodes, and is ignored for
+    /// fifos and sockets.
+    pub fn mknod<P: AsRef<[u8]>>(&mut self, path: P, file_type: FileType, rdev: u32) -> Result<()> {
+        let rdev = match file_type {
+            FileType::BlockDevice | FileType::CharDevice => rdev,
+            FileType::Fifo | FileType::Socket => 0,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("not a special file type: {:?}", file_type),
+                ))
+            }
+        };
+        self.create(SqshPath::new(path), Kind::Special(file_type, rdev))
+    }
+
+    /// Sets the permission bits, including setuid, setgid and sticky.
+    pub fn set_mode<P: AsRef<[u8]>>(&mut self, path: P, mode: u16) -> Result<()> {
+        self.update(SqshPath::new(path), |node| node.mode = mode & 0o7777)
+    }
+
+    pub fn set_owner<P: AsRef<[u8]>>(&mut self, path: P, uid: u32, gid: u32) -> Result<()> {
+        self.update(SqshPath::new(path), |node| {
+            node.uid = uid;
+            node.gid = gid;
+        })
+    }
+
+    pub fn set_mtime<P: AsRef<[u8]>>(&mut self, path: P, mtime: u32) -> Result<()> {
+        self.update(SqshPath::new(path), |node| node.mtime = mtime)
+    }
+
+    /// Removes an entry, and everything under it for a directory.
+    pub fn remove<P: AsRef<[u8]>>(&mut self, path: P) -> Result<()> {
+        let path = SqshPath::new(path);
+        if path.is_root() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the root can't be removed",
+            ));
+        }
+        self.node(&path)?;
+        self.changes
+            .retain(|changed, _| changed.strip_prefix(&path).is_none());
+        self.changes.insert(path, None);
+        Ok(())
+    }
+
+    /// Writes an image of the tree the overlay shows to `out`, with the
+    /// compressor, block size and flags of the image.
+    ///
+    /// The data and fragment blocks of the files the image still has are
+    /// copied without recompressing them, and so are the xattrs, which
+    /// created and replaced entries don't have. Only new and replaced files
+    /// are compressed. Hard links of the image are kept unless one of the
+    /// paths was changed. Inodes are renumbered and the metadata tables
+    /// rewritten, the id table with only the uids and gids still used.
+    pub fn commit<W: Write + Seek>(&self, options: &CommitOptions, out: W) -> Result<Committed> {
+        block_on(self.commit_to(options, SeekSink::new(out)?))
+    }
+
+    /// Dry run of `commit`, which compresses the new and replaced files as
+    /// it would but writes nothing, for the size of the image it would
+    /// write before writing it.
+    pub fn estimate(&self, options: &CommitOptions) -> Result<Committed> {
+        self.commit(options, io::empty())
+    }
+
+    async fn commit_to<S: Sink>(&self, options: &CommitOptions, out: S) -> Result<Committed> {
+        #[cfg(feature = "index")]
+        if let Some(path) = &options.digest_manifest {
+            let mut manifest = vec![];
+            self.content_index(path)?.write_to(&mut manifest)?;
+            let mut overlay = CowOverlay::new(self.image)?;
+            overlay.changes = self.changes.clone();
+            overlay.write_file(path, manifest)?;
+            return overlay.write_image(options, out).await;
+        }
+        self.write_image(options, out).await
+    }
+
+    // Writes the image of `commit`, leaving out the digest manifest.
+    async fn write_image<S: Sink>(&self, options: &CommitOptions, out: S) -> Result<Committed> {
+        let sb = self.image.superblock();
+        let mut ids = NewIds::default();
+        let nodes = self.tree(options, &mut ids)?;
+        let options = RewriteOptions {
+            ids: ids.ids,
+            fragments: options
+                .fragments
+                .unwrap_or(!sb.flags().contains(Flags::FRAGMENTS_ARE_NOT_USED)),
+            mkfs_time: options.mkfs_time.unwrap_or(sb.mkfs_time()),
+            zstd_dictionary: options.zstd_dictionary,
+            grouping: options.grouping,
+            padding: options.padding.unwrap_or(PADDING_SIZE),
+            alignment: options.alignment,
+        };
+        let rewritten = rewrite(self.image, nodes, &options, out).await?;
+        let fragment_fill = match rewritten.fragment_blocks {
+            0 => 0.0,
+            blocks => rewritten.fragment_bytes as f64 / (blocks * sb.block_size() as u64) as f64,
+        };
+        Ok(Committed {
+            bytes_used: rewritten.bytes_used,
+            copied: rewritten.copied,
+            written: rewritten.written,
+            deduplicated: rewritten.deduplicated,
+            files: rewritten.files,
+            bytes_in: rewritten.bytes_in,
+            fragment_blocks: rewritten.fragment_blocks,
+            fragment_fill,
+        })
+    }
+
+    // Digests of the regular files of the tree, but the one at `skip`.
+    #[cfg(feature = "index")]
+    fn content_index(&self, skip: &SqshPath) -> Result<ContentIndex> {
+        let mut index = ContentIndex::default();
+        let mut dirs = vec![(SqshPath::root(), self.root()?)];
+        while let Some((path, dir)) = dirs.pop() {
+            for (name, node) in self.children(&path, &dir)? {
+                let path = path.join(&name)?;
+                match node.file_type() {
+                    FileType::Directory => dirs.push((path, node)),
+                    FileType::Regular if &path != skip => {
+                        let mut hasher = Sha256::new();
+                        io::copy(&mut self.open(&path)?, &mut hasher)?;
+                        index.insert(hasher.finalize().into(), path.into_bytes());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    // Inodes of the tree to commit, the root first, adding their owners to
+    // `ids`.
+    fn tree(&self, options: &CommitOptions, ids: &mut NewIds) -> Result<Vec<rewrite::Node>> {
+        let root = self.root()?;
+        let mut nodes = vec![self.rewrite_node(&root, &SqshPath::root(), options, ids)?];
+        // inodes of the image kept as is, by number, to keep hard links
+        let mut seen: HashMap<u32, usize> = HashMap::new();
+        let mut dirs = vec![(0, SqshPath::root(), root)];
+        while let Some((dir, path, node)) = dirs.pop() {
+            let mut children = vec![];
+            for (name, child) in self.children(&path, &node)? {
+                let child_path = path.join(&name)?;
+                let number = match &child.kind {
+                    Kind::Base(inode) if !child.changed => Some(inode.inode_number()),
+                    _ => None,
+                };
+                let index = match number.and_then(|n| seen.get(&n)) {
+                    Some(&index) => {
+                        if child.is_dir() {
+                            return Err(Error::new(
+                                ErrorKind::InvalidData,
+                                format!("directory linked more than once: {}", child_path),
+                            ));
+                        }
+                        nodes[index].links += 1;
+                        index
+                    }
+                    None => {
+                        nodes.push(self.rewrite_node(&child, &child_path, options, ids)?);
+                        let index = nodes.len() - 1;
+                        if let Some(number) = number {
+                            seen.insert(number, index);
+                        }
+                        if child.is_dir() {
+                            dirs.push((index, child_path, child));
+                        }
+                        index
+                    }
+                };
+                children.push((name, index));
+            }
+            nodes[dir].children = children;
+        }
+        Ok(nodes)
+    }
+
+    fn rewrite_node(
+        &self,
+        node: &Node,
+        path: &SqshPath,
+        options: &CommitOptions,
+        ids: &mut NewIds,
+    ) -> Result<rewrite::Node> {
+        let uid = ids.index(options.uid.unwrap_or(node.uid))?;
+        let gid = ids.index(options.gid.unwrap_or(node.gid))?;
+        let mode = options.mode_rules.iter().fold(node.mode, |mode, rule| {
+            rule.apply(path, node.file_type(), mode)
+        });
+        let mtime = match options.clamp_mtime {
+            Some(epoch) => node.mtime.min(epoch),
+            None => node.mtime,
+        };
+        let attrs = (mode, uid, gid, mtime);
+        let sb = self.image.superblock();
+        let mut contents = None;
+        let mut compression = CompressionChoice::Default;
+        let inode = match &node.kind {
+            Kind::Base(inode) if !node.changed => {
+                let mut inode = inode.clone();
+                inode.set_mode(inode.mode() & !0o7777 | mode);
+                inode.set_uid(uid);
+                inode.set_guid(gid);
+                inode.set_mtime(mtime);
+                inode
+            }
+            Kind::Base(inode) => {
+                let mut inode = inode.clone();
+                inode.set_mode(inode.mode() & !0o7777 | mode);
+                inode.set_uid(uid);
+                inode.set_guid(gid);
+                inode.set_mtime(mtime);
+                inode
+            }
+            Kind::Dir => {
+                // the listing is written with the directory
+                let mut fields = vec![0; 4];
+                fields.extend_from_slice(&2u32.to_le_bytes());
+                fields.extend_from_slice(&3u16.to_le_bytes());
+                fields.extend_from_slice(&[0; 6]);
+                new_inode(FileType::Directory.inode_type(), attrs, &fields, sb)?
+            }
+            Kind::File(data) => {
+                // the data and block list are written with the file
+                contents = Some(data.clone());
+                if let Some(choose) = &options.compression {
+                    compression = (choose.0)(path, &node.entry());
+                }
+                let extension = rewrite::extension(path.file_name().unwrap_or_default());
+                if compression == CompressionChoice::Default
+                    && options.uncompressed_extensions.contains(&extension)
+                {
+                    compression = CompressionChoice::Uncompressed;
+                }
+                let mut fields = vec![0; 4];
+                fields.extend_from_slice(&INVALID_FRAG.to_le_bytes());
+                fields.extend_from_slice(&[0; 8]);
+                new_inode(FileType::Regular.inode_type(), attrs, &fields, sb)?
+            }
+            Kind::Symlink(target) => {
+                let mut fields = 1u32.to_le_bytes().to_vec();
+                fields.extend_from_slice(&(target.len() as u32).to_le_bytes());
+                fields.extend_from_slice(target);
+                new_inode(FileType::Symlink.inode_type(), attrs, &fields, sb)?
+            }
+            Kind::Special(file_type, rdev) => {
+                let mut fields = 1u32.to_le_bytes().to_vec();
+                if matches!(file_type, FileType::BlockDevice | FileType::CharDevice) {
+                    fields.extend_from_slice(&rdev.to_le_bytes());
+                }
+                new_inode(file_type.inode_type(), attrs, &fields, sb)?
+            }
+        };
+        Ok(rewrite::Node {
+            inode,
+            children: vec![],
+            links: 1,
+            contents,
+            compression,
+        })
+    }
+
+    fn create(&mut self, path: SqshPath, kind: Kind) -> Result<()> {
+        let Some(parent) = path.parent() else {
+            return Err(Error::new(ErrorKind::AlreadyExists, "/: already exists"));
+        };
+        if !self.node(&parent)?.is_dir() {
+            return Err(Error::new(
+                ErrorKind::NotADirectory,
+                format!("{}: not a directory", parent),
+            ));
+        }
+        match self.node(&path) {
+            Ok(_) => Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{}: already exists", path),
+            )),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                self.changes.insert(path, Some(Node::new(kind)));
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn update(&mut self, path: SqshPath, change: impl FnOnce(&mut Node)) -> Result<()> {
+        let mut node = self.node(&path)?;
+        change(&mut node);
+        node.changed = true;
+        self.changes.insert(path, Some(node));
+        Ok(())
+    }
+
+    fn base_node(&self, inode: InodeHeader) -> Result<Node> {
+        let (uid, gid) = self.ids.owner(&inode)?;
+        Ok(Node {
+            mode: inode.mode() & 0o7777,
+            uid,
+            gid,
+            mtime: inode.mtime(),
+            kind: Kind::Base(inode),
+            changed: false,
+        })
+    }
+
+    pub(crate) fn root(&self) -> Result<Node> {
+        match self.changes.get(&SqshPath::root()) {
+            Some(Some(root)) => Ok(root.clone()),
+            _ => self.base_node(self.image.root()?),
+        }
+    }
+
+    fn node(&self, path: &SqshPath) -> Result<Node> {
+        let mut node = self.root()?;
+        let mut current = SqshPath::root();
+        for name in path.components() {
+            if !node.is_dir() {
+                return Err(Error::new(
+                    ErrorKind::NotADirectory,
+                    format!("{}: not a directory", current),
+                ));
+            }
+            let child = current.join(name)?;
+            node = match self.changes.get(&child) {
+                Some(Some(changed)) => changed.clone(),
+                Some(None) => return Err(not_found(&child)),
+                None => match &node.kind {
+                    Kind::Base(dir) => {
+                        let entry = self
+                            .image
+                            .read_dir(dir)?
+                            .into_iter()
+                            .find(|e| e.name() == name)
+                            .ok_or_else(|| not_found(&child))?;
+                        self.base_node(self.image.open_entry(&entry)?)?
+                    }
+                    _ => return Err(not_found(&child)),
+                },
+            };
+            current = child;
+        }
+        Ok(node)
+    }
+
+    // Entries of the directory `dir` at `path`, by name.
+    pub(crate) fn children(&self, path: &SqshPath, dir: &Node) -> Result<BTreeMap<Vec<u8>, Node>> {
+        if !dir.is_dir() {
+            return Err(Error::new(
+                ErrorKind::NotADirectory,
+                format!("{}: not a directory", path),
+            ));
+        }
+        let mut children = BTreeMap::new();
+        if let Kind::Base(inode) = &dir.kind {
+            let entries = self.image.read_dir(inode)?;
+            for (entry, inode) in entries.iter().zip(self.image.stat_all(&entries)?) {
+                if !self.changes.contains_key(&path.join(entry.name())?) {
+                    children.insert(entry.name().to_vec(), self.base_node(inode)?);
+                }
+            }
+        }
+        for (changed, node) in &self.changes {
+            if let (Some(node), Some(name)) = (node, changed.file_name()) {
+                if changed.parent().as_ref() == Some(path) {
+                    children.insert(name.to_vec(), node.clone());
+                }
+            }
+        }
+        Ok(children)
+    }
+}
+
+/// Variants of `write_file` and `commit` for async services, taking and
+/// writing to the `futures-io` traits. tokio types can be adapted with the
+/// compat module of tokio-util. Compression runs on the calling task, which
+/// yields between blocks. The overlay keeps the contents of the files
+/// written to it in memory until they are committed, as for `write_file`.
+#[cfg(feature = "async")]
+impl<R: ReadSeek> CowOverlay<'_, R> {
+    /// Like `write_file`, reading the contents from `source` to its end.
+    pub async fn write_file_async<P: AsRef<[u8]>, S: AsyncRead + Unpin>(
+        &mut self,
+        path: P,
+        mut source: S,
+    ) -> Result<()> {
+        let mut data = vec![];
+        loop {
+            let len = data.len();
+            data.resize(len + 64 * 1024, 0);
+            let read = poll_fn(|cx| Pin::new(&mut source).poll_read(cx, &mut data[len..])).await?;
+            data.truncate(len + read);
+            if read == 0 {
+                break;
+            }
+        }
+        self.write_file(path, data)
+    }
+
+    /// Like `commit`, writing the image to `out` a block at a time as it
+    /// is compressed, then seeking back to write the superblock, which is
+    /// only known at the end. `out` is flushed.
+    pub async fn commit_async<W: AsyncWrite + AsyncSeek + Unpin>(
+        &self,
+        options: &CommitOptions,
+        out: W,
+    ) -> Result<Committed> {
+        self.commit_to(options, AsyncSink::new(out).await?).await
+    }
+}
+
+// Sink of `commit_async`, which keeps what is written until it is drained,
+// then lets other tasks run.
+#[cfg(feature = "async")]
+struct AsyncSink<W> {
+    inner: W,
+    buf: Vec<u8>,
+    base: u64,
+}
+
+#[cfg(feature = "async")]
+impl<W: AsyncWrite + AsyncSeek + Unpin> AsyncSink<W> {
+    async fn new(mut inner: W) -> Result<Self> {
+        let base = seek_async(&mut inner, io::SeekFrom::Current(0)).await?;
+        Ok(Self {
+            inner,
+            buf: vec![],
+            base,
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl<W> Write for AsyncSink<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl<W: AsyncWrite + AsyncSeek + Unpin> Sink for AsyncSink<W> {
+    async fn drain(&mut self) -> Result<()> {
+        write_all_async(&mut self.inner, &self.buf).await?;
+        self.buf.clear();
+        // pending once, for the tasks woken meanwhile
+        let mut yielded = false;
+        poll_fn(|cx| {
+            if yielded {
+                return Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn finish(&mut self, superblock: &[u8]) -> Result<()> {
+        self.drain().await?;
+        let end = seek_async(&mut self.inner, io::SeekFrom::Current(0)).await?;
+        seek_async(&mut self.inner, io::SeekFrom::Start(self.base)).await?;
+        write_all_async(&mut self.inner, superblock).await?;
+        seek_async(&mut self.inner, io::SeekFrom::Start(end)).await?;
+        poll_fn(|cx| Pin::new(&mut self.inner).poll_flush(cx)).await
+    }
+}
+
+#[cfg(feature = "async")]
+async fn write_all_async<W: AsyncWrite + Unpin>(out: &mut W, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        let written = poll_fn(|cx| Pin::new(&mut *out).poll_write(cx, buf)).await?;
+        if written == 0 {
+            return Err(Error::from(ErrorKind::WriteZero));
+        }
+        buf = &buf[written..];
+    }
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+async fn seek_async<W: AsyncSeek + Unpin>(out: &mut W, pos: io::SeekFrom) -> Result<u64> {
+    poll_fn(|cx| Pin::new(&mut *out).poll_seek(cx, pos)).await
+}
+
+// Id table of the new image, which only has the ids its inodes use, each
+// once, in the order they are first used.
+#[derive(Default)]
+struct NewIds {
+    ids: Vec<u32>,
+    indexes: HashMap<u32, u16>,
+}
+
+impl NewIds {
+    // Index of `id` in the table, which is added if missing.
+    fn index(&mut self, id: u32) -> Result<u16> {
+        if let Some(&index) = self.indexes.get(&id) {
+            return Ok(index);
+        }
+        let index = u16::try_from(self.ids.len()).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("more than {} distinct uids and gids", u16::MAX as u32 + 1),
+            )
+        })?;
+        self.ids.push(id);
+        self.indexes.insert(id, index);
+        Ok(index)
+    }
+}
@@ -0,0 +1,185 @@
+use std::borrow::Cow;
+use std::fmt::{Debug, Display};
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Deref;
+
+/// Absolute path inside an image. Paths are `/` separated bytes on every
+/// platform and aren't required to be valid UTF-8; they are kept normalized,
+/// without empty, `.` or `..` components.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SqshPath(Vec<u8>);
+
+impl SqshPath {
+    pub fn root() -> Self {
+        Self(b"/".to_vec())
+    }
+
+    /// Normalizes `path`, which is taken to be relative to the root whether
+    /// it starts with a `/` or not. `..` never goes above the root.
+    ///
+    /// Since symlinks aren't followed, `a/..` is the same as `.` even if `a`
+    /// is a link to another directory.
+    pub fn new<P: AsRef<[u8]>>(path: P) -> Self {
+        let mut names: Vec<&[u8]> = vec![];
+        for name in path.as_ref().split(|c| *c == b'/') {
+            match name {
+                b"" | b"." => {}
+                b".." => {
+                    names.pop();
+                }
+                name => names.push(name),
+            }
+        }
+        let mut normalized = Self::root();
+        for name in names {
+            normalized.push_name(name);
+        }
+        normalized
+    }
+
+    fn push_name(&mut self, name: &[u8]) {
+        if !self.is_root() {
+            self.0.push(b'/');
+        }
+        self.0.extend_from_slice(name);
+    }
+
+    /// Appends a directory entry name, which must be a single component.
+    pub fn push(&mut self, name: &[u8]) -> Result<()> {
+        if matches!(name, b"" | b"." | b"..") || name.contains(&b'/') {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid entry name {:?}", String::from_utf8_lossy(name)),
+            ));
+        }
+        self.push_name(name);
+        Ok(())
+    }
+
+    pub fn join(&self, name: &[u8]) -> Result<Self> {
+        let mut path = self.clone();
+        path.push(name)?;
+        Ok(path)
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0 == b"/"
+    }
+
+    pub fn parent(&self) -> Option<Self> {
+        if self.is_root() {
+            return None;
+        }
+        let end = self.0.iter().rposition(|c| *c == b'/').unwrap_or(0);
+        Some(Self::new(&self.0[..end]))
+    }
+
+    pub fn file_name(&self) -> Option<&[u8]> {
+        self.components().last()
+    }
+
+    pub fn components(&self) -> impl DoubleEndedIterator<Item = &[u8]> {
+        self.0[1..].split(|c| *c == b'/').filter(|c| !c.is_empty())
+    }
+
+    /// Components of this path below `base`, or None if it isn't below it.
+    pub fn strip_prefix<'a>(
+        &'a self,
+        base: &SqshPath,
+    ) -> Option<impl Iterator<Item = &'a [u8]> + 'a> {
+        let mut components = self.components();
+        for base in base.components() {
+            if components.next() != Some(base) {
+                return None;
+            }
+        }
+        Some(components)
+    }
+
+    /// Whether this path matches a glob `pattern`, taken to be relative to
+    /// the root. `*` matches any run of bytes within a component, `?` any
+    /// single byte, and a `**` component any number of components.
+    pub fn matches<P: AsRef<[u8]>>(&self, pattern: P) -> bool {
+        let pattern: Vec<&[u8]> = pattern
+            .as_ref()
+            .split(|c| *c == b'/')
+            .filter(|c| !c.is_empty())
+            .collect();
+        let path: Vec<&[u8]> = self.components().collect();
+        glob_match(&pattern, &path)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    /// Path for display, with invalid UTF-8 replaced.
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+}
+
+impl Default for SqshPath {
+    fn default() -> Self {
+        Self::root()
+    }
+}
+
+impl Deref for SqshPath {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for SqshPath {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<SqshPath> for Vec<u8> {
+    fn from(path: SqshPath) -> Self {
+        path.0
+    }
+}
+
+impl Display for SqshPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_string_lossy())
+    }
+}
+
+impl Debug for SqshPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.to_string_lossy(), f)
+    }
+}
+
+// Matches path components against pattern components, see `matches`.
+fn glob_match(pattern: &[&[u8]], path: &[&[u8]]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&b"**", rest)) => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((name, path)) => component_match(first, name) && glob_match(rest, path),
+            None => false,
+        },
+    }
+}
+
+fn component_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| component_match(rest, &name[i..])),
+        Some((&p, rest)) => match name.split_first() {
+            Some((&n, name)) => (p == b'?' || p == n) && component_match(rest, name),
+            None => false,
+        },
+    }
+}
@@ -0,0 +1,158 @@
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// Most buffers kept for reuse.
+pub(crate) const MAX_POOLED: usize = 8;
+/// Largest buffer kept, the maximum block size. Larger ones are freed.
+pub(crate) const MAX_POOLED_CAPACITY: usize = 1 << 20;
+
+static POOL: Mutex<BufferPool> = Mutex::new(BufferPool::new());
+
+/// Buffers kept for reuse: at most `MAX_POOLED` of them, none larger than
+/// `MAX_POOLED_CAPACITY`, and no more than the smallest of `limits` bytes of
+/// capacity in all.
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    buffers: Vec<Vec<u8>>,
+    // limits added by memory budgets and not removed yet
+    limits: Vec<usize>,
+}
+
+impl BufferPool {
+    pub(crate) const fn new() -> Self {
+        Self {
+            buffers: Vec::new(),
+            limits: Vec::new(),
+        }
+    }
+
+    /// The last buffer given back, or a new empty one.
+    pub(crate) fn take(&mut self) -> Vec<u8> {
+        self.buffers.pop().unwrap_or_default()
+    }
+
+    /// Keeps `buf` for reuse if the pool has room for it, frees it
+    /// otherwise.
+    pub(crate) fn give(&mut self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0
+            || buf.capacity() > MAX_POOLED_CAPACITY
+            || self.buffers.len() >= MAX_POOLED
+            || self.bytes() + buf.capacity() > self.limit()
+        {
+            return;
+        }
+        buf.clear();
+        self.buffers.push(buf);
+    }
+
+    /// Capacity the pool keeps at most: the smallest limit added, if below
+    /// what `MAX_POOLED` buffers of `MAX_POOLED_CAPACITY` take.
+    pub(crate) fn limit(&self) -> usize {
+        let limit = MAX_POOLED * MAX_POOLED_CAPACITY;
+        self.limits.iter().fold(limit, |limit, &l| limit.min(l))
+    }
+
+    /// Keeps the capacity of the pool to `bytes` until the limit is
+    /// removed, freeing buffers past it.
+    pub(crate) fn add_limit(&mut self, bytes: usize) {
+        self.limits.push(bytes);
+        while self.bytes() > self.limit() {
+            self.buffers.pop();
+        }
+    }
+
+    /// Removes a limit added with `add_limit`, the next smallest applying.
+    pub(crate) fn remove_limit(&mut self, bytes: usize) {
+        if let Some(i) = self.limits.iter().position(|&l| l == bytes) {
+            self.limits.swap_remove(i);
+        }
+    }
+
+    /// Capacity of the buffers kept.
+    pub(crate) fn bytes(&self) -> usize {
+        self.buffers.iter().map(Vec::capacity).sum()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.buffers.len()
+    }
+}
+
+/// Limit on the bytes kept by the pool shared by all images, held by an
+/// image with a memory budget. The smallest limit held applies, and a limit
+/// no longer does once dropped.
+#[derive(Debug)]
+pub(crate) struct PoolLimit(usize);
+
+impl PoolLimit {
+    pub(crate) fn new(bytes: usize) -> Self {
+        if let Ok(mut pool) = POOL.lock() {
+            pool.add_limit(bytes);
+        }
+        Self(bytes)
+    }
+}
+
+impl Clone for PoolLimit {
+    fn clone(&self) -> Self {
+        Self::new(self.0)
+    }
+}
+
+impl Drop for PoolLimit {
+    fn drop(&mut self) {
+        if let Ok(mut pool) = POOL.lock() {
+            pool.remove_limit(self.0);
+        }
+    }
+}
+
+/// Capacity of the buffers in the shared pool, and the most it keeps.
+#[cfg(all(test, feature = "testing"))]
+pub(crate) fn pooled_bytes() -> (usize, usize) {
+    POOL.lock()
+        .map(|pool| (pool.bytes(), pool.limit()))
+        .unwrap_or_default()
+}
+
+/// Scratch buffer for reading and decompressing blocks, taken from a pool
+/// shared by all images and threads and given back when dropped. Buffers
+/// are reused last in, first out; see `BufferPool` for what is kept.
+#[derive(Debug, Default)]
+pub(crate) struct PooledBuffer(Vec<u8>);
+
+impl PooledBuffer {
+    /// An empty buffer, with the capacity it had when last used.
+    pub(crate) fn take() -> Self {
+        let buf = match POOL.lock() {
+            Ok(mut pool) => pool.take(),
+            Err(_) => vec![],
+        };
+        Self(buf)
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let buf = mem::take(&mut self.0);
+        if let Ok(mut pool) = POOL.lock() {
+            pool.give(buf);
+        }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
@@ -0,0 +1,52 @@
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::sync::Arc;
+
+use positioned_io::{ReadAt, Size};
+
+/// Reads and seeks over a `positioned_io::ReadAt`, so positioned readers
+/// can back an `Image`. The underlying reader is shared, and each
+/// `ReadAtReader` keeps its own position, letting images opened with
+/// `ImageSource::from_read_at` read from several threads at once.
+#[derive(Debug)]
+pub struct ReadAtReader<I> {
+    inner: Arc<I>,
+    position: u64,
+}
+
+impl<I: ReadAt + Size> ReadAtReader<I> {
+    pub fn new(inner: Arc<I>) -> Self {
+        Self { inner, position: 0 }
+    }
+
+    pub fn get_ref(&self) -> &I {
+        &self.inner
+    }
+}
+
+impl<I: ReadAt + Size> Read for ReadAtReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.inner.read_at(self.position, buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<I: ReadAt + Size> Seek for ReadAtReader<I> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, "invalid seek");
+        self.position = match pos {
+            SeekFrom::Start(position) => position,
+            SeekFrom::Current(offset) => self
+                .position
+                .checked_add_signed(offset)
+                .ok_or_else(invalid)?,
+            SeekFrom::End(offset) => self
+                .inner
+                .size()?
+                .ok_or_else(|| Error::new(ErrorKind::Unsupported, "reader has no known size"))?
+                .checked_add_signed(offset)
+                .ok_or_else(invalid)?,
+        };
+        Ok(self.position)
+    }
+}
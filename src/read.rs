@@ -1,12 +1,154 @@
-use crate::compressors::{Compressor, Decompress};
-use crate::fragments::FRAGMENT_ENTRY_SIZE;
-use crate::superblock::Superblock;
-use crate::{ReadSeek, METADATA_SIZE};
-use std::io::{copy, Read, Result, SeekFrom, Write};
+use crate::compressors::Decompress;
+use crate::metrics::Metrics;
+use crate::pool::PooledBuffer;
+use crate::utils::decode_le_slice;
+use crate::{ReadSeek, COMPRESSED_BIT_BLOCK, METADATA_SIZE};
+use std::io::{copy, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Range;
+use std::sync::Arc;
 
 const COMPRESSED_BIT: u16 = 1 << 15;
+const READ_AHEAD: usize = 64 * 1024;
+/// Largest read issued when scanning a table.
+const BULK_READ: usize = 1 << 20;
 
-fn read_block_header<R: ReadSeek + ?Sized>(reader: &mut R) -> Result<(bool, u16)> {
+/// Wraps the reader of an image to keep track of its position, so seeking
+/// to where it already is doesn't reach the underlying reader. Small reads
+/// are served from a read ahead buffer, coalescing reads of adjacent blocks,
+/// such as table scans or file streaming, into a few large ones.
+#[derive(Clone, Debug)]
+pub(crate) struct TrackedReader<R> {
+    inner: R,
+    // position of buf[0] in the stream
+    buf_start: u64,
+    buf: Vec<u8>,
+    // current position, relative to buf_start
+    pos: usize,
+    // position of inner, if known
+    inner_pos: Option<u64>,
+    // range about to be read sequentially, see `set_extent`
+    extent: Range<u64>,
+    // largest read in `extent`
+    bulk_read: usize,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl<R: ReadSeek> TrackedReader<R> {
+    pub(crate) fn new(inner: R, metrics: Arc<dyn Metrics>) -> Self {
+        Self {
+            inner,
+            buf_start: 0,
+            buf: vec![],
+            pos: 0,
+            inner_pos: None,
+            extent: 0..0,
+            bulk_read: BULK_READ,
+            metrics,
+        }
+    }
+
+    pub(crate) fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = metrics;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Lowers the size of the reads of `set_extent`, down to the normal
+    /// read ahead.
+    pub(crate) fn set_bulk_read(&mut self, bytes: usize) {
+        self.bulk_read = bytes.clamp(READ_AHEAD, BULK_READ);
+    }
+
+    /// Announces that `extent` is about to be read sequentially, such as a
+    /// whole metadata table, so reads inside it fetch up to `BULK_READ`
+    /// bytes at once. An empty range goes back to the normal read ahead.
+    pub(crate) fn set_extent(&mut self, extent: Range<u64>) {
+        self.extent = extent;
+    }
+
+    fn position(&self) -> u64 {
+        self.buf_start + self.pos as u64
+    }
+
+    fn seek_inner(&mut self, position: u64) -> Result<()> {
+        if self.inner_pos != Some(position) {
+            self.inner_pos = None;
+            self.inner_pos = Some(self.inner.seek(SeekFrom::Start(position))?);
+        }
+        Ok(())
+    }
+}
+
+impl<R: ReadSeek> Read for TrackedReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize> {
+        if self.pos == self.buf.len() {
+            let position = self.position();
+            self.seek_inner(position)?;
+            self.buf_start = position;
+            self.buf.clear();
+            self.pos = 0;
+            let ahead = match self.extent.contains(&position) {
+                true => {
+                    (self.extent.end - position).clamp(READ_AHEAD as u64, self.bulk_read as u64)
+                }
+                false => READ_AHEAD as u64,
+            } as usize;
+            if ahead == READ_AHEAD {
+                self.buf.shrink_to(READ_AHEAD);
+            }
+            if out.len() >= ahead {
+                let read = self.inner.read(out)?;
+                self.metrics.bytes_read(read as u64);
+                self.buf_start += read as u64;
+                self.inner_pos = Some(self.buf_start);
+                return Ok(read);
+            }
+            self.buf.resize(ahead, 0);
+            let read = self.inner.read(&mut self.buf);
+            self.buf.truncate(*read.as_ref().unwrap_or(&0));
+            self.metrics.bytes_read(self.buf.len() as u64);
+            self.inner_pos = Some(position + self.buf.len() as u64);
+            read?;
+        }
+        let len = out.len().min(self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl<R: ReadSeek> Seek for TrackedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(target) => target,
+            SeekFrom::Current(offset) => self
+                .position()
+                .checked_add_signed(offset)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid seek"))?,
+            SeekFrom::End(_) => {
+                self.inner_pos = None;
+                let target = self.inner.seek(pos)?;
+                self.inner_pos = Some(target);
+                target
+            }
+        };
+        match target.checked_sub(self.buf_start) {
+            Some(offset) if offset <= self.buf.len() as u64 => self.pos = offset as usize,
+            _ => {
+                self.buf.clear();
+                self.buf_start = target;
+                self.pos = 0;
+            }
+        }
+        Ok(target)
+    }
+}
+
+pub(crate) fn read_block_header<R: ReadSeek + ?Sized>(reader: &mut R) -> Result<(bool, u16)> {
     let mut block_header: [u8; 2] = [0; 2];
     reader.read_exact(&mut block_header[..])?;
     let block_header = u16::from_le_bytes(block_header);
@@ -15,139 +157,182 @@ fn read_block_header<R: ReadSeek + ?Sized>(reader: &mut R) -> Result<(bool, u16)
     let compressed_size = block_header & !(COMPRESSED_BIT);
 
     if compressed_size as usize > METADATA_SIZE {
-        panic!("bad metadata size");
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("bad metadata block size {}", compressed_size),
+        ));
     }
 
     Ok((compressed, compressed_size))
 }
 
-pub fn read_block<R: ReadSeek + ?Sized, W: Write + ?Sized>(
+pub fn read_block<R: ReadSeek + ?Sized, W: Write + ?Sized, C: Decompress + ?Sized>(
     reader: &mut R,
     writer: &mut W,
-    compressor: &Compressor,
+    compressor: &C,
     start: u64,
     expected: Option<u32>,
 ) -> Result<u16> {
     reader.seek(SeekFrom::Start(start))?;
     let (compressed, compressed_size) = read_block_header(reader)?;
 
-    if compressed {
-        let mut buf = Vec::with_capacity(compressed_size as usize);
-        copy(&mut reader.take(compressed_size as u64), &mut buf)?;
-
-        eprintln!("try decompress, buf.len {}", buf.len());
-        let written = compressor.decompress(&mut (&buf[..]), writer)?;
-        if let Some(expected) = expected {
-            if expected as u64 != written {
-                panic!("expected ({}) != written ({})", expected, written);
-            }
+    let written = if compressed {
+        let mut buf = PooledBuffer::take();
+        copy(&mut reader.take(compressed_size as u64), &mut *buf)?;
+        compressor.decompress(&mut (&buf[..]), writer)?
+    } else {
+        copy(&mut reader.take(compressed_size as u64), writer)?
+    };
+    if let Some(expected) = expected {
+        if expected as u64 != written {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "metadata block at {} holds {} bytes, expected {}",
+                    start, written, expected
+                ),
+            ));
         }
-        Ok(compressed_size + 2)
+    }
+    Ok(compressed_size + 2)
+}
+
+/// Reads the on-disk bytes of a data or fragment block whose size word, as
+/// stored in block lists and fragment entries, is `size`.
+pub(crate) fn read_raw_data_block<R: ReadSeek + ?Sized>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    start: u64,
+    size: u32,
+) -> Result<()> {
+    let disk_size = (size & !COMPRESSED_BIT_BLOCK) as u64;
+    reader.seek(SeekFrom::Start(start))?;
+    buf.clear();
+    copy(&mut reader.take(disk_size), buf)?;
+    if buf.len() as u64 != disk_size {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated data block"));
+    }
+    Ok(())
+}
+
+/// Decompresses a block read by `read_raw_data_block`. Returns the
+/// decompressed length.
+pub(crate) fn decompress_data_block<W: Write + ?Sized, C: Decompress + ?Sized>(
+    raw: &[u8],
+    writer: &mut W,
+    compressor: &C,
+    size: u32,
+) -> Result<u64> {
+    if size & COMPRESSED_BIT_BLOCK == 0 {
+        compressor.decompress(&mut &raw[..], writer)
     } else {
-        copy(&mut reader.take(compressed_size as u64), writer)?;
-        Ok(compressed_size + 2)
+        writer.write_all(raw)?;
+        Ok(raw.len() as u64)
     }
 }
 
+/// Number of bytes in the `block`th metadata block of an indexed table of
+/// `bytes` bytes. Every block but the last is full.
+pub(crate) fn indexed_block_size(bytes: usize, block: usize) -> usize {
+    bytes
+        .saturating_sub(block * METADATA_SIZE)
+        .min(METADATA_SIZE)
+}
+
+/// Reads the u64 block pointers indexing a table of `bytes` bytes, stored
+/// at `index_start`.
+pub(crate) fn read_table_index<R: ReadSeek + ?Sized>(
+    reader: &mut R,
+    index_start: u64,
+    bytes: usize,
+) -> Result<Vec<u64>> {
+    if bytes == 0 {
+        return Ok(vec![]);
+    }
+    let blocks = bytes.div_ceil(METADATA_SIZE);
+    // `bytes` comes from counts in the superblock, so nothing is allocated
+    // from it until the index is found to be there
+    let mut index = vec![];
+    reader.seek(SeekFrom::Start(index_start))?;
+    copy(
+        &mut reader.take((blocks * mem::size_of::<u64>()) as u64),
+        &mut index,
+    )?;
+    // an index cut off mid-pointer is truncated, not malformed
+    index.truncate(index.len() / mem::size_of::<u64>() * mem::size_of::<u64>());
+    let index: Vec<u64> = decode_le_slice(&index)?;
+    if index.len() != blocks {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            format!(
+                "table index truncated: {} of {} entries",
+                index.len(),
+                blocks
+            ),
+        ));
+    }
+    Ok(index)
+}
+
+/// Reads a table stored as metadata blocks referenced by an index of u64
+/// pointers, as the fragment, id, export and xattr id tables are. Each
+/// block is checked to decompress to the size its position implies.
 #[derive(Debug)]
-pub struct FragmentTableReader<'a, R: ReadSeek> {
+pub(crate) struct IndexedTableReader<'a, R, C: ?Sized> {
     reader: R,
-    compressor: &'a Compressor,
-    position: usize,
+    compressor: &'a C,
     index: Vec<u64>,
-    fragments: usize,
-    buffer_position: u64,
+    bytes: usize,
+    // next block to read
+    block: usize,
     buffer: Vec<u8>,
+    buffer_position: usize,
 }
 
-impl<'a, R: ReadSeek> FragmentTableReader<'a, R> {
-    pub fn new(mut reader: R, compressor: &'a Compressor, superblock: &Superblock) -> Result<Self> {
-        let fragments = superblock.fragments();
-        let indexes =
-            ((fragments as usize * FRAGMENT_ENTRY_SIZE) + METADATA_SIZE - 1) / METADATA_SIZE;
-        let indexes_bytes = indexes * 8;
-
-        let mut index = Vec::with_capacity(indexes_bytes);
-        reader.seek(SeekFrom::Start(superblock.fragment_table_start() as u64))?;
-        copy(&mut (&mut reader).take(indexes_bytes as u64), &mut index)?;
-
-        let index: Vec<u64> = index
-            .chunks(8)
-            .map(|x| {
-                let v = match x.try_into() {
-                    Ok(v) => v,
-                    Err(e) => {
-                        panic!("{}", e);
-                    }
-                };
-                u64::from_le_bytes(v)
-            })
-            .collect();
-
-        Ok(Self {
+impl<'a, R: ReadSeek, C: Decompress + ?Sized> IndexedTableReader<'a, R, C> {
+    /// `index` is read with `read_table_index`, for a table of `bytes`
+    /// bytes.
+    pub(crate) fn new(reader: R, compressor: &'a C, index: Vec<u64>, bytes: usize) -> Self {
+        Self {
             reader,
             compressor,
-            position: 0,
             index,
-            fragments: fragments as usize,
-            buffer_position: 0,
+            bytes,
+            block: 0,
             buffer: Vec::with_capacity(METADATA_SIZE),
-        })
+            buffer_position: 0,
+        }
     }
 
-    pub fn fragments(&self) -> usize {
-        self.fragments
+    /// Reads the rest of the table.
+    pub(crate) fn read_table(mut self) -> Result<Vec<u8>> {
+        let mut table = vec![];
+        self.read_to_end(&mut table)?;
+        Ok(table)
     }
 }
 
-impl<'a, R: ReadSeek> Read for FragmentTableReader<'a, R> {
-    fn read(&mut self, writer: &mut [u8]) -> Result<usize> {
-        let mut written = 0;
-        let writer_len = writer.len();
-        let index_len = self.index.len();
-
-        if (self.buffer_position as usize) < self.buffer.len() {
-            let buffer_remainder = self.buffer.len() - self.buffer_position as usize;
-            if writer_len < buffer_remainder {
-                writer.copy_from_slice(
-                    &self.buffer[self.buffer_position as usize
-                        ..(self.buffer_position as usize + writer_len)],
-                );
-                self.buffer_position += writer_len as u64;
-                return Ok(writer_len);
+impl<R: ReadSeek, C: Decompress + ?Sized> Read for IndexedTableReader<'_, R, C> {
+    fn read(&mut self, out: &mut [u8]) -> Result<usize> {
+        if self.buffer_position == self.buffer.len() {
+            if self.block == self.index.len() {
+                return Ok(0);
             }
-            writer[..buffer_remainder]
-                .copy_from_slice(&self.buffer[self.buffer_position as usize..]);
-            written += buffer_remainder;
-            self.buffer.truncate(0);
+            self.buffer.clear();
             self.buffer_position = 0;
+            let expected = indexed_block_size(self.bytes, self.block);
+            read_block(
+                &mut self.reader,
+                &mut self.buffer,
+                self.compressor,
+                self.index[self.block],
+                Some(expected as u32),
+            )?;
+            self.block += 1;
         }
-
-        if self.position == index_len {
-            return Ok(written);
-        }
-
-        let expected = match (self.position + 1) != self.index.len() {
-            true => METADATA_SIZE as u32,
-            false => ((self.fragments * FRAGMENT_ENTRY_SIZE) & (METADATA_SIZE - 1)) as u32,
-        };
-
-        let block_size = read_block(
-            &mut self.reader,
-            &mut self.buffer,
-            self.compressor,
-            self.index[self.position],
-            Some(expected),
-        )?;
-
-        eprintln!("block size read {}", block_size);
-        self.position += 1;
-
-        let left_to_write = self.buffer.len().min(writer_len - written);
-        writer[written..(written + left_to_write)].copy_from_slice(&self.buffer[..left_to_write]);
-        written += left_to_write;
-        self.buffer_position += left_to_write as u64;
-
-        Ok(written)
+        let len = out.len().min(self.buffer.len() - self.buffer_position);
+        out[..len].copy_from_slice(&self.buffer[self.buffer_position..self.buffer_position + len]);
+        self.buffer_position += len;
+        Ok(len)
     }
 }
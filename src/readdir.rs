@@ -0,0 +1,88 @@
+use std::io::{Error, ErrorKind, Result};
+use std::vec;
+
+use crate::image::Image;
+use crate::inode::{DirectoryEntry, FileType, InodeHeader};
+use crate::ReadSeek;
+
+/// Directory entry as FUSE `readdir` replies and NFS `READDIR` results
+/// carry it. `offset` is the cookie to pass back to resume listing after
+/// this entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReaddirEntry {
+    /// Inode number as stored in the image. FUSE wants the root to be 1,
+    /// which mksquashfs images don't have, so frontends map it.
+    pub ino: u64,
+    pub offset: i64,
+    pub file_type: FileType,
+    pub name: Vec<u8>,
+}
+
+/// Entries of a directory from a cookie on, see `Image::readdir`.
+pub struct Readdir {
+    entries: vec::IntoIter<DirectoryEntry>,
+    // `.` and `..` not yet returned, with their inode numbers
+    dots: vec::IntoIter<(&'static [u8], u32)>,
+    offset: i64,
+}
+
+impl Readdir {
+    pub(crate) fn new<R: ReadSeek>(
+        image: &Image<R>,
+        dir: &InodeHeader,
+        offset: i64,
+    ) -> Result<Self> {
+        if offset < 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid directory offset {}", offset),
+            ));
+        }
+        let parent = image.parent_number(dir)?;
+        let skip = offset as usize;
+        // cookies count `.` and `..` first, then entries in on-disk order,
+        // which never changes in a read-only image
+        let mut dots = vec![(&b"."[..], dir.inode_number()), (&b".."[..], parent)];
+        dots.drain(..skip.min(2));
+        let mut entries = image.read_dir(dir)?;
+        if let Some(entry) = entries.iter().find(|e| e.file_type().is_none()) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{}: invalid inode type {}",
+                    entry.name_lossy(),
+                    entry.inode_type()
+                ),
+            ));
+        }
+        entries.drain(..skip.saturating_sub(2).min(entries.len()));
+        Ok(Self {
+            entries: entries.into_iter(),
+            dots: dots.into_iter(),
+            offset,
+        })
+    }
+}
+
+impl Iterator for Readdir {
+    type Item = ReaddirEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (ino, file_type, name) = match self.dots.next() {
+            Some((name, number)) => (number, FileType::Directory, name.to_vec()),
+            None => {
+                let entry = self.entries.next()?;
+                // checked when listing
+                let file_type = entry.file_type().unwrap();
+                (entry.inode_number(), file_type, entry.name().to_vec())
+            }
+        };
+        self.offset += 1;
+        Some(ReaddirEntry {
+            ino: ino as u64,
+            offset: self.offset,
+            file_type,
+            name,
+        })
+    }
+}
@@ -0,0 +1,216 @@
+use std::io::{Result, Write};
+
+use crate::audit::{Finding, Issue};
+use crate::integrity::BadBlock;
+use crate::path::SqshPath;
+use crate::warning::Warning;
+
+/// How serious a report item is, named as SARIF levels are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+}
+
+/// One result of an audit or check, in a form that can be written as JSON
+/// or SARIF.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReportItem {
+    /// Stable identifier of the kind of item, like `setuid`.
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    /// Path in the image the item is about, if any.
+    pub path: Option<SqshPath>,
+    /// Offset in the image the item is about, if any.
+    pub offset: Option<u64>,
+}
+
+impl From<&Finding> for ReportItem {
+    fn from(finding: &Finding) -> Self {
+        let (rule, severity) = match finding.issue {
+            Issue::Setuid => ("setuid", Severity::Warning),
+            Issue::Setgid => ("setgid", Severity::Warning),
+            Issue::WorldWritable => ("world-writable", Severity::Warning),
+            Issue::DeviceOutsideDev => ("device-outside-dev", Severity::Error),
+        };
+        Self {
+            rule,
+            severity,
+            message: finding.to_string(),
+            path: Some(finding.path.clone()),
+            offset: None,
+        }
+    }
+}
+
+impl From<&BadBlock> for ReportItem {
+    fn from(block: &BadBlock) -> Self {
+        Self {
+            rule: "bad-block",
+            severity: Severity::Error,
+            message: format!(
+                "bytes {:#x}..{:#x}: {}",
+                block.range.start, block.range.end, block.error
+            ),
+            path: block.paths.first().cloned(),
+            offset: Some(block.range.start),
+        }
+    }
+}
+
+impl From<&Warning> for ReportItem {
+    fn from(warning: &Warning) -> Self {
+        let (rule, severity, offset) = match warning {
+            Warning::UnknownFlags(_) => ("unknown-flags", Severity::Warning, Some(0)),
+            Warning::Version(..) => ("version", Severity::Warning, Some(0)),
+            Warning::UnusedField { .. } => ("unused-field", Severity::Note, None),
+            Warning::Padding { offset } => ("padding", Severity::Note, Some(*offset)),
+            Warning::RootNotDirectory(_) => ("root-not-directory", Severity::Error, None),
+            Warning::LinkCount { .. } => ("link-count", Severity::Warning, None),
+            Warning::Truncated { len, .. } => ("truncated", Severity::Error, Some(*len)),
+            Warning::MissingTable(_) => ("missing-table", Severity::Error, None),
+            Warning::Unreadable(_) => ("unreadable", Severity::Error, None),
+            Warning::XattrNotSet { .. } => ("xattr-not-set", Severity::Warning, None),
+        };
+        Self {
+            rule,
+            severity,
+            message: warning.to_string(),
+            path: match warning {
+                Warning::Unreadable(path) | Warning::XattrNotSet { path, .. } => Some(path.clone()),
+                _ => None,
+            },
+            offset,
+        }
+    }
+}
+
+/// Items collected from audits and checks of an image, to hand to CI in a
+/// machine-readable form.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub items: Vec<ReportItem>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push<T: Into<ReportItem>>(&mut self, item: T) {
+        self.items.push(item.into());
+    }
+
+    /// Highest severity of the items, if any.
+    pub fn max_severity(&self) -> Option<Severity> {
+        self.items.iter().map(|i| i.severity).min()
+    }
+
+    /// Writes the items as a JSON array of objects with `rule`, `severity`,
+    /// `message`, and `path` and `offset` when known.
+    pub fn write_json<W: Write>(&self, mut out: W) -> Result<()> {
+        let mut json = String::from("[");
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("\n  {\"rule\": ");
+            push_string(&mut json, item.rule);
+            json.push_str(", \"severity\": ");
+            push_string(&mut json, item.severity.as_str());
+            json.push_str(", \"message\": ");
+            push_string(&mut json, &item.message);
+            if let Some(path) = &item.path {
+                json.push_str(", \"path\": ");
+                push_string(&mut json, &path.to_string_lossy());
+            }
+            if let Some(offset) = item.offset {
+                json.push_str(&format!(", \"offset\": {}", offset));
+            }
+            json.push('}');
+        }
+        json.push_str("\n]\n");
+        out.write_all(json.as_bytes())
+    }
+
+    /// Writes the items as a SARIF 2.1.0 log of a single run. `image` is
+    /// the URI given to the image, which offsets are reported into; paths
+    /// are logical locations inside it.
+    pub fn write_sarif<W: Write>(&self, image: &str, mut out: W) -> Result<()> {
+        let mut rules: Vec<&str> = self.items.iter().map(|i| i.rule).collect();
+        rules.sort();
+        rules.dedup();
+
+        let mut json = String::from(concat!(
+            "{\n",
+            "  \"version\": \"2.1.0\",\n",
+            "  \"$schema\": \"https://json.schemastore.org/sarif-2.1.0.json\",\n",
+            "  \"runs\": [{\n",
+            "    \"tool\": {\"driver\": {\"name\": \"squashfs\", \"rules\": ["
+        ));
+        for (i, rule) in rules.iter().enumerate() {
+            if i > 0 {
+                json.push_str(", ");
+            }
+            json.push_str("{\"id\": ");
+            push_string(&mut json, rule);
+            json.push('}');
+        }
+        json.push_str("]}},\n    \"results\": [");
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("\n      {\"ruleId\": ");
+            push_string(&mut json, item.rule);
+            json.push_str(", \"level\": ");
+            push_string(&mut json, item.severity.as_str());
+            json.push_str(", \"message\": {\"text\": ");
+            push_string(&mut json, &item.message);
+            json.push_str(
+                "}, \"locations\": [{\"physicalLocation\": {\"artifactLocation\": {\"uri\": ",
+            );
+            push_string(&mut json, image);
+            json.push('}');
+            if let Some(offset) = item.offset {
+                json.push_str(&format!(", \"region\": {{\"byteOffset\": {}}}", offset));
+            }
+            json.push('}');
+            if let Some(path) = &item.path {
+                json.push_str(", \"logicalLocations\": [{\"fullyQualifiedName\": ");
+                push_string(&mut json, &path.to_string_lossy());
+                json.push_str(", \"kind\": \"resource\"}]");
+            }
+            json.push_str("}]}");
+        }
+        json.push_str("\n    ]\n  }]\n}\n");
+        out.write_all(json.as_bytes())
+    }
+}
+
+// Appends `s` as a JSON string.
+pub(crate) fn push_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
@@ -0,0 +1,767 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use crate::compressors::{compressor_name, Compress, Compressor, XZCompressor, ZSTDCompressor};
+use crate::image::{Image, TableKind};
+use crate::inode::{read_inode_header, FileType, InodeHeader, InodeRef};
+use crate::overlay::{CompressionChoice, Grouping};
+use crate::superblock::{Flags, Superblock};
+use crate::write::{DirectoryWriter, MetadataWriter, NewDirectoryEntry, NewDirectoryInode};
+use crate::xattr::XATTR_ID_ENTRY_SIZE;
+use crate::{
+    ReadSeek, COMPRESSED_BIT_BLOCK, INVALID_BLK, INVALID_FRAG, INVALID_XATTR, SUPERBLOCK_SIZE,
+};
+
+// Inode of the new image, numbered by its index plus 1. The root is first.
+pub(crate) struct Node {
+    pub(crate) inode: InodeHeader,
+    pub(crate) children: Vec<(Vec<u8>, usize)>,
+    pub(crate) links: u32,
+    // contents of a regular file that isn't in the source image, written
+    // out in place of the blocks `inode` points to
+    pub(crate) contents: Option<Arc<[u8]>>,
+    // how `contents` is compressed
+    pub(crate) compression: CompressionChoice,
+}
+
+pub(crate) struct RewriteOptions {
+    // id table of the new image, which the uid and gid indexes of the
+    // inodes point into
+    pub(crate) ids: Vec<u32>,
+    // whether the tails of new files are packed into fragment blocks
+    pub(crate) fragments: bool,
+    pub(crate) mkfs_time: u32,
+    // largest zstd dictionary to train over the tails of new files
+    pub(crate) zstd_dictionary: Option<usize>,
+    // order the data of new files is written in
+    pub(crate) grouping: Grouping,
+    // multiple of bytes the image is padded to, and the data of each file
+    // and each fragment block start at, 0 for none
+    pub(crate) padding: u64,
+    pub(crate) alignment: u64,
+}
+
+pub(crate) struct Rewritten {
+    pub(crate) bytes_used: u64,
+    // data and fragment blocks copied from the source image, and those
+    // written for new files
+    pub(crate) copied: u64,
+    pub(crate) written: u64,
+    // data and fragment blocks of the source image shared by several files
+    // and copied once, beyond that once
+    pub(crate) deduplicated: u64,
+    // new files, the bytes of their contents, the fragment blocks packing
+    // their tails and the bytes of those tails
+    pub(crate) files: u64,
+    pub(crate) bytes_in: u64,
+    pub(crate) fragment_blocks: u64,
+    pub(crate) fragment_bytes: u64,
+}
+
+// Data blocks of a file, or a fragment block, to copy.
+struct Extent {
+    start: u64,
+    len: u64,
+    fragment: Option<u32>,
+}
+
+// Where `rewrite` writes the image to: straight through, or buffered and
+// handed on each time it is drained, between blocks and files.
+pub(crate) trait Sink: Write {
+    // Hands on what was written since the last drain, waiting until it is.
+    async fn drain(&mut self) -> Result<()>;
+
+    // Writes `superblock` over the start of the image once the rest is
+    // written, and flushes.
+    async fn finish(&mut self, superblock: &[u8]) -> Result<()>;
+}
+
+// Sink writing to a writer, at its position when created.
+pub(crate) struct SeekSink<W> {
+    inner: W,
+    base: u64,
+}
+
+impl<W: Write + Seek> SeekSink<W> {
+    pub(crate) fn new(mut inner: W) -> Result<Self> {
+        let base = inner.stream_position()?;
+        Ok(Self { inner, base })
+    }
+}
+
+impl<W: Write> Write for SeekSink<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write + Seek> Sink for SeekSink<W> {
+    async fn drain(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn finish(&mut self, superblock: &[u8]) -> Result<()> {
+        let end = self.inner.stream_position()?;
+        self.inner.seek(SeekFrom::Start(self.base))?;
+        self.inner.write_all(superblock)?;
+        self.inner.seek(SeekFrom::Start(end))?;
+        self.inner.flush()
+    }
+}
+
+// Runs `future` to its end on the current thread. Meant for `rewrite` to a
+// `SeekSink`, which is never pending.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+// Writes an image of `nodes` to `out`, copying the data and fragment blocks
+// of the source image they point to, and the xattrs, as they are stored.
+pub(crate) async fn rewrite<R: ReadSeek, S: Sink>(
+    image: &Image<R>,
+    mut nodes: Vec<Node>,
+    options: &RewriteOptions,
+    out: S,
+) -> Result<Rewritten> {
+    let sb = image.superblock();
+    let flags = sb.flags();
+    let mut compressor = if flags.contains(Flags::COMPRESSOR_OPTIONS_PRESENT) {
+        image.compressor()?
+    } else {
+        // the defaults of mksquashfs, which only stores options that differ
+        match image.compressor()? {
+            Compressor::GZIP(_) => Compressor::GZIP(Default::default()),
+            Compressor::XZ(_) => {
+                let mut xz = XZCompressor::default();
+                xz.set_dictionary_size(sb.block_size());
+                Compressor::XZ(xz)
+            }
+            Compressor::ZSTD(_) => Compressor::ZSTD(Default::default()),
+            compressor => compressor,
+        }
+    };
+    if let Some(max_size) = options.zstd_dictionary {
+        let Compressor::ZSTD(zstd) = &mut compressor else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("dictionaries need zstd, the image uses {}", compressor),
+            ));
+        };
+        // blocks copied as stored may need the dictionary they were
+        // compressed with
+        if zstd.dictionary().is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the image already has a zstd dictionary",
+            ));
+        }
+        let block_size = sb.block_size() as usize;
+        let tails: Vec<&[u8]> = nodes
+            .iter()
+            .filter_map(|n| n.contents.as_deref())
+            .map(|data| &data[data.len() - data.len() % block_size..])
+            .filter(|tail| !tail.is_empty())
+            .collect();
+        let dictionary = ZSTDCompressor::train_dictionary(&tails, max_size)?;
+        zstd.set_dictionary(Some(dictionary.into()))?;
+    }
+    let mut out = Output {
+        inner: out,
+        position: 0,
+    };
+    out.write_all(&[0; SUPERBLOCK_SIZE])?;
+    if options.zstd_dictionary.is_some() {
+        // stored uncompressed, as mksquashfs does
+        let mut block = MetadataWriter::new(compressor.clone());
+        block.set_uncompressed(true);
+        compressor.write_options(&mut block)?;
+        out.write_all(&block.finish()?)?;
+    } else if flags.contains(Flags::COMPRESSOR_OPTIONS_PRESENT) {
+        let mut header = vec![];
+        image.copy_raw(SUPERBLOCK_SIZE as u64, 2, &mut header)?;
+        let len = u16::from_le_bytes([header[0], header[1]]) & 0x7fff;
+        image.copy_raw(SUPERBLOCK_SIZE as u64, 2 + len as u64, &mut out)?;
+    }
+    out.inner.drain().await?;
+
+    // copy data and fragment blocks in image order, sharing deduplicated
+    // blocks as the source image did
+    let fragment_table = image.fragments()?;
+    let mut extents = vec![];
+    let mut fragments_used = vec![false; fragment_table.len()];
+    for node in nodes.iter().filter(|n| n.contents.is_none()) {
+        let (start, blocks, fragment) = match &node.inode {
+            InodeHeader::Regular(r) => (r.start_block() as u64, r.blocks(), r.fragment()),
+            InodeHeader::LRegular(r) => (r.start_block(), r.blocks(), r.fragment()),
+            _ => continue,
+        };
+        extents.push(Extent {
+            start,
+            len: data_len(blocks),
+            fragment: None,
+        });
+        if fragment != INVALID_FRAG {
+            let used = fragments_used.get_mut(fragment as usize).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("fragment index out of range: {}", fragment),
+                )
+            })?;
+            if !*used {
+                *used = true;
+                let entry = &fragment_table[fragment as usize];
+                extents.push(Extent {
+                    start: entry.start_block(),
+                    len: (entry.size() & !COMPRESSED_BIT_BLOCK) as u64,
+                    fragment: Some(fragment),
+                });
+            }
+        }
+    }
+    extents.sort_by_key(|e| (e.start, e.len));
+
+    let mut data = HashMap::new();
+    let mut fragment_map = HashMap::new();
+    let mut fragments = MetadataWriter::new(compressor.clone());
+    fragments.set_uncompressed(flags.contains(Flags::FRAGMENTS_STORED_UNCOMPRESSED));
+    let mut copied = 0;
+    let mut deduplicated = 0;
+    for extent in &extents {
+        let start = match data.get(&(extent.start, extent.len)) {
+            Some(&start) => {
+                deduplicated += extent.len;
+                start
+            }
+            None => {
+                if extent.len > 0 {
+                    out.pad_to(options.alignment)?;
+                }
+                let start = out.position;
+                copy_extent(image, extent, &mut out).await?;
+                copied += extent.len;
+                data.insert((extent.start, extent.len), start);
+                start
+            }
+        };
+        if let Some(fragment) = extent.fragment {
+            let entry = &fragment_table[fragment as usize];
+            fragment_map.insert(fragment, fragment_map.len() as u32);
+            fragments.write_all(&start.to_le_bytes())?;
+            fragments.write_all(&entry.size().to_le_bytes())?;
+            fragments.write_all(&0u32.to_le_bytes())?;
+        }
+    }
+
+    // then the data of new files, with their tails in new fragment blocks
+    // after the fragments kept
+    let start = out.position;
+    let mut files = DataWriter {
+        compressor: &compressor,
+        superblock: *sb,
+        packing: options.fragments,
+        alignment: options.alignment,
+        fragment: vec![],
+        fragment_count: fragment_map.len() as u32,
+        fragments,
+        files: 0,
+        bytes_in: 0,
+        fragment_bytes: 0,
+    };
+    for index in write_order(&nodes, options.grouping) {
+        let node = &mut nodes[index];
+        if let Some(contents) = &node.contents {
+            node.inode = files
+                .write_file(&mut out, &node.inode, contents, node.compression)
+                .await?;
+        }
+    }
+    files.flush_fragment(&mut out)?;
+    out.inner.drain().await?;
+    let DataWriter {
+        fragments,
+        fragment_count,
+        files,
+        bytes_in,
+        fragment_bytes,
+        ..
+    } = files;
+    let written = out.position - start;
+
+    let uncompressed_inodes = flags.contains(Flags::INODES_STORED_UNCOMPRESSED);
+    let mut writer = TreeWriter {
+        nodes: &nodes,
+        refs: vec![None; nodes.len()],
+        inodes: MetadataWriter::new(compressor.clone()),
+        dirs: DirectoryWriter::new(compressor.clone()),
+        data: &data,
+        fragments: &fragment_map,
+    };
+    writer.inodes.set_uncompressed(uncompressed_inodes);
+    writer.dirs.set_uncompressed(uncompressed_inodes);
+    // the root's parent is past the last inode, as mksquashfs does
+    let root = writer.write_node(0, nodes.len() as u32 + 1)?;
+    let TreeWriter {
+        refs, inodes, dirs, ..
+    } = writer;
+
+    let mut sb = *sb;
+    sb.set_inode_table_start(out.position as i64);
+    out.write_all(&inodes.finish()?)?;
+    sb.set_directory_table_start(out.position as i64);
+    out.write_all(&dirs.finish()?)?;
+    sb.set_fragment_table_start(write_indexed_table(&mut out, fragments)?);
+    if sb.export_table_start() != INVALID_BLK {
+        let mut export = MetadataWriter::new(compressor.clone());
+        export.set_uncompressed(uncompressed_inodes);
+        for inode_ref in refs.into_iter().flatten() {
+            export.write_all(&u64::from(inode_ref).to_le_bytes())?;
+        }
+        sb.set_export_table_start(write_indexed_table(&mut out, export)? as i64);
+    }
+    let mut ids = MetadataWriter::new(compressor.clone());
+    ids.set_uncompressed(flags.contains(Flags::IDTABLE_UNCOMPRESSED));
+    for id in &options.ids {
+        ids.write_all(&id.to_le_bytes())?;
+    }
+    let no_ids = u16::try_from(options.ids.len())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "too many ids"))?;
+    sb.set_id_table_start(write_indexed_table(&mut out, ids)?);
+    if let Some(store) = image.xattr_store()? {
+        sb.set_xattr_id_table_start(write_xattrs(image, store, &compressor, &mut out)? as i64);
+    }
+
+    if options.zstd_dictionary.is_some() {
+        sb.set_flags(sb.flags() | Flags::COMPRESSOR_OPTIONS_PRESENT);
+    }
+    if fragment_count > fragment_map.len() as u32 {
+        sb.set_flags(sb.flags() - Flags::FRAGMENTS_ARE_NOT_USED);
+    }
+    sb.set_inodes(nodes.len() as u32);
+    sb.set_fragments(fragment_count);
+    sb.set_no_ids(no_ids);
+    sb.set_mkfs_time(options.mkfs_time);
+    sb.set_root_inode(u64::from(root) as i64);
+    sb.set_bytes_used(out.position);
+    let bytes_used = out.position;
+    out.pad_to(options.padding)?;
+    out.inner.finish(&sb.to_bytes()).await?;
+    Ok(Rewritten {
+        bytes_used,
+        copied,
+        written,
+        deduplicated,
+        files,
+        bytes_in,
+        fragment_blocks: (fragment_count - fragment_map.len() as u32) as u64,
+        fragment_bytes,
+    })
+}
+
+// Builds an inode from its fields as stored, after the type, with the
+// attributes of `attrs`.
+pub(crate) fn new_inode(
+    inode_type: u16,
+    attrs: (u16, u16, u16, u32),
+    fields: &[u8],
+    superblock: &Superblock,
+) -> Result<InodeHeader> {
+    let (mode, uid, gid, mtime) = attrs;
+    let mut inode = inode_type.to_le_bytes().to_vec();
+    inode.extend_from_slice(&mode.to_le_bytes());
+    inode.extend_from_slice(&uid.to_le_bytes());
+    inode.extend_from_slice(&gid.to_le_bytes());
+    inode.extend_from_slice(&mtime.to_le_bytes());
+    // renumbered when written
+    inode.extend_from_slice(&0u32.to_le_bytes());
+    inode.extend_from_slice(fields);
+    read_inode_header(&mut &inode[..], superblock)
+}
+
+// Writes the data of the regular files that aren't in the source image.
+struct DataWriter<'a> {
+    compressor: &'a Compressor,
+    superblock: Superblock,
+    packing: bool,
+    alignment: u64,
+    // fragment block being filled, whose index is `fragment_count`
+    fragment: Vec<u8>,
+    fragment_count: u32,
+    fragments: MetadataWriter,
+    // files written, their bytes, and the bytes of the fragment blocks
+    // written
+    files: u64,
+    bytes_in: u64,
+    fragment_bytes: u64,
+}
+
+impl DataWriter<'_> {
+    // Writes `data`, returning the inode of the file with the attributes of
+    // `attrs`. Full blocks of zeros are stored as sparse. The tail of a file
+    // stored uncompressed is its last block rather than in a fragment block
+    // compressed with others.
+    async fn write_file<S: Sink>(
+        &mut self,
+        out: &mut Output<S>,
+        attrs: &InodeHeader,
+        data: &[u8],
+        compression: CompressionChoice,
+    ) -> Result<InodeHeader> {
+        let block_size = self.superblock.block_size() as usize;
+        let compressor = match compression {
+            CompressionChoice::Level(level) => with_level(self.compressor, level)?,
+            _ => self.compressor.clone(),
+        };
+        let stored = compression == CompressionChoice::Uncompressed;
+        self.files += 1;
+        self.bytes_in += data.len() as u64;
+        let tail_len = match self.packing && !stored {
+            true => data.len() % block_size,
+            false => 0,
+        };
+        let (blocks, tail) = data.split_at(data.len() - tail_len);
+        let uncompressed = stored
+            || self
+                .superblock
+                .flags()
+                .contains(Flags::DATA_BLOCKS_STORED_UNCOMPRESSED);
+
+        if !blocks.is_empty() {
+            out.pad_to(self.alignment)?;
+        }
+        let start = out.position;
+        let mut sizes = vec![];
+        let mut sparse = 0;
+        for block in blocks.chunks(block_size) {
+            if block.len() == block_size && block.iter().all(|&b| b == 0) {
+                sizes.push(0);
+                sparse += block_size as u64;
+            } else {
+                sizes.push(write_block(out, &compressor, block, uncompressed)?);
+                out.inner.drain().await?;
+            }
+        }
+        let (fragment, offset) = if tail.is_empty() {
+            (INVALID_FRAG, 0)
+        } else {
+            if self.fragment.len() + tail.len() > block_size {
+                self.flush_fragment(out)?;
+            }
+            let offset = self.fragment.len() as u32;
+            self.fragment.extend_from_slice(tail);
+            (self.fragment_count, offset)
+        };
+
+        let basic = start <= u32::MAX as u64 && data.len() <= u32::MAX as usize;
+        let mut fields = vec![];
+        if basic {
+            fields.extend_from_slice(&(start as u32).to_le_bytes());
+            fields.extend_from_slice(&fragment.to_le_bytes());
+            fields.extend_from_slice(&offset.to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        } else {
+            fields.extend_from_slice(&start.to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            fields.extend_from_slice(&sparse.to_le_bytes());
+            fields.extend_from_slice(&1u32.to_le_bytes());
+            fields.extend_from_slice(&fragment.to_le_bytes());
+            fields.extend_from_slice(&offset.to_le_bytes());
+            fields.extend_from_slice(&INVALID_XATTR.to_le_bytes());
+        }
+        for size in sizes {
+            fields.extend_from_slice(&size.to_le_bytes());
+        }
+        // the inode is checked against the fragment count when parsed
+        let mut superblock = self.superblock;
+        superblock.set_fragments(self.fragment_count + 1);
+        new_inode(
+            if basic { 2 } else { 9 },
+            (attrs.mode(), attrs.uid(), attrs.guid(), attrs.mtime()),
+            &fields,
+            &superblock,
+        )
+    }
+
+    fn flush_fragment<W: Write>(&mut self, out: &mut Output<W>) -> Result<()> {
+        if self.fragment.is_empty() {
+            return Ok(());
+        }
+        out.pad_to(self.alignment)?;
+        let start = out.position;
+        let fragment = std::mem::take(&mut self.fragment);
+        let uncompressed = self
+            .superblock
+            .flags()
+            .contains(Flags::FRAGMENTS_STORED_UNCOMPRESSED);
+        let size = write_block(out, self.compressor, &fragment, uncompressed)?;
+        self.fragment_bytes += fragment.len() as u64;
+        self.fragments.write_all(&start.to_le_bytes())?;
+        self.fragments.write_all(&size.to_le_bytes())?;
+        self.fragments.write_all(&0u32.to_le_bytes())?;
+        self.fragment_count += 1;
+        Ok(())
+    }
+}
+
+// Appends a data or fragment block, returning its size word.
+fn write_block<W: Write>(
+    out: &mut Output<W>,
+    compressor: &Compressor,
+    block: &[u8],
+    uncompressed: bool,
+) -> Result<u32> {
+    let mut compressed = vec![];
+    if !uncompressed {
+        compressor.compress(&mut &block[..], &mut compressed)?;
+    }
+    if !uncompressed && compressed.len() < block.len() {
+        out.write_all(&compressed)?;
+        Ok(compressed.len() as u32)
+    } else {
+        out.write_all(block)?;
+        Ok(block.len() as u32 | COMPRESSED_BIT_BLOCK)
+    }
+}
+
+// `compressor` at another level, which blocks decompress the same with.
+fn with_level(compressor: &Compressor, level: u32) -> Result<Compressor> {
+    let mut compressor = compressor.clone();
+    match &mut compressor {
+        Compressor::GZIP(gzip) if (1..=9).contains(&level) => gzip.set_compression_level(level),
+        Compressor::ZSTD(zstd) if (1..=22).contains(&level) => zstd.set_compression_level(level),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} has no compression level {}",
+                    compressor_name(compressor.id()).unwrap_or("the compressor"),
+                    level
+                ),
+            ))
+        }
+    }
+    Ok(compressor)
+}
+
+struct TreeWriter<'a> {
+    nodes: &'a [Node],
+    refs: Vec<Option<InodeRef>>,
+    inodes: MetadataWriter,
+    dirs: DirectoryWriter,
+    // new start of the data blocks at a source start and length
+    data: &'a HashMap<(u64, u64), u64>,
+    // new index of the fragments kept
+    fragments: &'a HashMap<u32, u32>,
+}
+
+impl TreeWriter<'_> {
+    // Writes the inode of node `index`, after those of its children.
+    fn write_node(&mut self, index: usize, parent: u32) -> Result<InodeRef> {
+        if let Some(inode_ref) = self.refs[index] {
+            return Ok(inode_ref);
+        }
+        let node = &self.nodes[index];
+        let number = index as u32 + 1;
+        let inode_ref = if node.inode.is_dir() {
+            let mut listing = Vec::with_capacity(node.children.len());
+            for (name, child) in &node.children {
+                listing.push(NewDirectoryEntry {
+                    name: name.clone(),
+                    inode: self.write_node(*child, number)?,
+                    inode_number: *child as u32 + 1,
+                    file_type: self.nodes[*child].inode.file_type(),
+                });
+            }
+            let subdirs = listing
+                .iter()
+                .filter(|e| e.file_type == FileType::Directory)
+                .count() as u32;
+            let listing = self.dirs.write_dir(listing)?;
+            let inode_ref = self.inodes.position();
+            NewDirectoryInode {
+                mode: node.inode.mode(),
+                uid: node.inode.uid(),
+                gid: node.inode.guid(),
+                mtime: node.inode.mtime(),
+                inode_number: number,
+                nlink: 2 + subdirs,
+                parent_inode: parent,
+                xattr: node.inode.xattr().unwrap_or(INVALID_XATTR),
+            }
+            .write_to(&listing, &mut self.inodes)?;
+            inode_ref
+        } else {
+            let mut inode = node.inode.clone();
+            inode.set_inode_number(number);
+            inode.set_nlink(node.links);
+            match &mut inode {
+                // new files point to their data already
+                _ if node.contents.is_some() => {}
+                InodeHeader::Regular(r) => {
+                    let len = data_len(r.blocks());
+                    let start = self.data[&(r.start_block() as u64, len)];
+                    let start = u32::try_from(start)
+                        .map_err(|_| Error::new(ErrorKind::InvalidInput, "file data past 4GiB"))?;
+                    r.set_start_block(start);
+                    r.set_fragment(self.fragment(r.fragment()));
+                }
+                InodeHeader::LRegular(r) => {
+                    let len = data_len(r.blocks());
+                    r.set_start_block(self.data[&(r.start_block(), len)]);
+                    r.set_fragment(self.fragment(r.fragment()));
+                }
+                _ => {}
+            }
+            let inode_ref = self.inodes.position();
+            inode.write_to(&mut self.inodes)?;
+            inode_ref
+        };
+        self.refs[index] = Some(inode_ref);
+        Ok(inode_ref)
+    }
+
+    fn fragment(&self, fragment: u32) -> u32 {
+        match fragment {
+            INVALID_FRAG => INVALID_FRAG,
+            fragment => self.fragments[&fragment],
+        }
+    }
+}
+
+// Indexes of `nodes` in the order their data is written, files being
+// grouped by the first name they are found by.
+fn write_order(nodes: &[Node], grouping: Grouping) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..nodes.len()).collect();
+    if grouping == Grouping::Extension {
+        let mut names: Vec<Option<&[u8]>> = vec![None; nodes.len()];
+        for node in nodes {
+            for (name, child) in &node.children {
+                names[*child].get_or_insert(name);
+            }
+        }
+        order.sort_by_cached_key(|&index| extension(names[index].unwrap_or_default()));
+    }
+    order
+}
+
+// Copies the blocks of `extent` as stored, a chunk at a time.
+async fn copy_extent<R: ReadSeek, S: Sink>(
+    image: &Image<R>,
+    extent: &Extent,
+    out: &mut Output<S>,
+) -> Result<()> {
+    const CHUNK: u64 = 1 << 20;
+    let mut copied = 0;
+    while copied < extent.len {
+        let len = (extent.len - copied).min(CHUNK);
+        image.copy_raw(extent.start + copied, len, out)?;
+        out.inner.drain().await?;
+        copied += len;
+    }
+    Ok(())
+}
+
+// Extension of a file name, lowercased, empty for names without one.
+pub(crate) fn extension(name: &[u8]) -> Vec<u8> {
+    match name.iter().rposition(|&b| b == b'.') {
+        Some(dot) if dot > 0 => name[dot + 1..].to_ascii_lowercase(),
+        _ => vec![],
+    }
+}
+
+fn data_len(blocks: &[u32]) -> u64 {
+    blocks
+        .iter()
+        .map(|b| (b & !COMPRESSED_BIT_BLOCK) as u64)
+        .sum()
+}
+
+// Copies the xattr key/value store as is, so that the references of the
+// xattr id table stay valid, and writes the id table after it. Returns
+// where the xattr table header is.
+fn write_xattrs<R: ReadSeek, W: Write>(
+    image: &Image<R>,
+    store: Range<u64>,
+    compressor: &Compressor,
+    out: &mut Output<W>,
+) -> Result<u64> {
+    let ids = image.raw_table(TableKind::XattrId)?;
+    let kv_start = out.position;
+    image.copy_raw(store.start, store.end - store.start, out)?;
+    let mut table = MetadataWriter::new(compressor.clone());
+    table.set_uncompressed(
+        image
+            .superblock()
+            .flags()
+            .contains(Flags::XATTRS_STORED_UNCOMPRESSED),
+    );
+    table.write_all(&ids)?;
+    let start = out.position;
+    let (table, blocks) = table.finish_indexed()?;
+    out.write_all(&table)?;
+    let header = out.position;
+    out.write_all(&kv_start.to_le_bytes())?;
+    let count = (ids.len() / XATTR_ID_ENTRY_SIZE) as u32;
+    out.write_all(&count.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    for block in blocks {
+        out.write_all(&(start + block).to_le_bytes())?;
+    }
+    Ok(header)
+}
+
+// Appends a table followed by its index, returning where the index starts.
+fn write_indexed_table<W: Write>(out: &mut Output<W>, table: MetadataWriter) -> Result<u64> {
+    let start = out.position;
+    let (table, blocks) = table.finish_indexed()?;
+    out.write_all(&table)?;
+    let index_start = out.position;
+    for block in blocks {
+        out.write_all(&(start + block).to_le_bytes())?;
+    }
+    Ok(index_start)
+}
+
+// Tracks the offset in the new image, which may not start at the start of
+// the output.
+struct Output<W> {
+    inner: W,
+    position: u64,
+}
+
+impl<W: Write> Output<W> {
+    // Pads with zeros to a multiple of `alignment` bytes, if not 0.
+    fn pad_to(&mut self, alignment: u64) -> Result<()> {
+        if alignment > 0 {
+            let padding = self.position.next_multiple_of(alignment) - self.position;
+            io::copy(&mut io::repeat(0).take(padding), self)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
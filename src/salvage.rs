@@ -0,0 +1,510 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Result, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::path::Path;
+
+use crate::compressors::{Compressor, Decompress};
+#[cfg(unix)]
+use crate::image::Image;
+use crate::inode::{read_directory_listing, read_inode_header, DirectoryEntry, InodeHeader};
+use crate::path::SqshPath;
+use crate::superblock::Superblock;
+#[cfg(unix)]
+use crate::ReadSeek;
+use crate::{MAGIC, METADATA_SIZE, SUPERBLOCK_SIZE};
+
+const COMPRESSED_BIT: u16 = 1 << 15;
+// bytes read at a time while scanning for metadata blocks
+const SCAN_CHUNK_SIZE: usize = 256 * 1024;
+// directory of the inodes whose parent couldn't be recovered
+const LOST_AND_FOUND: &[u8] = b"lost+found";
+// directory table starts tried among those most listings vote for
+const MAX_VOTED_CANDIDATES: usize = 8;
+
+#[derive(Debug)]
+pub struct RecoveredBlock {
+    /// Offset of the block header in the scanned blob.
+    pub start: u64,
+    pub disk_size: u16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct RecoveredInode {
+    /// Start of the metadata block holding the inode header.
+    pub block: u64,
+    /// Offset of the header in the decompressed block.
+    pub offset: u16,
+    pub inode: InodeHeader,
+}
+
+/// Path given to a recovered inode by `rebuild_tree`.
+#[derive(Debug)]
+pub struct RecoveredEntry {
+    /// Path from the image root, or from `/lost+found/#<inode number>` for
+    /// inodes whose parent directory couldn't be recovered, as fsck names
+    /// them.
+    pub path: SqshPath,
+    /// Index of the inode in `Salvage::inodes`.
+    pub inode: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Salvage {
+    pub blocks: Vec<RecoveredBlock>,
+    pub inodes: Vec<RecoveredInode>,
+    /// The recovered inodes by path, parents before their entries.
+    pub entries: Vec<RecoveredEntry>,
+}
+
+// Fails once more than a metadata block worth of data is written, so a
+// bogus stream can't make us allocate without bound.
+struct MetadataSink(Vec<u8>);
+
+impl Write for MetadataSink {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.0.len() + buf.len() > METADATA_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "metadata block too large",
+            ));
+        }
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn looks_compressed(compressor: &Compressor, data: &[u8]) -> bool {
+    match compressor {
+        Compressor::GZIP(_) => {
+            data.len() >= 2
+                && data[0] & 0x0f == 8
+                && u16::from_be_bytes([data[0], data[1]]).is_multiple_of(31)
+        }
+        Compressor::XZ(_) => data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]),
+        Compressor::ZSTD(_) => data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]),
+        Compressor::Undefined => false,
+    }
+}
+
+/// Scans `[start, end)` for compressed metadata blocks without relying on
+/// any table pointer. Uncompressed blocks can't be told apart from other
+/// data and are not reported. The range is read a chunk at a time, so only
+/// the blocks found are kept in memory.
+pub fn scan_metadata_blocks<R: Read + Seek>(
+    reader: &mut R,
+    compressor: &Compressor,
+    start: u64,
+    end: u64,
+) -> Result<Vec<RecoveredBlock>> {
+    reader.seek(SeekFrom::Start(start))?;
+    let mut reader = reader.take(end.saturating_sub(start));
+    // bytes of the range from `blob_start` on, enough to hold a whole block
+    // at `pos` unless the range ends first
+    let mut blob = Vec::with_capacity(SCAN_CHUNK_SIZE + METADATA_SIZE + 2);
+    let mut blob_start = start;
+    let mut eof = false;
+
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    loop {
+        if !eof && blob.len() - pos <= METADATA_SIZE + 2 {
+            blob.drain(..pos);
+            blob_start += pos as u64;
+            pos = 0;
+            let filled = blob.len();
+            blob.resize(filled + SCAN_CHUNK_SIZE, 0);
+            let read = reader.read(&mut blob[filled..])?;
+            blob.truncate(filled + read);
+            eof = read == 0;
+            continue;
+        }
+        if pos + 2 >= blob.len() {
+            break;
+        }
+        let header = u16::from_le_bytes([blob[pos], blob[pos + 1]]);
+        let size = (header & !COMPRESSED_BIT) as usize;
+        let data_end = pos + 2 + size;
+        if header & COMPRESSED_BIT != 0
+            || size == 0
+            || size > METADATA_SIZE
+            || data_end > blob.len()
+            || !looks_compressed(compressor, &blob[pos + 2..])
+        {
+            pos += 1;
+            continue;
+        }
+
+        let mut sink = MetadataSink(Vec::with_capacity(METADATA_SIZE));
+        match compressor.decompress(&mut &blob[pos + 2..data_end], &mut sink) {
+            Ok(_) if !sink.0.is_empty() => {
+                blocks.push(RecoveredBlock {
+                    start: blob_start + pos as u64,
+                    disk_size: size as u16,
+                    data: sink.0,
+                });
+                pos = data_end;
+            }
+            _ => pos += 1,
+        }
+    }
+    Ok(blocks)
+}
+
+fn plausible_inode(bytes: &[u8], superblock: &Superblock) -> bool {
+    if bytes.len() < 16 {
+        return false;
+    }
+    let inode_type = u16::from_le_bytes([bytes[0], bytes[1]]);
+    let mode = u16::from_le_bytes([bytes[2], bytes[3]]);
+    let inode_number = u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]);
+    (1..=14).contains(&inode_type)
+        && mode <= 0o7777
+        && inode_number != 0
+        && inode_number <= superblock.inodes()
+}
+
+/// Parses inode headers out of runs of contiguous recovered blocks. Parsing
+/// of a run stops at the first record that doesn't look like an inode.
+pub fn recover_inodes(blocks: &[RecoveredBlock], superblock: &Superblock) -> Vec<RecoveredInode> {
+    let mut inodes = Vec::new();
+    let mut run_start = 0;
+    while run_start < blocks.len() {
+        let mut run_end = run_start + 1;
+        while run_end < blocks.len()
+            && blocks[run_end - 1].start + blocks[run_end - 1].disk_size as u64 + 2
+                == blocks[run_end].start
+        {
+            run_end += 1;
+        }
+
+        let run = &blocks[run_start..run_end];
+        let data: Vec<u8> = run.iter().flat_map(|b| b.data.iter().copied()).collect();
+        let mut offset = 0;
+        while offset < data.len() && plausible_inode(&data[offset..], superblock) {
+            let mut record = &data[offset..];
+            let inode = match read_inode_header(&mut record, superblock) {
+                Ok(inode) => inode,
+                Err(_) => break,
+            };
+
+            let mut block_offset = offset;
+            let mut block = &run[0];
+            for b in run {
+                block = b;
+                if block_offset < b.data.len() {
+                    break;
+                }
+                block_offset -= b.data.len();
+            }
+            inodes.push(RecoveredInode {
+                block: block.start,
+                offset: block_offset as u16,
+                inode,
+            });
+            offset = data.len() - record.len();
+        }
+        run_start = run_end;
+    }
+    inodes
+}
+
+// Where the listing of a directory is, relative to the directory table, and
+// its size.
+fn listing_location(inode: &InodeHeader) -> Option<(u32, u16, usize)> {
+    let (block, offset, file_size) = match inode {
+        InodeHeader::Directory(d) => (d.start_block(), d.offset(), d.file_size() as u32),
+        InodeHeader::LDirectory(d) => (d.start_block(), d.offset(), d.file_size()),
+        _ => return None,
+    };
+    // the size accounts for the implicit `.` and `..` entries
+    Some((block, offset, file_size.saturating_sub(3) as usize))
+}
+
+// Parses a directory listing from the recovered block starting at `start`
+// and the blocks contiguous to it.
+fn read_listing(
+    blocks: &[RecoveredBlock],
+    by_start: &HashMap<u64, usize>,
+    start: u64,
+    offset: u16,
+    size: usize,
+) -> Option<Vec<DirectoryEntry>> {
+    let mut index = *by_start.get(&start)?;
+    let mut data = blocks[index].data.clone();
+    while data.len() < offset as usize + size {
+        let block = &blocks[index];
+        index = *by_start.get(&(block.start + block.disk_size as u64 + 2))?;
+        data.extend_from_slice(&blocks[index].data);
+    }
+    read_directory_listing(&mut &data[offset as usize..], size as u64).ok()
+}
+
+// Parses the listings of the recovered directories, for a directory table
+// starting at `table_start`, into the names and inodes of their entries.
+// Listings that don't parse, or name inodes of another type than their
+// entries say, are left out, as are entries whose inode wasn't recovered.
+fn read_listings(
+    blocks: &[RecoveredBlock],
+    inodes: &[RecoveredInode],
+    by_start: &HashMap<u64, usize>,
+    by_number: &HashMap<u32, usize>,
+    table_start: u64,
+) -> HashMap<usize, Vec<(Vec<u8>, usize)>> {
+    let mut listings = HashMap::new();
+    for (i, inode) in inodes.iter().enumerate() {
+        let Some((block, offset, size)) = listing_location(&inode.inode) else {
+            continue;
+        };
+        let entries = match size {
+            0 => vec![],
+            _ => match read_listing(blocks, by_start, table_start + block as u64, offset, size) {
+                Some(entries) => entries,
+                None => continue,
+            },
+        };
+        let mut children = vec![];
+        let mut matches = true;
+        for entry in &entries {
+            if let Some(child) = by_number.get(&entry.inode_number()) {
+                matches &= entry.file_type() == Some(inodes[*child].inode.file_type());
+                children.push((entry.name().to_vec(), *child));
+            }
+        }
+        if matches {
+            listings.insert(i, children);
+        }
+    }
+    listings
+}
+
+/// Gives paths to recovered inodes from the directory listings found in the
+/// recovered blocks, which are looked for where the superblock says the
+/// directory table is and, if it is damaged, where the inode table ends.
+/// Inodes that no recovered listing leads to from the root are put in
+/// `/lost+found`, with the entries of the directories among them.
+pub fn rebuild_tree(
+    blocks: &[RecoveredBlock],
+    inodes: &[RecoveredInode],
+    superblock: &Superblock,
+) -> Vec<RecoveredEntry> {
+    let by_start: HashMap<u64, usize> = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, b)| (b.start, i))
+        .collect();
+    let mut by_number: HashMap<u32, usize> = HashMap::new();
+    for (i, inode) in inodes.iter().enumerate() {
+        by_number.entry(inode.inode.inode_number()).or_insert(i);
+    }
+
+    // Where the directory table starts: where the superblock says, right
+    // after a run of inode blocks, as writers place it, or where the most
+    // listings would start a recovered block. The start under which most
+    // listings parse and match the inodes they name is taken.
+    let stored = superblock.directory_table_start() as u64;
+    let mut candidates = vec![stored];
+    let inode_blocks: HashSet<u64> = inodes.iter().map(|i| i.block).collect();
+    for block in blocks.iter().filter(|b| inode_blocks.contains(&b.start)) {
+        let next = block.start + block.disk_size as u64 + 2;
+        if !inode_blocks.contains(&next) {
+            candidates.push(next);
+        }
+    }
+    let dir_blocks: HashSet<u32> = inodes
+        .iter()
+        .filter_map(|i| listing_location(&i.inode))
+        .map(|(block, _, _)| block)
+        .collect();
+    let mut votes: HashMap<u64, usize> = HashMap::new();
+    for block in dir_blocks {
+        for b in blocks.iter().filter(|b| b.start >= block as u64) {
+            *votes.entry(b.start - block as u64).or_default() += 1;
+        }
+    }
+    let mut votes: Vec<(u64, usize)> = votes.into_iter().collect();
+    votes.sort_by_key(|(start, count)| (std::cmp::Reverse(*count), *start));
+    candidates.extend(
+        votes
+            .iter()
+            .take(MAX_VOTED_CANDIDATES)
+            .map(|(start, _)| start),
+    );
+
+    let mut listings: HashMap<usize, Vec<(Vec<u8>, usize)>> = HashMap::new();
+    let mut tried = HashSet::new();
+    for table_start in candidates {
+        if !tried.insert(table_start) {
+            continue;
+        }
+        let found = read_listings(blocks, inodes, &by_start, &by_number, table_start);
+        if found.len() > listings.len() {
+            listings = found;
+        }
+    }
+    let listed: HashSet<usize> = listings
+        .values()
+        .flatten()
+        .map(|(_, child)| *child)
+        .collect();
+
+    let root_ref = superblock.root_inode_ref();
+    let root = inodes.iter().position(|i| {
+        i.inode.is_dir()
+            && i.block == superblock.inode_table_start() as u64 + root_ref.block() as u64
+            && i.offset == root_ref.offset()
+    });
+    let mut entries = vec![];
+    let mut visited = HashSet::new();
+    if let Some(root) = root {
+        add_subtree(
+            SqshPath::root(),
+            root,
+            &listings,
+            &mut visited,
+            &mut entries,
+        );
+    }
+    let lost_and_found = SqshPath::root().join(LOST_AND_FOUND).unwrap();
+    // subtrees whose top no listing names first, then what is left, such
+    // as the entries of directories in loops
+    for pass in [false, true] {
+        for (i, recovered) in inodes.iter().enumerate() {
+            if visited.contains(&i) || (!pass && listed.contains(&i)) {
+                continue;
+            }
+            let name = format!("#{}", recovered.inode.inode_number());
+            let path = lost_and_found.join(name.as_bytes()).unwrap();
+            add_subtree(path, i, &listings, &mut visited, &mut entries);
+        }
+    }
+    entries
+}
+
+fn add_subtree(
+    path: SqshPath,
+    inode: usize,
+    listings: &HashMap<usize, Vec<(Vec<u8>, usize)>>,
+    visited: &mut HashSet<usize>,
+    entries: &mut Vec<RecoveredEntry>,
+) {
+    let mut pending = vec![(path, inode)];
+    while let Some((path, inode)) = pending.pop() {
+        if !visited.insert(inode) {
+            continue;
+        }
+        for (name, child) in listings.get(&inode).into_iter().flatten().rev() {
+            // names garbled beyond use as a path are left to lost+found
+            if let Ok(path) = path.join(name) {
+                pending.push((path, *child));
+            }
+        }
+        entries.push(RecoveredEntry { path, inode });
+    }
+}
+
+/// Writes the directories, regular files and symlinks of `salvage` under
+/// `dest`, by their recovered paths. Ownership, modes and times are not
+/// restored, and other entries are left out. Files whose contents can't
+/// be read, wholly or in part, are written as far as they could be read
+/// and returned with the error.
+#[cfg(unix)]
+pub(crate) fn extract<R: ReadSeek>(
+    image: &Image<R>,
+    salvage: &Salvage,
+    dest: &Path,
+) -> Result<Vec<(SqshPath, io::Error)>> {
+    use std::ffi::OsStr;
+    use std::fs::{self, File};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::symlink;
+
+    let mut failed = vec![];
+    fs::create_dir_all(dest)?;
+    for entry in &salvage.entries {
+        let target = entry
+            .path
+            .components()
+            .fold(dest.to_path_buf(), |target, name| {
+                target.join(OsStr::from_bytes(name))
+            });
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let inode = &salvage.inodes[entry.inode].inode;
+        match inode {
+            InodeHeader::Directory(_) | InodeHeader::LDirectory(_) => fs::create_dir_all(&target)?,
+            InodeHeader::Regular(_) | InodeHeader::LRegular(_) => {
+                let mut file = File::create(&target)?;
+                let copied = image
+                    .open_file(inode)
+                    .and_then(|mut contents| io::copy(&mut contents, &mut file));
+                if let Err(e) = copied {
+                    failed.push((entry.path.clone(), e));
+                }
+            }
+            InodeHeader::Symlink(s) | InodeHeader::LSymlink(s) => {
+                symlink(OsStr::from_bytes(s.target()), &target)?
+            }
+            _ => {}
+        }
+    }
+    Ok(failed)
+}
+
+fn plausible_superblock(sb: &Superblock) -> bool {
+    let bytes_used = sb.bytes_used();
+    sb.version_major() == 4
+        && sb.version_minor() == 0
+        && (1..=6).contains(&sb.compressor())
+        && bytes_used >= SUPERBLOCK_SIZE as u64
+        && sb.inode_table_start() >= SUPERBLOCK_SIZE as i64
+        && sb.inode_table_start() < sb.directory_table_start()
+        && (sb.directory_table_start() as u64) < bytes_used
+        && sb.id_table_start() < bytes_used
+}
+
+/// Finds every plausible squashfs superblock in an arbitrary byte stream,
+/// such as a flash or disk dump, returning each with its offset.
+pub fn scan_for_images<R: Read>(mut reader: R) -> Result<Vec<(u64, Superblock)>> {
+    const CHUNK_SIZE: usize = 64 * 1024;
+    let magic = MAGIC.to_le_bytes();
+
+    let mut found = Vec::new();
+    // offset in the stream of window[0]
+    let mut window_start = 0u64;
+    let mut window = Vec::with_capacity(CHUNK_SIZE + SUPERBLOCK_SIZE);
+    let mut eof = false;
+    while !eof {
+        let filled = window.len();
+        window.resize(filled + CHUNK_SIZE, 0);
+        let read = reader.read(&mut window[filled..])?;
+        window.truncate(filled + read);
+        eof = read == 0;
+
+        // only look at positions with a full superblock behind them, unless
+        // the stream is exhausted
+        let searchable = match eof {
+            true => window.len(),
+            false => window.len().saturating_sub(SUPERBLOCK_SIZE - 1),
+        };
+        let mut pos = 0;
+        while pos < searchable {
+            if window[pos..].starts_with(&magic) && window.len() - pos >= SUPERBLOCK_SIZE {
+                if let Ok(sb) = Superblock::new(&mut &window[pos..pos + SUPERBLOCK_SIZE]) {
+                    if plausible_superblock(&sb) {
+                        found.push((window_start + pos as u64, sb));
+                    }
+                }
+            }
+            pos += 1;
+        }
+        window.drain(..searchable);
+        window_start += searchable as u64;
+    }
+    Ok(found)
+}
@@ -0,0 +1,153 @@
+use std::fmt::{self, Display};
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant};
+
+use crate::compressors::{Compress, Compressor, GzipCompressor, ZSTDCompressor};
+
+/// How `choose_compressor` trades compression ratio for speed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SelectionPolicy {
+    /// Smallest output, however slow.
+    BestRatio,
+    /// Fastest candidate whose output is at most `slack` larger than the
+    /// smallest, like 0.05 for 5%.
+    FastestWithin(f64),
+    /// Smallest output among candidates compressing at least this many
+    /// bytes per second, or the fastest if none does.
+    MinThroughput(u64),
+}
+
+/// Result of compressing the samples with one candidate.
+#[derive(Clone, Debug)]
+pub struct CompressorTrial {
+    pub compressor: Compressor,
+    /// Total size of the samples.
+    pub input: u64,
+    /// Total size once compressed, counting blocks that don't shrink as
+    /// stored uncompressed, like the image would.
+    pub output: u64,
+    pub elapsed: Duration,
+}
+
+impl CompressorTrial {
+    /// Output size over input size.
+    pub fn ratio(&self) -> f64 {
+        self.output as f64 / self.input.max(1) as f64
+    }
+
+    /// Input bytes compressed per second.
+    pub fn throughput(&self) -> f64 {
+        self.input as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+
+impl Display for CompressorTrial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: ratio {:.3}, {:.1} MiB/s",
+            self.compressor,
+            self.ratio(),
+            self.throughput() / (1 << 20) as f64
+        )
+    }
+}
+
+/// Outcome of `choose_compressor`, with every trial to report how the
+/// decision was made.
+#[derive(Clone, Debug)]
+pub struct CompressorChoice {
+    pub policy: SelectionPolicy,
+    /// Index of the chosen candidate in `trials`.
+    pub chosen: usize,
+    pub trials: Vec<CompressorTrial>,
+}
+
+impl CompressorChoice {
+    pub fn compressor(&self) -> &Compressor {
+        &self.trials[self.chosen].compressor
+    }
+}
+
+impl Display for CompressorChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "policy {:?}", self.policy)?;
+        for (i, trial) in self.trials.iter().enumerate() {
+            let mark = if i == self.chosen { '*' } else { ' ' };
+            writeln!(f, "{} {}", mark, trial)?;
+        }
+        Ok(())
+    }
+}
+
+/// Gzip, xz and zstd at a few levels, for `choose_compressor`.
+pub fn default_candidates() -> Vec<Compressor> {
+    let mut candidates = vec![];
+    for level in [1, 6, 9] {
+        let mut gzip = GzipCompressor::default();
+        gzip.set_compression_level(level);
+        candidates.push(Compressor::GZIP(gzip));
+    }
+    candidates.push(Compressor::XZ(Default::default()));
+    for level in [3, 15, 19] {
+        let mut zstd = ZSTDCompressor::default();
+        zstd.set_compression_level(level);
+        candidates.push(Compressor::ZSTD(zstd));
+    }
+    candidates
+}
+
+/// Compresses `samples`, blocks representative of the data to store, with
+/// each candidate and picks one according to `policy`.
+pub fn choose_compressor(
+    samples: &[&[u8]],
+    candidates: &[Compressor],
+    policy: SelectionPolicy,
+) -> Result<CompressorChoice> {
+    if candidates.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "no candidate compressors",
+        ));
+    }
+    let mut trials = Vec::with_capacity(candidates.len());
+    for compressor in candidates {
+        let mut trial = CompressorTrial {
+            compressor: compressor.clone(),
+            input: 0,
+            output: 0,
+            elapsed: Duration::ZERO,
+        };
+        let mut compressed = vec![];
+        for sample in samples {
+            compressed.clear();
+            let start = Instant::now();
+            let size = compressor.compress(&mut &sample[..], &mut compressed)?;
+            trial.elapsed += start.elapsed();
+            trial.input += sample.len() as u64;
+            trial.output += size.min(sample.len() as u64);
+        }
+        trials.push(trial);
+    }
+
+    let smallest = trials.iter().map(|t| t.output).min().unwrap_or(0);
+    let by_output = |i: &usize| (trials[*i].output, trials[*i].elapsed);
+    let by_time = |i: &usize| (trials[*i].elapsed, trials[*i].output);
+    let indices = 0..trials.len();
+    let chosen = match policy {
+        SelectionPolicy::BestRatio => indices.min_by_key(by_output),
+        SelectionPolicy::FastestWithin(slack) => indices
+            .filter(|i| trials[*i].output as f64 <= smallest as f64 * (1.0 + slack))
+            .min_by_key(by_time),
+        SelectionPolicy::MinThroughput(min) => indices
+            .clone()
+            .filter(|i| trials[*i].throughput() >= min as f64)
+            .min_by_key(by_output)
+            .or_else(|| indices.min_by_key(by_time)),
+    };
+    Ok(CompressorChoice {
+        policy,
+        chosen: chosen.unwrap_or(0),
+        trials,
+    })
+}
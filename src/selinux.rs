@@ -0,0 +1,144 @@
+use std::io::{BufRead, Error, ErrorKind, Result};
+
+use regex::bytes::Regex;
+
+use crate::image::Image;
+use crate::inode::{FileType, InodeHeader};
+use crate::ReadSeek;
+
+pub const SELINUX_XATTR: &[u8] = b"security.selinux";
+
+/// File type as spelled in file_contexts: `-` regular file, `d` directory,
+/// `l` symlink, `c` and `b` devices, `p` fifo and `s` socket.
+pub fn file_type(inode: &InodeHeader) -> char {
+    match inode.file_type() {
+        FileType::Directory => 'd',
+        FileType::Regular => '-',
+        FileType::Symlink => 'l',
+        FileType::BlockDevice => 'b',
+        FileType::CharDevice => 'c',
+        FileType::Fifo => 'p',
+        FileType::Socket => 's',
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Label {
+    pub path: Vec<u8>,
+    pub file_type: char,
+    /// Context with the trailing NUL stripped, if the inode has one.
+    pub context: Option<Vec<u8>>,
+}
+
+/// Lists the SELinux label of every path in the image.
+pub fn labels<R: ReadSeek>(image: &Image<R>) -> Result<Vec<Label>> {
+    let mut labels = vec![];
+    for entry in image.walk("/")? {
+        let entry = entry?;
+        let context = image
+            .xattrs(&entry.inode)?
+            .into_iter()
+            .find(|x| x.name == SELINUX_XATTR)
+            .map(|x| match x.value.strip_suffix(b"\0") {
+                Some(value) => value.to_vec(),
+                None => x.value,
+            });
+        labels.push(Label {
+            path: entry.path.into(),
+            file_type: file_type(&entry.inode),
+            context,
+        });
+    }
+    Ok(labels)
+}
+
+struct Spec {
+    regex: Regex,
+    // matches a single path only
+    exact: bool,
+    file_type: Option<char>,
+    // None for <<none>>
+    context: Option<Vec<u8>>,
+}
+
+/// A parsed file_contexts specification.
+pub struct FileContexts(Vec<Spec>);
+
+impl FileContexts {
+    pub fn parse<R: BufRead>(reader: R) -> Result<Self> {
+        let mut specs = vec![];
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (pattern, file_type, context) = match fields[..] {
+                [pattern, context] => (pattern, None, context),
+                [pattern, file_type, context] => {
+                    let file_type = match file_type {
+                        "--" => '-',
+                        "-d" => 'd',
+                        "-l" => 'l',
+                        "-c" => 'c',
+                        "-b" => 'b',
+                        "-p" => 'p',
+                        "-s" => 's',
+                        _ => return Err(bad_spec(line)),
+                    };
+                    (pattern, Some(file_type), context)
+                }
+                _ => return Err(bad_spec(line)),
+            };
+            let regex = Regex::new(&format!("^(?:{})$", pattern)).map_err(|_| bad_spec(line))?;
+            specs.push(Spec {
+                regex,
+                exact: !pattern.contains(|c: char| ".^$?*+|[({\\".contains(c)),
+                file_type,
+                context: match context {
+                    "<<none>>" => None,
+                    context => Some(context.as_bytes().to_vec()),
+                },
+            });
+        }
+        Ok(Self(specs))
+    }
+
+    /// Expected context of a path. Like libselinux, exact paths win over
+    /// regular expressions and otherwise the last matching entry wins.
+    /// Returns None if nothing matches or the entry is `<<none>>`.
+    pub fn lookup(&self, path: &[u8], file_type: char) -> Option<&[u8]> {
+        let matches = |s: &&Spec| {
+            s.file_type.map(|t| t == file_type).unwrap_or(true) && s.regex.is_match(path)
+        };
+        let spec = self
+            .0
+            .iter()
+            .rev()
+            .filter(|s| s.exact)
+            .find(matches)
+            .or_else(|| self.0.iter().rev().filter(|s| !s.exact).find(matches))?;
+        spec.context.as_deref()
+    }
+
+    /// Compares image labels against the specification and returns the
+    /// labels that differ from what it expects. Paths it has no context for
+    /// are not checked.
+    pub fn mismatches<'a>(&self, labels: &'a [Label]) -> Vec<&'a Label> {
+        labels
+            .iter()
+            .filter(|l| match self.lookup(&l.path, l.file_type) {
+                Some(expected) => l.context.as_deref() != Some(expected),
+                None => false,
+            })
+            .collect()
+    }
+}
+
+fn bad_spec(line: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("bad file_contexts line: {}", line),
+    )
+}
@@ -0,0 +1,54 @@
+use std::fs::File;
+use std::io::Result;
+use std::path::PathBuf;
+#[cfg(feature = "positioned-io")]
+use std::sync::Arc;
+
+use crate::image::Image;
+#[cfg(feature = "positioned-io")]
+use crate::positioned::ReadAtReader;
+use crate::ReadSeek;
+
+/// Opens any number of `Image`s over the same underlying file, each with a
+/// reader of its own. An `Image` reads through a single cursor and can't be
+/// shared between threads; a source can, so every thread opens its own
+/// image instead of serializing on one reader.
+///
+/// Readers must not share their position: `File::try_clone` returns a
+/// handle with the same cursor, so reopen the file instead.
+pub struct ImageSource<R: ReadSeek> {
+    open: Box<dyn Fn() -> Result<R> + Send + Sync>,
+}
+
+impl ImageSource<File> {
+    pub fn from_path<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        Self::new(move || File::open(&path))
+    }
+}
+
+#[cfg(feature = "positioned-io")]
+impl<I> ImageSource<ReadAtReader<I>>
+where
+    I: positioned_io::ReadAt + positioned_io::Size + Send + Sync + 'static,
+{
+    /// Creates a source whose images all read from `inner`, each keeping
+    /// its own position.
+    pub fn from_read_at(inner: I) -> Self {
+        let inner = Arc::new(inner);
+        Self::new(move || Ok(ReadAtReader::new(inner.clone())))
+    }
+}
+
+impl<R: ReadSeek> ImageSource<R> {
+    /// Creates a source calling `open` for the reader of each image.
+    pub fn new<F: Fn() -> Result<R> + Send + Sync + 'static>(open: F) -> Self {
+        Self {
+            open: Box::new(open),
+        }
+    }
+
+    pub fn open(&self) -> Result<Image<R>> {
+        Image::new((self.open)()?)
+    }
+}
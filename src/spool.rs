@@ -0,0 +1,101 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{copy, Cursor, Read, Result, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Seekable copy of a stream, kept in memory up to a limit and in a
+/// temporary file past it, for `Image::from_stream`. The file is removed
+/// when the spool is dropped.
+#[derive(Debug)]
+pub struct Spooled {
+    inner: Inner,
+}
+
+#[derive(Debug)]
+enum Inner {
+    Memory(Cursor<Vec<u8>>),
+    // the path is kept where open files can't be removed
+    File(File, Option<PathBuf>),
+}
+
+impl Spooled {
+    /// Reads `stream` to its end, keeping it in memory if it is at most
+    /// `memory_limit` bytes long.
+    pub fn new<S: Read>(mut stream: S, memory_limit: u64) -> Result<Self> {
+        let mut buf = vec![];
+        stream
+            .by_ref()
+            .take(memory_limit.saturating_add(1))
+            .read_to_end(&mut buf)?;
+        if buf.len() as u64 <= memory_limit {
+            return Ok(Self {
+                inner: Inner::Memory(Cursor::new(buf)),
+            });
+        }
+
+        let (mut file, path) = temp_file()?;
+        file.write_all(&buf)?;
+        drop(buf);
+        copy(&mut stream, &mut file)?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            inner: Inner::File(file, path),
+        })
+    }
+
+    /// Whether the stream was longer than the memory limit.
+    pub fn is_file(&self) -> bool {
+        matches!(self.inner, Inner::File(..))
+    }
+}
+
+static SPOOL_COUNT: AtomicU32 = AtomicU32::new(0);
+
+fn temp_file() -> Result<(File, Option<PathBuf>)> {
+    let path = std::env::temp_dir().join(format!(
+        "squashfs-spool-{}-{}",
+        std::process::id(),
+        SPOOL_COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    if cfg!(unix) {
+        // unlinked right away, so nothing is left behind even on a crash
+        fs::remove_file(&path)?;
+        Ok((file, None))
+    } else {
+        Ok((file, Some(path)))
+    }
+}
+
+impl Drop for Spooled {
+    fn drop(&mut self) {
+        let inner = mem::replace(&mut self.inner, Inner::Memory(Cursor::default()));
+        if let Inner::File(file, Some(path)) = inner {
+            drop(file);
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Read for Spooled {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match &mut self.inner {
+            Inner::Memory(cursor) => cursor.read(buf),
+            Inner::File(file, _) => file.read(buf),
+        }
+    }
+}
+
+impl Seek for Spooled {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        match &mut self.inner {
+            Inner::Memory(cursor) => cursor.seek(pos),
+            Inner::File(file, _) => file.seek(pos),
+        }
+    }
+}
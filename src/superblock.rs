@@ -1,13 +1,49 @@
 use bitflags::bitflags;
 
-use crate::utils::get_set_field;
-use crate::{INVALID_BLK, MAGIC, SUPERBLOCK_SIZE};
-use std::fmt::{Debug, Display};
-use std::io::{Error, ErrorKind, Read, Result};
-use std::{mem, slice};
+use crate::inode::{FileType, InodeRef};
+use crate::utils::{get_set_field, split_array};
+use crate::{inode_ref, INVALID_BLK, MAGIC, SUPERBLOCK_SIZE};
+use std::fmt::{self, Debug, Display};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+
+/// Block sizes mksquashfs and the kernel accept.
+pub const MIN_BLOCK_SIZE: u32 = 4096;
+pub const MAX_BLOCK_SIZE: u32 = 1 << 20;
+
+/// Why a superblock was rejected, carried by the `InvalidData` errors of
+/// `Superblock::new` and `Superblock::validate`, and of reading the root
+/// inode it points to. Reach it with
+/// `error.get_ref().and_then(|e| e.downcast_ref::<SuperblockError>())`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SuperblockError {
+    /// Not a power of two between `MIN_BLOCK_SIZE` and `MAX_BLOCK_SIZE`, or
+    /// not `1 << block_log`.
+    BlockSize { block_size: u32, block_log: u16 },
+    /// The root inode is of this type instead of a directory.
+    RootNotDirectory(FileType),
+}
+
+impl Display for SuperblockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SuperblockError::BlockSize {
+                block_size,
+                block_log,
+            } => write!(
+                f,
+                "invalid block size {} with block log {}",
+                block_size, block_log
+            ),
+            SuperblockError::RootNotDirectory(file_type) => {
+                write!(f, "root inode is a {:?}, not a directory", file_type)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SuperblockError {}
 
 #[derive(Clone, Copy, Debug)]
-#[repr(C)]
 pub struct Superblock {
     magic: [u8; 4],
     inodes: [u8; 4],
@@ -34,34 +70,20 @@ pub struct Superblock {
     export_table_start: [u8; 8],
 }
 
+// fields are byte arrays, so the struct is laid out as on disk
+const _: () = assert!(std::mem::size_of::<Superblock>() == SUPERBLOCK_SIZE);
+
 impl Superblock {
     // TODO: check Result
     pub fn new<R: Read>(reader: &mut R) -> Result<Self> {
-        let mut sb: Self = unsafe { mem::zeroed() };
-        unsafe {
-            let sb_slice = slice::from_raw_parts_mut(&mut sb as *mut _ as *mut u8, SUPERBLOCK_SIZE);
-            reader.read_exact(sb_slice)?;
-        }
+        let mut bytes = [0; SUPERBLOCK_SIZE];
+        reader.read_exact(&mut bytes)?;
+        let sb = Self::from_bytes(&bytes);
 
         if sb.magic() != MAGIC {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("invalid magic {}", sb.magic()),
-            ));
-        }
-        if sb.block_size().ilog2() != sb.block_log().into() {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("invalid block size {}", sb.block_size()),
-            ));
-        }
-        if sb.xattr_id_table_start() != INVALID_BLK {
-            return Err(Error::new(
-                ErrorKind::Other,
-                r##"Xattrs in filesystem! These are not 
-                supported on this build of Mksquashfs\n"##,
-            ));
+            return Err(Error::other(format!("invalid magic {}", sb.magic())));
         }
+        sb.check_block_size()?;
         Ok(sb)
     }
 
@@ -84,6 +106,324 @@ impl Superblock {
     get_set_field!(directory_table_start, set_directory_table_start, i64);
     get_set_field!(fragment_table_start, set_fragment_table_start, u64);
     get_set_field!(export_table_start, set_export_table_start, i64);
+
+    /// Starts a superblock for a new image, see `SuperblockBuilder`.
+    pub fn builder() -> SuperblockBuilder {
+        SuperblockBuilder::new()
+    }
+
+    /// All flag bits, including those unknown to `Flags`, which `flags`
+    /// leaves out.
+    pub fn raw_flags(&self) -> u16 {
+        u16::from_le_bytes(self.flags)
+    }
+
+    fn check_block_size(&self) -> Result<()> {
+        let (block_size, block_log) = (self.block_size(), self.block_log());
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+            || !block_size.is_power_of_two()
+            || block_size.ilog2() != block_log as u32
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                SuperblockError::BlockSize {
+                    block_size,
+                    block_log,
+                },
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn root_inode_ref(&self) -> InodeRef {
+        InodeRef::from(self.root_inode() as u64)
+    }
+
+    /// Splits the on-disk layout into fields, without any checks.
+    pub fn from_bytes(bytes: &[u8; SUPERBLOCK_SIZE]) -> Self {
+        let mut rest = &bytes[..];
+        Self {
+            magic: split_array(&mut rest),
+            inodes: split_array(&mut rest),
+            mkfs_time: split_array(&mut rest),
+            block_size: split_array(&mut rest),
+            fragments: split_array(&mut rest),
+            compressor: split_array(&mut rest),
+            block_log: split_array(&mut rest),
+            flags: split_array(&mut rest),
+            no_ids: split_array(&mut rest),
+            version_major: split_array(&mut rest),
+            version_minor: split_array(&mut rest),
+            root_inode: split_array(&mut rest),
+            bytes_used: split_array(&mut rest),
+            id_table_start: split_array(&mut rest),
+            xattr_id_table_start: split_array(&mut rest),
+            inode_table_start: split_array(&mut rest),
+            directory_table_start: split_array(&mut rest),
+            fragment_table_start: split_array(&mut rest),
+            export_table_start: split_array(&mut rest),
+        }
+    }
+
+    /// The on-disk layout, as read by `new`.
+    pub fn to_bytes(&self) -> [u8; SUPERBLOCK_SIZE] {
+        let fields: [&[u8]; 19] = [
+            &self.magic,
+            &self.inodes,
+            &self.mkfs_time,
+            &self.block_size,
+            &self.fragments,
+            &self.compressor,
+            &self.block_log,
+            &self.flags,
+            &self.no_ids,
+            &self.version_major,
+            &self.version_minor,
+            &self.root_inode,
+            &self.bytes_used,
+            &self.id_table_start,
+            &self.xattr_id_table_start,
+            &self.inode_table_start,
+            &self.directory_table_start,
+            &self.fragment_table_start,
+            &self.export_table_start,
+        ];
+        let mut bytes = [0; SUPERBLOCK_SIZE];
+        let mut offset = 0;
+        for field in fields {
+            bytes[offset..offset + field.len()].copy_from_slice(field);
+            offset += field.len();
+        }
+        bytes
+    }
+
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> Result<u64> {
+        writer.write_all(&self.to_bytes())?;
+        Ok(SUPERBLOCK_SIZE as u64)
+    }
+
+    /// Checks the fields are consistent with each other and with an image
+    /// of `image_len` bytes. Used before writing a patched superblock.
+    pub fn validate(&self, image_len: u64) -> Result<()> {
+        let invalid = |msg: String| Err(Error::new(ErrorKind::InvalidData, msg));
+        if self.magic() != MAGIC {
+            return invalid(format!("invalid magic {}", self.magic()));
+        }
+        self.check_block_size()?;
+        if self.version_major() != 4 {
+            return invalid(format!("unsupported version {}", self.version_major()));
+        }
+        let unknown = self.raw_flags() & !Flags::all().bits();
+        if unknown != 0 {
+            return invalid(format!("unknown flags {:#x}", unknown));
+        }
+        if self.bytes_used() < SUPERBLOCK_SIZE as u64 || self.bytes_used() > image_len {
+            return invalid(format!(
+                "bytes_used {} outside of the {} byte image",
+                self.bytes_used(),
+                image_len
+            ));
+        }
+        let tables = [
+            ("id table", self.id_table_start() as i64),
+            ("xattr id table", self.xattr_id_table_start()),
+            ("inode table", self.inode_table_start()),
+            ("directory table", self.directory_table_start()),
+            ("fragment table", self.fragment_table_start() as i64),
+            ("export table", self.export_table_start()),
+        ];
+        for (name, start) in tables {
+            if start != INVALID_BLK
+                && !(SUPERBLOCK_SIZE as i64..self.bytes_used() as i64).contains(&start)
+            {
+                return invalid(format!("{} start {} out of range", name, start));
+            }
+        }
+        if self.root_inode_ref().offset() >= inode_ref::MAX_OFFSET {
+            return invalid(format!(
+                "root inode {} out of its block",
+                self.root_inode_ref()
+            ));
+        }
+        if self.flags().contains(Flags::NFSEXPORT_TABLE_EXISTS)
+            && self.export_table_start() == INVALID_BLK
+        {
+            return invalid("export flag set without an export table".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Superblock for an image being written, starting from a valid version
+/// 4.0 one: gzip with 128 KiB blocks, the optional xattr id, fragment and
+/// export tables marked absent, and `NO_XATTRS_IN_ARCHIVE` set. The writer
+/// fills in the table starts as it lays them out, and `build` checks the
+/// result as `Superblock::validate` does against `bytes_used`.
+#[derive(Clone, Copy, Debug)]
+pub struct SuperblockBuilder {
+    sb: Superblock,
+}
+
+impl SuperblockBuilder {
+    pub fn new() -> Self {
+        let mut sb = Superblock::from_bytes(&[0; SUPERBLOCK_SIZE]);
+        sb.set_magic(MAGIC);
+        sb.set_compressor(1);
+        sb.set_flags(Flags::NO_XATTRS_IN_ARCHIVE);
+        sb.set_version_major(4);
+        sb.set_version_minor(0);
+        sb.set_xattr_id_table_start(INVALID_BLK);
+        sb.set_fragment_table_start(INVALID_BLK as u64);
+        sb.set_export_table_start(INVALID_BLK);
+        let mut builder = Self { sb };
+        builder.block_size(128 * 1024);
+        builder
+    }
+
+    /// Sets the block size and its log.
+    pub fn block_size(&mut self, block_size: u32) -> &mut Self {
+        self.sb.set_block_size(block_size);
+        self.sb
+            .set_block_log(block_size.checked_ilog2().unwrap_or(0) as u16);
+        self
+    }
+
+    pub fn compressor(&mut self, id: u16) -> &mut Self {
+        self.sb.set_compressor(id);
+        self
+    }
+
+    /// Replaces all flags, including those set along with the export and
+    /// xattr id tables.
+    pub fn flags(&mut self, flags: Flags) -> &mut Self {
+        self.sb.set_flags(flags);
+        self
+    }
+
+    pub fn mkfs_time(&mut self, mkfs_time: u32) -> &mut Self {
+        self.sb.set_mkfs_time(mkfs_time);
+        self
+    }
+
+    pub fn inodes(&mut self, inodes: u32) -> &mut Self {
+        self.sb.set_inodes(inodes);
+        self
+    }
+
+    pub fn fragments(&mut self, fragments: u32) -> &mut Self {
+        self.sb.set_fragments(fragments);
+        self
+    }
+
+    pub fn ids(&mut self, ids: u16) -> &mut Self {
+        self.sb.set_no_ids(ids);
+        self
+    }
+
+    pub fn root_inode(&mut self, root: InodeRef) -> &mut Self {
+        self.sb.set_root_inode(u64::from(root) as i64);
+        self
+    }
+
+    pub fn bytes_used(&mut self, bytes_used: u64) -> &mut Self {
+        self.sb.set_bytes_used(bytes_used);
+        self
+    }
+
+    pub fn inode_table_start(&mut self, start: u64) -> &mut Self {
+        self.sb.set_inode_table_start(start as i64);
+        self
+    }
+
+    pub fn directory_table_start(&mut self, start: u64) -> &mut Self {
+        self.sb.set_directory_table_start(start as i64);
+        self
+    }
+
+    pub fn id_table_start(&mut self, start: u64) -> &mut Self {
+        self.sb.set_id_table_start(start);
+        self
+    }
+
+    pub fn fragment_table_start(&mut self, start: u64) -> &mut Self {
+        self.sb.set_fragment_table_start(start);
+        self
+    }
+
+    /// Sets where the index of the export table starts, setting
+    /// `NFSEXPORT_TABLE_EXISTS`.
+    pub fn export_table_start(&mut self, start: u64) -> &mut Self {
+        self.sb.set_export_table_start(start as i64);
+        self.sb
+            .set_flags(self.sb.flags() | Flags::NFSEXPORT_TABLE_EXISTS);
+        self
+    }
+
+    /// Sets where the xattr id table starts, clearing
+    /// `NO_XATTRS_IN_ARCHIVE`.
+    pub fn xattr_id_table_start(&mut self, start: u64) -> &mut Self {
+        self.sb.set_xattr_id_table_start(start as i64);
+        self.sb
+            .set_flags(self.sb.flags() - Flags::NO_XATTRS_IN_ARCHIVE);
+        self
+    }
+
+    /// Checks the superblock is one an image can have. The inode,
+    /// directory and id tables are required, and the inode table must come
+    /// first: it ends where the directory table starts.
+    pub fn build(&self) -> Result<Superblock> {
+        let sb = self.sb;
+        let required = [
+            ("inode table", sb.inode_table_start()),
+            ("directory table", sb.directory_table_start()),
+            ("id table", sb.id_table_start() as i64),
+        ];
+        for (name, start) in required {
+            if start < SUPERBLOCK_SIZE as i64 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} start not set", name),
+                ));
+            }
+        }
+        if sb.inode_table_start() >= sb.directory_table_start() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the inode table must come before the directory table",
+            ));
+        }
+        sb.validate(sb.bytes_used())?;
+        Ok(sb)
+    }
+}
+
+impl Default for SuperblockBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rewrites the superblock of an image in place. `patch` edits the current
+/// superblock, which is validated against the image before being written;
+/// the image is left untouched if validation fails.
+///
+/// Tables aren't moved or rewritten: clearing `NFSEXPORT_TABLE_EXISTS`, for
+/// example, should come with `set_export_table_start(INVALID_BLK)`, leaving
+/// the table as unused bytes.
+pub fn patch_superblock<F, P>(image: &mut F, patch: P) -> Result<Superblock>
+where
+    F: Read + Write + Seek,
+    P: FnOnce(&mut Superblock),
+{
+    let image_len = image.seek(SeekFrom::End(0))?;
+    image.seek(SeekFrom::Start(0))?;
+    let mut sb = Superblock::new(image)?;
+    patch(&mut sb);
+    sb.validate(image_len)?;
+    image.seek(SeekFrom::Start(0))?;
+    sb.write_to(image)?;
+    image.flush()?;
+    Ok(sb)
 }
 
 bitflags! {
@@ -104,14 +444,75 @@ bitflags! {
     }
 }
 
+const FLAG_DESCRIPTIONS: [(Flags, &str); 12] = [
+    (
+        Flags::INODES_STORED_UNCOMPRESSED,
+        "inodes uncompressed: the inode and directory tables are stored as is",
+    ),
+    (
+        Flags::DATA_BLOCKS_STORED_UNCOMPRESSED,
+        "data uncompressed: file data blocks are stored as is",
+    ),
+    (
+        Flags::UNUSED,
+        "check: left over from squashfs 3, ignored by readers",
+    ),
+    (
+        Flags::FRAGMENTS_STORED_UNCOMPRESSED,
+        "fragments uncompressed: fragment blocks are stored as is",
+    ),
+    (
+        Flags::FRAGMENTS_ARE_NOT_USED,
+        "fragments not used: files always occupy whole blocks, tails included",
+    ),
+    (
+        Flags::FRAGMENTS_ALWAYS_GENERATED,
+        "fragments always generated: tails of files larger than a block are packed into fragments too",
+    ),
+    (
+        Flags::DATA_DEDUPLICATED,
+        "duplicates removed: files with identical data share their blocks",
+    ),
+    (
+        Flags::NFSEXPORT_TABLE_EXISTS,
+        "exportable: an export table maps inode numbers to inodes, so the image can be exported over NFS",
+    ),
+    (
+        Flags::XATTRS_STORED_UNCOMPRESSED,
+        "xattrs uncompressed: the xattr tables are stored as is",
+    ),
+    (
+        Flags::NO_XATTRS_IN_ARCHIVE,
+        "no xattrs: files carry no extended attributes",
+    ),
+    (
+        Flags::COMPRESSOR_OPTIONS_PRESENT,
+        "compressor options: non-default compressor settings follow the superblock",
+    ),
+    (
+        Flags::IDTABLE_UNCOMPRESSED,
+        "ids uncompressed: the uid/gid table is stored as is",
+    ),
+];
+
 impl Flags {
     pub fn from_le_bytes(bytes: [u8; 2]) -> Self {
-        unsafe { Self::from_bits_unchecked(u16::from_le_bytes(bytes)) }
+        Self::from_bits_truncate(u16::from_le_bytes(bytes))
     }
 
     pub fn to_le_bytes(self) -> [u8; 2] {
         self.bits.to_le_bytes()
     }
+
+    /// Explains each set flag and what it means for the image, in bit
+    /// order, for reports.
+    pub fn describe(&self) -> Vec<&'static str> {
+        FLAG_DESCRIPTIONS
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, description)| *description)
+            .collect()
+    }
 }
 
 impl Display for Flags {
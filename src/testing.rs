@@ -0,0 +1,579 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result, Write};
+
+use crate::compressors::{Compress, Compressor, GzipCompressor};
+use crate::idmap::IdMap;
+use crate::inode::{FileType, InodeRef};
+use crate::superblock::{Flags, Superblock};
+use crate::write::{DirectoryWriter, MetadataWriter, NewDirectoryEntry};
+use crate::xattr::{split_name, XATTR_VALUE_OOL};
+use crate::{
+    COMPRESSED_BIT_BLOCK, INVALID_FRAG, INVALID_XATTR, METADATA_SIZE, PADDING_SIZE, SUPERBLOCK_SIZE,
+};
+
+// full names and values
+type XattrList = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Node of the tree `generate` builds an image from. Owners and mtimes
+/// default to 0, and modes to 0755 for directories, 0777 for symlinks and
+/// 0644 otherwise.
+#[derive(Clone, Debug)]
+pub struct Spec {
+    kind: Kind,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+    xattrs: XattrList,
+}
+
+#[derive(Clone, Debug)]
+enum Kind {
+    Dir(Vec<(Vec<u8>, Spec)>),
+    File(Vec<u8>),
+    Symlink(Vec<u8>),
+    BlockDevice(u32),
+    CharDevice(u32),
+    Fifo,
+    Socket,
+}
+
+impl Spec {
+    fn new(kind: Kind, mode: u16) -> Self {
+        Self {
+            kind,
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+            xattrs: vec![],
+        }
+    }
+
+    pub fn dir<N: Into<Vec<u8>>>(entries: impl IntoIterator<Item = (N, Spec)>) -> Self {
+        let entries = entries
+            .into_iter()
+            .map(|(name, spec)| (name.into(), spec))
+            .collect();
+        Self::new(Kind::Dir(entries), 0o755)
+    }
+
+    pub fn file<D: Into<Vec<u8>>>(data: D) -> Self {
+        Self::new(Kind::File(data.into()), 0o644)
+    }
+
+    pub fn symlink<T: Into<Vec<u8>>>(target: T) -> Self {
+        Self::new(Kind::Symlink(target.into()), 0o777)
+    }
+
+    /// Block device whose number is `rdev`, encoded as stored in inodes.
+    pub fn block_device(rdev: u32) -> Self {
+        Self::new(Kind::BlockDevice(rdev), 0o644)
+    }
+
+    pub fn char_device(rdev: u32) -> Self {
+        Self::new(Kind::CharDevice(rdev), 0o644)
+    }
+
+    pub fn fifo() -> Self {
+        Self::new(Kind::Fifo, 0o644)
+    }
+
+    pub fn socket() -> Self {
+        Self::new(Kind::Socket, 0o644)
+    }
+
+    /// Sets the permission bits, including setuid, setgid and sticky.
+    pub fn with_mode(mut self, mode: u16) -> Self {
+        self.mode = mode & 0o7777;
+        self
+    }
+
+    /// Sets the owner, as host ids mapped by the `GenerateOptions` id maps.
+    pub fn with_owner(mut self, uid: u32, gid: u32) -> Self {
+        self.uid = uid;
+        self.gid = gid;
+        self
+    }
+
+    pub fn with_mtime(mut self, mtime: u32) -> Self {
+        self.mtime = mtime;
+        self
+    }
+
+    /// Adds an xattr by its full name, which must be in the user, trusted
+    /// or security namespace. Values longer than 128 bytes are stored out
+    /// of line and shared between inodes, as mksquashfs does.
+    pub fn with_xattr<N: Into<Vec<u8>>, V: Into<Vec<u8>>>(mut self, name: N, value: V) -> Self {
+        self.xattrs.push((name.into(), value.into()));
+        self
+    }
+
+    fn file_type(&self) -> FileType {
+        match self.kind {
+            Kind::Dir(_) => FileType::Directory,
+            Kind::File(_) => FileType::Regular,
+            Kind::Symlink(_) => FileType::Symlink,
+            Kind::BlockDevice(_) => FileType::BlockDevice,
+            Kind::CharDevice(_) => FileType::CharDevice,
+            Kind::Fifo => FileType::Fifo,
+            Kind::Socket => FileType::Socket,
+        }
+    }
+}
+
+/// Controls the images `generate` builds.
+#[derive(Clone, Debug)]
+pub struct GenerateOptions {
+    pub compressor: Compressor,
+    pub block_size: u32,
+    /// Packs file tails into fragment blocks rather than storing them as
+    /// short last blocks.
+    pub fragments: bool,
+    pub mkfs_time: u32,
+    /// Maps owners to image ids with `IdMap::unmap`. Ids are kept as is
+    /// when unset.
+    pub uid_map: Option<IdMap>,
+    pub gid_map: Option<IdMap>,
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        Self {
+            compressor: Compressor::GZIP(GzipCompressor::default()),
+            block_size: 128 * 1024,
+            fragments: true,
+            mkfs_time: 0,
+            uid_map: None,
+            gid_map: None,
+        }
+    }
+}
+
+/// Builds an image of the tree under the directory `root`, for tests that
+/// need fixtures without running mksquashfs. Images have no export table,
+/// and all-zero blocks are stored as sparse. Inodes with xattrs are stored
+/// with their extended type.
+pub fn generate(root: &Spec, options: &GenerateOptions) -> Result<Vec<u8>> {
+    if !matches!(root.kind, Kind::Dir(_)) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the root must be a directory",
+        ));
+    }
+    if !options.block_size.is_power_of_two() || !(4096..=1 << 20).contains(&options.block_size) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid block size {}", options.block_size),
+        ));
+    }
+    // images have no compressor options, so the kernel decodes xz with a
+    // dictionary of the block size, or of a metadata block if larger
+    let mut options = options.clone();
+    if let Compressor::XZ(xz) = &mut options.compressor {
+        let limit = options.block_size.max(METADATA_SIZE as u32);
+        xz.set_dictionary_size(xz.dictionary_size().min(limit));
+    }
+    let options = &options;
+    let mut generator = Generator {
+        options,
+        image: vec![0; SUPERBLOCK_SIZE],
+        inodes: MetadataWriter::new(options.compressor.clone()),
+        dirs: DirectoryWriter::new(options.compressor.clone()),
+        fragment: vec![],
+        fragments: MetadataWriter::new(options.compressor.clone()),
+        fragment_count: 0,
+        ids: vec![],
+        xattrs: MetadataWriter::new(options.compressor.clone()),
+        xattr_ids: MetadataWriter::new(options.compressor.clone()),
+        xattr_lists: HashMap::new(),
+        xattr_values: HashMap::new(),
+        // the root is inode 1
+        next_inode: 2,
+    };
+    // the root's parent is past the last inode, as mksquashfs does
+    let root_ref = generator.write_node(root, 1, count_inodes(root) + 1)?;
+    generator.flush_fragment()?;
+    generator.finish(root_ref)
+}
+
+fn count_inodes(spec: &Spec) -> u32 {
+    match &spec.kind {
+        Kind::Dir(entries) => 1 + entries.iter().map(|(_, e)| count_inodes(e)).sum::<u32>(),
+        _ => 1,
+    }
+}
+
+struct Generator<'a> {
+    options: &'a GenerateOptions,
+    image: Vec<u8>,
+    inodes: MetadataWriter,
+    dirs: DirectoryWriter,
+    // fragment block being filled
+    fragment: Vec<u8>,
+    fragments: MetadataWriter,
+    fragment_count: u32,
+    ids: Vec<u32>,
+    // key/value store and xattr id table
+    xattrs: MetadataWriter,
+    xattr_ids: MetadataWriter,
+    // xattr id of each distinct list, and where out of line values are
+    xattr_lists: HashMap<XattrList, u32>,
+    xattr_values: HashMap<Vec<u8>, InodeRef>,
+    next_inode: u32,
+}
+
+// longest value stored inline, as in mksquashfs
+const XATTR_INLINE_MAX: usize = 128;
+
+impl<'a> Generator<'a> {
+    // Writes the inode of `spec`, after those of its children.
+    fn write_node(&mut self, spec: &Spec, number: u32, parent: u32) -> Result<InodeRef> {
+        let mut inode = self.inode_header(spec, number)?;
+        let xattr = self.xattr_index(&spec.xattrs)?;
+        match &spec.kind {
+            Kind::Dir(entries) => {
+                let first = self.next_inode;
+                self.next_inode += entries.len() as u32;
+                let mut listing = Vec::with_capacity(entries.len());
+                for (child, (name, entry)) in (first..).zip(entries) {
+                    listing.push(NewDirectoryEntry {
+                        name: name.clone(),
+                        inode: self.write_node(entry, child, number)?,
+                        inode_number: child,
+                        file_type: entry.file_type(),
+                    });
+                }
+                let subdirs = entries
+                    .iter()
+                    .filter(|(_, e)| matches!(e.kind, Kind::Dir(_)))
+                    .count() as u32;
+                let listing = self.dirs.write_dir(listing)?;
+                if listing.index.is_empty()
+                    && listing.file_size <= u16::MAX as u32
+                    && xattr.is_none()
+                {
+                    set_type(&mut inode, 1);
+                    push(&mut inode, listing.start.block());
+                    push(&mut inode, 2 + subdirs);
+                    inode.extend_from_slice(&(listing.file_size as u16).to_le_bytes());
+                    inode.extend_from_slice(&listing.start.offset().to_le_bytes());
+                    push(&mut inode, parent);
+                } else {
+                    set_type(&mut inode, 8);
+                    push(&mut inode, 2 + subdirs);
+                    push(&mut inode, listing.file_size);
+                    push(&mut inode, listing.start.block());
+                    push(&mut inode, parent);
+                    inode.extend_from_slice(&(listing.index.len() as u16).to_le_bytes());
+                    inode.extend_from_slice(&listing.start.offset().to_le_bytes());
+                    push(&mut inode, xattr.unwrap_or(INVALID_XATTR));
+                    for index in &listing.index {
+                        index.write_to(&mut inode)?;
+                    }
+                }
+            }
+            Kind::File(data) => self.write_file(&mut inode, data, xattr)?,
+            Kind::Symlink(target) => {
+                set_type(&mut inode, 3);
+                push(&mut inode, 1);
+                push(&mut inode, target.len() as u32);
+                inode.extend_from_slice(target);
+            }
+            Kind::BlockDevice(rdev) | Kind::CharDevice(rdev) => {
+                let block = matches!(spec.kind, Kind::BlockDevice(_));
+                set_type(&mut inode, if block { 4 } else { 5 });
+                push(&mut inode, 1);
+                push(&mut inode, *rdev);
+            }
+            Kind::Fifo | Kind::Socket => {
+                set_type(
+                    &mut inode,
+                    if matches!(spec.kind, Kind::Fifo) {
+                        6
+                    } else {
+                        7
+                    },
+                );
+                push(&mut inode, 1);
+            }
+        }
+        // the extended types of symlinks, devices and ipc inodes only add
+        // the xattr index at the end
+        if let (
+            Some(xattr),
+            Kind::Symlink(_)
+            | Kind::BlockDevice(_)
+            | Kind::CharDevice(_)
+            | Kind::Fifo
+            | Kind::Socket,
+        ) = (xattr, &spec.kind)
+        {
+            let basic = u16::from_le_bytes([inode[0], inode[1]]);
+            set_type(&mut inode, basic + 7);
+            push(&mut inode, xattr);
+        }
+        let inode_ref = self.inodes.position();
+        self.inodes.write_all(&inode)?;
+        Ok(inode_ref)
+    }
+
+    // Fields shared by all inodes, with the type left for the caller.
+    fn inode_header(&mut self, spec: &Spec, number: u32) -> Result<Vec<u8>> {
+        let uid = self
+            .options
+            .uid_map
+            .as_ref()
+            .map_or(spec.uid, |m| m.unmap(spec.uid));
+        let gid = self
+            .options
+            .gid_map
+            .as_ref()
+            .map_or(spec.gid, |m| m.unmap(spec.gid));
+        let mut inode = vec![0; 2];
+        inode.extend_from_slice(&spec.mode.to_le_bytes());
+        inode.extend_from_slice(&self.id_index(uid)?.to_le_bytes());
+        inode.extend_from_slice(&self.id_index(gid)?.to_le_bytes());
+        push(&mut inode, spec.mtime);
+        push(&mut inode, number);
+        Ok(inode)
+    }
+
+    fn id_index(&mut self, id: u32) -> Result<u16> {
+        let index = match self.ids.iter().position(|&i| i == id) {
+            Some(index) => index,
+            None => {
+                self.ids.push(id);
+                self.ids.len() - 1
+            }
+        };
+        u16::try_from(index).map_err(|_| Error::new(ErrorKind::InvalidInput, "too many ids"))
+    }
+
+    // Writes the xattrs of an inode to the key/value store, or finds an
+    // identical list already written, and returns its xattr id.
+    fn xattr_index(&mut self, xattrs: &[(Vec<u8>, Vec<u8>)]) -> Result<Option<u32>> {
+        if xattrs.is_empty() {
+            return Ok(None);
+        }
+        if let Some(index) = self.xattr_lists.get(xattrs) {
+            return Ok(Some(*index));
+        }
+        // out of line values go first, so the entries stay contiguous
+        let mut entries = vec![];
+        let mut size = 0;
+        for (name, value) in xattrs {
+            let (mut xattr_type, stored) = split_name(name).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unsupported xattr {}", String::from_utf8_lossy(name)),
+                )
+            })?;
+            // as listed, with the prefix and a nul, plus the value
+            size += name.len() + 1 + value.len();
+            let value = if value.len() > XATTR_INLINE_MAX {
+                xattr_type |= XATTR_VALUE_OOL;
+                let value_ref = match self.xattr_values.get(value) {
+                    Some(value_ref) => *value_ref,
+                    None => {
+                        let value_ref = self.xattrs.position();
+                        self.xattrs.write_all(&(value.len() as u32).to_le_bytes())?;
+                        self.xattrs.write_all(value)?;
+                        self.xattr_values.insert(value.clone(), value_ref);
+                        value_ref
+                    }
+                };
+                u64::from(value_ref).to_le_bytes().to_vec()
+            } else {
+                value.clone()
+            };
+            entries.extend_from_slice(&xattr_type.to_le_bytes());
+            entries.extend_from_slice(&(stored.len() as u16).to_le_bytes());
+            entries.extend_from_slice(stored);
+            push(&mut entries, value.len() as u32);
+            entries.extend_from_slice(&value);
+        }
+        let start = self.xattrs.position();
+        self.xattrs.write_all(&entries)?;
+
+        let index = self.xattr_lists.len() as u32;
+        self.xattr_ids.write_all(&u64::from(start).to_le_bytes())?;
+        self.xattr_ids
+            .write_all(&(xattrs.len() as u32).to_le_bytes())?;
+        self.xattr_ids.write_all(&(size as u32).to_le_bytes())?;
+        self.xattr_lists.insert(xattrs.to_vec(), index);
+        Ok(Some(index))
+    }
+
+    fn write_file(&mut self, inode: &mut Vec<u8>, data: &[u8], xattr: Option<u32>) -> Result<()> {
+        let block_size = self.options.block_size as usize;
+        let tail_len = if self.options.fragments {
+            data.len() % block_size
+        } else {
+            0
+        };
+        let (blocks, tail) = data.split_at(data.len() - tail_len);
+
+        let start = self.image.len() as u64;
+        let mut sizes = vec![];
+        let mut sparse = 0;
+        for block in blocks.chunks(block_size) {
+            if block.len() == block_size && block.iter().all(|&b| b == 0) {
+                sizes.push(0);
+                sparse += block_size as u64;
+            } else {
+                sizes.push(self.write_block(block)?);
+            }
+        }
+        let (fragment, offset) = if tail.is_empty() {
+            (INVALID_FRAG, 0)
+        } else {
+            if self.fragment.len() + tail.len() > block_size {
+                self.flush_fragment()?;
+            }
+            let offset = self.fragment.len() as u32;
+            self.fragment.extend_from_slice(tail);
+            (self.fragment_count, offset)
+        };
+
+        if start <= u32::MAX as u64 && data.len() <= u32::MAX as usize && xattr.is_none() {
+            set_type(inode, 2);
+            push(inode, start as u32);
+            push(inode, fragment);
+            push(inode, offset);
+            push(inode, data.len() as u32);
+        } else {
+            set_type(inode, 9);
+            inode.extend_from_slice(&start.to_le_bytes());
+            inode.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            inode.extend_from_slice(&sparse.to_le_bytes());
+            push(inode, 1);
+            push(inode, fragment);
+            push(inode, offset);
+            push(inode, xattr.unwrap_or(INVALID_XATTR));
+        }
+        for size in sizes {
+            push(inode, size);
+        }
+        Ok(())
+    }
+
+    // Appends a data or fragment block, returning its size word.
+    fn write_block(&mut self, block: &[u8]) -> Result<u32> {
+        let mut compressed = vec![];
+        self.options
+            .compressor
+            .compress(&mut &block[..], &mut compressed)?;
+        if compressed.len() < block.len() {
+            self.image.extend_from_slice(&compressed);
+            Ok(compressed.len() as u32)
+        } else {
+            self.image.extend_from_slice(block);
+            Ok(block.len() as u32 | COMPRESSED_BIT_BLOCK)
+        }
+    }
+
+    fn flush_fragment(&mut self) -> Result<()> {
+        if self.fragment.is_empty() {
+            return Ok(());
+        }
+        let start = self.image.len() as u64;
+        let fragment = std::mem::take(&mut self.fragment);
+        let size = self.write_block(&fragment)?;
+        self.fragments.write_all(&start.to_le_bytes())?;
+        self.fragments.write_all(&size.to_le_bytes())?;
+        self.fragments.write_all(&0u32.to_le_bytes())?;
+        self.fragment_count += 1;
+        Ok(())
+    }
+
+    // Appends the tables and writes the superblock.
+    fn finish(self, root: InodeRef) -> Result<Vec<u8>> {
+        let Self {
+            options,
+            mut image,
+            inodes,
+            dirs,
+            fragments,
+            fragment_count,
+            ids,
+            xattrs,
+            xattr_ids,
+            xattr_lists,
+            next_inode,
+            ..
+        } = self;
+        let inode_table_start = image.len() as u64;
+        image.extend_from_slice(&inodes.finish()?);
+        let directory_table_start = image.len() as u64;
+        image.extend_from_slice(&dirs.finish()?);
+        let fragment_table_start = append_indexed_table(&mut image, fragments)?;
+        let mut id_table = MetadataWriter::new(options.compressor.clone());
+        for id in &ids {
+            id_table.write_all(&id.to_le_bytes())?;
+        }
+        let id_table_start = append_indexed_table(&mut image, id_table)?;
+
+        let mut flags = Flags::NO_XATTRS_IN_ARCHIVE;
+        if !options.fragments {
+            flags |= Flags::FRAGMENTS_ARE_NOT_USED;
+        }
+        let mut builder = Superblock::builder();
+        if !xattr_lists.is_empty() {
+            // the id table index follows a header locating the store
+            let kv_start = image.len() as u64;
+            image.extend_from_slice(&xattrs.finish()?);
+            let ids_start = image.len() as u64;
+            let (table, blocks) = xattr_ids.finish_indexed()?;
+            image.extend_from_slice(&table);
+            let xattr_id_table_start = image.len() as u64;
+            image.extend_from_slice(&kv_start.to_le_bytes());
+            image.extend_from_slice(&(xattr_lists.len() as u64).to_le_bytes());
+            for block in blocks {
+                image.extend_from_slice(&(ids_start + block).to_le_bytes());
+            }
+            flags -= Flags::NO_XATTRS_IN_ARCHIVE;
+            builder.xattr_id_table_start(xattr_id_table_start);
+        }
+        let sb = builder
+            .inodes(next_inode - 1)
+            .mkfs_time(options.mkfs_time)
+            .block_size(options.block_size)
+            .fragments(fragment_count)
+            .compressor(options.compressor.id())
+            .flags(flags)
+            .ids(ids.len() as u16)
+            .root_inode(root)
+            .bytes_used(image.len() as u64)
+            .id_table_start(id_table_start)
+            .inode_table_start(inode_table_start)
+            .directory_table_start(directory_table_start)
+            .fragment_table_start(fragment_table_start)
+            .build()?;
+        image[..SUPERBLOCK_SIZE].copy_from_slice(&sb.to_bytes());
+
+        let padded = (image.len() as u64).div_ceil(PADDING_SIZE) * PADDING_SIZE;
+        image.resize(padded as usize, 0);
+        Ok(image)
+    }
+}
+
+// Appends a table followed by its index, returning where the index starts.
+fn append_indexed_table(image: &mut Vec<u8>, table: MetadataWriter) -> Result<u64> {
+    let start = image.len() as u64;
+    let (table, blocks) = table.finish_indexed()?;
+    image.extend_from_slice(&table);
+    let index_start = image.len() as u64;
+    for block in blocks {
+        image.extend_from_slice(&(start + block).to_le_bytes());
+    }
+    Ok(index_start)
+}
+
+fn set_type(inode: &mut [u8], inode_type: u16) {
+    inode[..2].copy_from_slice(&inode_type.to_le_bytes());
+}
+
+fn push(inode: &mut Vec<u8>, field: u32) {
+    inode.extend_from_slice(&field.to_le_bytes());
+}
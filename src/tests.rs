@@ -1,6 +1,40 @@
-
-use crate::{superblock::Superblock, utils::get_set_field_tuple, SUPERBLOCK_SIZE};
-use std::mem;
+#[cfg(feature = "testing")]
+use crate::cache::CacheConfig;
+use crate::cache::{CacheLimits, LruCache};
+use crate::compressors::{Compress, Compressor, Decompress};
+use crate::idmap::IdMap;
+use crate::image::Image;
+use crate::inode::{
+    read_directory_listing, read_inode_header, DirectoryEntry, DirectoryHeader, FileType,
+    InodeHeader, InodeRef,
+};
+use crate::inode_ref;
+use crate::metrics::NoMetrics;
+#[cfg(feature = "testing")]
+use crate::metrics::{BlockKind, CacheKind, Metrics, Phase};
+use crate::path::SqshPath;
+use crate::pool::{BufferPool, MAX_POOLED, MAX_POOLED_CAPACITY};
+use crate::read::{read_block, read_table_index, IndexedTableReader, TrackedReader};
+use crate::salvage::{recover_inodes, scan_for_images, scan_metadata_blocks};
+use crate::selection::{choose_compressor, default_candidates, SelectionPolicy};
+use crate::source::ImageSource;
+use crate::spool::Spooled;
+use crate::view::InodeViews;
+use crate::warning::Warning;
+use crate::write::{DirectoryWriter, MetadataWriter, NewDirectoryEntry};
+use crate::xattr::{FileCapabilities, PosixAcl, Xattr};
+use crate::{
+    superblock::{patch_superblock, Flags, Superblock, SuperblockError},
+    utils::{decode_le_slice, get_set_field_tuple},
+    SUPERBLOCK_SIZE,
+};
+#[cfg(feature = "testing")]
+use crate::{COMPRESSED_BIT_BLOCK, PADDING_SIZE};
+use std::collections::HashMap;
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+#[cfg(feature = "testing")]
+use std::{sync::Mutex, time::Duration};
 
 struct TestField([u8; 4]);
 
@@ -18,6 +52,4436 @@ fn get_set_field() {
 }
 
 #[test]
-fn superblock_size() {
-    assert_eq!(mem::size_of::<Superblock>(), SUPERBLOCK_SIZE);
+fn decode_le_table() {
+    let bytes = [
+        1, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    ];
+    let values: Vec<i64> = decode_le_slice(&bytes).unwrap();
+    assert_eq!(values, vec![1, -1]);
+    assert!(decode_le_slice::<u32>(&bytes[..6]).is_err());
+}
+
+#[test]
+fn superblock_round_trip() {
+    let bytes = test_superblock_bytes();
+    let mut sb = test_superblock();
+    assert_eq!(sb.to_bytes(), bytes);
+    sb.set_bytes_used(0x1122_3344_5566);
+    let mut written = vec![];
+    assert_eq!(sb.write_to(&mut written).unwrap(), SUPERBLOCK_SIZE as u64);
+    let read = Superblock::new(&mut &written[..]).unwrap();
+    assert_eq!(read.bytes_used(), 0x1122_3344_5566);
+    assert_eq!(written[..40], bytes[..40]);
+}
+
+#[test]
+fn flags_describe() {
+    assert!(Flags::empty().describe().is_empty());
+    let flags = Flags::FRAGMENTS_ARE_NOT_USED | Flags::INODES_STORED_UNCOMPRESSED;
+    let described = flags.describe();
+    assert_eq!(described.len(), 2);
+    assert!(described[0].starts_with("inodes uncompressed"));
+    assert!(described[1].starts_with("fragments not used"));
+    assert_eq!(Flags::all().describe().len(), 12);
+}
+
+#[test]
+fn superblock_block_size() {
+    for (block_size, block_log) in [
+        (1u32 << 21, 21u16),
+        (2048, 11),
+        (3 << 12, 13),
+        (1 << 16, 17),
+    ] {
+        let mut bytes = test_superblock_bytes();
+        bytes[12..16].copy_from_slice(&block_size.to_le_bytes());
+        bytes[22..24].copy_from_slice(&block_log.to_le_bytes());
+        let error = Superblock::new(&mut &bytes[..]).unwrap_err();
+        assert_eq!(
+            error.get_ref().and_then(|e| e.downcast_ref()),
+            Some(&SuperblockError::BlockSize {
+                block_size,
+                block_log
+            })
+        );
+    }
+}
+
+#[test]
+fn compress_round_trip() {
+    let data: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8).collect();
+    for compressor in [
+        Compressor::GZIP(Default::default()),
+        Compressor::XZ(Default::default()),
+        Compressor::ZSTD(Default::default()),
+    ] {
+        let mut compressed = vec![];
+        let written = compressor
+            .compress(&mut &data[..], &mut compressed)
+            .unwrap();
+        assert_eq!(written as usize, compressed.len());
+        let mut decompressed = vec![];
+        compressor
+            .decompress(&mut &compressed[..], &mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data, "{}", compressor);
+    }
+}
+
+#[test]
+fn compressor_options_honoured() {
+    use crate::compressors::{GzipCompressor, XZCompressor, XZFilters};
+
+    let data: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8).collect();
+    let err = Compressor::Undefined
+        .compress(&mut &data[..], &mut vec![])
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+    let err = Compressor::Undefined
+        .decompress(&mut &data[..], &mut vec![])
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+    assert!(Compressor::Undefined.write_options(&mut vec![]).is_err());
+
+    // options compression can't follow are refused rather than written
+    // for data that doesn't match them
+    let mut gzip = GzipCompressor::default();
+    gzip.set_window_size(12);
+    let err = Compressor::GZIP(gzip)
+        .compress(&mut &data[..], &mut vec![])
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+    let mut gzip = GzipCompressor::default();
+    gzip.set_strategies(0x0003);
+    assert!(Compressor::GZIP(gzip)
+        .compress(&mut &data[..], &mut vec![])
+        .is_err());
+    let mut options = vec![];
+    Compressor::XZ(XZCompressor::default())
+        .write_options(&mut options)
+        .unwrap();
+    // a filter squashfs doesn't define
+    options[4..8].copy_from_slice(&0x41u32.to_le_bytes());
+    let xz = Compressor::new(4, true, &mut Cursor::new(options)).unwrap();
+    assert!(xz.compress(&mut &data[..], &mut vec![]).is_err());
+
+    let mut xz = XZCompressor::default();
+    xz.set_filters(XZFilters::X86 | XZFilters::ARM);
+    let xz = Compressor::XZ(xz);
+    let mut compressed = vec![];
+    let result = xz.compress(&mut &data[..], &mut compressed);
+    if cfg!(feature = "native-codecs") {
+        result.unwrap();
+        let mut decompressed = vec![];
+        xz.decompress(&mut &compressed[..], &mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    } else {
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+}
+
+fn test_superblock_bytes() -> [u8; SUPERBLOCK_SIZE] {
+    let mut bytes = [0u8; SUPERBLOCK_SIZE];
+    bytes[0..4].copy_from_slice(&crate::MAGIC.to_le_bytes());
+    bytes[4..8].copy_from_slice(&10u32.to_le_bytes());
+    bytes[12..16].copy_from_slice(&(128 * 1024u32).to_le_bytes());
+    bytes[20..22].copy_from_slice(&1u16.to_le_bytes());
+    bytes[22..24].copy_from_slice(&17u16.to_le_bytes());
+    bytes[28..30].copy_from_slice(&4u16.to_le_bytes());
+    bytes[40..48].copy_from_slice(&4096u64.to_le_bytes());
+    bytes[48..56].copy_from_slice(&300u64.to_le_bytes());
+    bytes[56..64].copy_from_slice(&crate::INVALID_BLK.to_le_bytes());
+    bytes[64..72].copy_from_slice(&96u64.to_le_bytes());
+    bytes[72..80].copy_from_slice(&200u64.to_le_bytes());
+    bytes
+}
+
+fn test_superblock() -> Superblock {
+    Superblock::new(&mut &test_superblock_bytes()[..]).unwrap()
+}
+
+// Counts the calls reaching the underlying reader.
+#[derive(Default)]
+struct CountingReader {
+    data: Cursor<Vec<u8>>,
+    reads: usize,
+    seeks: usize,
+}
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads += 1;
+        self.data.read(buf)
+    }
+}
+
+impl Seek for CountingReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.seeks += 1;
+        self.data.seek(pos)
+    }
+}
+
+#[test]
+fn tracked_reader() {
+    let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    let mut reader = TrackedReader::new(
+        CountingReader {
+            data: Cursor::new(data.clone()),
+            ..Default::default()
+        },
+        Arc::new(NoMetrics),
+    );
+    let mut buf = [0; 100];
+    for i in 0..10 {
+        reader.seek(SeekFrom::Start(i * 100)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..], data[i as usize * 100..][..100]);
+    }
+    reader.seek(SeekFrom::Start(150_000)).unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(buf[..], data[150_000..150_100]);
+    reader.seek(SeekFrom::Current(-50)).unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(buf[..], data[150_050..150_150]);
+
+    let mut rest = vec![];
+    reader.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest[..], data[150_150..]);
+    let inner = reader.into_inner();
+    assert_eq!((inner.seeks, inner.reads), (2, 3));
+}
+
+// Table scans announce their extent, so a table of several metadata
+// blocks is fetched in one read rather than one per read ahead.
+#[test]
+fn tracked_reader_extent() {
+    let compressor = Compressor::GZIP(Default::default());
+    // incompressible, so the table is larger than two read aheads
+    let mut seed = 1u32;
+    let data: Vec<u8> = (0..20 * crate::METADATA_SIZE)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as u8
+        })
+        .collect();
+    let mut writer = MetadataWriter::new(compressor.clone());
+    writer.write_all(&data).unwrap();
+    let table = writer.finish().unwrap();
+    assert_eq!(table.len(), 20 * (crate::METADATA_SIZE + 2));
+
+    let reads = |extent: std::ops::Range<u64>, bulk_read: Option<usize>| {
+        let mut reader = TrackedReader::new(
+            CountingReader {
+                data: Cursor::new(table.clone()),
+                ..Default::default()
+            },
+            Arc::new(NoMetrics),
+        );
+        reader.set_extent(extent);
+        if let Some(bytes) = bulk_read {
+            reader.set_bulk_read(bytes);
+        }
+        let mut read = vec![];
+        let mut start = 0;
+        while start < table.len() as u64 {
+            start += read_block(&mut reader, &mut read, &compressor, start, None).unwrap() as u64;
+        }
+        assert_eq!(read, data);
+        reader.into_inner().reads
+    };
+    let extent = 0..table.len() as u64;
+    assert_eq!(reads(0..0, None), 3);
+    assert_eq!(reads(extent.clone(), None), 1);
+    assert_eq!(reads(extent.clone(), Some(100_000)), 2);
+    // never below the normal read ahead
+    assert_eq!(reads(extent, Some(1)), 3);
+}
+
+// A sparse block read after a table scan isn't fetched, and the scan's
+// extent doesn't leak into file reads.
+#[cfg(feature = "testing")]
+#[test]
+fn tracked_reader_extent_sparse_block() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let mut data: Vec<u8> = (0..3 * 131072u32).map(|i| (i % 251) as u8).collect();
+    data[131072..2 * 131072].fill(0);
+    let files: Vec<(String, Spec)> = (0..2000)
+        .map(|i| (format!("f{:04}", i), Spec::file(vec![i as u8; 10])))
+        .chain([("sparse".to_string(), Spec::file(data.clone()))])
+        .collect();
+    let bytes = generate(&Spec::dir(files), &GenerateOptions::default()).unwrap();
+    let metrics = Arc::new(RecordedMetrics::default());
+    let image = Image::with_metrics(Cursor::new(bytes), metrics.clone()).unwrap();
+
+    let sb = image.superblock();
+    assert!(sb.directory_table_start() - sb.inode_table_start() > crate::METADATA_SIZE as i64);
+    let (_, inodes) = image.inodes().unwrap();
+    assert_eq!(inodes.len(), 2002);
+
+    let inode = image.lookup("/sparse").unwrap();
+    let InodeHeader::Regular(file) = &inode else {
+        unreachable!()
+    };
+    assert_eq!(file.blocks()[1], 0);
+    let mut read = vec![];
+    image
+        .open_file(&inode)
+        .unwrap()
+        .read_to_end(&mut read)
+        .unwrap();
+    assert!(read == data);
+    assert_eq!(metrics.blocks.lock().unwrap()[&BlockKind::Data], 2 * 131072);
+}
+
+#[test]
+fn superblock_patch() {
+    let mut bytes = test_superblock_bytes().to_vec();
+    bytes[80..88].copy_from_slice(&crate::INVALID_BLK.to_le_bytes());
+    bytes[88..96].copy_from_slice(&crate::INVALID_BLK.to_le_bytes());
+    bytes.resize(4096, 0);
+    let mut image = Cursor::new(bytes);
+
+    let sb = patch_superblock(&mut image, |sb| sb.set_mkfs_time(1234)).unwrap();
+    assert_eq!(sb.mkfs_time(), 1234);
+    assert_eq!(image.get_ref()[8..12], 1234u32.to_le_bytes());
+
+    let before = image.get_ref().clone();
+    let patched = patch_superblock(&mut image, |sb| {
+        sb.set_flags(sb.flags() | Flags::NFSEXPORT_TABLE_EXISTS)
+    });
+    assert!(patched.is_err());
+    assert!(patch_superblock(&mut image, |sb| sb.set_bytes_used(5000)).is_err());
+    let root = inode_ref::pack(0, inode_ref::MAX_OFFSET);
+    assert!(patch_superblock(&mut image, |sb| sb.set_root_inode(root as i64)).is_err());
+    assert_eq!(image.get_ref(), &before);
+}
+
+#[test]
+fn superblock_builder() {
+    let err = Superblock::builder().build().unwrap_err();
+    assert_eq!(err.to_string(), "inode table start not set");
+
+    let mut builder = Superblock::builder();
+    builder
+        .bytes_used(4096)
+        .inode_table_start(96)
+        .directory_table_start(200)
+        .id_table_start(300);
+    let sb = builder.build().unwrap();
+    assert_eq!(
+        (sb.magic(), sb.version_major(), sb.version_minor()),
+        (crate::MAGIC, 4, 0)
+    );
+    assert_eq!(
+        (sb.block_size(), sb.block_log(), sb.compressor()),
+        (128 * 1024, 17, 1)
+    );
+    assert_eq!(sb.flags(), Flags::NO_XATTRS_IN_ARCHIVE);
+    assert_eq!(sb.xattr_id_table_start(), crate::INVALID_BLK);
+    assert_eq!(sb.export_table_start(), crate::INVALID_BLK);
+    assert!(Superblock::new(&mut &sb.to_bytes()[..]).is_ok());
+
+    let sb = builder.export_table_start(400).build().unwrap();
+    assert!(sb.flags().contains(Flags::NFSEXPORT_TABLE_EXISTS));
+    assert!(builder.block_size(5000).build().is_err());
+    builder.block_size(4096);
+    assert!(builder.export_table_start(5000).build().is_err());
+    assert!(builder
+        .export_table_start(400)
+        .directory_table_start(90)
+        .build()
+        .is_err());
+}
+
+#[test]
+fn inode_ref_packing() {
+    let packed = inode_ref::pack(0x1234_5678, 0x9a);
+    assert_eq!(packed, 0x1234_5678_009a);
+    assert_eq!(inode_ref::unpack(packed), (0x1234_5678, 0x9a));
+    assert_eq!(
+        inode_ref::unpack(packed | 0xffff << 48),
+        (0x1234_5678, 0x9a)
+    );
+    let inode = InodeRef::from(packed);
+    assert_eq!((inode.block(), inode.offset()), (0x1234_5678, 0x9a));
+    assert_eq!(u64::from(InodeRef::new(0x1234_5678, 0x9a)), packed);
+}
+
+#[test]
+fn unsupported_compressor() {
+    use crate::compressors::UnsupportedCompressor;
+
+    let mut bytes = test_superblock_bytes().to_vec();
+    bytes[20..22].copy_from_slice(&2u16.to_le_bytes());
+    bytes.resize(4096, 0);
+    let err = Image::new(Cursor::new(bytes)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+    let payload = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<UnsupportedCompressor>());
+    assert_eq!(payload, Some(&UnsupportedCompressor { id: 2, name: "lzo" }));
+    assert_eq!(
+        err.to_string(),
+        "lzo compression (id 2) is not supported, only gzip, xz and zstd"
+    );
+}
+
+#[test]
+fn image_warnings() {
+    let mut bytes = test_superblock_bytes().to_vec();
+    bytes[24..26].copy_from_slice(&0x2000u16.to_le_bytes());
+    bytes[40..48].copy_from_slice(&4000u64.to_le_bytes());
+    bytes.resize(4096, 0);
+    bytes[4050] = 1;
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    assert_eq!(
+        image.warnings(),
+        [
+            Warning::UnknownFlags(0x2000),
+            Warning::Padding { offset: 4050 }
+        ]
+    );
+}
+
+#[test]
+fn image_source() {
+    let mut bytes = test_superblock_bytes().to_vec();
+    bytes.resize(4096, 0);
+    let source = ImageSource::new(move || Ok(Cursor::new(bytes.clone())));
+    std::thread::scope(|scope| {
+        let threads: Vec<_> = (0..2)
+            .map(|_| scope.spawn(|| source.open().unwrap().superblock().block_size()))
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), 131072);
+        }
+    });
+}
+
+#[test]
+fn image_from_stream() {
+    let mut bytes = test_superblock_bytes().to_vec();
+    bytes.resize(4096, 0);
+    for limit in [4096, 100] {
+        let mut spool = Spooled::new(&bytes[..], limit).unwrap();
+        assert_eq!(spool.is_file(), limit < 4096);
+        let mut read = vec![];
+        spool.read_to_end(&mut read).unwrap();
+        assert_eq!(read, bytes);
+
+        let image = Image::from_stream(&bytes[..], limit).unwrap();
+        assert_eq!(image.superblock().block_size(), 131072);
+    }
+}
+
+#[test]
+fn raw_bytes() {
+    let mut bytes = test_superblock_bytes().to_vec();
+    bytes[40..48].copy_from_slice(&3000u64.to_le_bytes());
+    bytes.extend((bytes.len()..4096).map(|i| i as u8));
+    let image = Image::new(Cursor::new(&bytes)).unwrap();
+    let mut raw = image.raw_bytes();
+    assert_eq!(raw.len(), 3000);
+    let mut read = vec![];
+    raw.read_to_end(&mut read).unwrap();
+    assert_eq!(read, &bytes[..3000]);
+    raw.seek(SeekFrom::End(-10)).unwrap();
+    read.clear();
+    raw.read_to_end(&mut read).unwrap();
+    assert_eq!(read, &bytes[2990..3000]);
+
+    // the padding is missing, but so is part of the image
+    let image = Image::new(Cursor::new(&bytes[..2000])).unwrap();
+    let err = image.raw_bytes().read_to_end(&mut vec![]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[cfg(feature = "positioned-io")]
+#[test]
+fn read_at_image() {
+    let mut bytes = test_superblock_bytes().to_vec();
+    bytes[24..26].copy_from_slice(&0x2000u16.to_le_bytes());
+    bytes.resize(4096, 0);
+    let image = Image::from_read_at(&bytes[..]).unwrap();
+    assert_eq!(image.superblock().block_size(), 131072);
+    assert_eq!(image.warnings(), [Warning::UnknownFlags(0x2000)]);
+}
+
+#[test]
+fn inode_write_round_trip() {
+    let header = |inode_type: u16, rest: &[u32]| {
+        let mut bytes = inode_type.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&0o644u16.to_le_bytes());
+        bytes.extend_from_slice(&[0; 8]);
+        for field in rest {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    };
+    // regular file of two full blocks, no fragment
+    let regular = header(2, &[7, 96, u32::MAX, 0, 200_000, 0x1000, 0x0100_2000]);
+    let mut lsymlink = header(10, &[8, 1, 3]);
+    lsymlink.extend_from_slice(b"a/b");
+    lsymlink.extend_from_slice(&5u32.to_le_bytes());
+    // file size 3000, 2 entries in the index at offset 12, xattr 3
+    let mut ldirectory = header(8, &[9, 2, 3000, 0x40, 1, 2 | 12 << 16, 3]);
+    for (index, start_block, name) in [(0, 0, &b"a"[..]), (1800, 0x2010, b"mmm")] {
+        for field in [index, start_block, name.len() as u32 - 1] {
+            ldirectory.extend_from_slice(&field.to_le_bytes());
+        }
+        ldirectory.extend_from_slice(name);
+    }
+    // three blocks, the second sparse, then a fragment; sparse bytes and
+    // an xattr index set
+    let size = 3 * 131072 + 100;
+    let mut lregular = header(9, &[10, 0x60, 0, size, 0, 131072, 0, 2, 4, 50, 6]);
+    for block in [0x1000u32, 0, 0x0100_2000] {
+        lregular.extend_from_slice(&block.to_le_bytes());
+    }
+    let directory = header(1, &[11, 0x20, 2, 40 | 5 << 16, 1]);
+    let block_device = header(4, &[12, 1, 0x0801]);
+    let lchar_device = header(12, &[13, 2, 0x0103, 7]);
+    let fifo = header(6, &[14, 1]);
+    let socket = header(7, &[15, 1]);
+    let lsocket = header(14, &[16, 3, 8]);
+
+    let sb = test_superblock();
+    let all = [
+        regular,
+        lsymlink,
+        ldirectory,
+        lregular,
+        directory,
+        block_device,
+        lchar_device,
+        fifo,
+        socket,
+        lsocket,
+    ];
+    for bytes in all {
+        let inode = read_inode_header(&mut &bytes[..], &sb).unwrap();
+        match &inode {
+            InodeHeader::LDirectory(dir) => {
+                assert_eq!(dir.inodes().len(), 2);
+                assert_eq!(dir.inodes()[1].name(), b"mmm");
+                assert_eq!(dir.xattr(), 3);
+            }
+            InodeHeader::LRegular(file) => {
+                assert_eq!(file.blocks(), [0x1000, 0, 0x0100_2000]);
+                assert_eq!(file.sparse(), 131072);
+                assert_eq!(file.xattr(), 6);
+            }
+            _ => {}
+        }
+        let mut written = vec![];
+        assert_eq!(inode.write_to(&mut written).unwrap(), bytes.len() as u64);
+        assert_eq!(written, bytes);
+    }
+}
+
+#[test]
+fn inode_bad_type() {
+    let sb = test_superblock();
+    for inode_type in [0u16, 15, 0xffff] {
+        let mut bytes = inode_type.to_le_bytes().to_vec();
+        bytes.resize(64, 0);
+        let err = read_inode_header(&mut &bytes[..], &sb).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn salvage_finds_inode_block() {
+    let compressor = Compressor::GZIP(Default::default());
+    // named pipe inode, mode 0644, inode number 3, nlink 1
+    let mut inode = vec![];
+    inode.extend_from_slice(&6u16.to_le_bytes());
+    inode.extend_from_slice(&0o644u16.to_le_bytes());
+    inode.extend_from_slice(&[0; 4]);
+    inode.extend_from_slice(&0u32.to_le_bytes());
+    inode.extend_from_slice(&3u32.to_le_bytes());
+    inode.extend_from_slice(&1u32.to_le_bytes());
+
+    let mut compressed = vec![];
+    compressor
+        .compress(&mut &inode[..], &mut compressed)
+        .unwrap();
+    let mut blob = vec![0xaa; 37];
+    blob.extend_from_slice(&(compressed.len() as u16).to_le_bytes());
+    blob.extend_from_slice(&compressed);
+    blob.extend_from_slice(&[0x55; 19]);
+
+    let blocks =
+        scan_metadata_blocks(&mut Cursor::new(&blob), &compressor, 0, blob.len() as u64).unwrap();
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].start, 37);
+    assert_eq!(blocks[0].data, inode);
+
+    let inodes = recover_inodes(&blocks, &test_superblock());
+    assert_eq!(inodes.len(), 1);
+    assert_eq!(inodes[0].offset, 0);
+    assert!(matches!(inodes[0].inode, InodeHeader::IPC(ref i) if i.inode_number() == 3));
+}
+
+#[cfg(all(unix, feature = "testing"))]
+#[test]
+fn salvage_rebuilds_tree() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+    use std::fs;
+
+    let data: Vec<u8> = (0..600_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let many: Vec<_> = (0..300)
+        .map(|i| (format!("f{:03}", i), Spec::file(format!("file {}", i))))
+        .collect();
+    let root = Spec::dir([
+        (
+            "etc",
+            Spec::dir([
+                ("data", Spec::file(data.clone())),
+                ("motd", Spec::file("hello")),
+            ]),
+        ),
+        ("bin", Spec::dir([("sh", Spec::symlink("../etc/motd"))])),
+        ("many", Spec::dir(many)),
+    ]);
+    let mut bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let sb = Superblock::new(&mut &bytes[..]).unwrap();
+
+    // the directory table pointer is lost, so listings are found by where
+    // the directory inodes point
+    let mut damaged = sb;
+    damaged.set_directory_table_start(sb.bytes_used() as i64);
+    bytes[..SUPERBLOCK_SIZE].copy_from_slice(&damaged.to_bytes());
+    let image = Image::new(Cursor::new(bytes.clone())).unwrap();
+    assert!(image.lookup("/etc/motd").is_err());
+    let salvage = image.salvage().unwrap();
+    let paths: Vec<String> = salvage
+        .entries
+        .iter()
+        .map(|e| e.path.to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(paths.len(), 307);
+    // listings are sorted by name
+    assert_eq!(paths[..5], ["/", "/bin", "/bin/sh", "/etc", "/etc/data"]);
+    assert!(paths.contains(&"/many/f299".to_string()));
+
+    let dest = std::env::temp_dir().join(format!("squashfs-salvage-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dest);
+    let failed = image.extract_salvaged(&salvage, &dest).unwrap();
+    assert!(failed.is_empty(), "{:?}", failed);
+    assert_eq!(fs::read(dest.join("etc/data")).unwrap(), data);
+    assert_eq!(fs::read(dest.join("many/f123")).unwrap(), b"file 123");
+    assert_eq!(
+        fs::read_link(dest.join("bin/sh")).unwrap(),
+        std::path::Path::new("../etc/motd")
+    );
+    let _ = fs::remove_dir_all(&dest);
+
+    // without the block holding the root inode, what its listing led to is
+    // put in lost+found by inode number
+    bytes[..SUPERBLOCK_SIZE].copy_from_slice(&sb.to_bytes());
+    let root_block = sb.inode_table_start() as usize + sb.root_inode_ref().block() as usize;
+    bytes[root_block..root_block + 2].copy_from_slice(&[0, 0]);
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let salvage = image.salvage().unwrap();
+    let path_of = |number: u32| {
+        salvage
+            .entries
+            .iter()
+            .find(|e| salvage.inodes[e.inode].inode.inode_number() == number)
+            .map(|e| e.path.to_string_lossy().into_owned())
+    };
+    assert_eq!(path_of(1), None);
+    // etc and bin are inodes 2 and 3, motd and sh 6 and 7
+    assert_eq!(path_of(2).unwrap(), "/lost+found/#2");
+    assert_eq!(path_of(6).unwrap(), "/lost+found/#2/motd");
+    assert_eq!(path_of(7).unwrap(), "/lost+found/#3/sh");
+}
+
+#[test]
+fn carve_superblocks() {
+    let sb = test_superblock_bytes();
+    let mut blob = vec![0x11; 100];
+    blob.extend_from_slice(&sb);
+    // magic followed by garbage
+    blob.extend_from_slice(&crate::MAGIC.to_le_bytes());
+    blob.extend_from_slice(&[0xff; 200]);
+    blob.extend_from_slice(&vec![0; 70_000]);
+    blob.extend_from_slice(&sb);
+
+    let found = scan_for_images(&blob[..]).unwrap();
+    let offsets: Vec<u64> = found.iter().map(|(offset, _)| *offset).collect();
+    assert_eq!(offsets, vec![100, 70_400]);
+    assert_eq!(found[0].1.bytes_used(), 4096);
+}
+
+#[test]
+fn directory_listing() {
+    // one header (2 entries, block 0x40, base inode 10) followed by a
+    // second header for a single entry
+    let mut listing = vec![];
+    for (count, start_block, inode_number) in [(1u32, 0x40u32, 10u32), (0, 0x80, 20)] {
+        listing.extend_from_slice(&count.to_le_bytes());
+        listing.extend_from_slice(&start_block.to_le_bytes());
+        listing.extend_from_slice(&inode_number.to_le_bytes());
+        let entries: &[(u16, i16, &[u8])] = match count {
+            1 => &[(0x10, 0, b"a"), (0x30, -2, b"bc")],
+            _ => &[(0x8, 1, b"d")],
+        };
+        for (offset, inode_offset, name) in entries {
+            listing.extend_from_slice(&offset.to_le_bytes());
+            listing.extend_from_slice(&inode_offset.to_le_bytes());
+            listing.extend_from_slice(&2u16.to_le_bytes());
+            listing.extend_from_slice(&(name.len() as u16 - 1).to_le_bytes());
+            listing.extend_from_slice(name);
+        }
+    }
+
+    let entries = read_directory_listing(&mut &listing[..], listing.len() as u64).unwrap();
+    let names: Vec<&[u8]> = entries.iter().map(|e| e.name()).collect();
+    assert_eq!(names, vec![&b"a"[..], b"bc", b"d"]);
+    assert_eq!(entries[1].inode_ref(), InodeRef::new(0x40, 0x30));
+    assert_eq!(entries[1].inode_number(), 8);
+    assert_eq!(entries[2].inode_number(), 21);
+    assert_eq!(entries[2].file_type(), Some(FileType::Regular));
+    assert!(read_directory_listing(&mut &listing[..], listing.len() as u64 - 1).is_err());
+}
+
+#[test]
+fn latin1_symlink() {
+    let mut inode = vec![];
+    inode.extend_from_slice(&3u16.to_le_bytes());
+    inode.extend_from_slice(&0o777u16.to_le_bytes());
+    inode.extend_from_slice(&[0; 8]);
+    inode.extend_from_slice(&5u32.to_le_bytes());
+    inode.extend_from_slice(&1u32.to_le_bytes());
+    inode.extend_from_slice(&4u32.to_le_bytes());
+    inode.extend_from_slice(b"caf\xe9");
+    let inode = read_inode_header(&mut &inode[..], &test_superblock()).unwrap();
+    let symlink = match &inode {
+        InodeHeader::Symlink(s) => s,
+        _ => panic!("not a symlink"),
+    };
+    assert_eq!(symlink.target(), b"caf\xe9");
+    assert_eq!(symlink.target_lossy(), "caf\u{fffd}");
+    assert!(inode.to_string().ends_with("symlink caf\u{fffd}"));
+}
+
+#[test]
+fn lru_cache() {
+    let mut cache = LruCache::new(CacheLimits::new(2, 100));
+    cache.insert(1, "a", 10);
+    cache.insert(2, "b", 10);
+    assert_eq!(cache.get(&1), Some("a"));
+    cache.insert(3, "c", 10);
+    assert_eq!(cache.get(&2), None);
+    assert_eq!(cache.get(&1), Some("a"));
+    assert_eq!(cache.get(&3), Some("c"));
+    // over the byte limit with the other two
+    cache.insert(4, "d", 85);
+    assert_eq!(cache.get(&1), None);
+    assert_eq!(cache.get(&4), Some("d"));
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.evictions), (4, 2, 2));
+    assert_eq!((stats.entries, stats.bytes), (2, 95));
+    // a value too large to keep doesn't leave the old one behind
+    cache.insert(4, "e", 101);
+    assert_eq!(cache.get(&4), None);
+    assert_eq!((cache.stats().entries, cache.stats().bytes), (1, 10));
+
+    let mut disabled = LruCache::new(CacheLimits::disabled());
+    disabled.insert(1, "a", 1);
+    assert_eq!(disabled.get(&1), None);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn stat_all_matches_open_entry() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    // enough inodes to span several metadata blocks, of mixed types and
+    // sizes so some straddle block boundaries
+    let entries = (0..600).map(|i| {
+        let spec = match i % 4 {
+            0 => Spec::file(vec![1; i]),
+            1 => Spec::symlink("x".repeat(i % 200 + 1)),
+            2 => Spec::dir([("f", Spec::file("f"))]),
+            _ => Spec::char_device(0x0103),
+        };
+        (format!("{:03}", i), spec)
+    });
+    let bytes = generate(&Spec::dir(entries), &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut entries = image.list_dir("/").unwrap();
+    // out of inode order, with a repeated entry
+    entries.reverse();
+    entries.swap(10, 400);
+    entries.push(entries[3].clone());
+
+    let inodes = image.stat_all(&entries).unwrap();
+    assert_eq!(inodes.len(), entries.len());
+    for (entry, inode) in entries.iter().zip(&inodes) {
+        let expected = image.open_entry(entry).unwrap();
+        let (mut a, mut b) = (vec![], vec![]);
+        inode.write_to(&mut a).unwrap();
+        expected.write_to(&mut b).unwrap();
+        assert_eq!(a, b, "{}", entry.name_lossy());
+    }
+    assert!(image.stat_all(&[]).unwrap().is_empty());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn try_exists_errors() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        ("d", Spec::dir([("f", Spec::file("f"))])),
+        ("link", Spec::symlink("d")),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes.clone())).unwrap();
+    assert!(image.try_exists("/d/f").unwrap());
+    assert!(image.try_exists("/").unwrap());
+    // not found, and a file or unfollowed link in the middle of the path
+    assert!(!image.try_exists("/d/g").unwrap());
+    assert!(!image.try_exists("/d/f/x").unwrap());
+    assert!(!image.try_exists("/link/f").unwrap());
+    assert!(image.exists("/d/f") && !image.exists("/d/f/x"));
+
+    // a corrupt directory table is an error, not a missing path
+    let mut corrupt = bytes;
+    let start = image.superblock().directory_table_start() as usize + 2;
+    corrupt[start..start + 8].fill(0xff);
+    let image = Image::new(Cursor::new(corrupt)).unwrap();
+    let err = image.try_exists("/d/f").unwrap_err();
+    assert!(!matches!(
+        err.kind(),
+        ErrorKind::NotFound | ErrorKind::NotADirectory
+    ));
+    assert!(!image.exists("/d/f"));
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn raw_tables() {
+    use crate::image::TableKind;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        ("a", Spec::file("a").with_owner(1000, 1000)),
+        ("b", Spec::file("b").with_xattr("user.k", "v")),
+        ("d", Spec::dir([("c", Spec::fifo())])),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let sb = *image.superblock();
+
+    let ids = image.raw_table(TableKind::Id).unwrap();
+    assert_eq!(ids.len(), sb.no_ids() as usize * 4);
+    let ids: Vec<u32> = decode_le_slice(&ids).unwrap();
+    assert_eq!(ids, image.id_table().unwrap().ids());
+
+    // every inode, back to back, the root last
+    let table = image.raw_table(TableKind::Inode).unwrap();
+    let mut rest = &table[..];
+    let mut inodes = vec![];
+    while !rest.is_empty() {
+        inodes.push(read_inode_header(&mut rest, &sb).unwrap());
+    }
+    assert_eq!(inodes.len(), sb.inodes() as usize);
+    assert_eq!(
+        inodes.last().unwrap().inode_number(),
+        image.root().unwrap().inode_number()
+    );
+
+    let InodeHeader::Directory(root) = image.root().unwrap() else {
+        unreachable!()
+    };
+    let listings = image.raw_table(TableKind::Directory).unwrap();
+    // a single directory block, so offsets into it are offsets in the table
+    assert_eq!(root.start_block(), 0);
+    let start = root.offset() as usize;
+    let listed =
+        read_directory_listing(&mut &listings[start..], root.file_size() as u64 - 3).unwrap();
+    let names: Vec<&[u8]> = listed.iter().map(|e| e.name()).collect();
+    assert_eq!(names, [&b"a"[..], b"b", b"d"]);
+
+    let fragments = image.raw_table(TableKind::Fragment).unwrap();
+    assert_eq!(fragments.len(), sb.fragments() as usize * 16);
+    assert!(!fragments.is_empty());
+    assert_eq!(
+        image.raw_table(TableKind::XattrId).unwrap().len(),
+        image.xattr_ids().unwrap().len() * 16
+    );
+    assert!(!image.raw_table(TableKind::Xattr).unwrap().is_empty());
+    // generated images have no export table
+    assert!(image.raw_table(TableKind::Export).unwrap().is_empty());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn open_by_ref_straddling() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    // long symlink inodes, so some straddle metadata block boundaries
+    let entries = (0..200).map(|i| {
+        let target = format!("{:03}", i).repeat(50 + i % 7);
+        (format!("{:03}", i), Spec::symlink(target))
+    });
+    let bytes = generate(&Spec::dir(entries), &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let root_ref = image.superblock().root_inode_ref();
+    assert_eq!(
+        image.open_by_ref(root_ref).unwrap().inode_number(),
+        image.root().unwrap().inode_number()
+    );
+
+    let mut straddling = 0;
+    for entry in image.list_dir("/").unwrap() {
+        let inode = image.open_by_ref(entry.inode_ref()).unwrap();
+        assert_eq!(inode.inode_number(), entry.inode_number());
+        let InodeHeader::Symlink(link) = &inode else {
+            panic!("{} is not a symlink", entry.name_lossy())
+        };
+        let i: usize = entry.name_lossy().parse().unwrap();
+        assert_eq!(
+            link.target(),
+            format!("{:03}", i).repeat(50 + i % 7).as_bytes()
+        );
+        let mut written = vec![];
+        inode.write_to(&mut written).unwrap();
+        if entry.inode_ref().offset() as usize + written.len() > crate::METADATA_SIZE {
+            straddling += 1;
+        }
+    }
+    assert!(straddling > 0);
+
+    // past the end of the inode table
+    let table = image.superblock().directory_table_start() - image.superblock().inode_table_start();
+    assert!(image
+        .open_by_ref(InodeRef::new(table as u32 + 100, 0))
+        .is_err());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn disk_usage_counts() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let big: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let root = Spec::dir([
+        ("big", Spec::file(big)),
+        ("sub", Spec::dir([("small", Spec::file(vec![7; 100]))])),
+        // sparse, and a whole number of blocks so no fragment
+        ("zeros", Spec::file(vec![0; 3 * 131072])),
+        ("link", Spec::symlink("big")),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+
+    let InodeHeader::Regular(big) = image.lookup("/big").unwrap() else {
+        unreachable!()
+    };
+    let big_blocks: u64 = big
+        .blocks()
+        .iter()
+        .map(|b| (b & !COMPRESSED_BIT_BLOCK) as u64)
+        .sum();
+    assert_eq!(big.blocks().len(), 2);
+
+    let usage = image.disk_usage("/").unwrap();
+    assert_eq!(usage.apparent_size, 300_000 + 100 + 3 * 131072);
+    assert_eq!(usage.disk_size, big_blocks);
+    assert_eq!(usage.fragment_bytes, (300_000 - 2 * 131072) + 100);
+
+    let sub = image.disk_usage("/sub").unwrap();
+    assert_eq!(
+        (sub.apparent_size, sub.disk_size, sub.fragment_bytes),
+        (100, 0, 100)
+    );
+    assert_eq!(image.disk_usage("/zeros").unwrap().disk_size, 0);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn dir_size_counts() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        (
+            "a",
+            Spec::dir([
+                ("f", Spec::file(vec![1; 1000])),
+                ("b", Spec::dir([("g", Spec::file(vec![2; 200_000]))])),
+                // a loop, never followed
+                ("up", Spec::symlink("..")),
+            ]),
+        ),
+        ("h", Spec::file("h")),
+        ("dev", Spec::dir([("null", Spec::char_device(0x0103))])),
+        ("fifo", Spec::fifo()),
+        ("sock", Spec::socket()),
+        ("empty", Spec::dir::<&str>([])),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+
+    let all = image.dir_size("/").unwrap();
+    assert_eq!(
+        (all.files, all.directories, all.symlinks, all.others),
+        (3, 4, 1, 3)
+    );
+    assert_eq!(all.size, 1000 + 200_000 + 1);
+    let a = image.dir_size("/a").unwrap();
+    assert_eq!((a.files, a.directories, a.symlinks, a.others), (2, 1, 1, 0));
+    assert_eq!(a.size, 201_000);
+    assert_eq!(image.dir_size("/empty").unwrap(), Default::default());
+
+    let err = image.dir_size("/h").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(
+        image.dir_size("/missing").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn image_cache_stats() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([(
+        "d",
+        Spec::dir([("f", Spec::file("f")), ("g", Spec::file("g"))]),
+    )]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let before = image.cache_stats();
+    let f = image.lookup("/d/f").unwrap();
+    let first = image.cache_stats();
+    assert!(first.inodes.misses > before.inodes.misses);
+    assert!(first.metadata_blocks.entries > 0);
+    assert!(first.metadata_blocks.bytes > 0);
+    assert!(first.inodes.entries > 0);
+
+    // the same path again is served from the caches
+    assert_eq!(
+        image.lookup("/d/f").unwrap().inode_number(),
+        f.inode_number()
+    );
+    let second = image.cache_stats();
+    assert_eq!(second.inodes.misses, first.inodes.misses);
+    assert!(second.inodes.hits > first.inodes.hits);
+    assert_eq!(second.metadata_blocks.misses, first.metadata_blocks.misses);
+    assert_eq!(
+        second.metadata_blocks.entries,
+        first.metadata_blocks.entries
+    );
+    image.lookup("/d/g").unwrap();
+    assert!(image.cache_stats().inodes.entries > second.inodes.entries);
+}
+
+#[test]
+fn sqsh_path() {
+    let path = SqshPath::new(b"a//./b/../c\xe9/");
+    assert_eq!(path.as_bytes(), b"/a/c\xe9");
+    assert_eq!(SqshPath::new("../.."), SqshPath::root());
+    assert_eq!(path.components().collect::<Vec<_>>(), [&b"a"[..], b"c\xe9"]);
+    assert_eq!(path.parent(), Some(SqshPath::new("a")));
+    let base = SqshPath::new("/a");
+    let below: Vec<&[u8]> = path.strip_prefix(&base).unwrap().collect();
+    assert_eq!(below, [&b"c\xe9"[..]]);
+    assert!(SqshPath::new("/ab").strip_prefix(&base).is_none());
+    assert!(path.join(b"..").is_err());
+    assert!(path.join(b"d/e").is_err());
+}
+
+#[cfg(feature = "index")]
+#[test]
+fn content_index_round_trip() {
+    use crate::index::ContentIndex;
+
+    let mut old = ContentIndex::default();
+    old.insert([1; 32], b"/etc/hosts".to_vec());
+    old.insert([1; 32], b"/odd\\name\n".to_vec());
+    old.insert([2; 32], b"/bin/sh".to_vec());
+    let mut saved = vec![];
+    old.write_to(&mut saved).unwrap();
+    assert!(saved.starts_with(b"0101"));
+    assert_eq!(ContentIndex::read_from(&saved[..]).unwrap(), old);
+
+    let mut new = ContentIndex::default();
+    new.insert([1; 32], b"/etc/hosts".to_vec());
+    new.insert([3; 32], b"/bin/sh".to_vec());
+    new.insert([3; 32], b"/bin/bash".to_vec());
+    let changes = old.changes(&new);
+    assert_eq!(changes.added, vec![b"/bin/bash".to_vec()]);
+    assert_eq!(changes.removed, vec![b"/odd\\name\n".to_vec()]);
+    assert_eq!(changes.modified, vec![b"/bin/sh".to_vec()]);
+}
+
+#[test]
+fn delta_round_trip() {
+    use crate::delta::{apply, diff};
+
+    let mut seed = 7u32;
+    let mut noise = |len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    };
+    let blocks: Vec<Vec<u8>> = (0..6).map(|_| noise(4096)).collect();
+    let mut old = test_superblock_bytes().to_vec();
+    old.extend(blocks.concat());
+    // drop a block, move one, and add a new one
+    let mut new = test_superblock_bytes().to_vec();
+    for b in [0, 2, 5, 3, 4] {
+        new.extend(&blocks[b]);
+    }
+    new.extend(noise(1000));
+
+    let mut patch = vec![];
+    diff(&old, &new, &mut patch).unwrap();
+    assert!(patch.len() < 1200, "patch too large: {}", patch.len());
+    let mut rebuilt = vec![];
+    apply(&old, &patch[..], &mut rebuilt).unwrap();
+    assert_eq!(rebuilt, new);
+
+    assert!(apply(&new, &patch[..], &mut vec![]).is_err());
+}
+
+#[cfg(feature = "index")]
+#[test]
+fn chunk_boundaries_resync() {
+    use crate::chunk::{ChunkIndex, Chunker};
+
+    let mut seed = 1u32;
+    let data: Vec<u8> = (0..200_000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect();
+    let chunker = Chunker::new(512, 2048, 8192);
+    let old = chunker.chunks(&data[..]).unwrap();
+    assert_eq!(old.size(), data.len() as u64);
+    assert!(old.chunks().iter().all(|c| c.size <= 8192));
+
+    let mut edited = b"inserted".to_vec();
+    edited.extend_from_slice(&data);
+    let new = chunker.chunks(&edited[..]).unwrap();
+    let shared = new
+        .chunks()
+        .iter()
+        .filter(|c| old.chunks().iter().any(|o| o.digest == c.digest))
+        .count();
+    assert!(shared + 2 >= new.chunks().len());
+
+    let mut saved = vec![];
+    new.write_to(&mut saved).unwrap();
+    assert_eq!(ChunkIndex::read_from(&saved[..]).unwrap(), new);
+}
+
+#[test]
+fn xattr_entry() {
+    let mut entry = vec![];
+    entry.extend_from_slice(&2u16.to_le_bytes());
+    entry.extend_from_slice(&7u16.to_le_bytes());
+    entry.extend_from_slice(b"selinux");
+    entry.extend_from_slice(&4u32.to_le_bytes());
+    entry.extend_from_slice(b"ctx\0");
+    let (xattr, out_of_line) = Xattr::from_reader(&mut &entry[..]).unwrap();
+    assert_eq!(xattr.name, b"security.selinux");
+    assert_eq!(xattr.value, b"ctx\0");
+    assert!(!out_of_line);
+
+    entry[0] = 7;
+    assert!(Xattr::from_reader(&mut &entry[..]).is_err());
+}
+
+#[test]
+fn id_map() {
+    let map = IdMap::parse(&b"# container\n0 100000 1000\n1000 1000 1\n"[..]).unwrap();
+    assert_eq!(map.map(0), 100000);
+    assert_eq!(map.map(999), 100999);
+    assert_eq!(map.map(1000), 1000);
+    assert_eq!(map.map(1001), 65534);
+    assert_eq!(map.unmap(100005), 5);
+    assert!(IdMap::parse(&b"0 100000\n"[..]).is_err());
+}
+
+#[test]
+fn file_capabilities() {
+    let mut value = vec![];
+    value.extend_from_slice(&0x0300_0001u32.to_le_bytes());
+    value.extend_from_slice(&((1u32 << 10) | (1 << 13)).to_le_bytes());
+    value.extend_from_slice(&(1u32 << 13).to_le_bytes());
+    value.extend_from_slice(&(1u32 << 6).to_le_bytes());
+    value.extend_from_slice(&0u32.to_le_bytes());
+    value.extend_from_slice(&1000u32.to_le_bytes());
+    let caps = FileCapabilities::from_bytes(&value).unwrap();
+    assert_eq!(caps.version, 3);
+    assert_eq!(caps.effective_set(), (1 << 10) | (1 << 13) | (1 << 38));
+    assert_eq!(caps.inheritable, 1 << 13);
+    assert_eq!(caps.rootid, Some(1000));
+    assert_eq!(
+        caps.to_string(),
+        "cap_net_raw=eip cap_net_bind_service,cap_perfmon=ep"
+    );
+
+    assert!(FileCapabilities::from_bytes(&value[..20]).is_err());
+}
+
+#[test]
+fn posix_acl() {
+    let mut value = 2u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in [
+        (0x01u16, 7u16, u32::MAX),
+        (0x02, 5, 1000),
+        (0x04, 5, u32::MAX),
+        (0x10, 5, u32::MAX),
+        (0x20, 0, u32::MAX),
+    ] {
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&perm.to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+    let acl = PosixAcl::from_bytes(&value).unwrap();
+    assert_eq!(
+        acl.to_string(),
+        "user::rwx,user:1000:r-x,group::r-x,mask::r-x,other::---"
+    );
+    assert!(PosixAcl::from_bytes(&value[..value.len() - 1]).is_err());
+}
+
+#[cfg(feature = "selinux")]
+#[test]
+fn file_contexts_lookup() {
+    use crate::selinux::FileContexts;
+
+    let spec = b"
+# comment
+/.*                  u:object_r:default_t:s0
+/etc(/.*)?           u:object_r:etc_t:s0
+/etc/shadow      --  u:object_r:shadow_t:s0
+/etc/.*\\.conf       u:object_r:conf_t:s0
+/proc(/.*)?          <<none>>
+";
+    let fc = FileContexts::parse(&spec[..]).unwrap();
+    assert_eq!(
+        fc.lookup(b"/usr", 'd'),
+        Some(&b"u:object_r:default_t:s0"[..])
+    );
+    assert_eq!(
+        fc.lookup(b"/etc/a.conf", '-'),
+        Some(&b"u:object_r:conf_t:s0"[..])
+    );
+    assert_eq!(
+        fc.lookup(b"/etc/shadow", '-'),
+        Some(&b"u:object_r:shadow_t:s0"[..])
+    );
+    assert_eq!(
+        fc.lookup(b"/etc/shadow", 'd'),
+        Some(&b"u:object_r:etc_t:s0"[..])
+    );
+    assert_eq!(fc.lookup(b"/proc/1", '-'), None);
+}
+
+#[test]
+fn metadata_writer() {
+    let compressor = Compressor::GZIP(Default::default());
+    let mut writer = MetadataWriter::new(compressor.clone());
+    let data: Vec<u8> = (0..20000u32).map(|i| (i % 7) as u8).collect();
+    writer.write_all(&data).unwrap();
+    let position = writer.position();
+    assert_eq!(writer.block_offsets().len(), 2);
+    assert!(position.block() as u64 > writer.block_offsets()[1]);
+    assert_eq!(position.offset() as usize, 20000 - 2 * crate::METADATA_SIZE);
+    let table = writer.finish().unwrap();
+
+    let mut reader = Cursor::new(&table);
+    let mut read = vec![];
+    let mut start = 0;
+    while start < table.len() as u64 {
+        let header = u16::from_le_bytes([table[start as usize], table[start as usize + 1]]);
+        assert_eq!(header & 0x8000, 0);
+        start += read_block(&mut reader, &mut read, &compressor, start, None).unwrap() as u64;
+    }
+    assert_eq!(read, data);
+
+    // blocks that don't shrink are stored as is
+    let mut writer = MetadataWriter::new(compressor);
+    writer.write_all(&[0x5a]).unwrap();
+    assert_eq!(writer.finish().unwrap(), [0x01, 0x80, 0x5a]);
+}
+
+#[test]
+fn indexed_table_reader() {
+    let compressor = Compressor::GZIP(Default::default());
+    // a table ending in a partial block, then ones filling their last block
+    for bytes in [100, crate::METADATA_SIZE, 3 * crate::METADATA_SIZE] {
+        let data: Vec<u8> = (0..bytes as u32).map(|i| (i % 251) as u8).collect();
+        let mut writer = MetadataWriter::new(compressor.clone());
+        writer.write_all(&data).unwrap();
+        let (mut image, blocks) = writer.finish_indexed().unwrap();
+        let index_start = image.len() as u64;
+        for block in &blocks {
+            image.extend_from_slice(&block.to_le_bytes());
+        }
+
+        let mut reader = Cursor::new(&image);
+        let index = read_table_index(&mut reader, index_start, bytes).unwrap();
+        assert_eq!(index, blocks);
+        let table = IndexedTableReader::new(&mut reader, &compressor, index.clone(), bytes)
+            .read_table()
+            .unwrap();
+        assert_eq!(table, data);
+
+        // blocks holding more or less than their position implies
+        assert!(
+            IndexedTableReader::new(&mut reader, &compressor, index, bytes - 1)
+                .read_table()
+                .is_err()
+        );
+        assert!(
+            read_table_index(&mut reader, index_start, bytes + 8 * crate::METADATA_SIZE).is_err()
+        );
+    }
+}
+
+#[test]
+fn directory_writer() {
+    let compressor = Compressor::GZIP(Default::default());
+    let mut writer = DirectoryWriter::new(compressor.clone());
+    let entry = |i: u32| NewDirectoryEntry {
+        name: format!("file-{:05}", i * 7919 % 3000).into_bytes(),
+        inode: InodeRef::new(if i < 1000 { 0 } else { 8000 }, (i % 1000 * 32) as u16),
+        inode_number: i + 2,
+        file_type: FileType::Regular,
+    };
+    let small = writer.write_dir(vec![entry(0), entry(1)]).unwrap();
+    assert!(small.index.is_empty());
+    let entries: Vec<_> = (0..3000).map(entry).collect();
+    let large = writer.write_dir(entries.clone()).unwrap();
+    assert!(writer.write_dir(vec![entry(0), entry(0)]).is_err());
+    let table = writer.finish().unwrap();
+
+    // decompress the table, noting where each block starts
+    let mut reader = Cursor::new(&table);
+    let mut data = vec![];
+    let mut blocks = HashMap::new();
+    let mut start = 0;
+    while start < table.len() as u64 {
+        blocks.insert(start as u32, data.len());
+        start += read_block(&mut reader, &mut data, &compressor, start, None).unwrap() as u64;
+    }
+    let listing_start = blocks[&large.start.block()] + large.start.offset() as usize;
+    let listing =
+        read_directory_listing(&mut &data[listing_start..], large.file_size as u64 - 3).unwrap();
+    let mut sorted = entries;
+    sorted.sort_by(|a, b| a.name.cmp(&b.name));
+    assert_eq!(listing.len(), sorted.len());
+    for (read, written) in listing.iter().zip(&sorted) {
+        assert_eq!(read.name(), written.name);
+        assert_eq!(read.inode_ref(), written.inode);
+        assert_eq!(read.inode_number(), written.inode_number);
+    }
+
+    // every index entry points to a header starting with its name
+    assert!(large.index.len() >= 3);
+    for index in &large.index {
+        let offset =
+            (large.start.offset() as usize + index.index() as usize) % crate::METADATA_SIZE;
+        let mut listing = &data[blocks[&index.start_block()] + offset..];
+        let header = DirectoryHeader::from_reader(&mut listing).unwrap();
+        let entry = DirectoryEntry::from_reader(&header, &mut listing).unwrap();
+        assert_eq!(entry.name(), index.name());
+    }
+}
+
+#[test]
+fn inode_views() {
+    // named pipe, inode number 3, followed by a symlink to "a/b"
+    let mut table = vec![];
+    for field in [6u16, 0o644, 0, 0, 0, 0, 3, 0, 1, 0] {
+        table.extend_from_slice(&field.to_le_bytes());
+    }
+    for field in [3u16, 0o777, 0, 0, 0, 0, 4, 0, 1, 0, 3, 0] {
+        table.extend_from_slice(&field.to_le_bytes());
+    }
+    table.extend_from_slice(b"a/b");
+
+    let sb = test_superblock();
+    let views: Vec<_> = InodeViews::new(&table, &sb)
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(views.len(), 2);
+    assert_eq!(views[0].file_type(), Some(FileType::Fifo));
+    assert_eq!(views[1].offset(), 20);
+    assert_eq!(views[1].inode_number(), 4);
+    assert_eq!(views[1].mode(), 0o777);
+    match views[1].to_header(&sb).unwrap() {
+        InodeHeader::Symlink(s) => assert_eq!(s.target(), b"a/b"),
+        _ => panic!("not a symlink"),
+    }
+
+    // a truncated inode ends the scan with an error
+    let mut views = InodeViews::new(&table[..table.len() - 1], &sb);
+    assert!(views.next().unwrap().is_ok());
+    assert!(views.next().unwrap().is_err());
+    assert!(views.next().is_none());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn generated_image() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let many: Vec<_> = (0..2000)
+        .map(|i| (format!("{:04}", i), Spec::fifo()))
+        .collect();
+    let root = Spec::dir([
+        ("data", Spec::file(data.clone()).with_owner(1000, 1000)),
+        ("many", Spec::dir(many)),
+        ("link", Spec::symlink("data")),
+    ]);
+    let options = GenerateOptions {
+        uid_map: Some(IdMap::new(vec![crate::idmap::IdRange {
+            inside: 0,
+            outside: 1000,
+            count: 1,
+        }])),
+        ..Default::default()
+    };
+    let image = Image::new(Cursor::new(generate(&root, &options).unwrap())).unwrap();
+    assert!(image.warnings().is_empty());
+
+    let inode = image.lookup("/data").unwrap();
+    let mut read = vec![];
+    image
+        .open_file(&inode)
+        .unwrap()
+        .read_to_end(&mut read)
+        .unwrap();
+    assert_eq!(read, data);
+    let ids = image.id_table().unwrap();
+    assert_eq!(ids.owner(&inode).unwrap(), (0, 1000));
+    assert_eq!(ids[inode.guid()], 1000);
+    assert_eq!(ids.iter().count(), ids.len());
+    assert_eq!(ids.get(ids.len() as u16), None);
+    for (index, id) in ids.iter().enumerate() {
+        assert_eq!(image.id(index as u16).unwrap(), *id);
+    }
+    assert!(image.id(ids.len() as u16).is_err());
+
+    assert!(matches!(
+        image.lookup("/many").unwrap(),
+        InodeHeader::LDirectory(_)
+    ));
+    let many = image.list_dir("/many").unwrap();
+    assert_eq!(many.len(), 2000);
+    let InodeHeader::LDirectory(dir) = image.lookup("/many").unwrap() else {
+        unreachable!()
+    };
+    let (header, entries) = image
+        .read_dir_header(dir.start_block(), dir.offset())
+        .unwrap();
+    assert_eq!(entries.len(), header.count() as usize + 1);
+    for (entry, listed) in entries.iter().zip(&many) {
+        assert_eq!(entry.name(), listed.name());
+    }
+    assert_eq!(image.walk("/").unwrap().count(), 2004);
+
+    let fs = image.read_fs().unwrap();
+    assert_eq!(fs.inodes().len(), 2004);
+    assert!(fs.root().is_dir());
+    assert!(fs.id_table().ids().contains(&1000));
+    assert!(fs.export_table().is_empty() && fs.xattr_ids().is_empty());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn generated_many_ids() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    // 6000 ids of 4 bytes span three 8KiB metadata blocks
+    let entries: Vec<_> = (0..3000u32)
+        .map(|i| {
+            let spec = Spec::fifo().with_owner(100_000 + 2 * i, 100_001 + 2 * i);
+            (format!("{:04}", i), spec)
+        })
+        .collect();
+    let bytes = generate(&Spec::dir(entries), &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes.clone())).unwrap();
+    let ids = image.id_table().unwrap();
+    assert_eq!(ids.len(), 6001);
+    assert_eq!(image.superblock().no_ids(), 6001);
+    let index = read_table_index(
+        &mut Cursor::new(&bytes),
+        image.superblock().id_table_start(),
+        ids.len() * 4,
+    )
+    .unwrap();
+    assert_eq!(index.len(), 3);
+    for (index, id) in ids.iter().enumerate() {
+        assert_eq!(image.id(index as u16).unwrap(), *id);
+    }
+    assert!(image.id(6001).is_err());
+
+    for i in [0, 1024, 2047, 2999] {
+        let inode = image.lookup(format!("/{:04}", i)).unwrap();
+        let (uid, gid) = ids.owner(&inode).unwrap();
+        assert_eq!((uid, gid), (100_000 + 2 * i, 100_001 + 2 * i));
+        assert_eq!(image.id(inode.uid()).unwrap(), uid);
+        assert_eq!(image.id(inode.guid()).unwrap(), gid);
+    }
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn generated_xattrs() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+    use crate::xattr::capabilities;
+
+    let mut capability = 0x0200_0001u32.to_le_bytes().to_vec();
+    capability.extend_from_slice(&(1u32 << 13).to_le_bytes());
+    capability.extend_from_slice(&[0; 12]);
+    let label = b"system_u:object_r:bin_t:s0\0".to_vec();
+    let long: Vec<u8> = (0..300u32).map(|i| (i % 251) as u8).collect();
+    // enough listed files for the store to span several metadata blocks
+    let many: Vec<_> = (0..400)
+        .map(|i| {
+            let spec = Spec::file(format!("{}", i))
+                .with_xattr("user.index", format!("{:020}", i))
+                .with_xattr("user.long", long.clone());
+            (format!("{:03}", i), spec)
+        })
+        .collect();
+    let root = Spec::dir([
+        (
+            "ping",
+            Spec::file("ping")
+                .with_xattr("security.capability", capability.clone())
+                .with_xattr("security.selinux", label.clone())
+                .with_xattr("user.long", long.clone())
+                .with_xattr("trusted.x", "y"),
+        ),
+        ("link", Spec::symlink("ping").with_xattr("trusted.x", "y")),
+        (
+            "null",
+            Spec::char_device(0x0103).with_xattr("trusted.x", "y"),
+        ),
+        ("fifo", Spec::fifo().with_xattr("user.long", long.clone())),
+        ("many", Spec::dir(many).with_xattr("user.dir", "")),
+        ("plain", Spec::file("plain")),
+    ]);
+    let image = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(image)).unwrap();
+    assert!(!image
+        .superblock()
+        .flags()
+        .contains(Flags::NO_XATTRS_IN_ARCHIVE));
+    assert!(
+        image
+            .metadata_blocks(crate::image::TableKind::Xattr)
+            .unwrap()
+            .len()
+            > 1
+    );
+
+    let xattrs = |path: &str| -> Vec<(Vec<u8>, Vec<u8>)> {
+        let inode = image.lookup(path).unwrap();
+        image
+            .xattrs(&inode)
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.name, x.value))
+            .collect()
+    };
+    assert_eq!(
+        xattrs("/ping"),
+        [
+            (b"security.capability".to_vec(), capability),
+            (b"security.selinux".to_vec(), label),
+            (b"user.long".to_vec(), long.clone()),
+            (b"trusted.x".to_vec(), b"y".to_vec()),
+        ]
+    );
+    assert_eq!(xattrs("/link"), [(b"trusted.x".to_vec(), b"y".to_vec())]);
+    assert_eq!(xattrs("/null"), xattrs("/link"));
+    assert_eq!(xattrs("/fifo"), [(b"user.long".to_vec(), long.clone())]);
+    assert_eq!(xattrs("/many"), [(b"user.dir".to_vec(), vec![])]);
+    assert!(xattrs("/plain").is_empty());
+    for i in [0, 199, 399] {
+        assert_eq!(
+            xattrs(&format!("/many/{:03}", i)),
+            [
+                (b"user.index".to_vec(), format!("{:020}", i).into_bytes()),
+                (b"user.long".to_vec(), long.clone()),
+            ]
+        );
+    }
+    assert!(matches!(
+        image.lookup("/link").unwrap(),
+        InodeHeader::LSymlink(_)
+    ));
+    assert!(matches!(
+        image.lookup("/null").unwrap(),
+        InodeHeader::LDev(_)
+    ));
+    assert!(matches!(
+        image.lookup("/fifo").unwrap(),
+        InodeHeader::LIPC(_)
+    ));
+    assert!(matches!(
+        image.lookup("/plain").unwrap(),
+        InodeHeader::Regular(_)
+    ));
+    // identical lists share an xattr id
+    assert_eq!(image.xattr_ids().unwrap().len(), 404);
+
+    let caps = capabilities(&image).unwrap();
+    assert_eq!(caps.len(), 1);
+    assert_eq!(caps[0].0, b"/ping");
+    assert_eq!(caps[0].1.to_string(), "cap_net_raw=ep");
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn lookup_symlinks() {
+    use crate::image::LookupOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    // l0 -> l1 -> ... -> l40 -> etc/motd
+    let mut entries: Vec<(String, Spec)> = (0..41)
+        .map(|i| {
+            let target = match i {
+                40 => "etc/motd".to_string(),
+                i => format!("l{}", i + 1),
+            };
+            (format!("l{}", i), Spec::symlink(target))
+        })
+        .collect();
+    entries.extend([
+        (
+            "etc".to_string(),
+            Spec::dir([
+                ("motd", Spec::file("motd")),
+                ("link", Spec::symlink("motd")),
+            ]),
+        ),
+        ("abs".to_string(), Spec::symlink("/etc")),
+        ("rel".to_string(), Spec::symlink("etc")),
+        ("up".to_string(), Spec::symlink("../../../etc")),
+        ("loop_a".to_string(), Spec::symlink("loop_b")),
+        ("loop_b".to_string(), Spec::symlink("loop_a")),
+    ]);
+    let bytes = generate(&Spec::dir(entries), &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let motd = image.lookup("/etc/motd").unwrap().inode_number();
+    let etc = image.lookup("/etc").unwrap().inode_number();
+    let resolve = |path: &str, options: &LookupOptions| {
+        image.lookup_with(path, options).map(|i| i.inode_number())
+    };
+    let follow_all = *LookupOptions::new()
+        .follow_intermediate(true)
+        .follow_final(true);
+    let follow_intermediate = *LookupOptions::new().follow_intermediate(true);
+    let follow_final = *LookupOptions::new().follow_final(true);
+
+    // the final component is only followed when asked
+    assert!(matches!(
+        image.lookup("/etc/link").unwrap(),
+        InodeHeader::Symlink(_)
+    ));
+    assert!(matches!(
+        image
+            .lookup_with("/etc/link", &follow_intermediate)
+            .unwrap(),
+        InodeHeader::Symlink(_)
+    ));
+    assert_eq!(resolve("/etc/link", &follow_final).unwrap(), motd);
+    assert_eq!(resolve("/abs", &follow_final).unwrap(), etc);
+
+    // links in the middle of the path
+    let err = image.lookup("/abs/motd").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotADirectory);
+    assert_eq!(
+        resolve("/abs/motd", &follow_final).unwrap_err().kind(),
+        ErrorKind::NotADirectory
+    );
+    for path in ["/abs/motd", "/rel/motd", "/rel/../etc/motd"] {
+        assert_eq!(
+            resolve(path, &follow_intermediate).unwrap(),
+            motd,
+            "{}",
+            path
+        );
+    }
+    // `..` after a link to a file is not a directory, as in Linux
+    assert_eq!(
+        resolve("/rel/link/../motd", &follow_intermediate)
+            .unwrap_err()
+            .kind(),
+        ErrorKind::NotADirectory
+    );
+    // a trailing slash makes the last component an intermediate one
+    assert_eq!(resolve("/abs/", &follow_intermediate).unwrap(), etc);
+
+    // `..` stops at the root, in paths and in link targets
+    assert_eq!(resolve("/../../etc/motd", &follow_all).unwrap(), motd);
+    assert_eq!(resolve("/etc/../../etc/motd", &follow_all).unwrap(), motd);
+    assert_eq!(resolve("/up/motd", &follow_all).unwrap(), motd);
+    // absolute targets start from the image root, not the link's directory
+    assert_eq!(resolve("/etc/../abs/link", &follow_all).unwrap(), motd);
+
+    // 40 links are followed, as in Linux
+    assert_eq!(resolve("/l1", &follow_all).unwrap(), motd);
+    let err = resolve("/l0", &follow_all).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("too many levels"));
+    assert_eq!(
+        resolve("/l0", follow_all.clone().max_symlinks(41)).unwrap(),
+        motd
+    );
+    let err = resolve("/loop_a", &follow_all).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(
+        resolve("/loop_a/x", &follow_intermediate)
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidData
+    );
+}
+
+#[cfg(feature = "testing")]
+#[derive(Debug, Default)]
+struct RecordedMetrics {
+    bytes_read: Mutex<u64>,
+    // decompressed bytes by block kind
+    blocks: Mutex<HashMap<BlockKind, u64>>,
+    cache: Mutex<Vec<(CacheKind, bool)>>,
+    phases: Mutex<Vec<Phase>>,
+}
+
+#[cfg(feature = "testing")]
+impl Metrics for RecordedMetrics {
+    fn bytes_read(&self, bytes: u64) {
+        *self.bytes_read.lock().unwrap() += bytes;
+    }
+
+    fn block_decompressed(&self, kind: BlockKind, _: u64, uncompressed: u64, _: Duration) {
+        *self.blocks.lock().unwrap().entry(kind).or_default() += uncompressed;
+    }
+
+    fn cache_hit(&self, cache: CacheKind) {
+        self.cache.lock().unwrap().push((cache, true));
+    }
+
+    fn cache_miss(&self, cache: CacheKind) {
+        self.cache.lock().unwrap().push((cache, false));
+    }
+
+    fn phase(&self, phase: Phase, _: Duration) {
+        self.phases.lock().unwrap().push(phase);
+    }
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn image_metrics() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let root = Spec::dir([("data", Spec::file(data.clone()))]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let metrics = Arc::new(RecordedMetrics::default());
+    let image = Image::with_metrics(Cursor::new(bytes), metrics.clone()).unwrap();
+    assert_eq!(*metrics.phases.lock().unwrap(), [Phase::Open]);
+
+    let inode = image.lookup("/data").unwrap();
+    image
+        .open_file(&inode)
+        .unwrap()
+        .read_to_end(&mut vec![])
+        .unwrap();
+    image.inodes().unwrap();
+    let dirent = &image.list_dir("/").unwrap()[0];
+    image.open_entry(dirent).unwrap();
+    image.open_entry(dirent).unwrap();
+
+    assert!(*metrics.bytes_read.lock().unwrap() > 0);
+    assert_eq!(metrics.blocks.lock().unwrap()[&BlockKind::Data], 300_000);
+    assert!(metrics.blocks.lock().unwrap()[&BlockKind::Metadata] > 0);
+    let cache = metrics.cache.lock().unwrap();
+    let events = |kind| -> Vec<bool> {
+        cache
+            .iter()
+            .filter(|(k, _)| *k == kind)
+            .map(|(_, hit)| *hit)
+            .collect()
+    };
+    // the lookup loads the inode, and both opens hit the cache
+    assert_eq!(events(CacheKind::Inodes), [false, true, true]);
+    // the inode and directory blocks are read once, by the first lookup
+    assert_eq!(
+        events(CacheKind::MetadataBlocks),
+        [false, false, true, true, true]
+    );
+    let phases = metrics.phases.lock().unwrap();
+    assert!(phases.contains(&Phase::Lookup));
+    assert!(phases.contains(&Phase::TableScan));
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn link_counts() {
+    use crate::compressors::GzipCompressor;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        (
+            "dir",
+            Spec::dir([("sub", Spec::dir(Vec::<(&str, Spec)>::new()))]),
+        ),
+        ("file", Spec::file("contents")),
+        ("link", Spec::symlink("link-target")),
+    ]);
+    // metadata compressed at level 0 is stored as is, so it can be patched
+    let mut gzip = GzipCompressor::default();
+    gzip.set_compression_level(0);
+    let options = GenerateOptions {
+        compressor: Compressor::GZIP(gzip),
+        ..Default::default()
+    };
+    let mut bytes = generate(&root, &options).unwrap();
+    let image = Image::new(Cursor::new(bytes.clone())).unwrap();
+    assert_eq!(image.check_links().unwrap(), []);
+    let number = image.lookup("/link").unwrap().inode_number();
+
+    // nlink and target size precede the target of a symlink inode
+    let target = bytes.windows(11).position(|w| w == b"link-target").unwrap();
+    assert_eq!(bytes[target - 8..target - 4], 1u32.to_le_bytes());
+    bytes[target - 8..target - 4].copy_from_slice(&3u32.to_le_bytes());
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let expected = Warning::LinkCount {
+        inode_number: number,
+        nlink: 3,
+        expected: 1,
+    };
+    let warnings = image.check_links().unwrap();
+    assert_eq!(warnings, [expected]);
+    assert_eq!(image.warnings(), warnings);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn root_not_directory() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([("file", Spec::file("not a directory"))]);
+    let mut bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes.clone())).unwrap();
+    let file = u64::from(image.list_dir("/").unwrap()[0].inode_ref());
+    bytes[32..40].copy_from_slice(&file.to_le_bytes());
+
+    let mut image = Image::new(Cursor::new(bytes)).unwrap();
+    for error in [image.root().unwrap_err(), image.inodes().unwrap_err()] {
+        assert_eq!(
+            error.get_ref().and_then(|e| e.downcast_ref()),
+            Some(&SuperblockError::RootNotDirectory(FileType::Regular))
+        );
+    }
+
+    image.set_lenient_root(true);
+    assert!(image.root().unwrap().is_file());
+    assert_eq!(image.inodes().unwrap().1.len(), 2);
+    assert_eq!(
+        image.warnings(),
+        [Warning::RootNotDirectory(FileType::Regular)]
+    );
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn entries_in_data_order() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let big: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let root = Spec::dir([
+        ("a", Spec::file("tail only")),
+        ("b", Spec::dir([("c", Spec::file(big.clone()))])),
+        ("d", Spec::symlink("a")),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+
+    let mut paths = vec![];
+    for entry in image.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        assert!(entry.hard_link.is_none());
+        let mut data = vec![];
+        if let Some(reader) = &mut entry.data {
+            reader.read_to_end(&mut data).unwrap();
+        }
+        paths.push((entry.path.to_string(), data.len()));
+    }
+    // the fragment holding the tail of `a` is written after the blocks of `c`
+    assert_eq!(
+        paths,
+        [
+            ("/".to_string(), 0),
+            ("/b".to_string(), 0),
+            ("/d".to_string(), 0),
+            ("/b/c".to_string(), 300_000),
+            ("/a".to_string(), 9),
+        ]
+    );
+}
+
+#[test]
+fn compressor_selection() {
+    let text: Vec<u8> = (0..64 * 1024u32)
+        .flat_map(|i| format!("line {}\n", i % 500).into_bytes())
+        .take(128 * 1024)
+        .collect();
+    let noise: Vec<u8> = (0..4096u32)
+        .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+        .collect();
+    let samples = [&text[..], &noise[..]];
+    let candidates = default_candidates();
+
+    let choice = choose_compressor(&samples, &candidates, SelectionPolicy::BestRatio).unwrap();
+    assert_eq!(choice.trials.len(), candidates.len());
+    let smallest = choice.trials.iter().map(|t| t.output).min().unwrap();
+    assert_eq!(choice.trials[choice.chosen].output, smallest);
+    for trial in &choice.trials {
+        // incompressible blocks are counted as stored
+        assert!(trial.output <= trial.input);
+    }
+    assert_eq!(choice.to_string().matches('*').count(), 1);
+
+    let choice =
+        choose_compressor(&samples, &candidates, SelectionPolicy::FastestWithin(0.0)).unwrap();
+    assert_eq!(choice.trials[choice.chosen].output, smallest);
+    let choice = choose_compressor(
+        &samples,
+        &candidates,
+        SelectionPolicy::MinThroughput(u64::MAX),
+    )
+    .unwrap();
+    let fastest = choice.trials.iter().map(|t| t.elapsed).min().unwrap();
+    assert_eq!(choice.trials[choice.chosen].elapsed, fastest);
+
+    assert!(choose_compressor(&samples, &[], SelectionPolicy::BestRatio).is_err());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn trim_paths() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let root = Spec::dir([
+        (
+            "usr",
+            Spec::dir([
+                ("bin", Spec::dir([("tool", Spec::file(data.clone()))])),
+                (
+                    "share",
+                    Spec::dir([(
+                        "locale",
+                        Spec::dir([
+                            ("de", Spec::dir([("tool.mo", Spec::file("de"))])),
+                            ("fr", Spec::dir([("tool.mo", Spec::file("fr"))])),
+                        ]),
+                    )]),
+                ),
+            ]),
+        ),
+        (
+            "debug",
+            Spec::dir([("tool.debug", Spec::file(data.clone()))]),
+        ),
+        ("readme", Spec::file("kept")),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+
+    let mut out = Cursor::new(vec![]);
+    let trimmed = image.trim(&["debug", "**/*.mo"], &mut out).unwrap();
+    let mut excluded: Vec<_> = trimmed.excluded.iter().map(|p| p.to_string()).collect();
+    excluded.sort();
+    assert_eq!(
+        excluded,
+        [
+            "/debug",
+            "/usr/share/locale/de/tool.mo",
+            "/usr/share/locale/fr/tool.mo"
+        ]
+    );
+
+    let bytes = out.into_inner();
+    assert_eq!(bytes.len() as u64 % PADDING_SIZE, 0);
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    assert_eq!(image.superblock().bytes_used(), trimmed.bytes_used);
+    assert_eq!(image.superblock().inodes(), 9);
+    assert!(!image.exists("debug"));
+    assert!(image.is_dir("usr/share/locale/fr"));
+    assert!(image.list_dir("usr/share/locale/fr").unwrap().is_empty());
+    for (path, expected) in [("usr/bin/tool", &data[..]), ("readme", b"kept")] {
+        let mut read = vec![];
+        let inode = image.lookup(path).unwrap();
+        image
+            .open_file(&inode)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, expected, "{}", path);
+    }
+    assert_eq!(image.check_links().unwrap(), []);
+    assert!(image.warnings().is_empty());
+}
+
+#[cfg(all(unix, feature = "testing"))]
+#[test]
+fn extract_conflicts() {
+    use crate::extract::{Conflict, ExtractOptions};
+    use crate::testing::{generate, GenerateOptions, Spec};
+    use std::fs;
+
+    let root = Spec::dir([
+        ("etc", Spec::dir([("conf", Spec::file("new"))])),
+        ("readme", Spec::file("new")),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let dest = std::env::temp_dir().join(format!("squashfs-extract-{}", std::process::id()));
+    let read = |path: &str| fs::read_to_string(dest.join(path)).unwrap();
+
+    for (on_conflict, merge) in [
+        (Conflict::Error, false),
+        (Conflict::Error, true),
+        (Conflict::Skip, true),
+        (Conflict::Overwrite, true),
+        (Conflict::Rename, false),
+    ] {
+        let _ = fs::remove_dir_all(&dest);
+        fs::create_dir_all(dest.join("etc")).unwrap();
+        fs::write(dest.join("etc/conf"), "old").unwrap();
+        fs::write(dest.join("etc/other"), "old").unwrap();
+        let options = ExtractOptions {
+            on_conflict,
+            merge,
+            ..Default::default()
+        };
+        let result = image.extract("/", &dest, &options);
+        match on_conflict {
+            Conflict::Error => {
+                let err = result.unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+                assert_eq!(read("etc/conf"), "old");
+            }
+            Conflict::Skip => {
+                result.unwrap();
+                assert_eq!(read("etc/conf"), "old");
+                assert_eq!(read("readme"), "new");
+            }
+            Conflict::Overwrite => {
+                result.unwrap();
+                assert_eq!(read("etc/conf"), "new");
+                assert_eq!(read("etc/other"), "old");
+            }
+            Conflict::Rename => {
+                // without merging, the whole directory is renamed
+                result.unwrap();
+                assert_eq!(read("etc/conf"), "old");
+                assert_eq!(read("etc.1/conf"), "new");
+                assert!(!dest.join("etc.1/other").exists());
+            }
+        }
+    }
+    fs::remove_dir_all(&dest).unwrap();
+}
+
+#[cfg(all(unix, feature = "testing"))]
+#[test]
+fn verify_against_extracted() {
+    use crate::extract::ExtractOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+    use crate::verify::Mismatch;
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    // owned by whoever runs the test, so extraction restores the owners
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let root = Spec::dir([
+        ("a", Spec::file("aaaa").with_owner(uid, gid)),
+        ("b", Spec::file("bbbb").with_owner(uid, gid)),
+        (
+            "d",
+            Spec::dir([("link", Spec::symlink("../a").with_owner(uid, gid))]).with_owner(uid, gid),
+        ),
+    ])
+    .with_owner(uid, gid);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let dest = std::env::temp_dir().join(format!("squashfs-verify-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dest);
+    image
+        .extract("/", &dest, &ExtractOptions::default())
+        .unwrap();
+    assert_eq!(image.verify_against(&dest).unwrap(), []);
+
+    fs::set_permissions(dest.join("a"), Permissions::from_mode(0o600)).unwrap();
+    // same size, other bytes
+    fs::write(dest.join("b"), "bbbc").unwrap();
+    fs::write(dest.join("d/new"), "").unwrap();
+    let mismatches = image.verify_against(&dest).unwrap();
+    assert_eq!(
+        mismatches,
+        [
+            (SqshPath::new("a"), Mismatch::Mode(0o644, 0o600)),
+            (SqshPath::new("b"), Mismatch::Content),
+            (SqshPath::new("d/new"), Mismatch::Unexpected),
+        ]
+    );
+    fs::remove_dir_all(&dest).unwrap();
+}
+
+#[cfg(all(unix, feature = "testing"))]
+#[test]
+fn extract_unreadable_dirs() {
+    use crate::extract::ExtractOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    // the owner can enter these but not list them
+    let root = Spec::dir([(
+        "drop",
+        Spec::dir([
+            ("file", Spec::file("data")),
+            (
+                "inner",
+                Spec::dir(Vec::<(&str, Spec)>::new())
+                    .with_mode(0o111)
+                    .with_mtime(1000),
+            ),
+        ])
+        .with_mode(0o311)
+        .with_mtime(2000),
+    )]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let dest = std::env::temp_dir().join(format!("squashfs-unreadable-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dest);
+    // owned by whoever runs the test, so it needs no privileges
+    let owner = |id| {
+        Some(IdMap::new(vec![crate::idmap::IdRange {
+            inside: 0,
+            outside: id,
+            count: 1,
+        }]))
+    };
+    let options = ExtractOptions {
+        uid_map: owner(unsafe { libc::getuid() }),
+        gid_map: owner(unsafe { libc::getgid() }),
+        ..Default::default()
+    };
+    image.extract("/", &dest, &options).unwrap();
+
+    let drop = fs::metadata(dest.join("drop")).unwrap();
+    assert_eq!(drop.mode() & 0o7777, 0o311);
+    assert_eq!(drop.mtime(), 2000);
+    let inner = fs::metadata(dest.join("drop/inner")).unwrap();
+    assert_eq!(inner.mode() & 0o7777, 0o111);
+    assert_eq!(inner.mtime(), 1000);
+    assert_eq!(fs::read(dest.join("drop/file")).unwrap(), b"data");
+
+    fs::set_permissions(dest.join("drop"), Permissions::from_mode(0o755)).unwrap();
+    fs::set_permissions(dest.join("drop/inner"), Permissions::from_mode(0o755)).unwrap();
+    fs::remove_dir_all(&dest).unwrap();
+}
+
+#[cfg(all(unix, feature = "testing"))]
+#[test]
+fn extract_filter() {
+    use crate::extract::{ExtractFilter, ExtractOptions};
+    use crate::testing::{generate, GenerateOptions, Spec};
+    use std::fs;
+
+    let root = Spec::dir([
+        (
+            "usr",
+            Spec::dir([(
+                "bin",
+                Spec::dir([
+                    ("su", Spec::file("su").with_mode(0o4755)),
+                    ("ls", Spec::file("ls").with_mode(0o755)),
+                ]),
+            )]),
+        ),
+        (
+            "etc",
+            Spec::dir([
+                ("big", Spec::file(vec![0; 5000]).with_mtime(100)),
+                ("small", Spec::file("small").with_mtime(200)),
+            ]),
+        ),
+        ("empty", Spec::dir::<&str>([])),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let dest = std::env::temp_dir().join(format!("squashfs-filter-{}", std::process::id()));
+
+    let extracted = |filter: ExtractFilter| {
+        let _ = fs::remove_dir_all(&dest);
+        let options = ExtractOptions {
+            filter: Some(filter),
+            ..Default::default()
+        };
+        image.extract("/", &dest, &options).unwrap();
+        let mut paths = vec![];
+        for entry in image.walk("/").unwrap() {
+            let path = entry.unwrap().path;
+            if fs::symlink_metadata(dest.join(&path.to_string_lossy()[1..])).is_ok() {
+                paths.push(path.to_string());
+            }
+        }
+        paths
+    };
+    let setuid = ExtractFilter {
+        mode_bits: 0o4000,
+        ..Default::default()
+    };
+    assert_eq!(extracted(setuid), ["/", "/usr", "/usr/bin", "/usr/bin/su"]);
+    let etc = ExtractFilter {
+        paths: vec![b"etc".to_vec()],
+        size: Some(0..=100),
+        ..Default::default()
+    };
+    assert_eq!(extracted(etc), ["/", "/etc", "/etc/small"]);
+    let old = ExtractFilter {
+        paths: vec![b"**/b*".to_vec()],
+        mtime: Some(50..=150),
+        ..Default::default()
+    };
+    assert_eq!(extracted(old), ["/", "/etc", "/etc/big"]);
+    fs::remove_dir_all(&dest).unwrap();
+}
+
+#[cfg(all(unix, feature = "testing"))]
+#[test]
+fn extract_symlink_rewrite() {
+    use crate::extract::{ExtractOptions, SymlinkRewrite};
+    use crate::testing::{generate, GenerateOptions, Spec};
+    use std::fs;
+    use std::path::PathBuf;
+
+    let root = Spec::dir([
+        (
+            "usr",
+            Spec::dir([
+                ("bin", Spec::dir([("x", Spec::file("x"))])),
+                (
+                    "local",
+                    Spec::dir([(
+                        "bin",
+                        Spec::dir([
+                            ("x", Spec::symlink("/usr/bin/x")),
+                            ("up", Spec::symlink("/usr/local/bin/")),
+                            ("rel", Spec::symlink("../../bin/x")),
+                            ("out", Spec::symlink("/etc/passwd")),
+                        ]),
+                    )]),
+                ),
+            ]),
+        ),
+        ("etc", Spec::symlink("/etc/passwd")),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let dest = std::env::temp_dir().join(format!("squashfs-symlinks-{}", std::process::id()));
+
+    let links = |path: &str, symlinks: SymlinkRewrite| {
+        let _ = fs::remove_dir_all(&dest);
+        let options = ExtractOptions {
+            symlinks,
+            ..Default::default()
+        };
+        image.extract(path, &dest, &options).unwrap();
+        [
+            "local/bin/x",
+            "local/bin/up",
+            "local/bin/rel",
+            "local/bin/out",
+        ]
+        .map(|link| fs::read_link(dest.join(link)).unwrap())
+    };
+    assert_eq!(
+        links("/usr", SymlinkRewrite::Keep),
+        [
+            "/usr/bin/x",
+            "/usr/local/bin/",
+            "../../bin/x",
+            "/etc/passwd"
+        ]
+        .map(PathBuf::from)
+    );
+    // absolute links into the extracted tree are rewritten, relative ones
+    // and those out of the tree, like /etc/passwd here, are kept
+    assert_eq!(
+        links("/usr", SymlinkRewrite::Relative),
+        ["../../bin/x", ".", "../../bin/x", "/etc/passwd"].map(PathBuf::from)
+    );
+    assert_eq!(
+        links("/usr", SymlinkRewrite::Prefix("/mnt".into())),
+        ["/mnt/bin/x", "/mnt/local/bin", "../../bin/x", "/etc/passwd"].map(PathBuf::from)
+    );
+    let _ = fs::remove_dir_all(&dest);
+    image
+        .extract(
+            "/",
+            &dest,
+            &ExtractOptions {
+                symlinks: SymlinkRewrite::Relative,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(
+        fs::read_link(dest.join("etc")).unwrap(),
+        PathBuf::from("etc/passwd")
+    );
+    assert_eq!(
+        fs::read_to_string(dest.join("usr/local/bin/x")).unwrap(),
+        "x"
+    );
+    fs::remove_dir_all(&dest).unwrap();
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn memory_budget() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    assert_eq!(CacheConfig::within(usize::MAX), CacheConfig::default());
+    let small = CacheConfig::within(30_000);
+    assert!(small.inodes.max_bytes + small.metadata_blocks.max_bytes <= 30_000);
+
+    let files = (0..2000).map(|i| (format!("file-{:04}", i), Spec::file(format!("{}", i))));
+    let bytes = generate(&Spec::dir(files), &GenerateOptions::default()).unwrap();
+    let mut image = Image::new(Cursor::new(bytes)).unwrap();
+    image.set_memory_budget(60_000);
+    assert_eq!(image.memory_budget(), Some(60_000));
+    for entry in image.walk("/").unwrap() {
+        let inode = entry.unwrap().inode;
+        if inode.is_file() {
+            image
+                .open_file(&inode)
+                .unwrap()
+                .read_to_end(&mut vec![])
+                .unwrap();
+        }
+    }
+    let stats = image.cache_stats();
+    assert!(stats.inodes.evictions > 0);
+    assert!(stats.inodes.bytes + stats.metadata_blocks.bytes <= 30_000);
+    // the buffer pool is shared, but no other test sets a budget
+    let (pooled, limit) = crate::pool::pooled_bytes();
+    assert!(pooled <= 15_000);
+    assert_eq!(limit, 15_000);
+    // a budget replaced or dropped stops limiting the pool
+    let clone = image.clone();
+    image.set_memory_budget(100_000);
+    assert_eq!(crate::pool::pooled_bytes().1, 15_000);
+    drop(clone);
+    assert_eq!(crate::pool::pooled_bytes().1, 25_000);
+    drop(image);
+    assert_eq!(
+        crate::pool::pooled_bytes().1,
+        MAX_POOLED * MAX_POOLED_CAPACITY
+    );
+}
+
+#[test]
+fn buffer_pool_bounds() {
+    let mut pool = BufferPool::new();
+    for _ in 0..MAX_POOLED + 3 {
+        pool.give(Vec::with_capacity(4096));
+    }
+    assert_eq!(pool.len(), MAX_POOLED);
+    for _ in 0..MAX_POOLED {
+        assert_eq!(pool.take().capacity(), 4096);
+    }
+    assert_eq!(pool.take().capacity(), 0);
+
+    pool.give(Vec::with_capacity(MAX_POOLED_CAPACITY + 1));
+    pool.give(vec![]);
+    assert_eq!(pool.len(), 0);
+    let mut full = Vec::with_capacity(MAX_POOLED_CAPACITY);
+    full.resize(100, 1);
+    pool.give(full);
+    // given back empty
+    let buf = pool.take();
+    assert!(buf.is_empty());
+    assert_eq!(buf.capacity(), MAX_POOLED_CAPACITY);
+}
+
+#[test]
+fn buffer_pool_limit() {
+    let mut pool = BufferPool::new();
+    for _ in 0..4 {
+        pool.give(Vec::with_capacity(10_000));
+    }
+    assert_eq!(pool.len(), 4);
+    pool.add_limit(25_000);
+    assert_eq!(pool.len(), 2);
+    assert!(pool.bytes() <= 25_000);
+    pool.give(Vec::with_capacity(10_000));
+    assert_eq!(pool.len(), 2);
+    // the smallest limit applies, until it is removed
+    pool.add_limit(1 << 30);
+    pool.give(Vec::with_capacity(10_000));
+    assert_eq!(pool.len(), 2);
+    pool.remove_limit(25_000);
+    assert_eq!(pool.limit(), MAX_POOLED * MAX_POOLED_CAPACITY);
+    pool.give(Vec::with_capacity(10_000));
+    assert_eq!(pool.len(), 3);
+    assert_eq!(pool.take().capacity(), 10_000);
+    assert_eq!(pool.len(), 2);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn walk_order() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+    use crate::walk::{Order, WalkOptions};
+
+    let root = Spec::dir([
+        (
+            "b",
+            Spec::dir([("z", Spec::file("")), ("Z", Spec::file(""))]),
+        ),
+        ("a", Spec::file("")),
+        ("\u{e9}", Spec::file("")),
+        ("B", Spec::file("")),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let walk = |order| -> Vec<String> {
+        image
+            .walk_with("/", WalkOptions::new().order(order))
+            .unwrap()
+            .map(|e| e.unwrap().path.to_string())
+            .collect()
+    };
+    let expected = ["/", "/B", "/a", "/b", "/b/Z", "/b/z", "/\u{e9}"];
+    assert_eq!(walk(Order::OnDisk), expected);
+    assert_eq!(walk(Order::ByName), expected);
+
+    // listings stored out of order are sorted byte-wise
+    let mut entries = image.list_dir("/").unwrap();
+    entries.reverse();
+    Order::ByName.sort(&mut entries);
+    let names: Vec<&[u8]> = entries.iter().map(|e| e.name()).collect();
+    assert_eq!(names, [&b"B"[..], b"a", b"b", "\u{e9}".as_bytes()]);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn list_dots() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+    use crate::walk::{ListOptions, Order};
+
+    let root = Spec::dir([
+        ("b", Spec::dir([("c", Spec::dir::<&str>([]))])),
+        ("a", Spec::file("")),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let names = |entries: &[DirectoryEntry]| -> Vec<Vec<u8>> {
+        entries.iter().map(|e| e.name().to_vec()).collect()
+    };
+
+    // manifests want the stored entries only
+    let plain = image.list_dir_with("/b", &ListOptions::new()).unwrap();
+    assert_eq!(names(&plain), [b"c".to_vec()]);
+
+    let entries = image
+        .list_dir_with("/b", ListOptions::new().dots(true))
+        .unwrap();
+    assert_eq!(
+        names(&entries),
+        [b".".to_vec(), b"..".to_vec(), b"c".to_vec()]
+    );
+    let b = image.lookup("/b").unwrap();
+    assert_eq!(entries[0].inode_number(), b.inode_number());
+    assert_eq!(
+        entries[1].inode_number(),
+        image.root().unwrap().inode_number()
+    );
+    assert_eq!(entries[0].file_type(), Some(FileType::Directory));
+    assert_eq!(
+        image
+            .open_by_ref(entries[0].inode_ref())
+            .unwrap()
+            .inode_number(),
+        b.inode_number()
+    );
+    assert_eq!(entries[1].inode_ref(), image.superblock().root_inode_ref());
+    let c = image
+        .list_dir_with("/b/c", ListOptions::new().dots(true))
+        .unwrap();
+    assert_eq!(names(&c), [b".".to_vec(), b"..".to_vec()]);
+    assert_eq!(c[0].inode_ref(), entries[2].inode_ref());
+    assert_eq!(c[1].inode_number(), b.inode_number());
+    // readdir agrees on `..`
+    let readdir: Vec<_> = image
+        .readdir(&image.lookup("/b/c").unwrap(), 0)
+        .unwrap()
+        .collect();
+    assert_eq!(readdir[1].ino, c[1].inode_number() as u64);
+
+    // the root is its own parent, and the dots stay first when sorted
+    let entries = image
+        .list_dir_with("/", ListOptions::new().dots(true).order(Order::ByName))
+        .unwrap();
+    assert_eq!(
+        names(&entries),
+        [b".".to_vec(), b"..".to_vec(), b"a".to_vec(), b"b".to_vec()]
+    );
+    assert_eq!(entries[0].inode_ref(), entries[1].inode_ref());
+    assert_eq!(entries[0].inode_number(), entries[1].inode_number());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn verify_blocks() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let big = "some text to compress\n".repeat(400);
+    let root = Spec::dir([
+        ("a", Spec::file(big.as_str())),
+        ("b", Spec::file("tail")),
+        ("c", Spec::file(big.as_str())),
+    ]);
+    let options = GenerateOptions {
+        block_size: 4096,
+        ..Default::default()
+    };
+    let mut bytes = generate(&root, &options).unwrap();
+    let image = Image::new(Cursor::new(bytes.clone())).unwrap();
+    assert!(image.verify_blocks("/").unwrap().is_empty());
+
+    let InodeHeader::Regular(a) = image.lookup("/a").unwrap() else {
+        panic!("not a regular file");
+    };
+    let start = a.start_block() as u64;
+    let end = start + (a.blocks()[0] & !COMPRESSED_BIT_BLOCK) as u64;
+    let fragment = image.fragments().unwrap()[0];
+    for i in start + 4..end {
+        bytes[i as usize] ^= 0x55;
+    }
+    bytes[fragment.start_block() as usize + 4] ^= 0x55;
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let bad = image.verify_blocks("/").unwrap();
+    assert_eq!(bad.len(), 2);
+    assert_eq!(bad[0].range, start..end);
+    assert_eq!(bad[0].paths, [SqshPath::new("/a")]);
+    let tails = &bad[1].paths;
+    assert_eq!(bad[1].range.start, fragment.start_block());
+    assert!(tails.contains(&SqshPath::new("/b")) && tails.len() == 3);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn compare_images() {
+    use crate::compare::{CompareOptions, Difference};
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let build = |mtime: u32, uid: u32, content: &str, extra: bool| {
+        let mut entries = vec![
+            (
+                "file",
+                Spec::file(content)
+                    .with_mtime(mtime)
+                    .with_owner(uid, 0)
+                    .with_xattr("user.a", "1")
+                    .with_xattr(if extra { "user.b" } else { "user.c" }, "2"),
+            ),
+            ("link", Spec::symlink("file").with_mtime(mtime)),
+            (
+                "dir",
+                Spec::dir([("inner", Spec::file("x").with_mtime(mtime))]).with_mtime(mtime),
+            ),
+        ];
+        if extra {
+            entries.push(("new", Spec::dir([("below", Spec::file(""))])));
+        }
+        let bytes = generate(&Spec::dir(entries), &GenerateOptions::default()).unwrap();
+        Image::new(Cursor::new(bytes)).unwrap()
+    };
+    let old = build(100, 0, "same", false);
+
+    // a rebuild of the same tree
+    let rebuilt = build(200, 1000, "same", false);
+    assert!(old
+        .compare(&rebuilt, "/", CompareOptions::new().content_only())
+        .unwrap()
+        .is_empty());
+    let differences = old
+        .compare(&rebuilt, "/", CompareOptions::new().ignore_mtime(true))
+        .unwrap();
+    assert_eq!(
+        differences,
+        [(SqshPath::new("/file"), Difference::Owner((0, 0), (1000, 0)))]
+    );
+    let differences = old.compare(&rebuilt, "/", &CompareOptions::new()).unwrap();
+    assert_eq!(differences.len(), 5);
+    assert!(differences.contains(&(SqshPath::new("/dir"), Difference::Mtime(100, 200))));
+
+    // contents always count, and added trees are listed once
+    let changed = build(100, 0, "diff", true);
+    let differences = old
+        .compare(&changed, "/", CompareOptions::new().content_only())
+        .unwrap();
+    assert_eq!(
+        differences,
+        [
+            (SqshPath::new("/file"), Difference::Content),
+            (SqshPath::new("/new"), Difference::Added),
+        ]
+    );
+    let xattr = |name: &str, value: &str| Xattr {
+        name: name.into(),
+        value: value.into(),
+    };
+    let differences = old
+        .compare(&changed, "/file", CompareOptions::new().ignore_mtime(true))
+        .unwrap();
+    assert_eq!(
+        differences,
+        [
+            (
+                SqshPath::new("/file"),
+                Difference::Xattrs(
+                    vec![xattr("user.a", "1"), xattr("user.c", "2")],
+                    vec![xattr("user.a", "1"), xattr("user.b", "2")]
+                )
+            ),
+            (SqshPath::new("/file"), Difference::Content),
+        ]
+    );
+    let differences = old
+        .compare(&changed, "/file", CompareOptions::new().ignore_xattrs(true))
+        .unwrap();
+    assert_eq!(differences, [(SqshPath::new("/file"), Difference::Content)]);
+    let differences = changed
+        .compare(&old, "/dir", &CompareOptions::new())
+        .unwrap();
+    assert!(differences.is_empty());
+}
+
+#[cfg(all(unix, feature = "testing"))]
+#[test]
+fn partial_extract() {
+    use crate::extract::ExtractOptions;
+    use crate::image::TableKind;
+    use crate::testing::{generate, GenerateOptions, Spec};
+    use std::fs;
+
+    let root = Spec::dir([("a", Spec::file(vec![7; 5000])), ("b", Spec::file("tail"))]);
+    let options = GenerateOptions {
+        block_size: 4096,
+        ..Default::default()
+    };
+    let bytes = generate(&root, &options).unwrap();
+    let sb = *Image::new(Cursor::new(bytes.clone())).unwrap().superblock();
+    let dest = std::env::temp_dir().join(format!("squashfs-partial-{}", std::process::id()));
+    let partial = ExtractOptions {
+        partial: true,
+        ..Default::default()
+    };
+
+    // cut in the middle of the id table index, the last thing written
+    let cut = sb.id_table_start() as usize + 4;
+    let image = Image::new(Cursor::new(bytes[..cut].to_vec())).unwrap();
+    assert!(image.warnings().contains(&Warning::Truncated {
+        len: cut as u64,
+        bytes_used: sb.bytes_used(),
+    }));
+    assert_eq!(image.missing_tables().unwrap(), [TableKind::Id]);
+    let _ = fs::remove_dir_all(&dest);
+    assert!(image
+        .extract("/", &dest, &ExtractOptions::default())
+        .is_err());
+    let _ = fs::remove_dir_all(&dest);
+    image.extract("/", &dest, &partial).unwrap();
+    assert_eq!(fs::read(dest.join("a")).unwrap(), vec![7; 5000]);
+    assert_eq!(fs::read(dest.join("b")).unwrap(), b"tail");
+
+    // without the fragment table, files with a tail are left out
+    let cut = sb.fragment_table_start() as usize + 4;
+    let image = Image::new(Cursor::new(bytes[..cut].to_vec())).unwrap();
+    let _ = fs::remove_dir_all(&dest);
+    image.extract("/", &dest, &partial).unwrap();
+    assert!(!dest.join("a").exists() && !dest.join("b").exists());
+    let warnings = image.warnings();
+    assert!(warnings.contains(&Warning::MissingTable(TableKind::Fragment)));
+    assert!(warnings.contains(&Warning::Unreadable(SqshPath::new("/a"))));
+    assert!(warnings.contains(&Warning::Unreadable(SqshPath::new("/b"))));
+    fs::remove_dir_all(&dest).unwrap();
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn handle_pool() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let contents: Vec<Vec<u8>> = (0..3u8)
+        .map(|i| (0..10000u32).map(|j| (j % 251) as u8 ^ i).collect())
+        .collect();
+    let root = Spec::dir(
+        contents
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (format!("f{}", i), Spec::file(c.clone()))),
+    );
+    let options = GenerateOptions {
+        block_size: 4096,
+        ..Default::default()
+    };
+    let image = Image::new(Cursor::new(generate(&root, &options).unwrap())).unwrap();
+    let mut pool = image.handles(1).unwrap();
+    let handles: Vec<_> = (0..3)
+        .map(|i| {
+            pool.open(&image.lookup(format!("/f{}", i)).unwrap())
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(pool.open_files(), 3);
+
+    // interleaved reads across blocks and into the fragment tail
+    for offset in [0, 4000, 9000, 100, 8190] {
+        for (i, handle) in handles.iter().enumerate() {
+            let mut buf = [0; 200];
+            let read = pool.read_at(*handle, offset, &mut buf).unwrap();
+            let end = (offset as usize + 200)
+                .min(10000)
+                .min((offset as usize / 4096 + 1) * 4096);
+            assert_eq!(&buf[..read], &contents[i][offset as usize..end]);
+            assert_eq!(pool.live(), 1);
+        }
+    }
+    assert_eq!(pool.read_at(handles[0], 10000, &mut [0; 10]).unwrap(), 0);
+
+    pool.close(handles[1]).unwrap();
+    assert_eq!(pool.open_files(), 2);
+    let err = pool.read_at(handles[1], 0, &mut [0; 10]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(pool.open(&image.root().unwrap()).is_err());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn walk_limits() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+    use crate::walk::WalkOptions;
+
+    let root = Spec::dir([
+        ("a", Spec::dir([("b", Spec::dir([("c", Spec::file(""))]))])),
+        ("d", Spec::file("")),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let walk = |options: &WalkOptions| -> Vec<Result<String, ErrorKind>> {
+        image
+            .walk_with("/", options)
+            .unwrap()
+            .map(|e| e.map(|e| e.path.to_string()).map_err(|e| e.kind()))
+            .collect()
+    };
+    assert_eq!(walk(WalkOptions::new().max_depth(3)).len(), 5);
+    assert_eq!(
+        walk(WalkOptions::new().max_depth(2)),
+        [
+            Ok("/".into()),
+            Ok("/a".into()),
+            Ok("/a/b".into()),
+            Err(ErrorKind::InvalidData)
+        ]
+    );
+    assert_eq!(
+        walk(WalkOptions::new().max_entries(2)),
+        [Ok("/".into()), Ok("/a".into()), Err(ErrorKind::InvalidData)]
+    );
+    // a subtree is limited from its own root
+    let sub = image
+        .walk_with("/a", WalkOptions::new().max_depth(2).max_entries(3))
+        .unwrap();
+    assert_eq!(sub.count(), 3);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn dir_stats() {
+    use crate::analyze::TypeStats;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        (
+            "lib",
+            Spec::dir([
+                ("big.so", Spec::file(vec![0; 3000])),
+                ("small.so", Spec::file(vec![0; 10])),
+                ("link", Spec::symlink("big.so")),
+                ("sub", Spec::dir([("mid", Spec::file(vec![0; 500]))])),
+            ]),
+        ),
+        ("readme", Spec::file(vec![0; 100])),
+        ("null", Spec::char_device(0x103)),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let stats = image.dir_stats("/", 2).unwrap();
+    let paths: Vec<String> = stats.iter().map(|d| d.path.to_string()).collect();
+    assert_eq!(paths, ["/", "/lib", "/lib/sub"]);
+
+    let lib = &stats[1];
+    assert_eq!(
+        lib.entries[&FileType::Regular],
+        TypeStats {
+            count: 2,
+            size: 3010
+        }
+    );
+    assert_eq!(
+        lib.entries[&FileType::Symlink],
+        TypeStats { count: 1, size: 6 }
+    );
+    assert_eq!(lib.total[&FileType::Regular].count, 3);
+    let largest: Vec<(String, u64)> = stats[0]
+        .largest
+        .iter()
+        .map(|(p, s)| (p.to_string(), *s))
+        .collect();
+    assert_eq!(
+        largest,
+        [("/lib/big.so".into(), 3000), ("/lib/sub/mid".into(), 500)]
+    );
+    assert_eq!(stats[0].total[&FileType::CharDevice].count, 1);
+    assert_eq!(stats[0].total[&FileType::Directory].count, 2);
+    assert_eq!(stats[0].total[&FileType::Regular].size, 3610);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn audit() {
+    use crate::audit::Issue;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        (
+            "dev",
+            Spec::dir([("null", Spec::char_device(0x103).with_mode(0o666))]),
+        ),
+        ("tmp", Spec::dir::<&str>([]).with_mode(0o1777)),
+        ("shared", Spec::dir::<&str>([]).with_mode(0o777)),
+        (
+            "bin",
+            Spec::dir([
+                ("su", Spec::file("").with_mode(0o4755).with_owner(0, 0)),
+                ("wall", Spec::file("").with_mode(0o2755).with_owner(0, 5)),
+                ("sh", Spec::symlink("busybox")),
+            ]),
+        ),
+        ("log", Spec::file("").with_mode(0o666)),
+        ("sda", Spec::block_device(0x800)),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut findings: Vec<(String, Issue)> = image
+        .audit("/")
+        .unwrap()
+        .into_iter()
+        .map(|f| (f.path.to_string(), f.issue))
+        .collect();
+    findings.sort();
+    assert_eq!(
+        findings,
+        [
+            ("/bin/su".into(), Issue::Setuid),
+            ("/bin/wall".into(), Issue::Setgid),
+            ("/log".into(), Issue::WorldWritable),
+            ("/sda".into(), Issue::DeviceOutsideDev),
+            ("/shared".into(), Issue::WorldWritable),
+        ]
+    );
+    let su = &image.audit("/bin").unwrap()[0];
+    assert_eq!((su.mode, su.uid, su.gid), (0o4755, 0, 0));
+    assert_eq!(su.to_string(), "/bin/su: setuid (Regular 4755 0:0)");
+}
+
+#[test]
+fn report_formats() {
+    use crate::audit::{Finding, Issue};
+    use crate::report::{Report, Severity};
+
+    let mut report = Report::new();
+    assert_eq!(report.max_severity(), None);
+    report.push(&Finding {
+        path: SqshPath::new("/bin/\"x\"\n"),
+        issue: Issue::Setuid,
+        file_type: FileType::Regular,
+        mode: 0o4755,
+        uid: 0,
+        gid: 0,
+    });
+    report.push(&Warning::Padding { offset: 4000 });
+    assert_eq!(report.max_severity(), Some(Severity::Warning));
+
+    let mut json = vec![];
+    report.write_json(&mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert_eq!(
+        json,
+        concat!(
+            "[\n",
+            r#"  {"rule": "setuid", "severity": "warning", "message": "/bin/\"x\"\n: setuid (Regular 4755 0:0)", "path": "/bin/\"x\"\n"},"#,
+            "\n",
+            r#"  {"rule": "padding", "severity": "note", "message": "non-zero padding at 4000", "offset": 4000}"#,
+            "\n]\n"
+        )
+    );
+    let items = Json::parse(&json);
+    assert_eq!(items.array()[1]["offset"], Json::Number(4000.0));
+    let mut sarif = vec![];
+    report.write_sarif("image.sqfs", &mut sarif).unwrap();
+    let sarif = Json::parse(std::str::from_utf8(&sarif).unwrap());
+    assert_eq!(sarif["version"], Json::from("2.1.0"));
+    let runs = sarif["runs"].array();
+    assert_eq!(runs.len(), 1);
+    let driver = &runs[0]["tool"]["driver"];
+    assert_eq!(driver["name"], Json::from("squashfs"));
+    let rules: Vec<&Json> = driver["rules"].array().iter().map(|r| &r["id"]).collect();
+    assert_eq!(rules, [&Json::from("padding"), &Json::from("setuid")]);
+
+    let results = runs[0]["results"].array();
+    assert_eq!(results.len(), 2);
+    for result in results {
+        assert!(rules.contains(&&result["ruleId"]));
+        let locations = result["locations"].array();
+        assert_eq!(locations.len(), 1);
+        assert_eq!(
+            locations[0]["physicalLocation"]["artifactLocation"]["uri"],
+            Json::from("image.sqfs")
+        );
+    }
+    let setuid = &results[0];
+    assert_eq!(setuid["ruleId"], Json::from("setuid"));
+    assert_eq!(setuid["level"], Json::from("warning"));
+    assert_eq!(
+        setuid["message"]["text"],
+        Json::from("/bin/\"x\"\n: setuid (Regular 4755 0:0)")
+    );
+    let location = &setuid["locations"].array()[0];
+    assert_eq!(location["physicalLocation"].get("region"), None);
+    let logical = location["logicalLocations"].array();
+    assert_eq!(logical[0]["fullyQualifiedName"], Json::from("/bin/\"x\"\n"));
+    assert_eq!(logical[0]["kind"], Json::from("resource"));
+    let padding = &results[1];
+    assert_eq!(padding["ruleId"], Json::from("padding"));
+    assert_eq!(padding["level"], Json::from("note"));
+    let location = &padding["locations"].array()[0];
+    assert_eq!(
+        location["physicalLocation"]["region"]["byteOffset"],
+        Json::Number(4000.0)
+    );
+    assert_eq!(location.get("logicalLocations"), None);
+}
+
+// Just enough of a JSON parser to check the structure of reports. Panics
+// on malformed input.
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl std::ops::Index<&str> for Json {
+    type Output = Json;
+
+    fn index(&self, key: &str) -> &Json {
+        self.get(key)
+            .unwrap_or_else(|| panic!("no {:?} in {:?}", key, self))
+    }
+}
+
+impl Json {
+    fn parse(s: &str) -> Json {
+        let mut chars = s.chars().peekable();
+        let json = Self::value(&mut chars);
+        Self::skip_space(&mut chars);
+        assert_eq!(chars.next(), None, "trailing characters");
+        json
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => panic!("not an object: {:?}", self),
+        }
+    }
+
+    fn array(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items,
+            _ => panic!("not an array: {:?}", self),
+        }
+    }
+
+    fn skip_space(chars: &mut std::iter::Peekable<std::str::Chars>) {
+        while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+    }
+
+    fn value(chars: &mut std::iter::Peekable<std::str::Chars>) -> Json {
+        Self::skip_space(chars);
+        match chars.peek().copied() {
+            Some('{') => {
+                chars.next();
+                let mut fields = vec![];
+                Self::skip_space(chars);
+                if chars.next_if_eq(&'}').is_none() {
+                    loop {
+                        Self::skip_space(chars);
+                        let Json::String(key) = Self::value(chars) else {
+                            panic!("object key not a string");
+                        };
+                        Self::skip_space(chars);
+                        assert_eq!(chars.next(), Some(':'));
+                        fields.push((key, Self::value(chars)));
+                        Self::skip_space(chars);
+                        match chars.next() {
+                            Some(',') => {}
+                            Some('}') => break,
+                            c => panic!("unexpected {:?} in object", c),
+                        }
+                    }
+                }
+                Json::Object(fields)
+            }
+            Some('[') => {
+                chars.next();
+                let mut items = vec![];
+                Self::skip_space(chars);
+                if chars.next_if_eq(&']').is_none() {
+                    loop {
+                        items.push(Self::value(chars));
+                        Self::skip_space(chars);
+                        match chars.next() {
+                            Some(',') => {}
+                            Some(']') => break,
+                            c => panic!("unexpected {:?} in array", c),
+                        }
+                    }
+                }
+                Json::Array(items)
+            }
+            Some('"') => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next().expect("unterminated string") {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some('u') => {
+                                let hex: String = chars.by_ref().take(4).collect();
+                                let c = u32::from_str_radix(&hex, 16).unwrap();
+                                s.push(char::from_u32(c).unwrap());
+                            }
+                            Some(c @ ('"' | '\\' | '/')) => s.push(c),
+                            c => panic!("bad escape {:?}", c),
+                        },
+                        c if (c as u32) < 0x20 => panic!("unescaped control character"),
+                        c => s.push(c),
+                    }
+                }
+                Json::String(s)
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(c) = chars
+                    .next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+                {
+                    number.push(c);
+                }
+                Json::Number(number.parse().unwrap())
+            }
+            _ => {
+                let word: String =
+                    std::iter::from_fn(|| chars.next_if(char::is_ascii_alphabetic)).collect();
+                match word.as_str() {
+                    "null" => Json::Null,
+                    "true" => Json::Bool(true),
+                    "false" => Json::Bool(false),
+                    _ => panic!("unexpected {:?}", word),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+#[test]
+fn fuzz_harnesses() {
+    use crate::fuzzing::{self, MalformedImage, Mutation};
+    use arbitrary::{Arbitrary, Unstructured};
+
+    let seeds = fuzzing::seeds().unwrap();
+    for (_, image) in &seeds {
+        fuzzing::superblock(image);
+        fuzzing::inode_table(image);
+        fuzzing::directory_table(image);
+        assert!(
+            Image::new(Cursor::new(image))
+                .unwrap()
+                .walk("/")
+                .unwrap()
+                .count()
+                > 0
+        );
+    }
+    fuzzing::compressor_options(&[1, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+    fuzzing::compressor_options(&[4, 0, 1]);
+
+    let mut image = seeds[1].1.clone();
+    Mutation::Superblock {
+        offset: 0,
+        value: 0,
+    }
+    .apply(&mut image);
+    assert!(Image::new(Cursor::new(&image)).is_err());
+    Mutation::Truncate { len: 100 }.apply(&mut image);
+    assert_eq!(image.len(), 100);
+
+    // a metadata block header claiming more than a block
+    let mut image = seeds[1].1.clone();
+    let sb = Superblock::new(&mut Cursor::new(&image)).unwrap();
+    Mutation::Flip {
+        offset: sb.inode_table_start() as u32 + 1,
+        mask: 0x60,
+    }
+    .apply(&mut image);
+    fuzzing::inode_table(&image);
+    let err = Image::new(Cursor::new(&image))
+        .and_then(|image| image.inodes())
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    // table bounds and counts nothing may be allocated from
+    let patch = |change: &dyn Fn(&mut Superblock)| {
+        let mut image = seeds[1].1.clone();
+        let mut sb = Superblock::from_bytes(image[..SUPERBLOCK_SIZE].try_into().unwrap());
+        change(&mut sb);
+        image[..SUPERBLOCK_SIZE].copy_from_slice(&sb.to_bytes());
+        image
+    };
+    let inode_table_start = sb.inode_table_start();
+    for image in [
+        patch(&|sb| sb.set_directory_table_start(1 << 40)),
+        patch(&|sb| sb.set_directory_table_start(inode_table_start - 1)),
+    ] {
+        fuzzing::inode_table(&image);
+        if let Ok(image) = Image::new(Cursor::new(image)) {
+            assert_eq!(image.inodes().unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+    fuzzing::inode_table(&patch(&|sb| sb.set_inodes(sb.inodes() ^ 1 << 30)));
+
+    let bytes: Vec<u8> = (0..4096u32).map(|i| (i * 31 % 251) as u8).collect();
+    let input = MalformedImage::arbitrary(&mut Unstructured::new(&bytes)).unwrap();
+    fuzzing::inode_table(&input.image);
+    fuzzing::directory_table(&input.image);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn readdir_cookies() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        ("a", Spec::file("a")),
+        ("b", Spec::dir([("c", Spec::fifo())])),
+        ("d", Spec::symlink("a")),
+    ]);
+    let image = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(image)).unwrap();
+    let root = image.root().unwrap();
+    let all: Vec<_> = image.readdir(&root, 0).unwrap().collect();
+    let names: Vec<_> = all.iter().map(|e| &e.name[..]).collect();
+    assert_eq!(names, [&b"."[..], b"..", b"a", b"b", b"d"]);
+    assert_eq!(
+        all.iter().map(|e| e.offset).collect::<Vec<_>>(),
+        [1, 2, 3, 4, 5]
+    );
+    assert_eq!(all[1].ino, all[0].ino);
+    assert_eq!(all[3].file_type, FileType::Directory);
+
+    for entry in &all {
+        let rest: Vec<_> = image.readdir(&root, entry.offset).unwrap().collect();
+        assert_eq!(rest, all[entry.offset as usize..]);
+    }
+    assert_eq!(image.readdir(&root, 100).unwrap().count(), 0);
+    assert!(image.readdir(&root, -1).is_err());
+
+    let b = image.lookup("/b").unwrap();
+    let sub: Vec<_> = image.readdir(&b, 1).unwrap().collect();
+    assert_eq!((sub[0].ino, sub[0].offset), (all[0].ino, 2));
+    assert_eq!(sub[1].name, b"c");
+    assert!(image.readdir(&image.lookup("/a").unwrap(), 0).is_err());
+}
+
+#[cfg(all(feature = "testing", feature = "index"))]
+#[test]
+fn mtree_spec() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        (
+            "a b",
+            Spec::file("hello").with_mode(0o640).with_owner(1000, 100),
+        ),
+        ("d", Spec::dir([("p", Spec::fifo())])),
+        ("link", Spec::symlink("a b")),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut spec = vec![];
+    crate::mtree::write_spec(&image, &mut spec).unwrap();
+    let expected = "\
+#mtree
+. type=dir mode=0755 uid=0 gid=0
+./a\\040b type=file mode=0640 uid=1000 gid=100 size=5 \
+sha256digest=2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824
+./d type=dir mode=0755 uid=0 gid=0
+./d/p type=fifo mode=0644 uid=0 gid=0
+./link type=link mode=0777 uid=0 gid=0 link=a\\040b
+";
+    assert_eq!(String::from_utf8(spec).unwrap(), expected);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn manifest_formats() {
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        (
+            "etc",
+            Spec::dir([
+                ("a,\"b\"", Spec::file("xy").with_owner(1, 2)),
+                ("z", Spec::symlink("../x")),
+            ]),
+        ),
+        ("x", Spec::char_device(0x0105)),
+    ]);
+    let image = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(image)).unwrap();
+    let manifest = image.manifest("/").unwrap();
+    assert_eq!(manifest.entries.len(), 5);
+    assert_eq!(manifest.entries[2].size, Some(2));
+    assert_eq!(manifest.entries[4].rdev, Some(0x0105));
+
+    let mut tree = vec![];
+    manifest.write_tree(&mut tree).unwrap();
+    assert_eq!(
+        String::from_utf8(tree).unwrap(),
+        "/\n├── etc\n│   ├── a,\"b\"\n│   └── z -> ../x\n└── x\n"
+    );
+    let mut tree = vec![];
+    image
+        .manifest("/etc")
+        .unwrap()
+        .write_tree(&mut tree)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(tree).unwrap(),
+        "/etc\n├── a,\"b\"\n└── z -> ../x\n"
+    );
+
+    let mut csv = vec![];
+    manifest.write_csv(&mut csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(
+        lines[0],
+        "path,type,mode,uid,gid,mtime,inode,size,target,rdev"
+    );
+    assert!(lines[3].starts_with("\"/etc/a,\"\"b\"\"\",file,0644,1,2,0,"));
+    assert!(lines[3].ends_with(",2,,"));
+    assert!(lines[4].ends_with(",,../x,"));
+
+    let mut json = vec![];
+    manifest.write_json(&mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.contains(r#"{"path": "/etc/a,\"b\"", "type": "file", "mode": 420, "uid": 1, "gid": 2, "mtime": 0, "inode": "#));
+    assert!(
+        json.contains(r#""type": "char", "mode": 420, "uid": 0, "gid": 0, "mtime": 0, "inode": "#)
+    );
+    assert!(json.contains(r#""rdev": 261}"#));
+}
+
+#[cfg(all(unix, feature = "testing"))]
+#[test]
+fn extract_transforms() {
+    use crate::extract::ExtractOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+    use crate::transform::{Gunzip, TransformWriter};
+    use flate2::write::GzEncoder;
+    use std::fs;
+
+    struct Upper(Box<dyn TransformWriter>);
+    impl Write for Upper {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write_all(&buf.to_ascii_uppercase())?;
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+    impl TransformWriter for Upper {
+        fn finish(self: Box<Self>) -> std::io::Result<()> {
+            self.0.finish()
+        }
+    }
+
+    let gzip = |data: &[u8]| {
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    };
+    let mut members = gzip(b"one ");
+    members.extend(gzip(b"two"));
+    let root = Spec::dir([
+        ("a.gz", Spec::file(members)),
+        ("b.txt", Spec::file("text")),
+        ("c.txt.gz", Spec::file(gzip(b"both"))),
+        ("d", Spec::file("as is")),
+    ]);
+    let image = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(image)).unwrap();
+    let dest = std::env::temp_dir().join(format!("squashfs-transforms-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dest);
+    let mut options = ExtractOptions::default();
+    options.transforms.add("*.gz", Gunzip).add(
+        "*.txt*",
+        |_: &SqshPath,
+         out: Box<dyn TransformWriter>|
+         -> std::io::Result<Box<dyn TransformWriter>> { Ok(Box::new(Upper(out))) },
+    );
+    image.extract("/", &dest, &options).unwrap();
+    assert_eq!(fs::read(dest.join("a.gz")).unwrap(), b"one two");
+    assert_eq!(fs::read(dest.join("b.txt")).unwrap(), b"TEXT");
+    assert_eq!(fs::read(dest.join("c.txt.gz")).unwrap(), b"BOTH");
+    assert_eq!(fs::read(dest.join("d")).unwrap(), b"as is");
+    let _ = fs::remove_dir_all(&dest);
+
+    // a file that isn't gzip fails the extraction
+    options.transforms = Default::default();
+    options.transforms.add("/d", Gunzip);
+    assert!(image.extract("/", &dest, &options).is_err());
+    let _ = fs::remove_dir_all(&dest);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn cow_overlay() {
+    use crate::overlay::CowOverlay;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        (
+            "etc",
+            Spec::dir([
+                ("version", Spec::file("1.0").with_owner(5, 6)),
+                ("hosts", Spec::file("localhost")),
+            ]),
+        ),
+        (
+            "var",
+            Spec::dir([("log", Spec::dir([("old", Spec::fifo())]))]),
+        ),
+        ("link", Spec::symlink("etc")),
+    ]);
+    let image = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(image)).unwrap();
+    let mut overlay = image.overlay().unwrap();
+    assert!(!overlay.is_changed());
+    assert_eq!(overlay.read("/etc/hosts").unwrap(), b"localhost");
+    assert!(!overlay.lookup("/etc").unwrap().changed);
+
+    overlay.write_file("/etc/version", "2.0").unwrap();
+    let version = overlay.lookup("etc/version").unwrap();
+    assert_eq!((version.uid, version.gid, version.size), (5, 6, Some(3)));
+    assert!(version.changed);
+    assert_eq!(overlay.read("/etc/version").unwrap(), b"2.0");
+    // the image itself is untouched
+    let mut data = vec![];
+    let inode = image.lookup("/etc/version").unwrap();
+    image
+        .open_file(&inode)
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert_eq!(data, b"1.0");
+
+    overlay.create_dir("/opt").unwrap();
+    overlay.write_file("/opt/new", vec![1; 10]).unwrap();
+    overlay.symlink("/opt/link", "new").unwrap();
+    overlay
+        .mknod("/opt/null", FileType::CharDevice, 0x0103)
+        .unwrap();
+    overlay.set_mode("/etc/hosts", 0o600).unwrap();
+    overlay.set_owner("/opt", 1, 1).unwrap();
+    assert_eq!(overlay.lookup("/etc/hosts").unwrap().mode, 0o600);
+    assert_eq!(overlay.lookup("/opt/null").unwrap().rdev, Some(0x0103));
+    let names = |overlay: &CowOverlay<'_, Cursor<Vec<u8>>>, dir| {
+        overlay
+            .list_dir(dir)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| String::from_utf8(name).unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(&overlay, "/"), ["etc", "link", "opt", "var"]);
+    assert_eq!(names(&overlay, "/opt"), ["link", "new", "null"]);
+
+    overlay.remove("/var/log").unwrap();
+    assert!(!overlay.exists("/var/log/old"));
+    assert!(names(&overlay, "/var").is_empty());
+    // a directory created again has none of the entries of the removed one
+    overlay.create_dir("/var/log").unwrap();
+    assert!(names(&overlay, "/var/log").is_empty());
+    assert!(overlay.is_dir("/var/log"));
+
+    let err = |r: std::io::Result<()>| r.unwrap_err().kind();
+    assert_eq!(err(overlay.create_dir("/etc")), ErrorKind::AlreadyExists);
+    assert_eq!(
+        err(overlay.write_file("/etc", "x")),
+        ErrorKind::AlreadyExists
+    );
+    assert_eq!(
+        err(overlay.write_file("/no/file", "x")),
+        ErrorKind::NotFound
+    );
+    // symlinks aren't followed
+    assert_eq!(
+        err(overlay.write_file("/link/file", "x")),
+        ErrorKind::NotADirectory
+    );
+    assert_eq!(err(overlay.remove("/")), ErrorKind::InvalidInput);
+    assert_eq!(err(overlay.remove("/missing")), ErrorKind::NotFound);
+    assert!(overlay.read("/opt").is_err());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn cow_overlay_commit() {
+    use crate::overlay::CommitOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let root = Spec::dir([
+        (
+            "etc",
+            Spec::dir([
+                ("version", Spec::file("1.0").with_owner(5, 6)),
+                ("hosts", Spec::file("localhost")),
+            ]),
+        ),
+        ("big", Spec::file(data.clone()).with_mtime(7)),
+        (
+            "tmp",
+            Spec::dir([("junk", Spec::file(data[..1000].to_vec()))]),
+        ),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut overlay = image.overlay().unwrap();
+    overlay.write_file("/etc/version", "2.0").unwrap();
+    overlay.set_mode("/etc/hosts", 0o600).unwrap();
+    overlay.remove("/tmp/junk").unwrap();
+    overlay.create_dir("/opt").unwrap();
+    overlay.set_owner("/opt", 1000, 1000).unwrap();
+    overlay.write_file("/opt/zeros", vec![0; 300_000]).unwrap();
+    overlay.symlink("/opt/big", "../big").unwrap();
+    overlay.mknod("/opt/fifo", FileType::Fifo, 0).unwrap();
+
+    let mut out = Cursor::new(vec![]);
+    let committed = overlay
+        .commit(CommitOptions::new().mkfs_time(42), &mut out)
+        .unwrap();
+    // the blocks of /big are copied, only new files are compressed
+    let big = image.lookup("/big").unwrap();
+    let InodeHeader::Regular(big) = big else {
+        panic!("not a basic file");
+    };
+    let big_len: u64 = big
+        .blocks()
+        .iter()
+        .map(|b| (b & !COMPRESSED_BIT_BLOCK) as u64)
+        .sum();
+    assert!(committed.copied > big_len);
+    assert!(committed.written < 1000);
+
+    let bytes = out.into_inner();
+    assert_eq!(bytes.len() as u64 % PADDING_SIZE, 0);
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let sb = image.superblock();
+    assert_eq!(sb.bytes_used(), committed.bytes_used);
+    assert_eq!((sb.mkfs_time(), sb.inodes()), (42, 10));
+    let read = |path: &str| {
+        let mut read = vec![];
+        let inode = image.lookup(path).unwrap();
+        image
+            .open_file(&inode)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        read
+    };
+    assert_eq!(read("/etc/version"), b"2.0");
+    assert_eq!(read("/etc/hosts"), b"localhost");
+    assert_eq!(read("/big"), data);
+    assert_eq!(read("/opt/zeros"), vec![0; 300_000]);
+    assert!(image.list_dir("/tmp").unwrap().is_empty());
+
+    let ids = image.id_table().unwrap();
+    let version = image.lookup("/etc/version").unwrap();
+    assert_eq!(ids.owner(&version).unwrap(), (5, 6));
+    assert_eq!(image.lookup("/etc/hosts").unwrap().mode() & 0o7777, 0o600);
+    assert_eq!(image.lookup("/big").unwrap().mtime(), 7);
+    let opt = image.lookup("/opt").unwrap();
+    assert_eq!(ids.owner(&opt).unwrap(), (1000, 1000));
+    assert_eq!(opt.nlink(), 2);
+    let InodeHeader::Symlink(link) = image.lookup("/opt/big").unwrap() else {
+        panic!("not a symlink");
+    };
+    assert_eq!(link.target(), b"../big");
+    assert_eq!(
+        image.lookup("/opt/fifo").unwrap().file_type(),
+        FileType::Fifo
+    );
+    assert_eq!(image.check_links().unwrap(), []);
+    assert!(image.warnings().is_empty());
+
+    // a committed image can be edited again, here without fragments
+    let mut overlay = image.overlay().unwrap();
+    overlay.write_file("/etc/version", "3.0").unwrap();
+    let mut out = Cursor::new(vec![]);
+    overlay
+        .commit(CommitOptions::new().fragments(false), &mut out)
+        .unwrap();
+    let image = Image::new(Cursor::new(out.into_inner())).unwrap();
+    let inode = image.lookup("/etc/version").unwrap();
+    let InodeHeader::Regular(version) = &inode else {
+        panic!("not a basic file");
+    };
+    assert_eq!(version.fragment(), crate::INVALID_FRAG);
+    let mut read = vec![];
+    image
+        .open_file(&inode)
+        .unwrap()
+        .read_to_end(&mut read)
+        .unwrap();
+    assert_eq!(read, b"3.0");
+}
+
+#[cfg(all(unix, feature = "testing"))]
+#[test]
+fn extract_xattr_namespaces() {
+    use crate::extract::ExtractOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+    use crate::xattr::XattrNamespaces;
+    use std::fs;
+
+    assert_eq!(
+        XattrNamespaces::of(b"user.mime_type"),
+        XattrNamespaces::USER
+    );
+    assert_eq!(
+        XattrNamespaces::of(b"trusted.overlay.opaque"),
+        XattrNamespaces::TRUSTED
+    );
+    let capability = Xattr {
+        name: crate::xattr::CAPABILITY_XATTR.to_vec(),
+        value: vec![],
+    };
+    assert_eq!(capability.namespace(), XattrNamespaces::SECURITY);
+    assert!(XattrNamespaces::of(b"system.posix_acl_access").is_empty());
+    assert!(XattrNamespaces::of(b"users.x").is_empty());
+
+    let long: Vec<u8> = (0..300u32).map(|i| (i % 251) as u8).collect();
+    let root = Spec::dir([
+        (
+            "file",
+            Spec::file("data")
+                .with_xattr("user.mime_type", "text/plain")
+                .with_xattr("user.long", long.clone()),
+        ),
+        (
+            "dir",
+            Spec::dir(Vec::<(&str, Spec)>::new()).with_xattr("user.dir", "1"),
+        ),
+        ("link", Spec::symlink("file").with_xattr("user.link", "1")),
+        ("fifo", Spec::fifo().with_xattr("user.fifo", "1")),
+        // past the largest value Linux allows
+        (
+            "big",
+            Spec::file("big").with_xattr("user.big", vec![1; 70_000]),
+        ),
+        ("plain", Spec::file("plain")),
+    ]);
+    let image = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(image)).unwrap();
+    let get = |path: &std::path::Path, name: &str| -> Option<Vec<u8>> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = std::ffi::CString::new(name).unwrap();
+        let mut value = vec![0; 1024];
+        let len = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        value.truncate(usize::try_from(len).ok()?);
+        Some(value)
+    };
+
+    let dest = std::env::temp_dir().join(format!("squashfs-xattrs-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dest);
+    let options = ExtractOptions {
+        xattrs: XattrNamespaces::USER | XattrNamespaces::SECURITY,
+        ..Default::default()
+    };
+    image.extract("/", &dest, &options).unwrap();
+    assert_eq!(fs::read(dest.join("file")).unwrap(), b"data");
+    assert_eq!(
+        get(&dest.join("file"), "user.mime_type").unwrap(),
+        b"text/plain"
+    );
+    assert_eq!(get(&dest.join("file"), "user.long").unwrap(), long);
+    assert_eq!(get(&dest.join("dir"), "user.dir").unwrap(), b"1");
+    // Linux refuses user xattrs there, so they are skipped
+    assert_eq!(get(&dest.join("link"), "user.link"), None);
+    assert_eq!(get(&dest.join("fifo"), "user.fifo"), None);
+    assert_eq!(fs::read(dest.join("big")).unwrap(), b"big");
+    assert_eq!(get(&dest.join("big"), "user.big"), None);
+    assert_eq!(get(&dest.join("plain"), "user.mime_type"), None);
+    let not_set: Vec<_> = image
+        .warnings()
+        .into_iter()
+        .filter_map(|w| match w {
+            Warning::XattrNotSet { path, name, .. } => Some((path, name)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(not_set, [(SqshPath::new("/big"), b"user.big".to_vec())]);
+    let _ = fs::remove_dir_all(&dest);
+
+    // without the user namespace selected, none are restored
+    let options = ExtractOptions {
+        xattrs: XattrNamespaces::SECURITY,
+        ..Default::default()
+    };
+    image.extract("/", &dest, &options).unwrap();
+    assert_eq!(get(&dest.join("file"), "user.mime_type"), None);
+    assert_eq!(get(&dest.join("dir"), "user.dir"), None);
+    let _ = fs::remove_dir_all(&dest);
+}
+
+#[cfg(all(feature = "testing", feature = "native-codecs"))]
+#[test]
+fn cow_overlay_zstd_dictionary() {
+    use crate::compressors::ZSTDCompressor;
+    use crate::overlay::CommitOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let options = GenerateOptions {
+        compressor: Compressor::ZSTD(ZSTDCompressor::default()),
+        ..Default::default()
+    };
+    let bytes = generate(&Spec::dir([("big", Spec::file(data.clone()))]), &options).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let config = |i: u32| {
+        format!(
+            "{{\"name\": \"service-{}\", \"enabled\": {}, \"restart\": \"on-failure\", \
+             \"user\": \"daemon\", \"limits\": {{\"files\": {}, \"procs\": {}}}}}\n",
+            i,
+            i.is_multiple_of(2),
+            1024 * (i % 7 + 1),
+            i % 13
+        )
+    };
+    let mut overlay = image.overlay().unwrap();
+    overlay.create_dir("/etc").unwrap();
+    for i in 0..400 {
+        overlay
+            .write_file(format!("/etc/{}.json", i), config(i))
+            .unwrap();
+    }
+
+    let mut plain = Cursor::new(vec![]);
+    let plain = overlay.commit(&CommitOptions::new(), &mut plain).unwrap();
+    let mut out = Cursor::new(vec![]);
+    let committed = overlay
+        .commit(CommitOptions::new().zstd_dictionary(4096), &mut out)
+        .unwrap();
+    // the fragment blocks shrink, the dictionary itself aside
+    assert!(committed.written < plain.written);
+
+    let image = Image::new(Cursor::new(out.into_inner())).unwrap();
+    assert!(image
+        .superblock()
+        .flags()
+        .contains(Flags::COMPRESSOR_OPTIONS_PRESENT));
+    let Compressor::ZSTD(zstd) = image.compressor().unwrap() else {
+        panic!("not zstd");
+    };
+    assert_eq!(zstd.compression_level(), 15);
+    let dictionary = zstd.dictionary().unwrap();
+    assert!(!dictionary.is_empty() && dictionary.len() <= 4096);
+    let read = |path: &str| {
+        let mut read = vec![];
+        let inode = image.lookup(path).unwrap();
+        image
+            .open_file(&inode)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        read
+    };
+    // blocks copied from the image were compressed without the dictionary
+    assert_eq!(read("/big"), data);
+    for i in [0, 199, 399] {
+        assert_eq!(read(&format!("/etc/{}.json", i)), config(i).as_bytes());
+    }
+
+    // a second dictionary would leave blocks compressed with the first
+    let overlay = image.overlay().unwrap();
+    let err = overlay
+        .commit(
+            CommitOptions::new().zstd_dictionary(4096),
+            Cursor::new(vec![]),
+        )
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn cow_overlay_grouping() {
+    use crate::overlay::{CommitOptions, Grouping};
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let bytes = generate(&Spec::dir::<&str>([]), &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut overlay = image.overlay().unwrap();
+    let mut seed = 1u32;
+    let mut noise = |len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    };
+    let text =
+        |i: usize| format!("{}: the quick brown fox jumps over the lazy dog\n", i).repeat(40);
+    // alike text files, each further from the last than the deflate window
+    // when written in name order
+    for i in 0..20 {
+        overlay
+            .write_file(format!("/{:02}.bin", i), noise(40_000))
+            .unwrap();
+        overlay
+            .write_file(format!("/{:02}.TXT", i), text(i))
+            .unwrap();
+    }
+
+    let commit = |grouping| {
+        let mut out = Cursor::new(vec![]);
+        let committed = overlay
+            .commit(CommitOptions::new().grouping(grouping), &mut out)
+            .unwrap();
+        (
+            committed,
+            Image::new(Cursor::new(out.into_inner())).unwrap(),
+        )
+    };
+    let (tree, _) = commit(Grouping::Tree);
+    let (grouped, image) = commit(Grouping::Extension);
+    assert!(grouped.written < tree.written);
+
+    // the text files are packed one after the other, after the others
+    let offsets: Vec<(u32, u32)> = (0..20)
+        .map(|i| {
+            let InodeHeader::Regular(file) = image.lookup(format!("/{:02}.TXT", i)).unwrap() else {
+                panic!("not a basic file");
+            };
+            (file.fragment(), file.offset())
+        })
+        .collect();
+    let mut sorted = offsets.clone();
+    sorted.sort();
+    assert_eq!(offsets, sorted);
+    let InodeHeader::Regular(last) = image.lookup("/19.bin").unwrap() else {
+        panic!("not a basic file");
+    };
+    assert!((last.fragment(), last.offset()) < offsets[0]);
+    let mut read = vec![];
+    let inode = image.lookup("/07.TXT").unwrap();
+    image
+        .open_file(&inode)
+        .unwrap()
+        .read_to_end(&mut read)
+        .unwrap();
+    assert_eq!(read, text(7).as_bytes());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn cow_overlay_uncompressed_extensions() {
+    use crate::overlay::CommitOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let bytes = generate(&Spec::dir::<&str>([]), &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let data = b"squashfs ".repeat(20_000);
+    let mut overlay = image.overlay().unwrap();
+    overlay.write_file("/photo.JPG", data.clone()).unwrap();
+    overlay.write_file("/notes.txt", data.clone()).unwrap();
+
+    let mut out = Cursor::new(vec![]);
+    overlay
+        .commit(
+            CommitOptions::new().uncompressed_extensions(["jpg", "png"]),
+            &mut out,
+        )
+        .unwrap();
+    let image = Image::new(Cursor::new(out.into_inner())).unwrap();
+    let file = |path: &str| {
+        let inode = image.lookup(path).unwrap();
+        let mut read = vec![];
+        image
+            .open_file(&inode)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+        let InodeHeader::Regular(file) = inode else {
+            panic!("not a basic file");
+        };
+        file
+    };
+    // the tail is a short last block rather than in a fragment block
+    let photo = file("/photo.JPG");
+    assert_eq!(photo.fragment(), crate::INVALID_FRAG);
+    assert_eq!(photo.blocks().len(), 2);
+    assert!(photo.blocks().iter().all(|b| b & COMPRESSED_BIT_BLOCK != 0));
+    let notes = file("/notes.txt");
+    assert_ne!(notes.fragment(), crate::INVALID_FRAG);
+    assert!(notes.blocks().iter().all(|b| b & COMPRESSED_BIT_BLOCK == 0));
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn cow_overlay_compression_choice() {
+    use crate::overlay::{CommitOptions, CompressionChoice, OverlayEntry};
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let bytes = generate(&Spec::dir::<&str>([]), &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let data: Vec<u8> = (0..200_000u32)
+        .flat_map(|i| format!("{} {}\n", i % 977, i * 31 % 1009).into_bytes())
+        .take(200_000)
+        .collect();
+    let mut overlay = image.overlay().unwrap();
+    for name in ["/default", "/fast", "/raw.gz", "/stored.jpg"] {
+        overlay.write_file(name, data.clone()).unwrap();
+    }
+    let choose = |path: &SqshPath, entry: &OverlayEntry| {
+        assert_eq!(entry.size, Some(200_000));
+        match path.as_bytes() {
+            b"/fast" => CompressionChoice::Level(1),
+            b"/raw.gz" => CompressionChoice::Uncompressed,
+            _ => CompressionChoice::Default,
+        }
+    };
+    let mut out = Cursor::new(vec![]);
+    overlay
+        .commit(
+            CommitOptions::new()
+                .uncompressed_extensions(["jpg"])
+                .compression(choose),
+            &mut out,
+        )
+        .unwrap();
+    let image = Image::new(Cursor::new(out.into_inner())).unwrap();
+    let blocks = |path: &str| {
+        let inode = image.lookup(path).unwrap();
+        let mut read = vec![];
+        image
+            .open_file(&inode)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+        let InodeHeader::Regular(file) = inode else {
+            panic!("not a basic file");
+        };
+        file.blocks().to_vec()
+    };
+    let default = blocks("/default");
+    let fast = blocks("/fast");
+    assert!(fast[0] & COMPRESSED_BIT_BLOCK == 0 && fast[0] > default[0]);
+    for path in ["/raw.gz", "/stored.jpg"] {
+        assert!(blocks(path).iter().all(|b| b & COMPRESSED_BIT_BLOCK != 0));
+    }
+
+    let err = overlay
+        .commit(
+            CommitOptions::new().compression(|_, _| CompressionChoice::Level(30)),
+            Cursor::new(vec![]),
+        )
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn cow_overlay_force_owner() {
+    use crate::overlay::CommitOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        ("a", Spec::file("a").with_owner(5, 6)),
+        ("b", Spec::file("b").with_owner(7, 8)),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut overlay = image.overlay().unwrap();
+    overlay.write_file("/c", "c").unwrap();
+    overlay.set_owner("/c", 1000, 1000).unwrap();
+
+    let commit = |options: &CommitOptions| {
+        let mut out = Cursor::new(vec![]);
+        overlay.commit(options, &mut out).unwrap();
+        let image = Image::new(Cursor::new(out.into_inner())).unwrap();
+        let ids = image.id_table().unwrap();
+        ["/", "/a", "/b", "/c"]
+            .map(|path| ids.owner(&image.lookup(path).unwrap()).unwrap())
+            .to_vec()
+    };
+    assert_eq!(
+        commit(CommitOptions::new().force_uid(1000)),
+        [(1000, 0), (1000, 6), (1000, 8), (1000, 1000)]
+    );
+    assert_eq!(
+        commit(CommitOptions::new().force_gid(100)),
+        [(0, 100), (5, 100), (7, 100), (1000, 100)]
+    );
+    assert_eq!(commit(CommitOptions::new().all_root()), [(0, 0); 4]);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn cow_overlay_mode_rules() {
+    use crate::overlay::{CommitOptions, ModeRule};
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        (
+            "bin",
+            Spec::dir([
+                ("su", Spec::file("su").with_mode(0o4755)),
+                ("sh", Spec::symlink("su")),
+            ])
+            .with_mode(0o700),
+        ),
+        ("notes", Spec::file("notes").with_mode(0o600)),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut overlay = image.overlay().unwrap();
+    overlay.write_file("/bin/new", "new").unwrap();
+    overlay.set_mode("/bin/new", 0o2711).unwrap();
+
+    let mut out = Cursor::new(vec![]);
+    overlay
+        .commit(
+            CommitOptions::new()
+                .mode_rule(ModeRule::set(0o755).file_type(FileType::Directory))
+                .mode_rule(ModeRule::remove(0o6000))
+                .mode_rule(ModeRule::add(0o044).matching("/notes")),
+            &mut out,
+        )
+        .unwrap();
+    let image = Image::new(Cursor::new(out.into_inner())).unwrap();
+    let mode = |path: &str| image.lookup(path).unwrap().mode() & 0o7777;
+    assert_eq!(mode("/"), 0o755);
+    assert_eq!(mode("/bin"), 0o755);
+    assert_eq!(mode("/bin/su"), 0o755);
+    assert_eq!(mode("/bin/new"), 0o711);
+    assert_eq!(mode("/bin/sh"), 0o777);
+    assert_eq!(mode("/notes"), 0o644);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn cow_overlay_clamp_mtime() {
+    use crate::overlay::CommitOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        ("old", Spec::file("old").with_mtime(7)),
+        ("new", Spec::file("new").with_mtime(5000)),
+    ])
+    .with_mtime(3000);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut overlay = image.overlay().unwrap();
+    overlay.write_file("/added", "added").unwrap();
+    overlay.write_file("/changed", "changed").unwrap();
+    overlay.set_mtime("/changed", 9000).unwrap();
+
+    let mut out = Cursor::new(vec![]);
+    overlay
+        .commit(
+            CommitOptions::new().clamp_mtime(1000).mkfs_time(1000),
+            &mut out,
+        )
+        .unwrap();
+    let image = Image::new(Cursor::new(out.into_inner())).unwrap();
+    assert_eq!(image.superblock().mkfs_time(), 1000);
+    let mtimes =
+        ["/", "/old", "/new", "/added", "/changed"].map(|path| image.lookup(path).unwrap().mtime());
+    assert_eq!(mtimes, [1000, 7, 1000, 0, 1000]);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn cow_overlay_id_table() {
+    use crate::overlay::CommitOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let root = Spec::dir([
+        ("a", Spec::file("a").with_owner(5, 6)),
+        ("b", Spec::file("b").with_owner(7, 8)),
+        ("c", Spec::file("c").with_owner(6, 5)),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut overlay = image.overlay().unwrap();
+    overlay.remove("/b").unwrap();
+    overlay.write_file("/d", "d").unwrap();
+    overlay.set_owner("/d", 5, 0).unwrap();
+
+    // only the ids still used, each once
+    let mut out = Cursor::new(vec![]);
+    overlay.commit(&CommitOptions::new(), &mut out).unwrap();
+    let image = Image::new(Cursor::new(out.into_inner())).unwrap();
+    let ids = image.id_table().unwrap();
+    let mut sorted = ids.ids().to_vec();
+    sorted.sort();
+    assert_eq!(sorted, [0, 5, 6]);
+    assert_eq!(image.superblock().no_ids(), 3);
+    let owners = ["/a", "/c", "/d"].map(|path| ids.owner(&image.lookup(path).unwrap()).unwrap());
+    assert_eq!(owners, [(5, 6), (6, 5), (5, 0)]);
+
+    let mut out = Cursor::new(vec![]);
+    overlay
+        .commit(CommitOptions::new().all_root(), &mut out)
+        .unwrap();
+    let image = Image::new(Cursor::new(out.into_inner())).unwrap();
+    assert_eq!(image.id_table().unwrap().ids(), [0]);
+
+    // indexes are 16 bits
+    let mut overlay = image.overlay().unwrap();
+    for i in 0..32_768 {
+        let path = format!("/{}", i);
+        overlay.write_file(&path, "").unwrap();
+        overlay.set_owner(&path, i + 1, i + 40_000).unwrap();
+    }
+    let err = overlay
+        .commit(&CommitOptions::new(), Cursor::new(vec![]))
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    overlay
+        .commit(CommitOptions::new().force_gid(0), Cursor::new(vec![]))
+        .unwrap();
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn cow_overlay_padding() {
+    use crate::overlay::CommitOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let root = Spec::dir([
+        ("big", Spec::file(data.clone())),
+        ("small", Spec::file("s")),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut overlay = image.overlay().unwrap();
+    overlay
+        .write_file("/new", data[..150_000].to_vec())
+        .unwrap();
+    overlay.write_file("/tail", "tail").unwrap();
+
+    let commit = |options: &CommitOptions| {
+        let mut out = Cursor::new(vec![]);
+        let committed = overlay.commit(options, &mut out).unwrap();
+        (committed.bytes_used, out.into_inner())
+    };
+    let (bytes_used, bytes) = commit(CommitOptions::new().padding(0));
+    assert_eq!(bytes.len() as u64, bytes_used);
+    let (_, bytes) = commit(CommitOptions::new().padding(65536));
+    assert_eq!(bytes.len() % 65536, 0);
+
+    let (_, bytes) = commit(CommitOptions::new().align_data(4096));
+    assert_eq!(bytes.len() as u64 % PADDING_SIZE, 0);
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    for (path, len) in [("/big", 300_000), ("/new", 150_000)] {
+        let inode = image.lookup(path).unwrap();
+        let InodeHeader::Regular(file) = &inode else {
+            panic!("not a basic file");
+        };
+        assert_eq!(file.start_block() % 4096, 0);
+        let mut read = vec![];
+        image
+            .open_file(&inode)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data[..len]);
+    }
+    let fragments = image.fragments().unwrap();
+    assert!(!fragments.is_empty());
+    assert!(fragments.iter().all(|f| f.start_block() % 4096 == 0));
+    assert!(image.check_links().unwrap().is_empty());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn cow_overlay_statistics() {
+    use crate::compressors::GzipCompressor;
+    use crate::overlay::CommitOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let block =
+        |seed: u32| -> Vec<u8> { (0..131_072u32).map(|i| (i * seed % 251) as u8).collect() };
+    let root = Spec::dir([("a", Spec::file(block(7))), ("b", Spec::file(block(11)))]);
+    // metadata compressed at level 0 is stored as is, so it can be patched
+    let mut gzip = GzipCompressor::default();
+    gzip.set_compression_level(0);
+    let options = GenerateOptions {
+        compressor: Compressor::GZIP(gzip),
+        ..Default::default()
+    };
+    let mut bytes = generate(&root, &options).unwrap();
+    let image = Image::new(Cursor::new(bytes.clone())).unwrap();
+    let start = |path: &str| {
+        let InodeHeader::Regular(file) = image.lookup(path).unwrap() else {
+            panic!("not a basic file");
+        };
+        file.start_block().to_le_bytes()
+    };
+    // point /b at the block of /a, as mksquashfs does for duplicates
+    let (a, b) = (start("/a"), start("/b"));
+    let mut fields = b.to_vec();
+    fields.extend_from_slice(&crate::INVALID_FRAG.to_le_bytes());
+    let at = bytes.windows(8).position(|w| w == fields).unwrap();
+    bytes[at..at + 4].copy_from_slice(&a);
+
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut overlay = image.overlay().unwrap();
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    overlay.write_file("/new", data).unwrap();
+    overlay.write_file("/tail", "tail").unwrap();
+    let committed = overlay
+        .commit(&CommitOptions::new(), Cursor::new(vec![]))
+        .unwrap();
+    assert_eq!(committed.copied, 131_072);
+    assert_eq!(committed.deduplicated, 131_072);
+    assert_eq!((committed.files, committed.bytes_in), (2, 300_004));
+    assert_eq!(committed.fragment_blocks, 1);
+    let tails = (300_000 % 131_072 + 4) as f64;
+    assert_eq!(committed.fragment_fill, tails / 131_072.0);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn cow_overlay_estimate() {
+    use crate::overlay::CommitOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let bytes = generate(
+        &Spec::dir([("big", Spec::file(data.clone()))]),
+        &GenerateOptions::default(),
+    )
+    .unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut overlay = image.overlay().unwrap();
+    overlay.write_file("/copy", data).unwrap();
+    overlay.write_file("/small", "small").unwrap();
+
+    let options = CommitOptions::new();
+    let estimated = overlay.estimate(&options).unwrap();
+    let mut out = Cursor::new(vec![]);
+    let committed = overlay.commit(&options, &mut out).unwrap();
+    assert_eq!(estimated.bytes_used, committed.bytes_used);
+    assert_eq!(estimated.written, committed.written);
+    assert_eq!(
+        out.into_inner().len() as u64,
+        estimated.bytes_used.next_multiple_of(PADDING_SIZE)
+    );
+}
+
+// Polls `future` to completion on the current thread.
+#[cfg(all(feature = "testing", feature = "async"))]
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::task::{Context, Poll, Waker};
+
+    let mut future = std::pin::pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+// Async reader and writer that are only ready every other poll, and move
+// at most 1000 bytes at a time. The most bytes offered to a single write
+// are kept.
+#[cfg(all(feature = "testing", feature = "async"))]
+#[derive(Default)]
+struct SlowStream {
+    data: Cursor<Vec<u8>>,
+    ready: bool,
+    largest_write: usize,
+}
+
+#[cfg(all(feature = "testing", feature = "async"))]
+impl SlowStream {
+    fn poll<T>(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+        io: impl FnOnce(&mut Cursor<Vec<u8>>) -> T,
+    ) -> std::task::Poll<T> {
+        self.ready = !self.ready;
+        if !self.ready {
+            cx.waker().wake_by_ref();
+            return std::task::Poll::Pending;
+        }
+        std::task::Poll::Ready(io(&mut self.data))
+    }
+}
+
+#[cfg(all(feature = "testing", feature = "async"))]
+impl futures_io::AsyncRead for SlowStream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let len = buf.len().min(1000);
+        self.get_mut().poll(cx, |data| data.read(&mut buf[..len]))
+    }
+}
+
+#[cfg(all(feature = "testing", feature = "async"))]
+impl futures_io::AsyncWrite for SlowStream {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let stream = self.get_mut();
+        stream.largest_write = stream.largest_write.max(buf.len());
+        let len = buf.len().min(1000);
+        stream.poll(cx, |data| data.write(&buf[..len]))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.get_mut().poll(cx, |_| Ok(()))
+    }
+
+    fn poll_close(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(all(feature = "testing", feature = "async"))]
+impl futures_io::AsyncSeek for SlowStream {
+    fn poll_seek(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        pos: SeekFrom,
+    ) -> std::task::Poll<std::io::Result<u64>> {
+        self.get_mut().poll(cx, |data| data.seek(pos))
+    }
+}
+
+#[cfg(all(feature = "testing", feature = "async"))]
+#[test]
+fn cow_overlay_async() {
+    use crate::overlay::CommitOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let mut seed = 1u32;
+    let data: Vec<u8> = (0..300_000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as u8
+        })
+        .collect();
+    let bytes = generate(
+        &Spec::dir([("motd", Spec::file("hello"))]),
+        &GenerateOptions::default(),
+    )
+    .unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut overlay = image.overlay().unwrap();
+    let source = SlowStream {
+        data: Cursor::new(data.clone()),
+        ..Default::default()
+    };
+    block_on(overlay.write_file_async("/data", source)).unwrap();
+    assert_eq!(overlay.read("/data").unwrap(), data);
+
+    let mut out = SlowStream::default();
+    let committed = block_on(overlay.commit_async(&CommitOptions::new(), &mut out)).unwrap();
+    let mut sync = Cursor::new(vec![]);
+    assert_eq!(
+        overlay
+            .commit(&CommitOptions::new(), &mut sync)
+            .unwrap()
+            .bytes_used,
+        committed.bytes_used
+    );
+    assert_eq!(out.data.get_ref(), sync.get_ref());
+    // written a block at a time, not as a whole
+    assert!(out.largest_write <= 128 * 1024);
+    let image = Image::new(Cursor::new(out.data.into_inner())).unwrap();
+    let mut read = vec![];
+    let inode = image.lookup("/data").unwrap();
+    image
+        .open_file(&inode)
+        .unwrap()
+        .read_to_end(&mut read)
+        .unwrap();
+    assert_eq!(read, data);
+}
+
+#[cfg(all(feature = "testing", feature = "index"))]
+#[test]
+fn cow_overlay_digest_manifest() {
+    use crate::index::ContentIndex;
+    use crate::overlay::CommitOptions;
+    use crate::testing::{generate, GenerateOptions, Spec};
+
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let root = Spec::dir([
+        (
+            "etc",
+            Spec::dir([
+                ("hosts", Spec::file("localhost")),
+                ("SHA256SUMS", Spec::file("stale")),
+            ]),
+        ),
+        ("big", Spec::file(data)),
+        ("old", Spec::file("removed")),
+        ("link", Spec::symlink("big")),
+    ]);
+    let bytes = generate(&root, &GenerateOptions::default()).unwrap();
+    let image = Image::new(Cursor::new(bytes)).unwrap();
+    let mut overlay = image.overlay().unwrap();
+    overlay
+        .write_file("/etc/hosts", "localhost\nrouter")
+        .unwrap();
+    overlay.write_file("/new\nline", "new").unwrap();
+    overlay.remove("/old").unwrap();
+
+    let mut out = Cursor::new(vec![]);
+    overlay
+        .commit(
+            CommitOptions::new().digest_manifest("/etc/SHA256SUMS"),
+            &mut out,
+        )
+        .unwrap();
+    // the overlay itself is left as it was
+    assert_eq!(overlay.read("/etc/SHA256SUMS").unwrap(), b"stale");
+
+    let image = Image::new(Cursor::new(out.into_inner())).unwrap();
+    let mut manifest = vec![];
+    let inode = image.lookup("/etc/SHA256SUMS").unwrap();
+    image
+        .open_file(&inode)
+        .unwrap()
+        .read_to_end(&mut manifest)
+        .unwrap();
+    let manifest = ContentIndex::read_from(&manifest[..]).unwrap();
+    let mut expected = ContentIndex::default();
+    for (digest, paths) in ContentIndex::build(&image).unwrap().iter() {
+        for path in paths.iter().filter(|p| p.as_slice() != b"/etc/SHA256SUMS") {
+            expected.insert(*digest, path.clone());
+        }
+    }
+    assert_eq!(manifest, expected);
+    let mut paths: Vec<&[u8]> = manifest
+        .iter()
+        .flat_map(|(_, paths)| paths.iter().map(Vec::as_slice))
+        .collect();
+    paths.sort();
+    assert_eq!(paths, [&b"/big"[..], b"/etc/hosts", b"/new\nline"]);
 }
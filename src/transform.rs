@@ -0,0 +1,126 @@
+use std::fmt::Debug;
+use std::io::{BufWriter, Result, Write};
+use std::sync::Arc;
+
+use flate2::write::MultiGzDecoder;
+
+use crate::path::SqshPath;
+
+/// Writer of the contents of an extracted file. Transforms wrap the writer
+/// of the file, or that of the transform registered before them.
+pub trait TransformWriter: Write + Send {
+    /// Writes out what is still buffered once all the contents are written,
+    /// then finishes the wrapped writer.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+impl<W: Write + Send> TransformWriter for BufWriter<W> {
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.flush()
+    }
+}
+
+/// Step applied to the contents of regular files as `Image::extract`
+/// streams them to disk, see `Transforms`. Closures taking the path in the
+/// image and the writer to wrap are transforms too.
+pub trait ContentTransform: Send + Sync {
+    fn wrap(
+        &self,
+        path: &SqshPath,
+        out: Box<dyn TransformWriter>,
+    ) -> Result<Box<dyn TransformWriter>>;
+}
+
+impl<F> ContentTransform for F
+where
+    F: Fn(&SqshPath, Box<dyn TransformWriter>) -> Result<Box<dyn TransformWriter>> + Send + Sync,
+{
+    fn wrap(
+        &self,
+        path: &SqshPath,
+        out: Box<dyn TransformWriter>,
+    ) -> Result<Box<dyn TransformWriter>> {
+        self(path, out)
+    }
+}
+
+/// Transforms to apply to the files whose path in the image matches a glob
+/// pattern, as for `SqshPath::matches`. Files matching several patterns go
+/// through each transform, in the order they were added.
+#[derive(Clone, Default)]
+pub struct Transforms(Vec<(Vec<u8>, Arc<dyn ContentTransform>)>);
+
+impl Transforms {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add<P, T>(&mut self, pattern: P, transform: T) -> &mut Self
+    where
+        P: Into<Vec<u8>>,
+        T: ContentTransform + 'static,
+    {
+        self.0.push((pattern.into(), Arc::new(transform)));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Wraps `out` in the transforms matching `path`, the first added
+    // outermost so it sees the contents as stored.
+    pub(crate) fn wrap(
+        &self,
+        path: &SqshPath,
+        mut out: Box<dyn TransformWriter>,
+    ) -> Result<Box<dyn TransformWriter>> {
+        for (pattern, transform) in self.0.iter().rev() {
+            if path.matches(pattern) {
+                out = transform.wrap(path, out)?;
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl Debug for Transforms {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(p, _)| String::from_utf8_lossy(p)))
+            .finish()
+    }
+}
+
+/// Decompresses gzip files, of one member or several concatenated. Files
+/// keep their name.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Gunzip;
+
+struct GunzipWriter(MultiGzDecoder<Box<dyn TransformWriter>>);
+
+impl Write for GunzipWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.0.flush()
+    }
+}
+
+impl TransformWriter for GunzipWriter {
+    fn finish(self: Box<Self>) -> Result<()> {
+        self.0.finish()?.finish()
+    }
+}
+
+impl ContentTransform for Gunzip {
+    fn wrap(
+        &self,
+        _path: &SqshPath,
+        out: Box<dyn TransformWriter>,
+    ) -> Result<Box<dyn TransformWriter>> {
+        Ok(Box::new(GunzipWriter(MultiGzDecoder::new(out))))
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result, Seek, Write};
+
+use crate::image::Image;
+use crate::overlay::{CompressionChoice, Grouping};
+use crate::path::SqshPath;
+use crate::rewrite::{block_on, rewrite, Node, RewriteOptions, SeekSink};
+use crate::{ReadSeek, PADDING_SIZE};
+
+/// Outcome of `Image::trim`.
+#[derive(Clone, Debug, Default)]
+pub struct Trimmed {
+    /// Paths matching a pattern, which were left out with everything under
+    /// them.
+    pub excluded: Vec<SqshPath>,
+    /// Size of the new image, before padding.
+    pub bytes_used: u64,
+}
+
+pub(crate) fn trim<R: ReadSeek, W: Write + Seek, P: AsRef<[u8]>>(
+    image: &Image<R>,
+    exclude: &[P],
+    out: W,
+) -> Result<Trimmed> {
+    let patterns: Vec<&[u8]> = exclude.iter().map(|p| p.as_ref()).collect();
+    let mut trimmed = Trimmed::default();
+    let nodes = keep_tree(image, &patterns, &mut trimmed.excluded)?;
+    let options = RewriteOptions {
+        ids: image.id_table()?.ids().to_vec(),
+        fragments: false,
+        mkfs_time: image.superblock().mkfs_time(),
+        zstd_dictionary: None,
+        grouping: Grouping::Tree,
+        padding: PADDING_SIZE,
+        alignment: 0,
+    };
+    let rewritten = block_on(rewrite(image, nodes, &options, SeekSink::new(out)?))?;
+    trimmed.bytes_used = rewritten.bytes_used;
+    Ok(trimmed)
+}
+
+// Reads the directory tree, leaving out the paths matching a pattern. Hard
+// linked inodes are read once and count their remaining links.
+fn keep_tree<R: ReadSeek>(
+    image: &Image<R>,
+    patterns: &[&[u8]],
+    excluded: &mut Vec<SqshPath>,
+) -> Result<Vec<Node>> {
+    let mut nodes = vec![Node {
+        inode: image.root()?,
+        children: vec![],
+        links: 1,
+        contents: None,
+        compression: CompressionChoice::Default,
+    }];
+    let mut seen: HashMap<u32, usize> = HashMap::new();
+    let mut dirs = vec![(0, SqshPath::root())];
+    while let Some((dir, path)) = dirs.pop() {
+        let mut children = vec![];
+        for entry in image.read_dir(&nodes[dir].inode)? {
+            let child_path = path.join(entry.name())?;
+            if patterns.iter().any(|p| child_path.matches(p)) {
+                excluded.push(child_path);
+                continue;
+            }
+            let node = match seen.get(&entry.inode_number()) {
+                Some(&node) => {
+                    if nodes[node].inode.is_dir() {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("directory linked more than once: {}", child_path),
+                        ));
+                    }
+                    nodes[node].links += 1;
+                    node
+                }
+                None => {
+                    let inode = image.open_entry(&entry)?;
+                    nodes.push(Node {
+                        inode,
+                        children: vec![],
+                        links: 1,
+                        contents: None,
+                        compression: CompressionChoice::Default,
+                    });
+                    let node = nodes.len() - 1;
+                    seen.insert(entry.inode_number(), node);
+                    if nodes[node].inode.is_dir() {
+                        dirs.push((node, child_path));
+                    }
+                    node
+                }
+            };
+            children.push((entry.name().to_vec(), node));
+        }
+        nodes[dir].children = children;
+    }
+    Ok(nodes)
+}
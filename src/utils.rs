@@ -1,3 +1,6 @@
+use std::io::{Error, ErrorKind, Read, Result};
+use std::mem;
+
 // TODO: remove inner and use tuple 0
 macro_rules! get_set_field {
     ($get_name:ident, $set_name:ident, $typ:ident) => {
@@ -14,23 +17,123 @@ macro_rules! get_set_field {
 macro_rules! get_set_field_tuple {
     ($get_name:ident, $set_name:ident, $typ:ident, $start:expr, $size:expr) => {
         pub fn $get_name(&self) -> $typ {
-            let mut buf: [u8; $size] = [0; $size];
-            let start = $start;
-            let end = start + $size;
-            buf.clone_from_slice(&self.0[start..end]);
-            $typ::from_le_bytes(buf)
+            $typ::from_le_bytes($crate::utils::field::<_, { $start }, { $size }>(&self.0))
         }
 
         pub fn $set_name(&mut self, value: $typ) {
-            let value = value.to_le_bytes();
-            let start = $start;
-            let end = start + $size;
-            for i in start..end {
-                self.0[i] = value[i - start];
-            }
+            $crate::utils::set_field::<_, { $start }, { $size }>(&mut self.0, value.to_le_bytes());
         }
     };
+    // The last field of a struct also records where the fields end, to
+    // check against the on-disk size.
+    ($get_name:ident, $set_name:ident, $typ:ident, $start:expr, $size:expr, last) => {
+        $crate::utils::get_set_field_tuple!($get_name, $set_name, $typ, $start, $size);
+
+        pub(crate) const FIELDS_END: usize = $start + $size;
+    };
+}
+
+// Enters a `tracing` span at `$level` until the end of the enclosing
+// block. Fields are only evaluated when the span is enabled, and the whole
+// thing compiles to nothing without the tracing feature.
+macro_rules! trace_span {
+    ($level:ident, $name:expr $(, $($fields:tt)*)?) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::span!(tracing::Level::$level, $name $(, $($fields)*)?).entered();
+    };
 }
 
 pub(crate) use get_set_field;
 pub(crate) use get_set_field_tuple;
+pub(crate) use trace_span;
+
+/// The `SIZE` bytes at `START` in an on-disk struct of `N` bytes. Fields
+/// that don't fit fail the build, so layouts can't drift past the struct
+/// sizes.
+pub(crate) fn field<const N: usize, const START: usize, const SIZE: usize>(
+    bytes: &[u8; N],
+) -> [u8; SIZE] {
+    const { assert!(START + SIZE <= N, "field past the end of its struct") };
+    let mut field = [0; SIZE];
+    field.copy_from_slice(&bytes[START..START + SIZE]);
+    field
+}
+
+pub(crate) fn set_field<const N: usize, const START: usize, const SIZE: usize>(
+    bytes: &mut [u8; N],
+    value: [u8; SIZE],
+) {
+    const { assert!(START + SIZE <= N, "field past the end of its struct") };
+    bytes[START..START + SIZE].copy_from_slice(&value);
+}
+
+/// Splits the next `N` bytes off the front of `bytes`, for parsing fixed
+/// layouts field by field. Panics if fewer than `N` bytes are left.
+pub(crate) fn split_array<const N: usize>(bytes: &mut &[u8]) -> [u8; N] {
+    let (field, rest) = bytes.split_at(N);
+    *bytes = rest;
+    let mut array = [0; N];
+    array.copy_from_slice(field);
+    array
+}
+
+/// Fixed-size little-endian integers that can be decoded in bulk from
+/// on-disk tables.
+pub(crate) trait FromLeSlice: Sized {
+    const SIZE: usize;
+
+    fn from_le_slice(bytes: &[u8]) -> Self;
+}
+
+macro_rules! impl_from_le_slice {
+    ($($typ:ident),*) => {
+        $(
+            impl FromLeSlice for $typ {
+                const SIZE: usize = mem::size_of::<$typ>();
+
+                fn from_le_slice(bytes: &[u8]) -> Self {
+                    let mut buf = [0; mem::size_of::<$typ>()];
+                    buf.copy_from_slice(bytes);
+                    $typ::from_le_bytes(buf)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_le_slice!(u16, u32, u64, i64);
+
+/// Decodes a packed little-endian table, failing if `bytes` is not a whole
+/// number of entries.
+pub(crate) fn decode_le_slice<T: FromLeSlice>(bytes: &[u8]) -> Result<Vec<T>> {
+    if !bytes.len().is_multiple_of(T::SIZE) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "table length {} is not a multiple of entry size {}",
+                bytes.len(),
+                T::SIZE
+            ),
+        ));
+    }
+    Ok(bytes.chunks_exact(T::SIZE).map(T::from_le_slice).collect())
+}
+
+// Whether `a` and `b` read the same bytes to their end.
+pub(crate) fn same_content<A: Read, B: Read>(mut a: A, mut b: B) -> Result<bool> {
+    let mut buf_a = vec![0; 64 * 1024];
+    let mut buf_b = vec![0; 64 * 1024];
+    loop {
+        let read = a.read(&mut buf_a)?;
+        if read == 0 {
+            return Ok(b.read(&mut buf_b[..1])? == 0);
+        }
+        match b.read_exact(&mut buf_b[..read]) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            result => result?,
+        }
+        if buf_a[..read] != buf_b[..read] {
+            return Ok(false);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{BufReader, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+use crate::image::Image;
+use crate::inode::{FileType, InodeHeader};
+use crate::path::SqshPath;
+use crate::utils::same_content;
+use crate::ReadSeek;
+
+/// Difference between an image and a directory tree, as returned by
+/// `Image::verify_against`. Owners are `(uid, gid)` pairs; values are given
+/// as in the image, then as found on disk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// In the image but not on disk.
+    Missing,
+    /// On disk but not in the image.
+    Unexpected,
+    FileType(FileType, FileType),
+    Mode(u16, u16),
+    Owner((u32, u32), (u32, u32)),
+    Size(u64, u64),
+    Content,
+    LinkTarget(Vec<u8>, Vec<u8>),
+}
+
+fn disk_file_type(file_type: fs::FileType) -> Option<FileType> {
+    Some(match file_type {
+        t if t.is_dir() => FileType::Directory,
+        t if t.is_file() => FileType::Regular,
+        t if t.is_symlink() => FileType::Symlink,
+        t if t.is_block_device() => FileType::BlockDevice,
+        t if t.is_char_device() => FileType::CharDevice,
+        t if t.is_fifo() => FileType::Fifo,
+        t if t.is_socket() => FileType::Socket,
+        _ => return None,
+    })
+}
+
+pub(crate) fn verify<R: ReadSeek>(
+    image: &Image<R>,
+    root: &Path,
+) -> Result<Vec<(SqshPath, Mismatch)>> {
+    let ids = image.id_table()?;
+    let mut mismatches = vec![];
+    // directories missing on disk, whose contents are not reported
+    let mut missing: Vec<SqshPath> = vec![];
+    for entry in image.walk("/")? {
+        let entry = entry?;
+        let path = entry.path;
+        let inode = &entry.inode;
+        if missing.iter().any(|dir| path.strip_prefix(dir).is_some()) {
+            continue;
+        }
+
+        let mut disk_path = root.to_path_buf();
+        for name in path.components() {
+            disk_path.push(OsStr::from_bytes(name));
+        }
+        let metadata = match fs::symlink_metadata(&disk_path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                if inode.is_dir() {
+                    missing.push(path.clone());
+                }
+                mismatches.push((path, Mismatch::Missing));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let file_type = inode.file_type();
+        match disk_file_type(metadata.file_type()) {
+            Some(found) if found == file_type => {}
+            found => {
+                if inode.is_dir() {
+                    missing.push(path.clone());
+                }
+                if let Some(found) = found {
+                    mismatches.push((path, Mismatch::FileType(file_type, found)));
+                }
+                continue;
+            }
+        }
+
+        let mode = metadata.mode() as u16 & 0o7777;
+        if file_type != FileType::Symlink && inode.mode() & 0o7777 != mode {
+            mismatches.push((path.clone(), Mismatch::Mode(inode.mode() & 0o7777, mode)));
+        }
+        let owner = ids.owner(inode)?;
+        if owner != (metadata.uid(), metadata.gid()) {
+            mismatches.push((
+                path.clone(),
+                Mismatch::Owner(owner, (metadata.uid(), metadata.gid())),
+            ));
+        }
+
+        let file_size = match inode {
+            InodeHeader::Regular(r) => Some(r.file_size() as u64),
+            InodeHeader::LRegular(r) => Some(r.file_size()),
+            _ => None,
+        };
+        if let Some(size) = file_size {
+            if size != metadata.len() {
+                mismatches.push((path, Mismatch::Size(size, metadata.len())));
+            } else if !same_content(
+                image.open_file(inode)?,
+                BufReader::new(File::open(&disk_path)?),
+            )? {
+                mismatches.push((path, Mismatch::Content));
+            }
+            continue;
+        }
+
+        match inode {
+            InodeHeader::Symlink(s) | InodeHeader::LSymlink(s) => {
+                let target = fs::read_link(&disk_path)?;
+                let target = target.as_os_str().as_bytes();
+                if s.target() != target {
+                    mismatches.push((
+                        path,
+                        Mismatch::LinkTarget(s.target().to_vec(), target.to_vec()),
+                    ));
+                }
+            }
+            InodeHeader::Directory(_) | InodeHeader::LDirectory(_) => {
+                let names: HashSet<Vec<u8>> = image
+                    .read_dir(inode)?
+                    .iter()
+                    .map(|e| e.name().to_vec())
+                    .collect();
+                let mut unexpected = vec![];
+                for dirent in fs::read_dir(&disk_path)? {
+                    let name = dirent?.file_name().as_bytes().to_vec();
+                    if !names.contains(&name) {
+                        unexpected.push(name);
+                    }
+                }
+                unexpected.sort();
+                for name in unexpected {
+                    mismatches.push((path.join(&name)?, Mismatch::Unexpected));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(mismatches)
+}
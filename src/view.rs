@@ -0,0 +1,172 @@
+use std::io::{Error, ErrorKind, Result};
+
+use crate::inode::{
+    fragment_blocks, read_inode_header, FileType, InodeHeader, DEV_INODE_HEADER_SIZE,
+    DIRECTORY_INDEX_SIZE, DIRECTORY_INODE_HEADER_SIZE, IPC_INODE_HEADER_SIZE,
+    LDEV_INODE_HEADER_SIZE, LDIRECTORY_INODE_HEADER_SIZE, LIPC_INODE_HEADER_SIZE,
+    LREGULAR_INODE_HEADER_SIZE, REGULAR_INODE_HEADER_SIZE, SYMLINK_INODE_HEADER_SIZE,
+};
+use crate::superblock::Superblock;
+use crate::utils::FromLeSlice;
+
+/// An inode borrowed from a decompressed inode table, as returned by
+/// `Image::inode_table`. Only the fields shared by all inode types are
+/// decoded; `to_header` parses the rest into an owned `InodeHeader`.
+///
+/// Scanning with views keeps a single copy of the table in memory, instead
+/// of the table and every inode parsed from it.
+#[derive(Clone, Copy, Debug)]
+pub struct InodeView<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> InodeView<'a> {
+    /// View of the inode at `offset` in `table`.
+    pub fn new(table: &'a [u8], offset: usize, superblock: &Superblock) -> Result<Self> {
+        let rest = table
+            .get(offset..)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "offset past the inode table"))?;
+        let len = inode_size(rest, superblock)?;
+        Ok(Self {
+            bytes: &rest[..len],
+            offset,
+        })
+    }
+
+    /// The inode as stored, including block lists, symlink targets and
+    /// directory indexes.
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Offset of the inode in the decompressed table.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn inode_type(&self) -> u16 {
+        u16::from_le_slice(&self.bytes[0..2])
+    }
+
+    pub fn file_type(&self) -> Option<FileType> {
+        FileType::from_inode_type(self.inode_type())
+    }
+
+    pub fn mode(&self) -> u16 {
+        u16::from_le_slice(&self.bytes[2..4])
+    }
+
+    pub fn uid(&self) -> u16 {
+        u16::from_le_slice(&self.bytes[4..6])
+    }
+
+    pub fn guid(&self) -> u16 {
+        u16::from_le_slice(&self.bytes[6..8])
+    }
+
+    pub fn mtime(&self) -> u32 {
+        u32::from_le_slice(&self.bytes[8..12])
+    }
+
+    pub fn inode_number(&self) -> u32 {
+        u32::from_le_slice(&self.bytes[12..16])
+    }
+
+    pub fn to_header(&self, superblock: &Superblock) -> Result<InodeHeader> {
+        read_inode_header(&mut &self.bytes[..], superblock)
+    }
+}
+
+/// Iterates over the inodes of a decompressed inode table in table order.
+/// Stops after the first error.
+#[derive(Debug)]
+pub struct InodeViews<'a> {
+    table: &'a [u8],
+    offset: usize,
+    superblock: &'a Superblock,
+}
+
+impl<'a> InodeViews<'a> {
+    pub fn new(table: &'a [u8], superblock: &'a Superblock) -> Self {
+        Self {
+            table,
+            offset: 0,
+            superblock,
+        }
+    }
+}
+
+impl<'a> Iterator for InodeViews<'a> {
+    type Item = Result<InodeView<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.table.len() {
+            return None;
+        }
+        let view = InodeView::new(self.table, self.offset, self.superblock);
+        self.offset = match &view {
+            Ok(view) => view.offset + view.bytes.len(),
+            Err(_) => self.table.len(),
+        };
+        Some(view)
+    }
+}
+
+// On-disk size of the inode at the start of `bytes`.
+fn inode_size(bytes: &[u8], superblock: &Superblock) -> Result<usize> {
+    let truncated = || Error::new(ErrorKind::UnexpectedEof, "truncated inode");
+    let u32_at = |at: usize| {
+        bytes
+            .get(at..at + 4)
+            .map(u32::from_le_slice)
+            .ok_or_else(truncated)
+    };
+    let inode_type = bytes
+        .get(0..2)
+        .map(u16::from_le_slice)
+        .ok_or_else(truncated)?;
+    let size = match inode_type {
+        1 => DIRECTORY_INODE_HEADER_SIZE as u64,
+        2 => {
+            let blocks = fragment_blocks(u32_at(20)?, u32_at(28)? as u64, superblock);
+            REGULAR_INODE_HEADER_SIZE as u64 + blocks.saturating_mul(4)
+        }
+        3 => SYMLINK_INODE_HEADER_SIZE as u64 + u32_at(20)? as u64,
+        4 | 5 => DEV_INODE_HEADER_SIZE as u64,
+        6 | 7 => IPC_INODE_HEADER_SIZE as u64,
+        8 => {
+            let count = bytes
+                .get(32..34)
+                .map(u16::from_le_slice)
+                .ok_or_else(truncated)?;
+            let mut size = LDIRECTORY_INODE_HEADER_SIZE;
+            for _ in 0..count {
+                // each index entry is followed by its name, of `size + 1` bytes
+                size += DIRECTORY_INDEX_SIZE + u32_at(size + 8)? as usize + 1;
+            }
+            size as u64
+        }
+        9 => {
+            let file_size = bytes
+                .get(24..32)
+                .map(u64::from_le_slice)
+                .ok_or_else(truncated)?;
+            let blocks = fragment_blocks(u32_at(44)?, file_size, superblock);
+            LREGULAR_INODE_HEADER_SIZE as u64 + blocks.saturating_mul(4)
+        }
+        10 => SYMLINK_INODE_HEADER_SIZE as u64 + u32_at(20)? as u64 + 4,
+        11 | 12 => LDEV_INODE_HEADER_SIZE as u64,
+        13 | 14 => LIPC_INODE_HEADER_SIZE as u64,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid inode type {}", inode_type),
+            ))
+        }
+    };
+    if size > bytes.len() as u64 {
+        return Err(truncated());
+    }
+    Ok(size as usize)
+}
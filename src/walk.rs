@@ -0,0 +1,260 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Result};
+use std::vec;
+
+use crate::file::FileReader;
+use crate::fragments::FragmentEntry;
+use crate::image::Image;
+use crate::inode::{DirectoryEntry, InodeHeader};
+use crate::path::SqshPath;
+use crate::ReadSeek;
+
+#[derive(Debug)]
+pub struct WalkEntry {
+    pub path: SqshPath,
+    pub inode: InodeHeader,
+}
+
+impl WalkEntry {
+    /// Path for display, with invalid UTF-8 replaced.
+    pub fn path_lossy(&self) -> Cow<'_, str> {
+        self.path.to_string_lossy()
+    }
+}
+
+/// Order of the entries of a directory, as listed by `Image::read_dir_with`
+/// and walked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Order {
+    /// As stored. mksquashfs stores entries sorted by name, byte-wise, but
+    /// other tools or damaged images may not.
+    #[default]
+    OnDisk,
+    /// Sorted by name, byte-wise, whatever the order stored, for output
+    /// that only depends on the contents of the image.
+    ByName,
+}
+
+impl Order {
+    pub(crate) fn sort(self, entries: &mut [DirectoryEntry]) {
+        if self == Order::ByName {
+            entries.sort_by(|a, b| a.name().cmp(b.name()));
+        }
+    }
+}
+
+/// Controls how `Image::list_dir_with` lists a directory.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ListOptions {
+    pub(crate) order: Order,
+    pub(crate) dots: bool,
+}
+
+impl ListOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn order(&mut self, order: Order) -> &mut Self {
+        self.order = order;
+        self
+    }
+
+    /// Lists `.` and `..` first, as readdir does. Squashfs doesn't store
+    /// them, so they are made up from the directory and its parent. Off by
+    /// default, for listings like manifests that only want stored entries.
+    pub fn dots(&mut self, dots: bool) -> &mut Self {
+        self.dots = dots;
+        self
+    }
+}
+
+/// Controls how `Image::walk_with` walks a tree.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WalkOptions {
+    order: Order,
+    max_depth: Option<usize>,
+    max_entries: Option<u64>,
+}
+
+impl WalkOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Order of the entries of each directory.
+    pub fn order(&mut self, order: Order) -> &mut Self {
+        self.order = order;
+        self
+    }
+
+    /// Fails the walk on reaching an entry more than `depth` levels below
+    /// its root, to bound the work done on untrusted images.
+    pub fn max_depth(&mut self, depth: usize) -> &mut Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Fails the walk on reaching more than `entries` entries, the root
+    /// included.
+    pub fn max_entries(&mut self, entries: u64) -> &mut Self {
+        self.max_entries = Some(entries);
+        self
+    }
+}
+
+/// Depth-first iterator over a directory tree, created by `Image::walk`.
+/// Directories are yielded before their contents, which come in the
+/// `Order` of the options, so walking the same image always yields the same
+/// sequence. Only one listing per level is held in memory. A directory
+/// already visited, such as one reached again through a corrupted entry, is
+/// yielded but not descended into.
+pub struct Walk<'a, R: ReadSeek> {
+    image: &'a Image<R>,
+    options: WalkOptions,
+    root: Option<WalkEntry>,
+    stack: Vec<(SqshPath, vec::IntoIter<DirectoryEntry>)>,
+    visited: HashSet<u32>,
+    entries: u64,
+    // set once a limit is hit, ending the walk
+    failed: bool,
+}
+
+impl<'a, R: ReadSeek> Walk<'a, R> {
+    pub(crate) fn new(
+        image: &'a Image<R>,
+        path: SqshPath,
+        inode: InodeHeader,
+        options: WalkOptions,
+    ) -> Self {
+        Self {
+            image,
+            options,
+            root: Some(WalkEntry { path, inode }),
+            stack: vec![],
+            visited: HashSet::new(),
+            entries: 0,
+            failed: false,
+        }
+    }
+
+    fn check_limits(&mut self) -> Result<()> {
+        self.entries += 1;
+        let error = match self.options {
+            WalkOptions {
+                max_entries: Some(max),
+                ..
+            } if self.entries > max => format!("walk exceeds {} entries", max),
+            WalkOptions {
+                max_depth: Some(max),
+                ..
+            } if self.stack.len() > max => format!("walk exceeds a depth of {}", max),
+            _ => return Ok(()),
+        };
+        self.failed = true;
+        Err(Error::new(ErrorKind::InvalidData, error))
+    }
+
+    fn visit(&mut self, entry: WalkEntry) -> Result<WalkEntry> {
+        if entry.inode.is_dir() && self.visited.insert(entry.inode.inode_number()) {
+            let listing = self.image.read_dir_with(&entry.inode, self.options.order)?;
+            self.stack.push((entry.path.clone(), listing.into_iter()));
+        }
+        Ok(entry)
+    }
+}
+
+impl<'a, R: ReadSeek> Iterator for Walk<'a, R> {
+    type Item = Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if let Some(root) = self.root.take() {
+            return Some(self.check_limits().and_then(|_| self.visit(root)));
+        }
+        loop {
+            let (parent, listing) = self.stack.last_mut()?;
+            let dirent = match listing.next() {
+                Some(dirent) => dirent,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            let path = parent.join(dirent.name());
+            let entry = self.check_limits().and(path).and_then(|path| {
+                let inode = self.image.open_entry(&dirent)?;
+                self.visit(WalkEntry { path, inode })
+            });
+            return Some(entry);
+        }
+    }
+}
+
+/// Entry yielded by `Entries`.
+pub struct Entry<'a, R: ReadSeek> {
+    pub path: SqshPath,
+    pub inode: InodeHeader,
+    /// Contents of a regular file, unless the entry is a hard link to an
+    /// earlier one.
+    pub data: Option<FileReader<'a, R>>,
+    /// Path of the earlier entry with the same inode, for inodes other than
+    /// directories reached through several paths.
+    pub hard_link: Option<SqshPath>,
+}
+
+/// Iterator over all the entries of an image, created by `Image::entries`.
+pub struct Entries<'a, R: ReadSeek> {
+    image: &'a Image<R>,
+    entries: vec::IntoIter<WalkEntry>,
+    // first path yielded for each inode, to report hard links
+    paths: HashMap<u32, SqshPath>,
+    fragments: Vec<FragmentEntry>,
+}
+
+impl<'a, R: ReadSeek> Entries<'a, R> {
+    pub(crate) fn new(
+        image: &'a Image<R>,
+        entries: Vec<WalkEntry>,
+        fragments: Vec<FragmentEntry>,
+    ) -> Self {
+        Self {
+            image,
+            entries: entries.into_iter(),
+            paths: HashMap::new(),
+            fragments,
+        }
+    }
+}
+
+impl<'a, R: ReadSeek> Iterator for Entries<'a, R> {
+    type Item = Result<Entry<'a, R>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let WalkEntry { path, inode } = self.entries.next()?;
+        let mut entry = Entry {
+            path,
+            inode,
+            data: None,
+            hard_link: None,
+        };
+        if !entry.inode.is_dir() {
+            if let Some(first) = self.paths.get(&entry.inode.inode_number()) {
+                entry.hard_link = Some(first.clone());
+                return Some(Ok(entry));
+            }
+            self.paths
+                .insert(entry.inode.inode_number(), entry.path.clone());
+        }
+        if entry.inode.is_file() {
+            match self.image.open_file(&entry.inode) {
+                Ok(data) => entry.data = Some(data.with_fragment_table(&self.fragments)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        Some(Ok(entry))
+    }
+}
@@ -0,0 +1,99 @@
+use std::fmt::Display;
+use std::io::ErrorKind;
+
+use crate::image::TableKind;
+use crate::inode::FileType;
+use crate::path::SqshPath;
+
+/// Oddity found while reading an image that doesn't stop it from being
+/// read, but suggests a buggy or unusual producer. Collected by the image
+/// and returned by `Image::warnings`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Warning {
+    /// Superblock flag bits with no known meaning.
+    UnknownFlags(u16),
+    /// Version other than 4.0, which is the only one this crate reads.
+    Version(u16, u16),
+    /// A field the format leaves unused is not zero. `index` is the entry
+    /// of the table the field belongs to, if any.
+    UnusedField {
+        field: &'static str,
+        index: Option<u32>,
+        value: u64,
+    },
+    /// The padding after `bytes_used`, up to the next 4 KiB boundary, holds
+    /// data. `offset` is the first non-zero byte.
+    Padding { offset: u64 },
+    /// The root inode isn't a directory, let through by
+    /// `Image::set_lenient_root`.
+    RootNotDirectory(FileType),
+    /// The `nlink` of an inode doesn't match the directory entries, as
+    /// found by `Image::check_links`.
+    LinkCount {
+        inode_number: u32,
+        nlink: u32,
+        expected: u32,
+    },
+    /// The image is shorter than its `bytes_used`, as when a download was
+    /// interrupted.
+    Truncated { len: u64, bytes_used: u64 },
+    /// A table is cut off by the end of the image, as found by
+    /// `Image::missing_tables` or a partial extraction.
+    MissingTable(TableKind),
+    /// A file left out of a partial extraction because its contents are
+    /// cut off.
+    Unreadable(SqshPath),
+    /// An xattr that couldn't be restored on an extracted entry, such as
+    /// for lack of privileges or support from the target filesystem.
+    XattrNotSet {
+        path: SqshPath,
+        name: Vec<u8>,
+        kind: ErrorKind,
+    },
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::UnknownFlags(flags) => write!(f, "unknown superblock flags {:#06x}", flags),
+            Warning::Version(major, minor) => write!(f, "unexpected version {}.{}", major, minor),
+            Warning::UnusedField {
+                field,
+                index: Some(index),
+                value,
+            } => write!(
+                f,
+                "unused field {} of entry {} is {:#x}",
+                field, index, value
+            ),
+            Warning::UnusedField { field, value, .. } => {
+                write!(f, "unused field {} is {:#x}", field, value)
+            }
+            Warning::Padding { offset } => write!(f, "non-zero padding at {}", offset),
+            Warning::RootNotDirectory(file_type) => {
+                write!(f, "root inode is a {:?}, not a directory", file_type)
+            }
+            Warning::LinkCount {
+                inode_number,
+                nlink,
+                expected,
+            } => write!(
+                f,
+                "inode {} has nlink {}, expected {}",
+                inode_number, nlink, expected
+            ),
+            Warning::Truncated { len, bytes_used } => {
+                write!(f, "image is {} bytes, expected {}", len, bytes_used)
+            }
+            Warning::MissingTable(kind) => write!(f, "{:?} table cut off", kind),
+            Warning::Unreadable(path) => write!(f, "{}: contents cut off", path),
+            Warning::XattrNotSet { path, name, kind } => write!(
+                f,
+                "{}: can't set {}: {}",
+                path,
+                String::from_utf8_lossy(name),
+                kind
+            ),
+        }
+    }
+}
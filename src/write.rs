@@ -0,0 +1,314 @@
+use std::io::{Error, ErrorKind, Result, Write};
+use std::mem;
+use std::ops::Range;
+
+use crate::compressors::{Compress, Compressor};
+use crate::inode::{
+    DirectoryEntry, DirectoryHeader, DirectoryIndex, FileType, InodeRef, DIRECTORY_ENTRY_SIZE,
+    DIRECTORY_HEADER_MAX_COUNT, DIRECTORY_HEADER_SIZE, LDIRECTORY_INODE_HEADER_SIZE,
+};
+use crate::{INVALID_XATTR, METADATA_SIZE};
+
+// set in metadata block headers of blocks stored uncompressed
+const UNCOMPRESSED_BIT: u16 = 1 << 15;
+// longest name a directory entry can hold
+const MAX_NAME_LEN: usize = 256;
+
+/// Packs a metadata table into blocks of `METADATA_SIZE` bytes, each
+/// preceded by its 2 byte header. Blocks are compressed unless that doesn't
+/// make them smaller, or compression is disabled with `set_uncompressed` to
+/// honour the `*_STORED_UNCOMPRESSED` superblock flags.
+///
+/// The table is kept in memory until `finish`, since its location in the
+/// image is usually only known once earlier tables are written.
+#[derive(Debug)]
+pub struct MetadataWriter {
+    compressor: Compressor,
+    uncompressed: bool,
+    // bytes not packed into a block yet
+    pending: Vec<u8>,
+    table: Vec<u8>,
+    // offset in `table` of each block
+    blocks: Vec<u64>,
+}
+
+impl MetadataWriter {
+    pub fn new(compressor: Compressor) -> Self {
+        Self {
+            compressor,
+            uncompressed: false,
+            pending: Vec::with_capacity(METADATA_SIZE),
+            table: vec![],
+            blocks: vec![],
+        }
+    }
+
+    pub fn set_uncompressed(&mut self, uncompressed: bool) {
+        self.uncompressed = uncompressed;
+    }
+
+    /// Where the next byte written will be, as a block offset relative to
+    /// the start of the table and an offset into the uncompressed block.
+    /// This is how inode references and directory headers locate data.
+    pub fn position(&self) -> InodeRef {
+        InodeRef::new(self.table.len() as u32, self.pending.len() as u16)
+    }
+
+    /// Offsets relative to the table start of the blocks written so far,
+    /// as stored in the index of the fragment, id, export and xattr id
+    /// tables.
+    pub fn block_offsets(&self) -> &[u64] {
+        &self.blocks
+    }
+
+    fn pack_block(&mut self) -> Result<()> {
+        let len = self.pending.len().min(METADATA_SIZE);
+        let block: Vec<u8> = self.pending.drain(..len).collect();
+        let mut compressed = vec![];
+        if !self.uncompressed {
+            self.compressor.compress(&mut &block[..], &mut compressed)?;
+        }
+        self.blocks.push(self.table.len() as u64);
+        if !self.uncompressed && compressed.len() < block.len() {
+            self.table
+                .extend_from_slice(&(compressed.len() as u16).to_le_bytes());
+            self.table.extend_from_slice(&compressed);
+        } else {
+            let header = block.len() as u16 | UNCOMPRESSED_BIT;
+            self.table.extend_from_slice(&header.to_le_bytes());
+            self.table.extend_from_slice(&block);
+        }
+        Ok(())
+    }
+
+    /// Packs the last, partial block and returns the table.
+    pub fn finish(self) -> Result<Vec<u8>> {
+        Ok(self.finish_indexed()?.0)
+    }
+
+    /// Like `finish`, also returning the offsets of all the blocks.
+    pub fn finish_indexed(mut self) -> Result<(Vec<u8>, Vec<u64>)> {
+        if !self.pending.is_empty() {
+            self.pack_block()?;
+        }
+        Ok((mem::take(&mut self.table), mem::take(&mut self.blocks)))
+    }
+}
+
+impl Write for MetadataWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.pending.extend_from_slice(buf);
+        while self.pending.len() >= METADATA_SIZE {
+            self.pack_block()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Entry of a directory to write, pointing to an inode already written.
+#[derive(Clone, Debug)]
+pub struct NewDirectoryEntry {
+    pub name: Vec<u8>,
+    pub inode: InodeRef,
+    pub inode_number: u32,
+    pub file_type: FileType,
+}
+
+/// Where `DirectoryWriter` put a listing, for the directory inode.
+#[derive(Clone, Debug)]
+pub struct DirectoryListing {
+    /// Block and offset of the listing in the directory table.
+    pub start: InodeRef,
+    /// Size of the listing plus 3, as stored in the inode.
+    pub file_size: u32,
+    /// Index for an extended directory inode, one entry for each metadata
+    /// block the listing reaches past its first.
+    pub index: Vec<DirectoryIndex>,
+}
+
+/// Writes the directory table one listing at a time. Entries are sorted by
+/// name and grouped under headers, and large listings get the index lookups
+/// use to skip to the right metadata block.
+#[derive(Debug)]
+pub struct DirectoryWriter {
+    table: MetadataWriter,
+}
+
+impl DirectoryWriter {
+    pub fn new(compressor: Compressor) -> Self {
+        Self {
+            table: MetadataWriter::new(compressor),
+        }
+    }
+
+    pub fn set_uncompressed(&mut self, uncompressed: bool) {
+        self.table.set_uncompressed(uncompressed);
+    }
+
+    pub fn write_dir(&mut self, mut entries: Vec<NewDirectoryEntry>) -> Result<DirectoryListing> {
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        for (i, entry) in entries.iter().enumerate() {
+            let name = &entry.name;
+            if name.is_empty()
+                || name.len() > MAX_NAME_LEN
+                || name.contains(&b'/')
+                || name == b"."
+                || name == b".."
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid entry name {:?}", String::from_utf8_lossy(name)),
+                ));
+            }
+            if i > 0 && entries[i - 1].name == *name {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("duplicate entry {:?}", String::from_utf8_lossy(name)),
+                ));
+            }
+        }
+
+        let start = self.table.position();
+        let mut index = vec![];
+        let mut written = 0;
+        let mut block = start.block();
+        for group in header_groups(start.offset() as usize, &entries) {
+            let entries = &entries[group];
+            let first = &entries[0];
+            let position = self.table.position();
+            if position.block() != block {
+                index.push(DirectoryIndex::new(
+                    written as u32,
+                    position.block(),
+                    &first.name,
+                ));
+                block = position.block();
+            }
+            let header = DirectoryHeader::new(
+                entries.len() as u32,
+                first.inode.block(),
+                first.inode_number,
+            );
+            written += header.write_to(&mut self.table)?;
+            for entry in entries {
+                let inode_offset = (entry.inode_number as i64 - first.inode_number as i64) as i16;
+                written += DirectoryEntry::new(
+                    &header,
+                    entry.inode.offset(),
+                    inode_offset,
+                    entry.file_type,
+                    &entry.name,
+                )
+                .write_to(&mut self.table)?;
+            }
+        }
+        let file_size = u32::try_from(written + 3)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "directory listing too large"))?;
+        Ok(DirectoryListing {
+            start,
+            file_size,
+            index,
+        })
+    }
+
+    pub fn finish(self) -> Result<Vec<u8>> {
+        self.table.finish()
+    }
+}
+
+/// Directory inode to write for a listing from `DirectoryWriter`. It is
+/// written as a basic directory inode unless the listing has an index, is
+/// too large for one, or there are xattrs.
+#[derive(Clone, Debug)]
+pub struct NewDirectoryInode {
+    pub mode: u16,
+    /// Index into the id table.
+    pub uid: u16,
+    /// Index into the id table.
+    pub gid: u16,
+    pub mtime: u32,
+    pub inode_number: u32,
+    /// 2 plus the number of subdirectories.
+    pub nlink: u32,
+    pub parent_inode: u32,
+    /// Index into the xattr id table, or `INVALID_XATTR`.
+    pub xattr: u32,
+}
+
+impl NewDirectoryInode {
+    pub fn write_to<W: Write + ?Sized>(
+        &self,
+        listing: &DirectoryListing,
+        writer: &mut W,
+    ) -> Result<u64> {
+        let basic = listing.index.is_empty()
+            && listing.file_size <= u16::MAX as u32
+            && self.xattr == INVALID_XATTR;
+        let mut inode = Vec::with_capacity(LDIRECTORY_INODE_HEADER_SIZE);
+        let inode_type: u16 = if basic { 1 } else { 8 };
+        inode.extend_from_slice(&inode_type.to_le_bytes());
+        inode.extend_from_slice(&self.mode.to_le_bytes());
+        inode.extend_from_slice(&self.uid.to_le_bytes());
+        inode.extend_from_slice(&self.gid.to_le_bytes());
+        inode.extend_from_slice(&self.mtime.to_le_bytes());
+        inode.extend_from_slice(&self.inode_number.to_le_bytes());
+        if basic {
+            inode.extend_from_slice(&listing.start.block().to_le_bytes());
+            inode.extend_from_slice(&self.nlink.to_le_bytes());
+            inode.extend_from_slice(&(listing.file_size as u16).to_le_bytes());
+            inode.extend_from_slice(&listing.start.offset().to_le_bytes());
+            inode.extend_from_slice(&self.parent_inode.to_le_bytes());
+        } else {
+            inode.extend_from_slice(&self.nlink.to_le_bytes());
+            inode.extend_from_slice(&listing.file_size.to_le_bytes());
+            inode.extend_from_slice(&listing.start.block().to_le_bytes());
+            inode.extend_from_slice(&self.parent_inode.to_le_bytes());
+            inode.extend_from_slice(&(listing.index.len() as u16).to_le_bytes());
+            inode.extend_from_slice(&listing.start.offset().to_le_bytes());
+            inode.extend_from_slice(&self.xattr.to_le_bytes());
+            for index in &listing.index {
+                index.write_to(&mut inode)?;
+            }
+        }
+        writer.write_all(&inode)?;
+        Ok(inode.len() as u64)
+    }
+}
+
+// Splits sorted entries into the runs sharing a header, for a listing
+// starting `offset` bytes into its metadata block. A header covers up to
+// 256 entries whose inodes share a block and whose inode numbers fit in an
+// i16 offset. A new header also starts in each metadata block, for the index
+// to point to.
+fn header_groups(offset: usize, entries: &[NewDirectoryEntry]) -> Vec<Range<usize>> {
+    let mut groups: Vec<Range<usize>> = vec![];
+    let mut header = offset;
+    let mut end = offset;
+    for (i, entry) in entries.iter().enumerate() {
+        let split = match groups.last() {
+            None => true,
+            Some(group) => {
+                let first = &entries[group.start];
+                let inode_offset = entry.inode_number as i64 - first.inode_number as i64;
+                group.len() > DIRECTORY_HEADER_MAX_COUNT as usize
+                    || entry.inode.block() != first.inode.block()
+                    || i16::try_from(inode_offset).is_err()
+                    || end / METADATA_SIZE != header / METADATA_SIZE
+            }
+        };
+        if split {
+            groups.push(i..i);
+            header = end;
+            end += DIRECTORY_HEADER_SIZE;
+        }
+        if let Some(group) = groups.last_mut() {
+            group.end += 1;
+        }
+        end += DIRECTORY_ENTRY_SIZE + entry.name.len();
+    }
+    groups
+}
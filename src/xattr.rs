@@ -0,0 +1,421 @@
+use std::fmt::Display;
+use std::io::{Error, ErrorKind, Read, Result};
+
+use bitflags::bitflags;
+
+use crate::image::Image;
+use crate::inode::InodeRef;
+use crate::utils::get_set_field_tuple;
+use crate::ReadSeek;
+
+// struct squashfs_xattr_id {
+// 	0 8 long long			xattr;
+// 	8 4 unsigned int		count;
+// 	12 4 unsigned int		size;
+// };
+
+pub const XATTR_ID_ENTRY_SIZE: usize = 16;
+
+/// Entry of the xattr id table, locating the xattrs of one or more inodes
+/// in the key/value store.
+#[derive(Clone, Debug)]
+pub struct XattrId([u8; XATTR_ID_ENTRY_SIZE]);
+
+const _: () = assert!(std::mem::size_of::<XattrId>() == XATTR_ID_ENTRY_SIZE);
+
+impl XattrId {
+    pub fn new(entry: [u8; XATTR_ID_ENTRY_SIZE]) -> Self {
+        Self(entry)
+    }
+
+    /// Location of the first key in the key/value store.
+    pub fn inode_ref(&self) -> InodeRef {
+        InodeRef::from(self.xattr())
+    }
+
+    get_set_field_tuple!(xattr, set_xattr, u64, 0, 8);
+    get_set_field_tuple!(count, set_count, u32, 8, 4);
+    get_set_field_tuple!(size, set_size, u32, 12, 4);
+}
+
+// struct squashfs_xattr_entry {
+// 	0 2 unsigned short		type;
+// 	2 2 unsigned short		size;
+// 	char			data[0];
+// };
+//
+// struct squashfs_xattr_val {
+// 	0 4 unsigned int		vsize;
+// 	char			value[0];
+// };
+
+/// Set in the entry type when the value is stored elsewhere in the key/value
+/// store, and the stored value is a reference to it.
+pub const XATTR_VALUE_OOL: u16 = 0x100;
+const XATTR_PREFIX_MASK: u16 = 0xff;
+
+// name prefixes by entry type
+pub(crate) const XATTR_PREFIXES: [&[u8]; 3] = [b"user.", b"trusted.", b"security."];
+
+/// Splits a full xattr name into its entry type and the name as stored,
+/// without the prefix. None if squashfs can't store the namespace.
+#[cfg(feature = "testing")]
+pub(crate) fn split_name(name: &[u8]) -> Option<(u16, &[u8])> {
+    XATTR_PREFIXES
+        .iter()
+        .zip(0..)
+        .find_map(|(prefix, xattr_type)| Some((xattr_type, name.strip_prefix(*prefix)?)))
+}
+
+bitflags! {
+    /// Set of xattr namespaces, which squashfs stores as name prefixes.
+    #[derive(Default)]
+    pub struct XattrNamespaces: u8 {
+        const USER = 0x01;
+        const TRUSTED = 0x02;
+        const SECURITY = 0x04;
+    }
+}
+
+impl XattrNamespaces {
+    /// Namespace of an xattr by its full name, empty if its prefix isn't
+    /// one squashfs can store.
+    pub fn of(name: &[u8]) -> Self {
+        XATTR_PREFIXES
+            .iter()
+            .zip([Self::USER, Self::TRUSTED, Self::SECURITY])
+            .find(|(prefix, _)| name.starts_with(prefix))
+            .map_or(Self::empty(), |(_, namespace)| namespace)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Xattr {
+    /// Full name, including the namespace prefix.
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl Xattr {
+    pub fn namespace(&self) -> XattrNamespaces {
+        XattrNamespaces::of(&self.name)
+    }
+
+    /// Parses a key/value pair. For out of line values, the returned value
+    /// is the 8 byte reference to the real one.
+    pub(crate) fn from_reader<R: Read + ?Sized>(reader: &mut R) -> Result<(Self, bool)> {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let xattr_type = u16::from_le_bytes([header[0], header[1]]);
+        let name_size = u16::from_le_bytes([header[2], header[3]]);
+        let prefix = XATTR_PREFIXES
+            .get((xattr_type & XATTR_PREFIX_MASK) as usize)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown xattr type {}", xattr_type),
+                )
+            })?;
+
+        let mut name = prefix.to_vec();
+        name.resize(prefix.len() + name_size as usize, 0);
+        reader.read_exact(&mut name[prefix.len()..])?;
+
+        let mut value_size = [0; 4];
+        reader.read_exact(&mut value_size)?;
+        let value_size = u32::from_le_bytes(value_size) as u64;
+        let mut value = vec![];
+        reader.take(value_size).read_to_end(&mut value)?;
+        if value.len() as u64 != value_size {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "truncated xattr value",
+            ));
+        }
+        Ok((Self { name, value }, xattr_type & XATTR_VALUE_OOL != 0))
+    }
+}
+
+pub const CAPABILITY_XATTR: &[u8] = b"security.capability";
+
+const VFS_CAP_REVISION_MASK: u32 = 0xff00_0000;
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x0000_0001;
+
+const CAPABILITY_NAMES: [&str; 41] = [
+    "cap_chown",
+    "cap_dac_override",
+    "cap_dac_read_search",
+    "cap_fowner",
+    "cap_fsetid",
+    "cap_kill",
+    "cap_setgid",
+    "cap_setuid",
+    "cap_setpcap",
+    "cap_linux_immutable",
+    "cap_net_bind_service",
+    "cap_net_broadcast",
+    "cap_net_admin",
+    "cap_net_raw",
+    "cap_ipc_lock",
+    "cap_ipc_owner",
+    "cap_sys_module",
+    "cap_sys_rawio",
+    "cap_sys_chroot",
+    "cap_sys_ptrace",
+    "cap_sys_pacct",
+    "cap_sys_admin",
+    "cap_sys_boot",
+    "cap_sys_nice",
+    "cap_sys_resource",
+    "cap_sys_time",
+    "cap_sys_tty_config",
+    "cap_mknod",
+    "cap_lease",
+    "cap_audit_write",
+    "cap_audit_control",
+    "cap_setfcap",
+    "cap_mac_override",
+    "cap_mac_admin",
+    "cap_syslog",
+    "cap_wake_alarm",
+    "cap_block_suspend",
+    "cap_audit_read",
+    "cap_perfmon",
+    "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+/// Decoded `security.capability` value (struct vfs_cap_data).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileCapabilities {
+    /// 1, 2 or 3.
+    pub version: u8,
+    /// Whether permitted capabilities are raised in the effective set on
+    /// exec. File capabilities have a single effective bit, not a set.
+    pub effective: bool,
+    /// Bit n is capability n, as in `CAP_*`.
+    pub permitted: u64,
+    pub inheritable: u64,
+    /// Namespace root uid, for version 3 capabilities.
+    pub rootid: Option<u32>,
+}
+
+impl FileCapabilities {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let u32_at = |i: usize| -> Result<u32> {
+            match bytes.get(i..i + 4) {
+                Some(b) => Ok(u32::from_le_bytes(b.try_into().unwrap())),
+                None => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "truncated capability xattr",
+                )),
+            }
+        };
+        let magic = u32_at(0)?;
+        let version = ((magic & VFS_CAP_REVISION_MASK) >> 24) as u8;
+        let (words, size) = match version {
+            1 => (1, 12),
+            2 => (2, 20),
+            3 => (2, 24),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown capability revision {:#x}", magic),
+                ))
+            }
+        };
+        if bytes.len() != size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "capability xattr is {} bytes, expected {}",
+                    bytes.len(),
+                    size
+                ),
+            ));
+        }
+
+        let mut permitted = 0;
+        let mut inheritable = 0;
+        for i in 0..words {
+            permitted |= (u32_at(4 + i * 8)? as u64) << (32 * i);
+            inheritable |= (u32_at(8 + i * 8)? as u64) << (32 * i);
+        }
+        Ok(Self {
+            version,
+            effective: magic & VFS_CAP_FLAGS_EFFECTIVE != 0,
+            permitted,
+            inheritable,
+            rootid: match version {
+                3 => Some(u32_at(20)?),
+                _ => None,
+            },
+        })
+    }
+
+    /// Capabilities raised in the effective set on exec.
+    pub fn effective_set(&self) -> u64 {
+        match self.effective {
+            true => self.permitted,
+            false => 0,
+        }
+    }
+}
+
+fn write_capability_names(f: &mut std::fmt::Formatter<'_>, set: u64) -> std::fmt::Result {
+    let mut first = true;
+    for bit in (0..64).filter(|bit| set & (1 << bit) != 0) {
+        if !first {
+            write!(f, ",")?;
+        }
+        first = false;
+        match CAPABILITY_NAMES.get(bit) {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "{}", bit)?,
+        }
+    }
+    Ok(())
+}
+
+impl Display for FileCapabilities {
+    /// Formats like getcap, e.g. `cap_net_admin,cap_net_raw=ep`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let both = self.permitted & self.inheritable;
+        let mut sets: Vec<(u64, String)> = vec![];
+        for (set, flags) in [
+            (both, "ip"),
+            (self.permitted & !both, "p"),
+            (self.inheritable & !both, "i"),
+        ] {
+            if set != 0 {
+                let mut flags = flags.to_string();
+                if self.effective && flags.contains('p') {
+                    flags.insert(0, 'e');
+                }
+                sets.push((set, flags));
+            }
+        }
+        for (i, (set, flags)) in sets.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write_capability_names(f, *set)?;
+            write!(f, "={}", flags)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lists the paths in the image carrying file capabilities.
+pub fn capabilities<R: ReadSeek>(image: &Image<R>) -> Result<Vec<(Vec<u8>, FileCapabilities)>> {
+    let mut found = vec![];
+    for entry in image.walk("/")? {
+        let entry = entry?;
+        let xattrs = image.xattrs(&entry.inode)?;
+        if let Some(xattr) = xattrs.iter().find(|x| x.name == CAPABILITY_XATTR) {
+            found.push((
+                entry.path.into(),
+                FileCapabilities::from_bytes(&xattr.value)?,
+            ));
+        }
+    }
+    Ok(found)
+}
+
+pub const ACL_ACCESS_XATTR: &[u8] = b"system.posix_acl_access";
+pub const ACL_DEFAULT_XATTR: &[u8] = b"system.posix_acl_default";
+
+const POSIX_ACL_XATTR_VERSION: u32 = 2;
+
+// struct posix_acl_xattr_entry {
+// 	0 2 unsigned short		e_tag;
+// 	2 2 unsigned short		e_perm;
+// 	4 4 unsigned int		e_id;
+// };
+const ACL_ENTRY_SIZE: usize = 8;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AclTag {
+    UserObj,
+    User(u32),
+    GroupObj,
+    Group(u32),
+    Mask,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    /// `rwx` bits, 4 is read.
+    pub perm: u16,
+}
+
+/// Decoded `system.posix_acl_access` or `system.posix_acl_default` value.
+///
+/// Squashfs only has room for the user, trusted and security namespaces, so
+/// images never carry these themselves; this decodes values obtained
+/// elsewhere, such as from the tree an image was built from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PosixAcl(pub Vec<AclEntry>);
+
+impl PosixAcl {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, format!("bad acl: {}", msg));
+        if bytes.len() < 4 || !(bytes.len() - 4).is_multiple_of(ACL_ENTRY_SIZE) {
+            return Err(invalid("bad size"));
+        }
+        if u32::from_le_bytes(bytes[..4].try_into().unwrap()) != POSIX_ACL_XATTR_VERSION {
+            return Err(invalid("unknown version"));
+        }
+        let mut entries = vec![];
+        for entry in bytes[4..].chunks_exact(ACL_ENTRY_SIZE) {
+            let id = u32::from_le_bytes(entry[4..].try_into().unwrap());
+            let tag = match u16::from_le_bytes([entry[0], entry[1]]) {
+                ACL_USER_OBJ => AclTag::UserObj,
+                ACL_USER => AclTag::User(id),
+                ACL_GROUP_OBJ => AclTag::GroupObj,
+                ACL_GROUP => AclTag::Group(id),
+                ACL_MASK => AclTag::Mask,
+                ACL_OTHER => AclTag::Other,
+                _ => return Err(invalid("unknown tag")),
+            };
+            entries.push(AclEntry {
+                tag,
+                perm: u16::from_le_bytes([entry[2], entry[3]]),
+            });
+        }
+        Ok(Self(entries))
+    }
+}
+
+impl Display for PosixAcl {
+    /// Formats like `getfacl -c`, on one line, e.g.
+    /// `user::rwx,user:1000:r-x,group::r-x,mask::r-x,other::---`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, entry) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            match entry.tag {
+                AclTag::UserObj => write!(f, "user::")?,
+                AclTag::User(id) => write!(f, "user:{}:", id)?,
+                AclTag::GroupObj => write!(f, "group::")?,
+                AclTag::Group(id) => write!(f, "group:{}:", id)?,
+                AclTag::Mask => write!(f, "mask::")?,
+                AclTag::Other => write!(f, "other::")?,
+            }
+            for (bit, c) in [(4, 'r'), (2, 'w'), (1, 'x')] {
+                write!(f, "{}", if entry.perm & bit != 0 { c } else { '-' })?;
+            }
+        }
+        Ok(())
+    }
+}
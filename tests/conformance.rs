@@ -0,0 +1,339 @@
+//! Checks the writer and the reader against squashfs-tools. The tests need
+//! `mksquashfs` and `unsquashfs` in the path, so they're ignored by default:
+//!
+//!     cargo test --features testing --test conformance -- --ignored
+#![cfg(all(unix, feature = "testing"))]
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use squashfs::compressors::Compressor;
+use squashfs::image::Image;
+use squashfs::inode::{FileType, InodeHeader};
+use squashfs::testing::{generate, GenerateOptions, Spec};
+use squashfs::ReadSeek;
+
+const BLOCK_SIZES: [u32; 3] = [4096, 128 * 1024, 1 << 20];
+
+// File type and permissions, owner and size (regular files only), keyed by
+// path without a leading `/`.
+type Manifest = BTreeMap<String, (String, String, Option<u64>)>;
+
+fn compressors() -> [(&'static str, Compressor); 3] {
+    [
+        ("gzip", Compressor::GZIP(Default::default())),
+        ("xz", Compressor::XZ(Default::default())),
+        ("zstd", Compressor::ZSTD(Default::default())),
+    ]
+}
+
+// Scratch directory, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("sqsh-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn run(command: &mut Command) -> String {
+    let output = command
+        .output()
+        .unwrap_or_else(|e| panic!("can't run {:?}: {}", command, e));
+    assert!(
+        output.status.success(),
+        "{:?} failed: {:?}",
+        command,
+        output
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// `ls -l` style type and permissions, as printed by `unsquashfs -lln`.
+fn permissions(file_type: FileType, mode: u16) -> String {
+    let mut perms = String::from(match file_type {
+        FileType::Directory => 'd',
+        FileType::Regular => '-',
+        FileType::Symlink => 'l',
+        FileType::BlockDevice => 'b',
+        FileType::CharDevice => 'c',
+        FileType::Fifo => 'p',
+        FileType::Socket => 's',
+    });
+    for (shift, special, set, unset) in [
+        (6, 0o4000, 's', 'S'),
+        (3, 0o2000, 's', 'S'),
+        (0, 0o1000, 't', 'T'),
+    ] {
+        let bits = mode >> shift;
+        perms.push(if bits & 4 != 0 { 'r' } else { '-' });
+        perms.push(if bits & 2 != 0 { 'w' } else { '-' });
+        perms.push(match (mode & special != 0, bits & 1 != 0) {
+            (true, true) => set,
+            (true, false) => unset,
+            (false, true) => 'x',
+            (false, false) => '-',
+        });
+    }
+    perms
+}
+
+fn manifest<R: ReadSeek>(image: &Image<R>) -> Manifest {
+    let ids = image.id_table().unwrap();
+    let mut manifest = Manifest::new();
+    for entry in image.walk("/").unwrap() {
+        let entry = entry.unwrap();
+        let inode = &entry.inode;
+        let size = match inode {
+            InodeHeader::Regular(r) => Some(r.file_size() as u64),
+            InodeHeader::LRegular(r) => Some(r.file_size()),
+            _ => None,
+        };
+        let owner = format!(
+            "{}/{}",
+            ids.get(inode.uid()).unwrap(),
+            ids.get(inode.guid()).unwrap()
+        );
+        let path = entry.path_lossy().trim_start_matches('/').to_string();
+        manifest.insert(
+            path,
+            (permissions(inode.file_type(), inode.mode()), owner, size),
+        );
+    }
+    manifest
+}
+
+fn unsquashfs_manifest(image: &Path) -> Manifest {
+    let listing = run(Command::new("unsquashfs").arg("-lln").arg(image));
+    let mut manifest = Manifest::new();
+    for line in listing.lines() {
+        let mut fields = line.split_whitespace();
+        let Some(perms) = fields.next() else { continue };
+        if perms.len() != 10 {
+            // summary lines
+            continue;
+        }
+        let owner = fields.next().unwrap().to_string();
+        let size = if perms.starts_with(['b', 'c']) {
+            // major, minor
+            fields.nth(1);
+            None
+        } else {
+            let size: u64 = fields.next().unwrap().parse().unwrap();
+            perms.starts_with('-').then_some(size)
+        };
+        // date and time
+        let path = fields.skip(2).collect::<Vec<_>>().join(" ");
+        let path = path.split(" -> ").next().unwrap();
+        let path = path.strip_prefix("squashfs-root").unwrap();
+        manifest.insert(
+            path.trim_start_matches('/').to_string(),
+            (perms.to_string(), owner, size),
+        );
+    }
+    manifest
+}
+
+fn sample_tree() -> Spec {
+    let data: Vec<u8> = (0..2_500_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut sparse = vec![0; 3 << 20];
+    sparse[100] = 1;
+    let many: Vec<_> = (0..1500)
+        .map(|i| (format!("f{:04}", i), Spec::file(format!("{}", i))))
+        .collect();
+    Spec::dir([
+        (
+            "bin",
+            Spec::dir([
+                ("su", Spec::file(&data[..5000]).with_mode(0o4755)),
+                ("sh", Spec::symlink("../usr/bin/sh")),
+            ]),
+        ),
+        ("data", Spec::file(data).with_owner(1000, 100)),
+        ("sparse", Spec::file(sparse)),
+        ("empty", Spec::file("")),
+        ("many", Spec::dir(many)),
+        (
+            "dev",
+            Spec::dir([
+                ("null", Spec::char_device(0x0103)),
+                ("sda", Spec::block_device(0x0800)),
+                ("fifo", Spec::fifo()),
+                ("sock", Spec::socket()),
+            ]),
+        ),
+        (
+            "tmp",
+            Spec::dir(Vec::<(&str, Spec)>::new()).with_mode(0o1777),
+        ),
+    ])
+}
+
+// Images from the writer list and extract the same with unsquashfs.
+#[test]
+#[ignore = "needs squashfs-tools"]
+fn writer_matches_unsquashfs() {
+    let tree = sample_tree();
+    for (name, compressor) in compressors() {
+        for block_size in BLOCK_SIZES {
+            for fragments in [true, false] {
+                let options = GenerateOptions {
+                    compressor: compressor.clone(),
+                    block_size,
+                    fragments,
+                    ..Default::default()
+                };
+                let bytes = generate(&tree, &options).unwrap();
+                let dir = TempDir::new(&format!("writer-{}-{}-{}", name, block_size, fragments));
+                let path = dir.0.join("image.sqfs");
+                fs::write(&path, &bytes).unwrap();
+
+                let image = Image::new(Cursor::new(bytes)).unwrap();
+                assert_eq!(
+                    manifest(&image),
+                    unsquashfs_manifest(&path),
+                    "{} {} {}",
+                    name,
+                    block_size,
+                    fragments
+                );
+
+                // devices need root to extract
+                let out = dir.0.join("out");
+                run(Command::new("unsquashfs")
+                    .arg("-no-xattrs")
+                    .arg("-d")
+                    .arg(&out)
+                    .arg(&path)
+                    .arg("data")
+                    .arg("sparse")
+                    .arg("many"));
+                for file in ["data", "sparse", "many/f1234"] {
+                    let inode = image.lookup(file).unwrap();
+                    let mut data = vec![];
+                    image
+                        .open_file(&inode)
+                        .unwrap()
+                        .read_to_end(&mut data)
+                        .unwrap();
+                    assert_eq!(data, fs::read(out.join(file)).unwrap(), "{}", file);
+                }
+            }
+        }
+    }
+}
+
+fn source_tree(root: &Path) {
+    let data: Vec<u8> = (0..700_000u32).map(|i| (i * 13 % 241) as u8).collect();
+    fs::create_dir_all(root.join("a/b/c")).unwrap();
+    fs::create_dir_all(root.join("many")).unwrap();
+    fs::write(root.join("a/data"), &data).unwrap();
+    fs::write(root.join("a/b/small"), b"small").unwrap();
+    fs::write(root.join("a/b/c/empty"), b"").unwrap();
+    fs::set_permissions(root.join("a/b/small"), fs::Permissions::from_mode(0o4750)).unwrap();
+    symlink("../data", root.join("a/b/link")).unwrap();
+    for i in 0..1200 {
+        fs::write(root.join(format!("many/{:05}", i)), i.to_string()).unwrap();
+    }
+}
+
+// The reader lists mksquashfs images like unsquashfs, and reads back the
+// files they were made from.
+#[test]
+#[ignore = "needs squashfs-tools"]
+fn reader_matches_mksquashfs() {
+    let dir = TempDir::new("reader");
+    let source = dir.0.join("source");
+    source_tree(&source);
+    for (name, _) in compressors() {
+        for block_size in BLOCK_SIZES {
+            let path = dir.0.join(format!("{}-{}.sqfs", name, block_size));
+            run(Command::new("mksquashfs")
+                .arg(&source)
+                .arg(&path)
+                .args(["-noappend", "-no-progress", "-comp", name])
+                .arg("-b")
+                .arg(block_size.to_string()));
+
+            let image = Image::new(fs::File::open(&path).unwrap()).unwrap();
+            assert!(image.warnings().is_empty(), "{:?}", image.warnings());
+            assert_eq!(
+                manifest(&image),
+                unsquashfs_manifest(&path),
+                "{} {}",
+                name,
+                block_size
+            );
+            assert_eq!(image.verify_against(&source).unwrap(), []);
+        }
+    }
+}
+
+// The reader returns the xattrs mksquashfs stored, with values long enough
+// to be stored out of line and lists spanning metadata blocks.
+#[test]
+#[ignore = "needs squashfs-tools"]
+fn xattrs_match_mksquashfs() {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = TempDir::new("xattrs");
+    let source = dir.0.join("source");
+    fs::create_dir_all(&source).unwrap();
+    let long: Vec<u8> = (0..300u32).map(|i| (i % 251) as u8).collect();
+    let mut expected = BTreeMap::new();
+    for i in 0..300 {
+        let path = source.join(format!("{:03}", i));
+        fs::write(&path, i.to_string()).unwrap();
+        let xattrs = [
+            (b"user.index".to_vec(), format!("{:040}", i).into_bytes()),
+            (b"user.long".to_vec(), long.clone()),
+        ];
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        for (name, value) in &xattrs {
+            let c_name = CString::new(name.clone()).unwrap();
+            let set = unsafe {
+                libc::lsetxattr(
+                    c_path.as_ptr(),
+                    c_name.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    0,
+                )
+            };
+            assert_eq!(set, 0, "{}", std::io::Error::last_os_error());
+        }
+        expected.insert(format!("{:03}", i), xattrs.to_vec());
+    }
+
+    let path = dir.0.join("xattrs.sqfs");
+    run(Command::new("mksquashfs").arg(&source).arg(&path).args([
+        "-noappend",
+        "-no-progress",
+        "-xattrs",
+    ]));
+    let image = Image::new(fs::File::open(&path).unwrap()).unwrap();
+    for (name, xattrs) in expected {
+        let mut read: Vec<_> = image
+            .xattrs(&image.lookup(&name).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.name, x.value))
+            .collect();
+        read.sort();
+        assert_eq!(read, xattrs, "{}", name);
+    }
+}
@@ -0,0 +1,220 @@
+//! Loop-mounts generated images with the kernel driver and checks it sees
+//! the same tree as the crate, to catch misreadings of the format the two
+//! sides of the unit tests would share. The tests need root and loop
+//! devices on Linux, so they're ignored by default:
+//!
+//!     sudo -E cargo test --features testing --test kernel -- --ignored
+#![cfg(all(target_os = "linux", feature = "testing"))]
+
+use std::fs;
+use std::io::{Cursor, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use squashfs::compressors::Compressor;
+use squashfs::image::Image;
+use squashfs::inode::{FileType, InodeHeader};
+use squashfs::testing::{generate, GenerateOptions, Spec};
+
+// Image loop-mounted read-only, unmounted and removed when dropped.
+struct Mount {
+    dir: PathBuf,
+    mountpoint: PathBuf,
+}
+
+impl Mount {
+    fn new(name: &str, image: &[u8]) -> Self {
+        let dir = std::env::temp_dir().join(format!("sqsh-mount-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mountpoint = dir.join("mnt");
+        fs::create_dir_all(&mountpoint).unwrap();
+        let path = dir.join("image.sqfs");
+        fs::write(&path, image).unwrap();
+        let status = Command::new("mount")
+            .args(["-t", "squashfs", "-o", "loop,ro"])
+            .arg(&path)
+            .arg(&mountpoint)
+            .status()
+            .unwrap();
+        assert!(status.success(), "mounting {} failed", name);
+        Self { dir, mountpoint }
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        let _ = Command::new("umount").arg(&self.mountpoint).status();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn sample_tree() -> Spec {
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut sparse = vec![0; 1 << 20];
+    sparse[70_000] = 1;
+    let many: Vec<_> = (0..700)
+        .map(|i| (format!("f{:04}", i), Spec::file(format!("{}", i))))
+        .collect();
+    Spec::dir([
+        (
+            "bin",
+            Spec::dir([
+                ("su", Spec::file(&data[..5000]).with_mode(0o4755)),
+                ("sh", Spec::symlink("../usr/bin/sh")),
+            ]),
+        ),
+        (
+            "data",
+            Spec::file(data)
+                .with_owner(1000, 100)
+                .with_mtime(1_600_000_000),
+        ),
+        ("sparse", Spec::file(sparse)),
+        ("empty", Spec::file("")),
+        ("many", Spec::dir(many)),
+        (
+            "dev",
+            Spec::dir([
+                ("null", Spec::char_device(0x0103)),
+                ("sda", Spec::block_device(0x0800)),
+                // major 1, minor 0x123, which needs the high minor bits
+                ("high", Spec::char_device(0x12_0123)),
+                ("fifo", Spec::fifo()),
+                ("sock", Spec::socket().with_owner(7, 8)),
+            ]),
+        ),
+        ("tmp", Spec::dir::<&str>([]).with_mode(0o1777)),
+    ])
+}
+
+fn file_type(metadata: &fs::Metadata) -> FileType {
+    let t = metadata.file_type();
+    match () {
+        _ if t.is_dir() => FileType::Directory,
+        _ if t.is_file() => FileType::Regular,
+        _ if t.is_symlink() => FileType::Symlink,
+        _ if t.is_block_device() => FileType::BlockDevice,
+        _ if t.is_char_device() => FileType::CharDevice,
+        _ if t.is_fifo() => FileType::Fifo,
+        _ => FileType::Socket,
+    }
+}
+
+// Compares what the kernel shows of every entry under `root` with what the
+// crate reads of the image.
+fn compare(image: &Image<Cursor<Vec<u8>>>, root: &Path, name: &str) {
+    let ids = image.id_table().unwrap();
+    for entry in image.walk("/").unwrap() {
+        let entry = entry.unwrap();
+        let inode = &entry.inode;
+        let path = root.join(std::ffi::OsStr::from_bytes(
+            entry.path.as_bytes().strip_prefix(b"/").unwrap(),
+        ));
+        let at = format!("{} {}", name, entry.path);
+        let metadata = fs::symlink_metadata(&path).unwrap();
+
+        assert_eq!(file_type(&metadata), inode.file_type(), "{}", at);
+        assert_eq!(
+            metadata.mode() & 0o7777,
+            inode.mode() as u32 & 0o7777,
+            "{}",
+            at
+        );
+        assert_eq!(
+            (metadata.uid(), metadata.gid()),
+            ids.owner(inode).unwrap(),
+            "{}",
+            at
+        );
+        assert_eq!(metadata.mtime(), inode.mtime() as i64, "{}", at);
+        assert_eq!(metadata.ino(), inode.inode_number() as u64, "{}", at);
+        match inode {
+            InodeHeader::Directory(d) => {
+                assert_eq!(metadata.nlink(), d.nlink() as u64, "{}", at);
+                assert_eq!(metadata.size(), d.file_size() as u64, "{}", at);
+                let mut names: Vec<_> = fs::read_dir(&path)
+                    .unwrap()
+                    .map(|e| e.unwrap().file_name().as_bytes().to_vec())
+                    .collect();
+                names.sort();
+                let listed: Vec<_> = image
+                    .read_dir(inode)
+                    .unwrap()
+                    .iter()
+                    .map(|e| e.name().to_vec())
+                    .collect();
+                assert_eq!(names, listed, "{}", at);
+            }
+            InodeHeader::LDirectory(d) => {
+                assert_eq!(metadata.nlink(), d.nlink() as u64, "{}", at);
+                assert_eq!(metadata.size(), d.file_size() as u64, "{}", at);
+            }
+            InodeHeader::Regular(_) | InodeHeader::LRegular(_) => {
+                let mut data = vec![];
+                image
+                    .open_file(inode)
+                    .unwrap()
+                    .read_to_end(&mut data)
+                    .unwrap();
+                assert_eq!(metadata.size(), data.len() as u64, "{}", at);
+                assert!(fs::read(&path).unwrap() == data, "{}: contents differ", at);
+            }
+            InodeHeader::Symlink(s) | InodeHeader::LSymlink(s) => {
+                let target = fs::read_link(&path).unwrap();
+                assert_eq!(target.as_os_str().as_bytes(), s.target(), "{}", at);
+            }
+            InodeHeader::Dev(d) => check_rdev(d.rdev(), metadata.rdev(), &at),
+            InodeHeader::LDev(d) => check_rdev(d.rdev(), metadata.rdev(), &at),
+            _ => {}
+        }
+    }
+}
+
+// Device numbers are stored as the kernel encodes them in 32 bits.
+fn check_rdev(rdev: u32, kernel: u64, at: &str) {
+    let (major, minor) = (
+        (rdev & 0xfff00) >> 8,
+        (rdev & 0xff) | ((rdev >> 12) & 0xfff00),
+    );
+    assert_eq!(
+        (libc::major(kernel), libc::minor(kernel)),
+        (major, minor),
+        "{}",
+        at
+    );
+}
+
+// What the kernel shows of generated images matches what the crate reads.
+#[test]
+#[ignore = "needs root and loop devices"]
+fn kernel_matches_reader() {
+    if unsafe { libc::geteuid() } != 0 {
+        eprintln!("skipped: mounting needs root");
+        return;
+    }
+    let tree = sample_tree();
+    let compressors = [
+        ("gzip", Compressor::GZIP(Default::default())),
+        ("xz", Compressor::XZ(Default::default())),
+        ("zstd", Compressor::ZSTD(Default::default())),
+    ];
+    for (name, compressor) in compressors {
+        for block_size in [4096, 128 * 1024] {
+            for fragments in [true, false] {
+                let options = GenerateOptions {
+                    compressor: compressor.clone(),
+                    block_size,
+                    fragments,
+                    ..Default::default()
+                };
+                let bytes = generate(&tree, &options).unwrap();
+                let name = format!("{}-{}-{}", name, block_size, fragments);
+                let mount = Mount::new(&name, &bytes);
+                let image = Image::new(Cursor::new(bytes)).unwrap();
+                compare(&image, &mount.mountpoint, &name);
+            }
+        }
+    }
+}